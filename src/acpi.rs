@@ -0,0 +1,415 @@
+/*
+* The firmware describes the hardware that can't be discovered by probing in the ACPI tables: the CPUs
+* and interrupt controllers (MADT), the power management registers (FADT), timers... Every table starts
+* with the same header (signature, length, checksum) and the root table (RSDT with 32 bit pointers, or XSDT
+* with 64 bit pointers since ACPI 2.0) lists the physical addresses of all the others.
+*
+* The root table is found through the ROOT_SYSTEM_DESCRIPTION_POINTER (RSDP). bootloader 0.9 doesn't
+* pass its address so we search for its signature "RSD PTR " on 16 byte boundaries in the first KiB of the
+* EXTENDED_BIOS_DATA_AREA and in the BIOS area 0xE0000-0xFFFFF, like the ACPI specification says.
+*
+* The tables are in RAM that the bootloader mapped with the physical memory, they are parsed once at
+* boot into the types below. Tables with a wrong checksum are ignored.
+* */
+use crate::apic::InterruptOverride;
+use crate::memory;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const HEADER_SIZE: usize = 36;
+// the real mode segment of the EBDA is stored at this address in the BIOS data area
+const EBDA_SEGMENT_POINTER: u64 = 0x40E;
+const BIOS_AREA: core::ops::Range<u64> = 0xE0000..0x100000;
+
+// a processor with its local APIC (MADT entry type 0, or 9 for x2APIC ids)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_id: u32,
+    pub apic_id: u32,
+    // usable now, the others can only be hot plugged if online_capable is set
+    pub enabled: bool,
+    pub online_capable: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    // the first GSI of its inputs
+    pub gsi_base: u32,
+}
+
+// MULTIPLE_APIC_DESCRIPTION_TABLE (signature "APIC")
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    // the machine also has the 8259 PICs (which have to be masked when using the APIC)
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+// the location of a register, in memory or in the I/O port space
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8, // 0 memory, 1 I/O port, 2 PCI configuration space
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+// FIXED_ACPI_DESCRIPTION_TABLE (signature "FACP"), only the fields the kernel uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub dsdt: u64,
+    pub sci_interrupt: u16,
+    // writing acpi_enable to this port switches the machine to ACPI mode (0 if it is always in it)
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm_timer_block: u32,
+    // the CMOS register of the RTC century, 0 if there is none
+    pub century_register: u8,
+    // IA-PC boot architecture flags, bit 1 is set if there is a PS/2 controller
+    pub boot_architecture_flags: u16,
+    pub flags: u32,
+    // only set if the reset register is supported (flags bit 10)
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+}
+
+pub struct AcpiTables {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    // the signature and physical address of every table listed by the root table
+    pub tables: Vec<([u8; 4], PhysAddr)>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+}
+
+impl AcpiTables {
+    // the physical address of the table, the DSDT isn't in the root table but referenced by the FADT
+    pub fn find(&self, signature: &[u8; 4]) -> Option<PhysAddr> {
+        if signature == b"DSDT" {
+            return self.fadt.map(|fadt| PhysAddr::new(fadt.dsdt));
+        }
+        self.tables
+            .iter()
+            .find(|(table, _)| table == signature)
+            .map(|&(_, address)| address)
+    }
+}
+
+static TABLES: OnceCell<AcpiTables> = OnceCell::uninit();
+
+// the parsed tables, None if there are no ACPI tables or init didn't run yet
+pub fn tables() -> Option<&'static AcpiTables> {
+    TABLES.try_get().ok()
+}
+
+pub fn madt() -> Option<&'static Madt> {
+    tables()?.madt.as_ref()
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    tables()?.fadt.as_ref()
+}
+
+// the bytes of the table including its header, e.g. the AML code of the DSDT
+pub fn table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let address = tables()?.find(signature)?;
+    unsafe { table_bytes(address.as_u64()) }
+}
+
+fn read_u8(bytes: &[u8], offset: usize) -> u8 {
+    bytes.get(offset).copied().unwrap_or(0)
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([read_u8(bytes, offset), read_u8(bytes, offset + 1)])
+}
+
+// fields past the end of the table (e.g. in an old FADT revision) read as 0
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+/*
+* The physical memory at the address as a slice.
+* Unsafe because the range must be mapped by the physical memory mapping.
+* */
+unsafe fn physical_bytes(address: u64, len: usize) -> &'static [u8] {
+    let virt = memory::phys_to_virt(PhysAddr::new(address));
+    core::slice::from_raw_parts(virt.as_ptr(), len)
+}
+
+// the complete table at the address, None if it doesn't have the expected size or checksum
+unsafe fn table_bytes(address: u64) -> Option<&'static [u8]> {
+    let header = physical_bytes(address, HEADER_SIZE);
+    let len = read_u32(header, 4) as usize;
+    if len < HEADER_SIZE {
+        return None;
+    }
+    let bytes = physical_bytes(address, len);
+    checksum_ok(bytes).then_some(bytes)
+}
+
+fn valid_rsdp(bytes: &[u8]) -> bool {
+    if &bytes[..8] != RSDP_SIGNATURE || !checksum_ok(&bytes[..20]) {
+        return false;
+    }
+    // revision 2 and later have a second checksum over the extended fields
+    read_u8(bytes, 15) < 2 || checksum_ok(&bytes[..36])
+}
+
+// search the EBDA and the BIOS area for the RSDP, returns its physical address
+fn find_rsdp() -> Option<u64> {
+    let ebda = (read_u16(unsafe { physical_bytes(EBDA_SEGMENT_POINTER, 2) }, 0) as u64) << 4;
+    let areas = [ebda..ebda + 1024, BIOS_AREA];
+    areas
+        .into_iter()
+        // no EBDA if the pointer is 0
+        .filter(|area| area.start != 0)
+        .flat_map(|area| area.step_by(16))
+        .find(|&address| valid_rsdp(unsafe { physical_bytes(address, 36) }))
+}
+
+// find and parse the tables, called after memory::init and the heap initialization
+pub fn init() {
+    let Some(rsdp_address) = find_rsdp() else {
+        log::warn!("acpi: no RSDP found");
+        return;
+    };
+    let rsdp = unsafe { physical_bytes(rsdp_address, 36) };
+    let revision = read_u8(rsdp, 15);
+    let mut oem_id = [0; 6];
+    oem_id.copy_from_slice(&rsdp[9..15]);
+
+    // prefer the XSDT, the RSDT can't point above 4 GiB
+    let xsdt = read_u64(rsdp, 24);
+    let (root, entry_size) = if revision >= 2 && xsdt != 0 {
+        (unsafe { table_bytes(xsdt) }, 8)
+    } else {
+        (unsafe { table_bytes(read_u32(rsdp, 16) as u64) }, 4)
+    };
+    let Some(root) = root else {
+        log::warn!("acpi: invalid root table");
+        return;
+    };
+
+    let mut tables = AcpiTables {
+        revision,
+        oem_id,
+        tables: Vec::new(),
+        madt: None,
+        fadt: None,
+    };
+    for offset in (HEADER_SIZE..root.len()).step_by(entry_size) {
+        let address = match entry_size {
+            8 => read_u64(root, offset),
+            _ => read_u32(root, offset) as u64,
+        };
+        let Some(bytes) = (unsafe { table_bytes(address) }) else {
+            log::warn!("acpi: ignoring invalid table at {:#x}", address);
+            continue;
+        };
+        let signature: [u8; 4] = bytes[..4].try_into().unwrap();
+        match &signature {
+            b"APIC" => tables.madt = Some(parse_madt(bytes)),
+            b"FACP" => tables.fadt = Some(parse_fadt(bytes)),
+            _ => {}
+        }
+        tables.tables.push((signature, PhysAddr::new(address)));
+    }
+
+    log::info!(
+        "acpi: revision {}, oem {}, tables {}",
+        revision,
+        core::str::from_utf8(&oem_id).unwrap_or("?").trim_end(),
+        Signatures(&tables.tables)
+    );
+    let _ = TABLES.try_init_once(|| tables);
+}
+
+// prints the signatures separated by spaces
+struct Signatures<'a>(&'a [([u8; 4], PhysAddr)]);
+
+impl core::fmt::Display for Signatures<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for (index, (signature, _)) in self.0.iter().enumerate() {
+            let separator = if index == 0 { "" } else { " " };
+            write!(
+                f,
+                "{}{}",
+                separator,
+                core::str::from_utf8(signature).unwrap_or("?")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/*
+* The MADT has the address of the local APICs and the flags, followed by variable length entries
+* that start with their type and length.
+* */
+fn parse_madt(bytes: &[u8]) -> Madt {
+    let mut madt = Madt {
+        local_apic_address: read_u32(bytes, 36) as u64,
+        has_legacy_pics: read_u32(bytes, 40) & 1 != 0,
+        processors: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+    let mut offset = 44;
+    while offset + 2 <= bytes.len() {
+        let kind = bytes[offset];
+        let len = bytes[offset + 1] as usize;
+        if len < 2 || offset + len > bytes.len() {
+            break;
+        }
+        let entry = &bytes[offset..offset + len];
+        match kind {
+            0 => madt.processors.push(Processor {
+                processor_id: read_u8(entry, 2) as u32,
+                apic_id: read_u8(entry, 3) as u32,
+                enabled: read_u32(entry, 4) & 1 != 0,
+                online_capable: read_u32(entry, 4) & 2 != 0,
+            }),
+            1 => madt.io_apics.push(IoApic {
+                id: read_u8(entry, 2),
+                address: read_u32(entry, 4) as u64,
+                gsi_base: read_u32(entry, 8),
+            }),
+            2 => {
+                // polarity in bits 0-1 and trigger mode in bits 2-3, 0 means the default of the bus (ISA:
+                // active high, edge triggered), 3 is active low or level triggered
+                let flags = read_u16(entry, 8);
+                madt.overrides.push(InterruptOverride {
+                    irq: read_u8(entry, 3),
+                    gsi: read_u32(entry, 4),
+                    active_low: flags & 0b11 == 0b11,
+                    level_triggered: (flags >> 2) & 0b11 == 0b11,
+                });
+            }
+            5 => madt.local_apic_address = read_u64(entry, 4),
+            9 => madt.processors.push(Processor {
+                processor_id: read_u32(entry, 12),
+                apic_id: read_u32(entry, 4),
+                enabled: read_u32(entry, 8) & 1 != 0,
+                online_capable: read_u32(entry, 8) & 2 != 0,
+            }),
+            _ => {}
+        }
+        offset += len;
+    }
+    madt
+}
+
+fn parse_generic_address(bytes: &[u8], offset: usize) -> GenericAddress {
+    GenericAddress {
+        address_space: read_u8(bytes, offset),
+        bit_width: read_u8(bytes, offset + 1),
+        bit_offset: read_u8(bytes, offset + 2),
+        access_size: read_u8(bytes, offset + 3),
+        address: read_u64(bytes, offset + 4),
+    }
+}
+
+// the offsets are the ones of the ACPI specification (FADT format)
+fn parse_fadt(bytes: &[u8]) -> Fadt {
+    const RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+    let flags = read_u32(bytes, 112);
+    // the 64 bit X_DSDT replaces the 32 bit DSDT field if it is set
+    let dsdt = match read_u64(bytes, 140) {
+        0 => read_u32(bytes, 40) as u64,
+        x_dsdt => x_dsdt,
+    };
+    Fadt {
+        dsdt,
+        sci_interrupt: read_u16(bytes, 46),
+        smi_command_port: read_u32(bytes, 48),
+        acpi_enable: read_u8(bytes, 52),
+        acpi_disable: read_u8(bytes, 53),
+        pm1a_control_block: read_u32(bytes, 64),
+        pm1b_control_block: read_u32(bytes, 68),
+        pm_timer_block: read_u32(bytes, 76),
+        century_register: read_u8(bytes, 108),
+        boot_architecture_flags: read_u16(bytes, 109),
+        flags,
+        reset_register: (flags & RESET_REGISTER_SUPPORTED != 0)
+            .then(|| parse_generic_address(bytes, 116)),
+        reset_value: read_u8(bytes, 128),
+    }
+}
+
+#[test_case]
+fn test_parse_madt_entries() {
+    use alloc::vec;
+
+    let mut bytes = vec![0u8; 44];
+    bytes[36..40].copy_from_slice(&0xFEE0_0000u32.to_le_bytes());
+    bytes[40] = 1;
+    // a local APIC, an IO APIC and an override of IRQ 9 to GSI 9 (active low, level triggered)
+    bytes.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+    bytes.extend_from_slice(&[1, 12, 2, 0, 0x00, 0x00, 0xC0, 0xFE, 0, 0, 0, 0]);
+    bytes.extend_from_slice(&[2, 10, 0, 9, 9, 0, 0, 0, 0x0F, 0]);
+    // an unknown entry is skipped
+    bytes.extend_from_slice(&[0x7F, 4, 0, 0]);
+    let madt = parse_madt(&bytes);
+    assert_eq!(madt.local_apic_address, 0xFEE0_0000);
+    assert!(madt.has_legacy_pics);
+    assert_eq!(madt.processors.len(), 1);
+    assert!(madt.processors[0].enabled && madt.processors[0].apic_id == 0);
+    assert_eq!(
+        madt.io_apics,
+        [IoApic {
+            id: 2,
+            address: 0xFEC0_0000,
+            gsi_base: 0
+        }]
+    );
+    assert_eq!(
+        madt.overrides,
+        [InterruptOverride {
+            irq: 9,
+            gsi: 9,
+            active_low: true,
+            level_triggered: true
+        }]
+    );
+}
+
+#[test_case]
+fn test_checksums_and_short_tables() {
+    let mut rsdp = [0u8; 36];
+    rsdp[..8].copy_from_slice(RSDP_SIGNATURE);
+    rsdp[15] = 0;
+    rsdp[8] = 0u8.wrapping_sub(rsdp[..20].iter().fold(0u8, |sum, &b| sum.wrapping_add(b)));
+    assert!(valid_rsdp(&rsdp));
+    rsdp[16] = 1;
+    assert!(!valid_rsdp(&rsdp));
+    // an old FADT without the 64 bit fields uses the 32 bit DSDT address
+    let mut fadt = [0u8; 116];
+    fadt[40..44].copy_from_slice(&0x1234u32.to_le_bytes());
+    let fadt = parse_fadt(&fadt);
+    assert_eq!(fadt.dsdt, 0x1234);
+    assert_eq!(fadt.reset_register, None);
+}
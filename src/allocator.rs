@@ -0,0 +1,177 @@
+/*
+* The alloc crate provides the heap allocated types of the standard library (Box, Vec, String, BTreeMap...)
+* but it needs a HEAP_ALLOCATOR to get memory from. The allocator is registered with the #[global_allocator]
+* attribute and has to implement the GlobalAlloc trait (alloc and dealloc functions).
+*
+* The heap is a region of virtual memory that we map to free frames at boot, the allocator then
+* manages the memory of that region. Two allocators are available, selected at compile time:
+*  * the fixed size block allocator (default feature fixed_size_block_allocator), fast and
+*    doesn't fragment for the small allocations the kernel mostly does
+*  * the linked_list_allocator crate (`--no-default-features`) which keeps the freed memory blocks
+*    in a linked list stored inside the freed blocks themselves
+* */
+use crate::memory;
+#[cfg(not(feature = "fixed_size_block_allocator"))]
+use alloc::alloc::{GlobalAlloc, Layout};
+#[cfg(not(feature = "fixed_size_block_allocator"))]
+use core::ptr::NonNull;
+use stats::{HeapStats, Instrumented};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+// any unused virtual address range works, this one is easy to recognize in page faults
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+
+#[cfg(feature = "fixed_size_block_allocator")]
+pub mod fixed_size_block;
+pub mod slab;
+pub mod stats;
+
+// both allocators are wrapped in the counting layer of the stats module
+#[cfg(feature = "fixed_size_block_allocator")]
+#[global_allocator]
+static ALLOCATOR: Instrumented<Locked<fixed_size_block::FixedSizeBlockAllocator>> =
+    Instrumented::new(Locked::new(fixed_size_block::FixedSizeBlockAllocator::new()));
+
+#[cfg(not(feature = "fixed_size_block_allocator"))]
+#[global_allocator]
+static ALLOCATOR: Instrumented<Locked<linked_list_allocator::Heap>> =
+    Instrumented::new(Locked::new(linked_list_allocator::Heap::empty()));
+
+// the allocation counters of the heap
+pub fn stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/*
+* The GlobalAlloc functions take &self but the allocators need to modify their state, and we can't
+* implement GlobalAlloc for spin::Mutex<A> directly since both the trait and the type are defined in other
+* crates (the ORPHAN_RULE), so we wrap the Mutex in our own type.
+*
+* The allocators run with interrupts disabled: a thread preempted (scheduler module) while it holds the lock
+* would make every thread that allocates with interrupts disabled spin forever.
+* */
+pub struct Locked<A> {
+    inner: spin::Mutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> spin::MutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
+
+#[cfg(not(feature = "fixed_size_block_allocator"))]
+unsafe impl GlobalAlloc for Locked<linked_list_allocator::Heap> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.lock()
+                .allocate_first_fit(layout)
+                .map_or(core::ptr::null_mut(), |ptr| ptr.as_ptr())
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.lock().deallocate(NonNull::new_unchecked(ptr), layout)
+        })
+    }
+}
+
+// map the heap region to free frames and hand it to the allocator
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    memory::with_frame_allocator(|frame_allocator| -> Result<(), MapToError<Size4KiB>> {
+        for page in page_range {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let flags = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | crate::protection::no_execute();
+            // the frame was just allocated so nothing else uses it
+            unsafe { memory::map_page(page, frame, flags, frame_allocator)? };
+        }
+        Ok(())
+    })?;
+
+    // unsafe because the heap region must be mapped and unused, which we just made sure of
+    unsafe {
+        ALLOCATOR.inner().lock().init(HEAP_START, HEAP_SIZE);
+    }
+
+    Ok(())
+}
+
+#[test_case]
+fn test_simple_allocation() {
+    use alloc::boxed::Box;
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn test_large_vec() {
+    use alloc::vec::Vec;
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+// allocates more than the heap size in total so it fails if freed memory isn't reused
+#[test_case]
+fn test_many_boxes() {
+    use alloc::boxed::Box;
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+// same as above but a long lived allocation stays allocated the whole time, a bump allocator
+// or an allocator that can't reuse the freed blocks next to it would run out of memory
+#[test_case]
+fn test_many_boxes_long_lived() {
+    use alloc::boxed::Box;
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn test_string_and_btree_map() {
+    use alloc::collections::BTreeMap;
+    use alloc::string::String;
+    let mut map = BTreeMap::new();
+    for key in ["b", "a", "c"] {
+        let mut value = String::from(key);
+        value.push('!');
+        map.insert(key, value);
+    }
+    let keys: alloc::vec::Vec<_> = map.keys().copied().collect();
+    assert_eq!(keys, ["a", "b", "c"]);
+    assert_eq!(map["a"], "a!");
+}
@@ -0,0 +1,137 @@
+/*
+* The linked list allocator has to walk the list of free regions on every allocation and the freed
+* regions get smaller over time (FRAGMENTATION). Most kernel allocations are small so instead we
+* round every allocation up to one of a few BLOCK_SIZES and keep a separate list of free blocks for each
+* size. Allocating is then just popping the head of the matching list and freeing is pushing the
+* block back, both are O(1) and blocks of the same size can always be reused.
+*
+* Like the linked list allocator the list nodes are stored in the free blocks themselves so the
+* smallest block has to be large enough (and aligned enough) for a ListNode.
+* Allocations larger than the largest block size go to a linked list allocator (the fallback).
+* */
+use super::Locked;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ptr, ptr::NonNull};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/*
+* The block sizes, each one is also used as the alignment of its blocks so they must be powers of 2.
+* Allocations with a bigger alignment than their size pick the block size matching the alignment.
+* Sizes larger than 2048 are rare in the kernel and would waste a lot of memory when rounded up
+* so they are handed to the fallback allocator.
+* */
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+pub struct FixedSizeBlockAllocator {
+    // the heads of the free lists, one per block size
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: linked_list_allocator::Heap,
+}
+
+impl FixedSizeBlockAllocator {
+    // creates an empty allocator, all the lists start empty and are filled lazily from the fallback
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: linked_list_allocator::Heap::empty(),
+        }
+    }
+
+    /*
+     * Unsafe because the caller must guarantee that the heap region is mapped, unused
+     * and that this is only called once
+     * */
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.fallback_allocator.init(heap_start, heap_size);
+    }
+
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        match self.fallback_allocator.allocate_first_fit(layout) {
+            Ok(ptr) => ptr.as_ptr(),
+            Err(_) => ptr::null_mut(),
+        }
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the index of the smallest block size that fits the layout, None if it needs the fallback allocator
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| self.alloc_block(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.dealloc_block(ptr, layout))
+    }
+}
+
+impl Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc_block(&self, layout: Layout) -> *mut u8 {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(index) => match allocator.list_heads[index].take() {
+                Some(node) => {
+                    allocator.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // the list is empty, allocate a new block from the fallback allocator
+                    // it is only freed back to the list so it can be reused for the same block size
+                    let block_size = BLOCK_SIZES[index];
+                    let block_align = block_size;
+                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                    allocator.fallback_alloc(layout)
+                }
+            },
+            None => allocator.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc_block(&self, ptr: *mut u8, layout: Layout) {
+        let mut allocator = self.lock();
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: allocator.list_heads[index].take(),
+                };
+                // the block is at least as big and aligned as a ListNode (the smallest block size is 8)
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                new_node_ptr.write(new_node);
+                allocator.list_heads[index] = Some(&mut *new_node_ptr);
+            }
+            None => {
+                let ptr = NonNull::new(ptr).unwrap();
+                allocator.fallback_allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_list_index_rounds_up_to_block_size() {
+    let index = |size, align| list_index(&Layout::from_size_align(size, align).unwrap());
+    assert_eq!(index(1, 1), Some(0));
+    assert_eq!(index(8, 8), Some(0));
+    assert_eq!(index(9, 1), Some(1));
+    // the alignment is respected by picking a bigger block
+    assert_eq!(index(8, 64), Some(3));
+    assert_eq!(index(2048, 8), Some(BLOCK_SIZES.len() - 1));
+    assert_eq!(index(2049, 8), None);
+}
@@ -0,0 +1,359 @@
+/*
+* A SLAB cache hands out objects of one type. It gets whole frames (the SLABS, through the physical memory
+* mapping) from the frame allocator and cuts them into slots for the objects: a header at the start of the
+* slab counts the slots that are in use, the free slots of all the slabs are in one list stored in the
+* slots themselves (like the fixed size block allocator). Allocating and freeing are O(1), the objects
+* don't use the heap and can't fragment it, and a freed slot is reused by the next object of the same type.
+*
+* The slabs are kept when their objects are freed, shrink gives the empty ones back to the frame allocator.
+*
+* With POISONING the bytes of a freed slot (after the free list link) are overwritten with POISON and
+* checked when the slot is handed out again. A write through a dangling pointer (use after free) changes
+* them and panics on the next allocation with the name of the cache.
+*
+* The caches are locked with interrupts disabled like the heap. Growing a cache allocates a frame, so the
+* objects can't be allocated by interrupt handlers.
+* */
+use crate::memory;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame};
+use x86_64::VirtAddr;
+
+const SLAB_SIZE: usize = 4096;
+// the byte freed slots are filled with, the one Linux uses
+pub const POISON: u8 = 0x6b;
+
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+struct SlabHeader {
+    next: Option<NonNull<SlabHeader>>,
+    // the slots of the slab that hold an object
+    in_use: usize,
+}
+
+struct Slabs {
+    // every slab of the cache
+    slabs: Option<NonNull<SlabHeader>>,
+    // the free slots of all the slabs
+    free: Option<NonNull<FreeSlot>>,
+    slab_count: usize,
+}
+
+// the pointers are only used with the lock held
+unsafe impl Send for Slabs {}
+
+const fn round_up(value: usize, align: usize) -> usize {
+    value.div_ceil(align) * align
+}
+
+const fn max(a: usize, b: usize) -> usize {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+pub struct Cache<T> {
+    name: &'static str,
+    poison: bool,
+    slabs: Mutex<Slabs>,
+    in_use: AtomicUsize,
+    allocations: AtomicUsize,
+    frees: AtomicUsize,
+    registered: AtomicBool,
+    _objects: PhantomData<T>,
+}
+
+// the objects are only handed to one owner at a time, they may be moved to another thread
+unsafe impl<T: Send> Sync for Cache<T> {}
+
+impl<T: Send> Cache<T> {
+    const ALIGN: usize = max(align_of::<T>(), align_of::<FreeSlot>());
+    const SLOT_SIZE: usize = round_up(max(size_of::<T>(), size_of::<FreeSlot>()), Self::ALIGN);
+    // the offset of the first slot, after the header
+    const FIRST_SLOT: usize = round_up(size_of::<SlabHeader>(), Self::ALIGN);
+    const SLOTS_PER_SLAB: usize = (SLAB_SIZE - Self::FIRST_SLOT) / Self::SLOT_SIZE;
+
+    pub const fn new(name: &'static str) -> Self {
+        assert!(Self::SLOTS_PER_SLAB > 0, "the objects don't fit in a slab");
+        Cache {
+            name,
+            poison: false,
+            slabs: Mutex::new(Slabs {
+                slabs: None,
+                free: None,
+                slab_count: 0,
+            }),
+            in_use: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            frees: AtomicUsize::new(0),
+            registered: AtomicBool::new(false),
+            _objects: PhantomData,
+        }
+    }
+
+    // a cache that poisons the freed slots
+    pub const fn with_poison(name: &'static str) -> Self {
+        let mut cache = Self::new(name);
+        cache.poison = true;
+        cache
+    }
+
+    // move the value into a slot of the cache, None if there is no frame left for a new slab
+    pub fn alloc(&'static self, value: T) -> Option<SlabBox<T>> {
+        if !self.registered.swap(true, Ordering::Relaxed) {
+            without_interrupts(|| CACHES.lock().push(self));
+        }
+        let slot = loop {
+            if let Some(slot) = without_interrupts(|| self.pop()) {
+                break slot;
+            }
+            self.grow()?;
+        };
+        let ptr = slot.cast::<T>();
+        unsafe { ptr.as_ptr().write(value) };
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        Some(SlabBox { cache: self, ptr })
+    }
+
+    fn header(slot: NonNull<FreeSlot>) -> *mut SlabHeader {
+        (slot.as_ptr() as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader
+    }
+
+    // the bytes of a free slot that poisoning overwrites
+    unsafe fn poisoned_bytes(slot: NonNull<FreeSlot>) -> &'static mut [u8] {
+        let start = slot.as_ptr().cast::<u8>().add(size_of::<FreeSlot>());
+        core::slice::from_raw_parts_mut(start, Self::SLOT_SIZE - size_of::<FreeSlot>())
+    }
+
+    fn pop(&self) -> Option<NonNull<FreeSlot>> {
+        let mut slabs = self.slabs.lock();
+        let slot = slabs.free?;
+        unsafe {
+            slabs.free = slot.as_ref().next;
+            (*Self::header(slot)).in_use += 1;
+            if self.poison
+                && Self::poisoned_bytes(slot)
+                    .iter()
+                    .any(|&byte| byte != POISON)
+            {
+                panic!(
+                    "slab cache {}: object at {:p} was written after it was freed",
+                    self.name, slot
+                );
+            }
+        }
+        Some(slot)
+    }
+
+    unsafe fn push(&self, slabs: &mut Slabs, slot: NonNull<FreeSlot>) {
+        if self.poison {
+            Self::poisoned_bytes(slot).fill(POISON);
+        }
+        slot.as_ptr().write(FreeSlot { next: slabs.free });
+        slabs.free = Some(slot);
+    }
+
+    // add a slab with all its slots free
+    fn grow(&self) -> Option<()> {
+        let frame = memory::with_frame_allocator(|allocator| allocator.allocate_frame())?;
+        let start = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+        without_interrupts(|| {
+            let mut slabs = self.slabs.lock();
+            unsafe {
+                let header = start.cast::<SlabHeader>();
+                header.write(SlabHeader {
+                    next: slabs.slabs,
+                    in_use: 0,
+                });
+                slabs.slabs = NonNull::new(header);
+                slabs.slab_count += 1;
+                for index in 0..Self::SLOTS_PER_SLAB {
+                    let slot = start.add(Self::FIRST_SLOT + index * Self::SLOT_SIZE);
+                    self.push(&mut slabs, NonNull::new_unchecked(slot.cast()));
+                }
+            }
+        });
+        Some(())
+    }
+
+    // called by SlabBox after the object was dropped
+    unsafe fn free(&self, ptr: NonNull<T>) {
+        let slot = ptr.cast::<FreeSlot>();
+        without_interrupts(|| {
+            let mut slabs = self.slabs.lock();
+            (*Self::header(slot)).in_use -= 1;
+            self.push(&mut slabs, slot);
+        });
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+        self.frees.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // give the slabs without objects back to the frame allocator, returns their number
+    pub fn shrink(&self) -> usize {
+        let empty = without_interrupts(|| {
+            let mut slabs = self.slabs.lock();
+            let is_empty = |slab: NonNull<SlabHeader>| unsafe { slab.as_ref().in_use == 0 };
+            // remove the slots of the empty slabs from the free list
+            let mut link = &mut slabs.free;
+            while let Some(slot) = *link {
+                if is_empty(NonNull::new(Self::header(slot)).unwrap()) {
+                    *link = unsafe { slot.as_ref().next };
+                } else {
+                    link = unsafe { &mut (*slot.as_ptr()).next };
+                }
+            }
+            // move the empty slabs to their own list
+            let mut empty: Option<NonNull<SlabHeader>> = None;
+            let mut count = 0;
+            let mut link = &mut slabs.slabs;
+            while let Some(slab) = *link {
+                if is_empty(slab) {
+                    unsafe {
+                        *link = slab.as_ref().next;
+                        (*slab.as_ptr()).next = empty;
+                    }
+                    empty = Some(slab);
+                    count += 1;
+                } else {
+                    link = unsafe { &mut (*slab.as_ptr()).next };
+                }
+            }
+            slabs.slab_count -= count;
+            empty
+        });
+        let mut count = 0;
+        let mut next = empty;
+        while let Some(slab) = next {
+            next = unsafe { slab.as_ref().next };
+            let phys =
+                memory::translate_addr(VirtAddr::from_ptr(slab.as_ptr())).expect("slab not mapped");
+            let frame = PhysFrame::containing_address(phys);
+            memory::with_frame_allocator(|allocator| unsafe { allocator.deallocate_frame(frame) });
+            count += 1;
+        }
+        count
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            name: self.name,
+            object_size: size_of::<T>(),
+            slabs: without_interrupts(|| self.slabs.lock().slab_count),
+            objects_per_slab: Self::SLOTS_PER_SLAB,
+            in_use: self.in_use.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            frees: self.frees.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub slabs: usize,
+    pub objects_per_slab: usize,
+    // the objects that are allocated now and since boot
+    pub in_use: usize,
+    pub allocations: usize,
+    pub frees: usize,
+}
+
+// the caches of all the object types, so they can be listed without knowing the types
+trait AnyCache: Sync {
+    fn stats(&self) -> CacheStats;
+    fn shrink(&self) -> usize;
+}
+
+impl<T: Send> AnyCache for Cache<T> {
+    fn stats(&self) -> CacheStats {
+        Cache::stats(self)
+    }
+
+    fn shrink(&self) -> usize {
+        Cache::shrink(self)
+    }
+}
+
+// the caches that allocated an object, locked with interrupts disabled
+static CACHES: Mutex<Vec<&'static dyn AnyCache>> = Mutex::new(Vec::new());
+
+pub fn caches() -> Vec<CacheStats> {
+    let caches = without_interrupts(|| CACHES.lock().clone());
+    caches.iter().map(|cache| cache.stats()).collect()
+}
+
+// shrink every cache, returns the number of freed slabs
+pub fn shrink_all() -> usize {
+    let caches = without_interrupts(|| CACHES.lock().clone());
+    caches.iter().map(|cache| cache.shrink()).sum()
+}
+
+// owns an object in a slot of a cache like a Box, the slot is freed when it is dropped
+pub struct SlabBox<T: Send + 'static> {
+    cache: &'static Cache<T>,
+    ptr: NonNull<T>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Send + Sync> Sync for SlabBox<T> {}
+
+impl<T: Send> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: Send> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.ptr.as_mut() }
+    }
+}
+
+impl<T: Send> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.ptr.as_ptr().drop_in_place();
+            self.cache.free(self.ptr);
+        }
+    }
+}
+
+#[test_case]
+fn test_slab_cache_reuses_and_poisons_slots() {
+    static CACHE: Cache<[u64; 3]> = Cache::with_poison("test");
+    let first = CACHE.alloc([1, 2, 3]).unwrap();
+    let second = CACHE.alloc([4, 5, 6]).unwrap();
+    assert_eq!(*first, [1, 2, 3]);
+    assert_eq!(second[2], 6);
+    let address = first.ptr;
+    drop(first);
+    // the freed slot is poisoned after its free list link
+    let bytes = unsafe { core::slice::from_raw_parts(address.as_ptr().cast::<u8>().add(8), 16) };
+    assert!(bytes.iter().all(|&byte| byte == POISON));
+    // the last freed slot is reused first
+    let third = CACHE.alloc([7, 8, 9]).unwrap();
+    assert_eq!(third.ptr, address);
+
+    let stats = CACHE.stats();
+    assert_eq!((stats.in_use, stats.allocations, stats.frees), (2, 3, 1));
+    assert_eq!(stats.slabs, 1);
+    assert!(caches().iter().any(|cache| cache.name == "test"));
+    drop((second, third));
+    assert_eq!(CACHE.shrink(), 1);
+    assert_eq!(CACHE.stats().slabs, 0);
+}
@@ -0,0 +1,168 @@
+/*
+* The heap allocator is wrapped in an INSTRUMENTED layer that counts every allocation before passing it on,
+* so the kernel can see how much of the heap it uses and find leaks: the allocations that are still live
+* after some code ran and should have freed everything it allocated.
+*
+* The counters are atomics, the allocator is called by interrupt handlers and other CPUs too and a lock
+* would only add another way to deadlock. Every allocation is counted in the SIZE_CLASS of its size (the
+* block sizes of the fixed size block allocator), the bigger ones in the last class.
+* */
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// the upper bound of every size class, the last one has no bound
+pub const SIZE_CLASSES: [usize; 10] = [8, 16, 32, 64, 128, 256, 512, 1024, 2048, usize::MAX];
+
+struct Counters {
+    allocated_bytes: AtomicUsize,
+    freed_bytes: AtomicUsize,
+    // allocated_bytes - freed_bytes in one counter, the two can't be read at the same time
+    in_use_bytes: AtomicUsize,
+    peak_bytes: AtomicUsize,
+    allocations: [AtomicUsize; SIZE_CLASSES.len()],
+    deallocations: [AtomicUsize; SIZE_CLASSES.len()],
+    failures: AtomicUsize,
+}
+
+pub struct Instrumented<A> {
+    inner: A,
+    counters: Counters,
+}
+
+impl<A> Instrumented<A> {
+    pub const fn new(inner: A) -> Self {
+        Instrumented {
+            inner,
+            counters: Counters {
+                allocated_bytes: AtomicUsize::new(0),
+                freed_bytes: AtomicUsize::new(0),
+                in_use_bytes: AtomicUsize::new(0),
+                peak_bytes: AtomicUsize::new(0),
+                allocations: [const { AtomicUsize::new(0) }; SIZE_CLASSES.len()],
+                deallocations: [const { AtomicUsize::new(0) }; SIZE_CLASSES.len()],
+                failures: AtomicUsize::new(0),
+            },
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    // a snapshot of the counters, the values are read one by one so a concurrent allocation may be half in it
+    pub fn stats(&self) -> HeapStats {
+        let counters = &self.counters;
+        HeapStats {
+            allocated_bytes: counters.allocated_bytes.load(Ordering::Relaxed),
+            freed_bytes: counters.freed_bytes.load(Ordering::Relaxed),
+            peak_bytes: counters.peak_bytes.load(Ordering::Relaxed),
+            allocations: core::array::from_fn(|i| counters.allocations[i].load(Ordering::Relaxed)),
+            deallocations: core::array::from_fn(|i| {
+                counters.deallocations[i].load(Ordering::Relaxed)
+            }),
+            failures: counters.failures.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn size_class(size: usize) -> usize {
+    SIZE_CLASSES
+        .iter()
+        .position(|&bound| size <= bound)
+        .unwrap_or(SIZE_CLASSES.len() - 1)
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Instrumented<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        let counters = &self.counters;
+        if ptr.is_null() {
+            counters.failures.fetch_add(1, Ordering::Relaxed);
+            return ptr;
+        }
+        counters
+            .allocated_bytes
+            .fetch_add(layout.size(), Ordering::Relaxed);
+        let in_use = counters
+            .in_use_bytes
+            .fetch_add(layout.size(), Ordering::Relaxed)
+            + layout.size();
+        counters.peak_bytes.fetch_max(in_use, Ordering::Relaxed);
+        counters.allocations[size_class(layout.size())].fetch_add(1, Ordering::Relaxed);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        let counters = &self.counters;
+        counters
+            .freed_bytes
+            .fetch_add(layout.size(), Ordering::Relaxed);
+        // the allocation was counted before, so this can't go below 0
+        counters
+            .in_use_bytes
+            .fetch_sub(layout.size(), Ordering::Relaxed);
+        counters.deallocations[size_class(layout.size())].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    // the bytes of all the allocations and deallocations since boot
+    pub allocated_bytes: usize,
+    pub freed_bytes: usize,
+    // the most bytes in use at any time
+    pub peak_bytes: usize,
+    // the number of allocations and deallocations in every size class
+    pub allocations: [usize; SIZE_CLASSES.len()],
+    pub deallocations: [usize; SIZE_CLASSES.len()],
+    // the allocations that failed because the heap was full
+    pub failures: usize,
+}
+
+impl HeapStats {
+    pub fn used_bytes(&self) -> usize {
+        self.allocated_bytes.saturating_sub(self.freed_bytes)
+    }
+
+    // the allocations of the size class that are not freed yet
+    pub fn live(&self, class: usize) -> usize {
+        self.allocations[class].saturating_sub(self.deallocations[class])
+    }
+
+    pub fn live_allocations(&self) -> usize {
+        (0..SIZE_CLASSES.len()).map(|class| self.live(class)).sum()
+    }
+
+    /*
+     * The allocations made since the earlier snapshot that are still live, and their bytes. Code that frees
+     * everything it allocates leaves both at 0 (unless something else allocated at the same time).
+     * */
+    pub fn leaked_since(&self, earlier: &HeapStats) -> (isize, isize) {
+        let allocations = self.live_allocations() as isize - earlier.live_allocations() as isize;
+        let bytes = self.used_bytes() as isize - earlier.used_bytes() as isize;
+        (allocations, bytes)
+    }
+}
+
+#[test_case]
+fn test_allocations_are_counted() {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    let before = super::stats();
+    let small = Box::new(7u32);
+    let large: Vec<u8> = Vec::with_capacity(4096);
+    let during = super::stats();
+    assert!(during.allocations[size_class(4)] > before.allocations[size_class(4)]);
+    assert!(
+        during.allocations[SIZE_CLASSES.len() - 1] > before.allocations[SIZE_CLASSES.len() - 1]
+    );
+    assert!(during.allocated_bytes >= before.allocated_bytes + 4 + 4096);
+    assert!(during.peak_bytes >= during.used_bytes());
+    drop((small, large));
+    let after = super::stats();
+    assert!(after.freed_bytes >= before.freed_bytes + 4 + 4096);
+    assert_eq!(size_class(9), 1);
+    assert_eq!(size_class(1 << 20), SIZE_CLASSES.len() - 1);
+}
@@ -0,0 +1,335 @@
+/*
+* The 8259 PICs only work for a single CPU and 15 interrupt lines. Modern machines have an ADVANCED_PIC
+* (APIC) split in two parts:
+*  * every CPU has a LOCAL_APIC which receives the interrupts for that CPU, has its own timer and sends
+*    interrupts to the other CPUs (IPIs). Its registers are memory mapped at 0xFEE00000 by default
+*    (the IA32_APIC_BASE MSR tells where).
+*  * the IO_APIC receives the interrupts of the devices and forwards them to the local APIC of a CPU,
+*    every input (a GLOBAL_SYSTEM_INTERRUPT, GSI) has a redirection entry choosing the vector, the CPU
+*    and how the line signals (edge/level, active high/low).
+*
+* The ISA IRQs (keyboard...) are connected to the IO APIC inputs with the same number, except where the
+* firmware reports an override (the PIT's IRQ 0 is usually GSI 2). They get the same vectors as with the
+* PIC (PIC_1_OFFSET + irq) so the handlers registered with register_irq_handler work with both.
+*
+* The PIT is replaced by the local APIC timer. Its frequency depends on the machine (it counts with the
+* bus clock) so it is calibrated by counting how much it decrements during a few PIT ticks.
+*
+* init switches from the PIC to the APIC, without an APIC (CPUID) or with the noapic command line option
+* the PIC stays in use.
+* */
+use crate::cpu::{self, Feature};
+use crate::interrupts::{self, InterruptIndex, PIC_1_OFFSET};
+use crate::{cmdline, memory, timer};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::PhysAddr;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+const DEFAULT_IO_APIC_ADDRESS: u64 = 0xFEC0_0000;
+
+// the vector of the interrupts the local APIC raises when an interrupt disappeared before it was delivered
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// local APIC registers (offsets from the base address)
+const LAPIC_ID: usize = 0x020;
+const LAPIC_EOI: usize = 0x0B0;
+const LAPIC_SPURIOUS: usize = 0x0F0;
+const LAPIC_IN_SERVICE: usize = 0x100;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3E0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+// the interrupt command register sends an INTER_PROCESSOR_INTERRUPT (IPI) to the CPU in the high half
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+// the timer counts down once every 16 bus clock cycles
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+// the number of PIT ticks the calibration measures
+const CALIBRATION_TICKS: u64 = 10;
+
+// IO APIC registers, selected by writing their number to IOREGSEL and accessed through IOWIN
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+// an ISA IRQ that isn't connected to the IO APIC input with its own number or doesn't signal like ISA lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+// where the IO APIC is and how the ISA IRQs are connected to it
+pub struct Platform<'a> {
+    pub io_apic_address: u64,
+    // the first GSI handled by the IO APIC
+    pub io_apic_gsi_base: u32,
+    pub overrides: &'a [InterruptOverride],
+}
+
+/*
+* The machines emulated by QEMU and nearly all PCs connect the PIT to input 2 and everything else 1:1,
+* this is used if the firmware has no MADT.
+* */
+const DEFAULT_OVERRIDES: [InterruptOverride; 1] = [InterruptOverride {
+    irq: 0,
+    gsi: 2,
+    active_low: false,
+    level_triggered: false,
+}];
+
+// the IO APIC and the overrides from the ACPI MADT, only the IO APIC with the ISA IRQs (GSI 0) is used
+fn platform() -> Platform<'static> {
+    let Some(madt) = crate::acpi::madt() else {
+        return Platform {
+            io_apic_address: DEFAULT_IO_APIC_ADDRESS,
+            io_apic_gsi_base: 0,
+            overrides: &DEFAULT_OVERRIDES,
+        };
+    };
+    let io_apic = madt
+        .io_apics
+        .iter()
+        .min_by_key(|io_apic| io_apic.gsi_base)
+        .map_or((DEFAULT_IO_APIC_ADDRESS, 0), |io_apic| {
+            (io_apic.address, io_apic.gsi_base)
+        });
+    Platform {
+        io_apic_address: io_apic.0,
+        io_apic_gsi_base: io_apic.1,
+        overrides: &madt.overrides,
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// the virtual addresses of the registers, 0 until init mapped them
+static LAPIC_BASE: AtomicU64 = AtomicU64::new(0);
+static IO_APIC_BASE: AtomicU64 = AtomicU64::new(0);
+
+// the interrupts are delivered by the APIC (instead of the PIC)
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// the CPU has a local APIC
+pub fn is_supported() -> bool {
+    cpu::has(Feature::Apic)
+}
+
+fn lapic_register(offset: usize) -> *mut u32 {
+    (LAPIC_BASE.load(Ordering::Relaxed) as usize + offset) as *mut u32
+}
+
+fn lapic_read(offset: usize) -> u32 {
+    unsafe { lapic_register(offset).read_volatile() }
+}
+
+fn lapic_write(offset: usize, value: u32) {
+    unsafe { lapic_register(offset).write_volatile(value) }
+}
+
+fn io_apic_read(register: u32) -> u32 {
+    let base = IO_APIC_BASE.load(Ordering::Relaxed) as usize;
+    unsafe {
+        ((base + IOREGSEL) as *mut u32).write_volatile(register);
+        ((base + IOWIN) as *const u32).read_volatile()
+    }
+}
+
+fn io_apic_write(register: u32, value: u32) {
+    let base = IO_APIC_BASE.load(Ordering::Relaxed) as usize;
+    unsafe {
+        ((base + IOREGSEL) as *mut u32).write_volatile(register);
+        ((base + IOWIN) as *mut u32).write_volatile(value);
+    }
+}
+
+fn set_redirection(input: u32, entry: u64) {
+    // the entry is masked while it is changed so no interrupt uses a half written entry
+    let register = IOAPIC_REDIRECTION_TABLE + input * 2;
+    io_apic_write(register, REDIRECTION_MASKED as u32);
+    io_apic_write(register + 1, (entry >> 32) as u32);
+    io_apic_write(register, entry as u32);
+}
+
+// the id of the local APIC of the running CPU
+pub fn lapic_id() -> u8 {
+    (lapic_read(LAPIC_ID) >> 24) as u8
+}
+
+// signal the local APIC that the interrupt was handled, called by the IRQ dispatcher
+pub fn end_of_interrupt() {
+    lapic_write(LAPIC_EOI, 0);
+}
+
+// the vector was delivered by the local APIC and not ended yet, the 256 bits are in 8 registers 16 bytes apart
+pub fn in_service(vector: u8) -> bool {
+    let register = LAPIC_IN_SERVICE + 0x10 * (vector as usize / 32);
+    lapic_read(register) & (1 << (vector % 32)) != 0
+}
+
+// send an interrupt to the local APIC with the id, the command is the low half of the ICR (vector and mode)
+pub fn send_ipi(apic_id: u8, command: u32) {
+    lapic_write(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+    // writing the low half sends the interrupt
+    lapic_write(LAPIC_ICR_LOW, command);
+    while lapic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+// reset the CPU, it waits for a startup IPI afterwards
+pub fn send_init_ipi(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+// start the CPU in real mode at the physical address vector * 4096
+pub fn send_startup_ipi(apic_id: u8, vector: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | vector as u32);
+}
+
+// enable the local APIC of an application processor, init already mapped the registers
+pub fn init_ap() {
+    lapic_write(
+        LAPIC_SPURIOUS,
+        SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+}
+
+// the redirection entry of an ISA IRQ and the IO APIC input it arrives on
+fn isa_redirection(irq: u8, platform: &Platform, destination: u8) -> (u32, u64) {
+    let over = platform.overrides.iter().find(|over| over.irq == irq);
+    let gsi = over.map_or(irq as u32, |over| over.gsi);
+    let mut entry = (PIC_1_OFFSET + irq) as u64 | (destination as u64) << 56;
+    if over.is_some_and(|over| over.active_low) {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if over.is_some_and(|over| over.level_triggered) {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    (gsi.wrapping_sub(platform.io_apic_gsi_base), entry)
+}
+
+/*
+* Switch the interrupts to the APIC, called after memory::init since the registers have to be mapped and
+* with interrupts enabled since the calibration waits for PIT ticks.
+* */
+pub fn init() {
+    if cmdline::flag("noapic") || !is_supported() {
+        log::info!("apic: not used, interrupts stay on the PIC");
+        return;
+    }
+    let platform = platform();
+
+    let mut apic_base = Msr::new(IA32_APIC_BASE_MSR);
+    let base = unsafe { apic_base.read() };
+    let lapic = unsafe { memory::map_mmio(PhysAddr::new(base & APIC_BASE_ADDRESS_MASK), 4096) }
+        .expect("mapping the local APIC failed");
+    let io_apic = unsafe { memory::map_mmio(PhysAddr::new(platform.io_apic_address), 4096) }
+        .expect("mapping the IO APIC failed");
+    LAPIC_BASE.store(lapic.as_u64(), Ordering::Relaxed);
+    IO_APIC_BASE.store(io_apic.as_u64(), Ordering::Relaxed);
+
+    unsafe { apic_base.write(base | APIC_BASE_ENABLE) };
+    crate::percpu::set_apic_id(lapic_id() as u32);
+    // software enable the local APIC (it ignores interrupts until then)
+    lapic_write(
+        LAPIC_SPURIOUS,
+        SPURIOUS_APIC_ENABLE | SPURIOUS_VECTOR as u32,
+    );
+    let counts_per_tick = calibrate_timer();
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // mask all the lines of the PICs, the IO APIC delivers the device interrupts from now on
+        unsafe { interrupts::PICS.lock().disable() };
+
+        let inputs = (io_apic_read(IOAPIC_VERSION) >> 16 & 0xFF) + 1;
+        for input in 0..inputs {
+            set_redirection(input, REDIRECTION_MASKED);
+        }
+        let destination = lapic_id();
+        // IRQ 0 is the PIT which is replaced by the APIC timer
+        for irq in 1..interrupts::IRQ_LINES as u8 {
+            let (input, entry) = isa_redirection(irq, &platform, destination);
+            if input < inputs {
+                set_redirection(input, entry);
+            }
+        }
+
+        lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic_write(
+            LAPIC_LVT_TIMER,
+            LVT_TIMER_PERIODIC | InterruptIndex::Timer.as_u8() as u32,
+        );
+        lapic_write(LAPIC_TIMER_INITIAL_COUNT, counts_per_tick);
+        ENABLED.store(true, Ordering::Relaxed);
+        log::info!(
+            "apic: local APIC {}, IO APIC with {} inputs, timer {} counts per tick",
+            destination,
+            inputs,
+            counts_per_tick
+        );
+    });
+}
+
+// the number of APIC timer counts per timer tick (1 / TICKS_PER_SECOND seconds)
+fn calibrate_timer() -> u32 {
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+
+    // start at the beginning of a tick so we measure whole ticks
+    let start = timer::ticks();
+    while timer::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    lapic_write(LAPIC_TIMER_INITIAL_COUNT, u32::MAX);
+    let end = timer::ticks() + CALIBRATION_TICKS;
+    while timer::ticks() < end {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT_COUNT);
+    lapic_write(LAPIC_TIMER_INITIAL_COUNT, 0);
+    (elapsed / CALIBRATION_TICKS as u32).max(1)
+}
+
+#[test_case]
+fn test_isa_redirection_uses_overrides() {
+    let overrides = [InterruptOverride {
+        irq: 9,
+        gsi: 20,
+        active_low: true,
+        level_triggered: true,
+    }];
+    let platform = Platform {
+        io_apic_address: DEFAULT_IO_APIC_ADDRESS,
+        io_apic_gsi_base: 0,
+        overrides: &overrides,
+    };
+    let (input, entry) = isa_redirection(1, &platform, 3);
+    assert_eq!(input, 1);
+    assert_eq!(entry, (PIC_1_OFFSET + 1) as u64 | 3 << 56);
+    let (input, entry) = isa_redirection(9, &platform, 0);
+    assert_eq!(input, 20);
+    assert_eq!(
+        entry,
+        (PIC_1_OFFSET + 9) as u64 | REDIRECTION_ACTIVE_LOW | REDIRECTION_LEVEL_TRIGGERED
+    );
+}
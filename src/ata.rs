@@ -0,0 +1,397 @@
+/*
+* Hard disks that speak the ADVANCED_TECHNOLOGY_ATTACHMENT (ATA) protocol are connected to two channels
+* (primary and secondary) with up to two drives each (master and slave). The IDE controllers we know of
+* (QEMU's PIIX, most chipsets in compatibility mode) have them at the legacy ports:
+*
+*     channel    registers (I/O base)   control   IRQ
+*     primary    0x1F0-0x1F7            0x3F6     14
+*     secondary  0x170-0x177            0x376     15
+*
+* The drives are used in PROGRAMMED_I/O (PIO) mode: the CPU copies every 16 bit word of a sector through the
+* data register, no DMA. After a read command the drive raises its IRQ when a sector is ready, after it was
+* written the drive raises it when the data is on the disk (or in its cache). The interrupt handler reads the
+* status register (which acknowledges the interrupt) and a waiting command continues, the CPU halts in
+* between instead of polling the drive. When interrupts are disabled the status is polled instead.
+*
+* The sectors are addressed with 28 bit LOGICAL_BLOCK_ADDRESSES (LBA), which covers disks up to 128 GiB.
+* */
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::interrupts;
+use alloc::string::String;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+// the largest sector number of a 28 bit LBA + 1
+const LBA28_SECTORS: u64 = 1 << 28;
+// how long a command may take before the drive is considered dead
+const TIMEOUT_MS: u64 = 2000;
+
+// offsets of the registers from the I/O base
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16 = 3;
+const REG_LBA_MID: u16 = 4;
+const REG_LBA_HIGH: u16 = 5;
+const REG_DRIVE: u16 = 6;
+const REG_STATUS: u16 = 7;
+const REG_COMMAND: u16 = 7;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DRIVE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_FLUSH_CACHE: u8 = 0xE7;
+const COMMAND_IDENTIFY: u8 = 0xEC;
+
+// bits of the drive register: the LBA bit and the always set bits 5 and 7
+const DRIVE_LBA: u8 = 0xE0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+struct Channel {
+    name: &'static str,
+    io_base: u16,
+    control_base: u16,
+    irq: u8,
+    // set by the interrupt handler together with the status it read
+    irq_fired: AtomicBool,
+    irq_status: AtomicU8,
+    // one command at a time, the drives of a channel share the registers
+    lock: Mutex<()>,
+}
+
+static CHANNELS: [Channel; 2] = [
+    Channel::new("primary", 0x1F0, 0x3F6, 14),
+    Channel::new("secondary", 0x170, 0x376, 15),
+];
+
+impl Channel {
+    const fn new(name: &'static str, io_base: u16, control_base: u16, irq: u8) -> Self {
+        Channel {
+            name,
+            io_base,
+            control_base,
+            irq,
+            irq_fired: AtomicBool::new(false),
+            irq_status: AtomicU8::new(0),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::new(self.io_base + register).write(value) }
+    }
+
+    // the status without acknowledging the interrupt
+    fn alternate_status(&self) -> u8 {
+        unsafe { Port::new(self.control_base).read() }
+    }
+
+    // the drive needs 400ns to put its status on the bus after a command or drive selection, reading the
+    // alternate status takes about 100ns
+    fn delay_400ns(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    fn select(&self, slave: bool, lba: u32) {
+        let drive = DRIVE_LBA | if slave { DRIVE_SLAVE } else { 0 };
+        self.write(REG_DRIVE, drive | (lba >> 24 & 0x0F) as u8);
+        self.delay_400ns();
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        let deadline = crate::timer::uptime_ms() + TIMEOUT_MS;
+        loop {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            if crate::timer::uptime_ms() > deadline {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    // wait until the drive signals completion, returns the status it had
+    fn wait(&self) -> Result<u8, BlockError> {
+        use x86_64::instructions::interrupts as cpu;
+
+        if !cpu::are_enabled() {
+            self.wait_not_busy()?;
+            return self.check(self.read(REG_STATUS));
+        }
+        let deadline = crate::timer::uptime_ms() + TIMEOUT_MS;
+        loop {
+            // like the executor, check and halt atomically so the interrupt can't arrive in between
+            cpu::disable();
+            if self.irq_fired.swap(false, Ordering::SeqCst) {
+                cpu::enable();
+                return self.check(self.irq_status.load(Ordering::SeqCst));
+            }
+            if crate::timer::uptime_ms() > deadline {
+                cpu::enable();
+                return Err(BlockError::Timeout);
+            }
+            cpu::enable_and_hlt();
+        }
+    }
+
+    fn check(&self, status: u8) -> Result<u8, BlockError> {
+        if status & (STATUS_ERROR | STATUS_DRIVE_FAULT) != 0 {
+            return Err(BlockError::Device(self.read(REG_ERROR)));
+        }
+        Ok(status)
+    }
+
+    fn command(&self, command: u8) {
+        self.irq_fired.store(false, Ordering::SeqCst);
+        self.write(REG_COMMAND, command);
+        self.delay_400ns();
+    }
+
+    fn read_words(&self, buffer: &mut [u8]) {
+        let mut data: Port<u16> = Port::new(self.io_base + REG_DATA);
+        for word in buffer.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_words(&self, buffer: &[u8]) {
+        let mut data: Port<u16> = Port::new(self.io_base + REG_DATA);
+        for word in buffer.chunks_exact(2) {
+            unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+
+    // the IDENTIFY data of the drive, None if there is no ATA drive (nothing or an ATAPI drive)
+    fn identify(&self, slave: bool) -> Option<[u8; SECTOR_SIZE]> {
+        // a bus without drives floats high
+        if self.alternate_status() == 0xFF {
+            return None;
+        }
+        self.select(slave, 0);
+        for register in [REG_SECTOR_COUNT, REG_LBA_LOW, REG_LBA_MID, REG_LBA_HIGH] {
+            self.write(register, 0);
+        }
+        self.command(COMMAND_IDENTIFY);
+        if self.read(REG_STATUS) == 0 {
+            return None;
+        }
+        self.wait_not_busy().ok()?;
+        // ATAPI and SATA drives abort the command and put their signature in the LBA registers
+        if self.read(REG_LBA_MID) != 0 || self.read(REG_LBA_HIGH) != 0 {
+            return None;
+        }
+        // a drive that never asks to transfer the data is treated like no drive
+        let deadline = crate::timer::uptime_ms() + TIMEOUT_MS;
+        loop {
+            let status = self.read(REG_STATUS);
+            if status & STATUS_ERROR != 0 {
+                return None;
+            }
+            if status & STATUS_DATA_REQUEST != 0 {
+                break;
+            }
+            if crate::timer::uptime_ms() > deadline {
+                return None;
+            }
+            core::hint::spin_loop();
+        }
+        let mut data = [0; SECTOR_SIZE];
+        self.read_words(&mut data);
+        // reading the status register acknowledged the interrupt of the command
+        self.irq_fired.store(false, Ordering::SeqCst);
+        Some(data)
+    }
+}
+
+fn primary_irq_handler() {
+    irq_handler(&CHANNELS[0]);
+}
+
+fn secondary_irq_handler() {
+    irq_handler(&CHANNELS[1]);
+}
+
+fn irq_handler(channel: &Channel) {
+    // reading the status register tells the drive that the interrupt was handled
+    channel
+        .irq_status
+        .store(channel.read(REG_STATUS), Ordering::SeqCst);
+    channel.irq_fired.store(true, Ordering::SeqCst);
+}
+
+// the strings of the IDENTIFY data have the two bytes of every word swapped and are padded with spaces
+fn identify_string(data: &[u8]) -> String {
+    let mut string = String::with_capacity(data.len());
+    for word in data.chunks_exact(2) {
+        string.push(word[1] as char);
+        string.push(word[0] as char);
+    }
+    String::from(string.trim())
+}
+
+pub struct AtaDrive {
+    channel: &'static Channel,
+    slave: bool,
+    // ata0 is the primary master, ata3 the secondary slave
+    number: usize,
+    pub model: String,
+    pub serial: String,
+    // the number of sectors addressable with 28 bit LBA
+    sectors: u64,
+}
+
+impl AtaDrive {
+    fn new(number: usize, slave: bool, data: &[u8; SECTOR_SIZE]) -> Self {
+        let word = |index: usize| u16::from_le_bytes([data[index * 2], data[index * 2 + 1]]) as u64;
+        AtaDrive {
+            channel: &CHANNELS[number / 2],
+            slave,
+            number,
+            // words 10-19 and 27-46
+            serial: identify_string(&data[20..40]),
+            model: identify_string(&data[54..94]),
+            // words 60-61
+            sectors: (word(61) << 16 | word(60)).min(LBA28_SECTORS),
+        }
+    }
+
+    // issue a read or write command for up to 256 sectors (a sector count of 0 means 256)
+    fn start(&self, command: u8, lba: u64, count: usize) -> Result<(), BlockError> {
+        let channel = self.channel;
+        channel.wait_not_busy()?;
+        channel.select(self.slave, lba as u32);
+        channel.write(REG_SECTOR_COUNT, count as u8);
+        channel.write(REG_LBA_LOW, lba as u8);
+        channel.write(REG_LBA_MID, (lba >> 8) as u8);
+        channel.write(REG_LBA_HIGH, (lba >> 16) as u8);
+        channel.command(command);
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> String {
+        alloc::format!("ata{}", self.number)
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_range(self, lba, buffer.len())?;
+        let _lock = self.channel.lock.lock();
+        for (index, chunk) in buffer.chunks_mut(256 * SECTOR_SIZE).enumerate() {
+            let lba = lba + index as u64 * 256;
+            self.start(COMMAND_READ_SECTORS, lba, chunk.len() / SECTOR_SIZE)?;
+            for sector in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                // the interrupt arrives when the sector is in the drive's buffer
+                self.channel.wait()?;
+                self.channel.read_words(sector);
+            }
+        }
+        Ok(())
+    }
+
+    // write the buffer to the sectors starting at lba and flush the drive's write cache
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_range(self, lba, buffer.len())?;
+        let _lock = self.channel.lock.lock();
+        let channel = self.channel;
+        for (index, chunk) in buffer.chunks(256 * SECTOR_SIZE).enumerate() {
+            let lba = lba + index as u64 * 256;
+            self.start(COMMAND_WRITE_SECTORS, lba, chunk.len() / SECTOR_SIZE)?;
+            for sector in chunk.chunks_exact(SECTOR_SIZE) {
+                // the drive doesn't interrupt before the first sector, it only sets DRQ
+                let status = channel.check(channel.wait_not_busy()?)?;
+                if status & STATUS_DATA_REQUEST == 0 {
+                    return Err(BlockError::Device(channel.read(REG_ERROR)));
+                }
+                channel.irq_fired.store(false, Ordering::SeqCst);
+                channel.write_words(sector);
+                // the interrupt arrives when the drive took the sector
+                channel.wait()?;
+            }
+        }
+        channel.select(self.slave, 0);
+        channel.command(COMMAND_FLUSH_CACHE);
+        channel.wait()?;
+        Ok(())
+    }
+}
+
+static DRIVES: OnceCell<Vec<AtaDrive>> = OnceCell::uninit();
+
+// the drives found by init
+pub fn drives() -> &'static [AtaDrive] {
+    DRIVES.get().map_or(&[], |drives| drives.as_slice())
+}
+
+// look for drives on both channels, called by init_devices
+pub fn init() {
+    let legacy = crate::pci::find(crate::pci::DeviceMatch::Class {
+        class: 0x01,
+        subclass: 0x01,
+    })
+    // bits 0 and 2 of the programming interface are set if a channel is in PCI native mode
+    .is_some_and(|controller| controller.prog_if & 0x05 == 0);
+    if !legacy {
+        log::info!("ata: no IDE controller in compatibility mode");
+        return;
+    }
+    interrupts::register_irq_handler(CHANNELS[0].irq, primary_irq_handler);
+    interrupts::register_irq_handler(CHANNELS[1].irq, secondary_irq_handler);
+    DRIVES.init_once(|| {
+        let mut drives = Vec::new();
+        for number in 0..4 {
+            let channel = &CHANNELS[number / 2];
+            let slave = number % 2 == 1;
+            let _lock = channel.lock.lock();
+            if let Some(data) = channel.identify(slave) {
+                drives.push(AtaDrive::new(number, slave, &data));
+            }
+        }
+        drives
+    });
+    for drive in drives() {
+        log::info!(
+            "ata: {}: {} ({} MiB) on the {} channel",
+            drive.name(),
+            drive.model,
+            drive.capacity() / 1024 / 1024,
+            drive.channel.name
+        );
+        block::register(drive);
+    }
+}
+
+#[test_case]
+fn test_identify_strings_and_read_the_boot_sector() {
+    assert_eq!(identify_string(b"EQUMH RADDSI K  "), "QEMU HARDDISK");
+    // QEMU boots the tests from the disk image on the primary master, its first sector is the boot sector
+    let Some(drive) = drives().first() else {
+        return;
+    };
+    let mut sector = [0; SECTOR_SIZE];
+    drive.read_sectors(0, &mut sector).unwrap();
+    assert_eq!(&sector[510..], &[0x55, 0xAA]);
+    assert_eq!(
+        drive.read_sectors(drive.sectors, &mut sector),
+        Err(BlockError::OutOfRange)
+    );
+}
@@ -0,0 +1,157 @@
+/*
+* With frame pointers (-C force-frame-pointers=yes in .cargo/config.toml) every function starts by pushing
+* the RBP of its caller and then points RBP at that saved value, so the frames form a linked list on the stack:
+*
+*     [rbp]     -> the RBP of the caller (the next frame)
+*     [rbp + 8] -> the return address into the caller (pushed by the call instruction)
+*
+* Following the list from the current RBP gives the return addresses of all the calls that led here.
+* A corrupted stack (the usual reason for a panic deep in driver code) can contain any value, so every
+* frame is checked to lie inside a known stack before it is read, otherwise the walk stops.
+* */
+use core::fmt;
+use core::ops::Range;
+use spin::Mutex;
+
+// the maximal number of return addresses kept by a backtrace
+pub const MAX_FRAMES: usize = 32;
+const MAX_STACKS: usize = 8;
+
+// the address ranges of the stacks the walk may read from (kernel stack, interrupt stacks...)
+static STACKS: Mutex<[Option<Range<u64>>; MAX_STACKS]> = Mutex::new([const { None }; MAX_STACKS]);
+
+/*
+* The bootloader allocates 512 pages for the kernel stack (kernel-stack-size default), the multiboot2 entry
+* reserves as many. It doesn't report where, but the current stack pointer is on the first page of it when
+* init runs so its top is the next page boundary.
+* */
+pub const KERNEL_STACK_SIZE: u64 = 512 * 4096;
+
+pub fn init() {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    let top = (rsp + 4095) & !4095;
+    register_stack(top - KERNEL_STACK_SIZE..top);
+}
+
+// allow the walk to read frames in the range, stacks that aren't registered end the backtrace
+pub fn register_stack(stack: Range<u64>) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut stacks = STACKS.lock();
+        if let Some(slot) = stacks.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(stack);
+        }
+    });
+}
+
+// the registered stack that contains the frame (the saved RBP and the return address)
+fn stack_of(frame: u64) -> Option<Range<u64>> {
+    // don't deadlock if the panic happened while registering a stack, the backtrace is just empty then
+    let stacks = STACKS.try_lock()?;
+    stacks
+        .iter()
+        .flatten()
+        .find(|stack| stack.start <= frame && frame.saturating_add(16) <= stack.end)
+        .cloned()
+}
+
+// the return addresses of the calls that lead to Backtrace::capture, the innermost call first
+#[derive(Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    #[inline(never)]
+    pub fn capture() -> Backtrace {
+        let rbp: u64;
+        unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+        unsafe { Self::from_frame_pointer(rbp) }
+    }
+
+    /*
+     * Walk the frames starting at the given frame pointer.
+     * Unsafe because the registered stacks must be mapped, only the frames inside them are read.
+     * */
+    pub unsafe fn from_frame_pointer(mut rbp: u64) -> Backtrace {
+        let mut backtrace = Backtrace {
+            frames: [0; MAX_FRAMES],
+            len: 0,
+        };
+        let Some(stack) = stack_of(rbp) else {
+            return backtrace;
+        };
+
+        while backtrace.len < MAX_FRAMES {
+            if !rbp.is_multiple_of(8) || rbp < stack.start || rbp.saturating_add(16) > stack.end {
+                break;
+            }
+            let frame = rbp as *const u64;
+            let return_address = *frame.add(1);
+            if return_address == 0 {
+                break;
+            }
+            backtrace.frames[backtrace.len] = return_address;
+            backtrace.len += 1;
+
+            // the callers' frames are higher up the stack (it grows downwards), anything else is a broken frame
+            let next = *frame;
+            if next <= rbp {
+                break;
+            }
+            rbp = next;
+        }
+        backtrace
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, &address) in self.frames().iter().enumerate() {
+            write!(f, "{:>2}: {:#018x}", index, address)?;
+            match crate::symbols::lookup_return_address(address) {
+                Some(symbol) => writeln!(f, " {}", symbol)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_backtrace_walks_nested_calls() {
+    #[inline(never)]
+    fn nested(depth: usize) -> Backtrace {
+        if depth == 0 {
+            Backtrace::capture()
+        } else {
+            // the addition after the call keeps it from becoming a tail call that reuses the frame
+            let backtrace = nested(depth - 1);
+            core::hint::black_box(depth + 1);
+            backtrace
+        }
+    }
+
+    let backtrace = nested(3);
+    // capture is called by nested(0) which is called by nested(1..3) and the test itself
+    assert!(backtrace.frames().len() >= 5);
+    let nested_calls = &backtrace.frames()[1..4];
+    assert!(nested_calls
+        .iter()
+        .all(|&address| address == nested_calls[0]));
+}
+
+#[test_case]
+fn test_backtrace_stops_outside_known_stacks() {
+    // the array is on the kernel stack, its saved RBP points outside of it so the walk ends after one frame
+    let fake_frame: [u64; 2] = [0xdead_beef_0000, 0x1234];
+    let rbp = fake_frame.as_ptr() as u64;
+    let backtrace = unsafe { Backtrace::from_frame_pointer(rbp) };
+    assert_eq!(backtrace.frames(), &[0x1234]);
+    assert_eq!(unsafe { Backtrace::from_frame_pointer(0x10) }.frames(), &[]);
+}
@@ -0,0 +1,393 @@
+/*
+* Disks of every kind (ATA, virtio, a ramdisk) implement the BlockDevice trait: an array of fixed size
+* sectors that can be read and written, so the filesystems don't care where the sectors are stored.
+*
+* The drivers register their disks here. A disk is split into partitions by the table in its first sectors,
+* every partition is registered as a device of its own (a window into the disk) and the filesystems mount
+* the partitions:
+*  * MBR: the first sector ends with 0x55AA and has 4 entries at offset 446 (type, first sector, sector
+*    count). Extended partitions (types 0x05, 0x0F) aren't followed.
+*  * GPT: the MBR has a single protective entry of type 0xEE, the GPT header is in sector 1 ("EFI PART") and
+*    points to the array of entries (type GUID, first and last sector, UTF-16 name). The CRC32s of the
+*    header and array aren't checked.
+* */
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+pub const SECTOR_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    // the sectors are beyond the end of the device
+    OutOfRange,
+    // the buffer isn't a multiple of the sector size
+    BufferSize,
+    ReadOnly,
+    // the device didn't finish the request in time
+    Timeout,
+    // the device reported an error, the value is driver specific (the ATA error register, virtio status)
+    Device(u8),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "sector out of range"),
+            BlockError::BufferSize => write!(f, "buffer isn't a multiple of the sector size"),
+            BlockError::ReadOnly => write!(f, "the device is read only"),
+            BlockError::Timeout => write!(f, "timeout"),
+            BlockError::Device(error) => write!(f, "device error {:#04x}", error),
+        }
+    }
+}
+
+pub trait BlockDevice: Send + Sync {
+    // the name the device is found by, e.g. ata0 and its partitions ata0p1, ata0p2
+    fn name(&self) -> String;
+
+    // the number of sectors
+    fn sectors(&self) -> u64;
+
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    // read the sectors starting at lba, the length of the buffer selects the number of sectors
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    fn capacity(&self) -> u64 {
+        self.sectors() * self.sector_size() as u64
+    }
+}
+
+// check that the request fits the device, returns the number of sectors
+pub fn check_range(device: &dyn BlockDevice, lba: u64, len: usize) -> Result<u64, BlockError> {
+    let sector_size = device.sector_size();
+    if !len.is_multiple_of(sector_size) {
+        return Err(BlockError::BufferSize);
+    }
+    let count = (len / sector_size) as u64;
+    match lba.checked_add(count) {
+        Some(end) if end <= device.sectors() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+// what the partition table says the partition contains
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    // the type byte of an MBR entry
+    Mbr(u8),
+    // the type GUID of a GPT entry, in the mixed endian byte order it is stored in
+    Gpt([u8; 16]),
+}
+
+// the "Microsoft basic data" type GUID EBD0A0A2-B9E5-4433-87C0-68B6B72699C7 used for FAT partitions
+pub const GPT_BASIC_DATA: [u8; 16] = [
+    0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7,
+];
+
+impl PartitionKind {
+    // FAT32 (with CHS or LBA addressing) or a GPT data partition
+    pub fn is_fat(&self) -> bool {
+        matches!(self, PartitionKind::Mbr(0x0B | 0x0C))
+            || *self == PartitionKind::Gpt(GPT_BASIC_DATA)
+    }
+}
+
+impl fmt::Display for PartitionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PartitionKind::Mbr(kind) => write!(f, "type {:#04x}", kind),
+            PartitionKind::Gpt(guid) => {
+                let u16 = |i: usize| u16::from_le_bytes([guid[i], guid[i + 1]]);
+                let u32 = u32::from_le_bytes([guid[0], guid[1], guid[2], guid[3]]);
+                write!(f, "{:08X}-{:04X}-{:04X}-", u32, u16(4), u16(6))?;
+                for (index, byte) in guid[8..].iter().enumerate() {
+                    if index == 2 {
+                        write!(f, "-")?;
+                    }
+                    write!(f, "{:02X}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// a range of sectors of a disk
+pub struct Partition {
+    disk: &'static dyn BlockDevice,
+    // 1 based like the names of Linux (sda1)
+    number: usize,
+    start: u64,
+    sectors: u64,
+    pub kind: PartitionKind,
+}
+
+impl Partition {
+    pub fn disk(&self) -> &'static dyn BlockDevice {
+        self.disk
+    }
+}
+
+impl BlockDevice for Partition {
+    fn name(&self) -> String {
+        format!("{}p{}", self.disk.name(), self.number)
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn sector_size(&self) -> usize {
+        self.disk.sector_size()
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buffer.len())?;
+        self.disk.read_sectors(self.start + lba, buffer)
+    }
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buffer.len())?;
+        self.disk.write_sectors(self.start + lba, buffer)
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// (first sector, sector count, kind) of the partitions of the disk
+fn parse_partitions(disk: &dyn BlockDevice) -> Result<Vec<(u64, u64, PartitionKind)>, BlockError> {
+    let sector_size = disk.sector_size();
+    let mut mbr = vec![0; sector_size];
+    disk.read_sectors(0, &mut mbr)?;
+    if mbr[510..512] != [0x55, 0xAA] {
+        return Ok(Vec::new());
+    }
+    let entries = (0..4).map(|index| &mbr[446 + index * 16..446 + (index + 1) * 16]);
+    let mut partitions = Vec::new();
+    for entry in entries {
+        let kind = entry[4];
+        let start = read_u32(entry, 8) as u64;
+        let sectors = read_u32(entry, 12) as u64;
+        match kind {
+            0x00 => {}
+            0xEE => return parse_gpt(disk),
+            // the logical partitions in an extended partition would need its chain of tables
+            0x05 | 0x0F => log::warn!(
+                "block: {}: extended partitions aren't supported",
+                disk.name()
+            ),
+            _ => partitions.push((start, sectors, PartitionKind::Mbr(kind))),
+        }
+    }
+    Ok(partitions)
+}
+
+fn parse_gpt(disk: &dyn BlockDevice) -> Result<Vec<(u64, u64, PartitionKind)>, BlockError> {
+    let sector_size = disk.sector_size();
+    let mut header = vec![0; sector_size];
+    disk.read_sectors(1, &mut header)?;
+    if &header[0..8] != b"EFI PART" {
+        log::warn!(
+            "block: {}: protective MBR without a GPT header",
+            disk.name()
+        );
+        return Ok(Vec::new());
+    }
+    let entries_lba = read_u64(&header, 72);
+    let entry_count = read_u32(&header, 80) as usize;
+    let entry_size = read_u32(&header, 84) as usize;
+    if entry_size < 128 || entry_count > 1024 {
+        return Ok(Vec::new());
+    }
+    let bytes = (entry_count * entry_size).div_ceil(sector_size) * sector_size;
+    let mut entries = vec![0; bytes];
+    disk.read_sectors(entries_lba, &mut entries)?;
+
+    let mut partitions = Vec::new();
+    for entry in entries.chunks_exact(entry_size).take(entry_count) {
+        let kind: [u8; 16] = entry[0..16].try_into().unwrap();
+        // an all zero type GUID is an unused entry
+        if kind == [0; 16] {
+            continue;
+        }
+        let first = read_u64(entry, 32);
+        // the last sector is inclusive
+        let last = read_u64(entry, 40);
+        if last >= first {
+            partitions.push((first, last - first + 1, PartitionKind::Gpt(kind)));
+        }
+    }
+    Ok(partitions)
+}
+
+static DEVICES: Mutex<Vec<&'static dyn BlockDevice>> = Mutex::new(Vec::new());
+static PARTITIONS: Mutex<Vec<&'static Partition>> = Mutex::new(Vec::new());
+
+// register a disk and its partitions, called by the drivers when they found a disk
+pub fn register(disk: &'static dyn BlockDevice) {
+    DEVICES.lock().push(disk);
+    let partitions = match parse_partitions(disk) {
+        Ok(partitions) => partitions,
+        Err(error) => {
+            log::warn!(
+                "block: {}: can't read the partition table: {}",
+                disk.name(),
+                error
+            );
+            return;
+        }
+    };
+    for (index, (start, sectors, kind)) in partitions.into_iter().enumerate() {
+        // a partition that doesn't fit the disk is a corrupt table
+        if start
+            .checked_add(sectors)
+            .is_none_or(|end| end > disk.sectors())
+        {
+            log::warn!(
+                "block: {}: partition {} is outside of the disk",
+                disk.name(),
+                index + 1
+            );
+            continue;
+        }
+        let partition: &'static Partition = Box::leak(Box::new(Partition {
+            disk,
+            number: index + 1,
+            start,
+            sectors,
+            kind,
+        }));
+        log::info!(
+            "block: {}: {} sectors at {}, {}",
+            partition.name(),
+            sectors,
+            start,
+            kind
+        );
+        DEVICES.lock().push(partition);
+        PARTITIONS.lock().push(partition);
+    }
+}
+
+// all disks and partitions
+pub fn devices() -> Vec<&'static dyn BlockDevice> {
+    DEVICES.lock().clone()
+}
+
+pub fn partitions() -> Vec<&'static Partition> {
+    PARTITIONS.lock().clone()
+}
+
+pub fn find(name: &str) -> Option<&'static dyn BlockDevice> {
+    devices().into_iter().find(|device| device.name() == name)
+}
+
+// a disk in memory, e.g. a disk image loaded by the bootloader
+pub struct RamDisk {
+    name: String,
+    data: Mutex<Vec<u8>>,
+}
+
+impl RamDisk {
+    // the data is padded with zeros to a whole number of sectors
+    pub fn new(name: &str, mut data: Vec<u8>) -> Self {
+        data.resize(data.len().div_ceil(SECTOR_SIZE) * SECTOR_SIZE, 0);
+        RamDisk {
+            name: String::from(name),
+            data: Mutex::new(data),
+        }
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn sectors(&self) -> u64 {
+        (self.data.lock().len() / SECTOR_SIZE) as u64
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buffer.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        buffer.copy_from_slice(&self.data.lock()[start..start + buffer.len()]);
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        check_range(self, lba, buffer.len())?;
+        let start = lba as usize * SECTOR_SIZE;
+        self.data.lock()[start..start + buffer.len()].copy_from_slice(buffer);
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_mbr_and_gpt_partitions() {
+    // an MBR with a FAT32 partition and an empty entry
+    let mut image = vec![0; 64 * SECTOR_SIZE];
+    image[446 + 4] = 0x0C;
+    image[446 + 8..446 + 12].copy_from_slice(&8u32.to_le_bytes());
+    image[446 + 12..446 + 16].copy_from_slice(&16u32.to_le_bytes());
+    image[510] = 0x55;
+    image[511] = 0xAA;
+    let disk = RamDisk::new("test", image.clone());
+    let partitions = parse_partitions(&disk).unwrap();
+    assert_eq!(partitions, [(8, 16, PartitionKind::Mbr(0x0C))]);
+    assert!(partitions[0].2.is_fat());
+
+    // a protective MBR with a GPT of 4 entries in sector 2, the second entry is used
+    image[446 + 4] = 0xEE;
+    image[SECTOR_SIZE..SECTOR_SIZE + 8].copy_from_slice(b"EFI PART");
+    image[SECTOR_SIZE + 72..SECTOR_SIZE + 80].copy_from_slice(&2u64.to_le_bytes());
+    image[SECTOR_SIZE + 80..SECTOR_SIZE + 84].copy_from_slice(&4u32.to_le_bytes());
+    image[SECTOR_SIZE + 84..SECTOR_SIZE + 88].copy_from_slice(&128u32.to_le_bytes());
+    let entry = 2 * SECTOR_SIZE + 128;
+    image[entry..entry + 16].copy_from_slice(&GPT_BASIC_DATA);
+    image[entry + 32..entry + 40].copy_from_slice(&34u64.to_le_bytes());
+    image[entry + 40..entry + 48].copy_from_slice(&63u64.to_le_bytes());
+    let disk = RamDisk::new("test", image);
+    assert_eq!(
+        parse_partitions(&disk).unwrap(),
+        [(34, 30, PartitionKind::Gpt(GPT_BASIC_DATA))]
+    );
+    assert_eq!(
+        format!("{}", PartitionKind::Gpt(GPT_BASIC_DATA)),
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7"
+    );
+
+    // reads through a partition are offset and limited to it
+    let disk: &'static RamDisk =
+        Box::leak(Box::new(RamDisk::new("test", vec![7; 8 * SECTOR_SIZE])));
+    let partition = Partition {
+        disk,
+        number: 1,
+        start: 4,
+        sectors: 2,
+        kind: PartitionKind::Mbr(0x83),
+    };
+    let mut sector = [0; SECTOR_SIZE];
+    partition.read_sectors(1, &mut sector).unwrap();
+    assert_eq!(sector[0], 7);
+    assert_eq!(
+        partition.read_sectors(2, &mut sector),
+        Err(BlockError::OutOfRange)
+    );
+}
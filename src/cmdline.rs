@@ -0,0 +1,139 @@
+/*
+* The kernel command line is a list of options separated by spaces, `key=value` or just `key` for a flag:
+*
+*     log_level=debug console=serial test_timeout=30
+*
+* bootloader 0.9 has no way to pass a command line, so it is read from QEMU's firmware configuration
+* device (fw_cfg) which exposes files given on the QEMU command line to the guest:
+*
+*     cargo run -- -fw_cfg name=opt/rust_os/cmdline,string="log_level=debug console=serial"
+*
+* (QEMU splits its options at commas, a comma inside the string is written as ",,", or the command line
+* can be read from a host file with file=<path> instead of string=). Without fw_cfg the command line set
+* at compile time in the RUST_OS_CMDLINE environment variable is used. Other boot protocols pass
+* theirs to init_with (GRUB the arguments of the multiboot2 command, multiboot2 module).
+*
+* The options known so far:
+*  * log_level=<off|error|warn|info|debug|trace>  the default level of the log messages
+*  * console=<vga|fb|serial,...>  the consoles of print! and the log messages (dmesg always gets them)
+*  * test_timeout=<seconds>  the time a test may run before the watchdog fails it
+*  * watchdog=<seconds>  panic if the kernel main loop is stuck for that long (watchdog module)
+*  * panic=<halt|reboot|qemu-exit>  what to do after a panic (panic_policy module)
+*  * noapic  keep using the legacy PIC and PIT instead of the APIC
+*  * keymap=<us|uk|de>  the keyboard layout
+*  * gdb[=com1|com2]  wait for gdb on the serial port (COM2 by default, gdb module)
+*  * ip=<dhcp|<address>/<prefix length>>, gateway=<address> and dns=<address>  the network configuration
+*    (DHCP by default, net module)
+* */
+use crate::fw_cfg;
+use conquer_once::spin::OnceCell;
+use core::str::FromStr;
+
+pub const MAX_CMDLINE_LEN: usize = 1024;
+// the name of the fw_cfg file, names starting with opt/ are free for the user
+pub const FW_CFG_FILE: &str = "opt/rust_os/cmdline";
+
+// the command line is copied here at boot so it lives for the rest of the kernel's life
+struct Storage {
+    bytes: [u8; MAX_CMDLINE_LEN],
+    len: usize,
+}
+
+static CMDLINE: OnceCell<Storage> = OnceCell::uninit();
+
+// read the command line from fw_cfg or the compile time default, called first thing by lib::init
+pub fn init() {
+    let mut storage = Storage {
+        bytes: [0; MAX_CMDLINE_LEN],
+        len: 0,
+    };
+    match fw_cfg::read_file(FW_CFG_FILE, &mut storage.bytes) {
+        Some(len) => storage.len = len,
+        None => storage.len = copy(option_env!("RUST_OS_CMDLINE").unwrap_or(""), &mut storage),
+    }
+    let _ = CMDLINE.try_init_once(|| storage);
+}
+
+// use the command line of the boot protocol, only the first call of init or init_with sets it
+pub fn init_with(cmdline: &str) {
+    let mut storage = Storage {
+        bytes: [0; MAX_CMDLINE_LEN],
+        len: 0,
+    };
+    storage.len = copy(cmdline, &mut storage);
+    let _ = CMDLINE.try_init_once(|| storage);
+}
+
+// copy as much as fits without cutting an option in half
+fn copy(cmdline: &str, storage: &mut Storage) -> usize {
+    let mut len = cmdline.len().min(MAX_CMDLINE_LEN);
+    if len < cmdline.len() {
+        len = cmdline.as_bytes()[..=len]
+            .iter()
+            .rposition(|&byte| byte == b' ')
+            .unwrap_or(0);
+    }
+    storage.bytes[..len].copy_from_slice(&cmdline.as_bytes()[..len]);
+    len
+}
+
+// the whole command line, empty if there is none or it isn't read yet
+pub fn as_str() -> &'static str {
+    let Ok(storage) = CMDLINE.try_get() else {
+        return "";
+    };
+    // the fw_cfg file can contain anything, a command line that isn't UTF-8 is ignored
+    let bytes = &storage.bytes[..storage.len];
+    core::str::from_utf8(bytes)
+        .unwrap_or("")
+        .trim_end_matches(['\0', '\n'])
+}
+
+// the options as (key, value) pairs, the value of a flag is ""
+pub fn options(cmdline: &str) -> impl Iterator<Item = (&str, &str)> {
+    cmdline
+        .split_ascii_whitespace()
+        .map(|option| option.split_once('=').unwrap_or((option, "")))
+}
+
+// the value of the option, if it is given several times the last one wins
+pub fn get(key: &str) -> Option<&'static str> {
+    find(as_str(), key)
+}
+
+fn find<'a>(cmdline: &'a str, key: &str) -> Option<&'a str> {
+    options(cmdline)
+        .filter(|&(option, _)| option == key)
+        .map(|(_, value)| value)
+        .last()
+}
+
+// a flag is set if it is given without a value or with a value other than 0, false, off or no
+pub fn flag(key: &str) -> bool {
+    get(key).is_some_and(|value| !matches!(value, "0" | "false" | "off" | "no"))
+}
+
+// the value converted to T, a value that doesn't parse is reported and ignored
+pub fn parse<T: FromStr>(key: &str) -> Option<T> {
+    let value = get(key)?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            log::warn!("ignoring invalid command line option {}={}", key, value);
+            None
+        }
+    }
+}
+
+#[test_case]
+fn test_options_are_parsed() {
+    let cmdline = "log_level=debug  quiet console=vga,serial log_level=trace test_timeout=";
+    let options: alloc::vec::Vec<_> = options(cmdline).collect();
+    assert_eq!(options.len(), 5);
+    assert_eq!(options[1], ("quiet", ""));
+    assert_eq!(find(cmdline, "console"), Some("vga,serial"));
+    // the last one wins
+    assert_eq!(find(cmdline, "log_level"), Some("trace"));
+    assert_eq!(find(cmdline, "test_timeout"), Some(""));
+    assert_eq!(find(cmdline, "log"), None);
+}
@@ -0,0 +1,302 @@
+/*
+* A CONSOLE is somewhere the kernel output is shown: the VGA text screen, a framebuffer or a terminal on the
+* serial port. print! and the log messages are written to all the active consoles (and the kernel message
+* buffer), so the screen and the terminal show the same output. The console option of the command line
+* selects them:
+*  * vga     the VGA text screen
+*  * fb      the framebuffer, once multiboot2::init registered the one GRUB set up (framebuffer::init)
+*  * serial  a terminal on COM1, it also sends the keys for the shell (task::serial)
+* The default is vga,serial. The VGA text screen isn't visible anymore once the screen shows a framebuffer,
+* so framebuffer::init replaces the vga console with the fb console.
+*
+* Every console writes the escape sequences of vga_buffer::ansi (colors, erasing, moving the cursor) in its
+* own way: the screens interpret them, a terminal gets them as they are.
+*
+* Interrupt handlers print too, so the registry is read with a RwLock and only changed with interrupts
+* disabled (like the log sinks), and the consoles lock their writers with interrupts disabled.
+* */
+use crate::framebuffer;
+use crate::serial::SERIAL1;
+use crate::vga_buffer::{self, Color, BUFFER_HEIGHT, BUFFER_WIDTH};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use spin::RwLock;
+use uart_16550::SerialPort;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const MAX_CONSOLES: usize = 4;
+
+pub trait Console: Sync {
+    // the name of the console option
+    fn name(&self) -> &'static str;
+    // write the output in the foreground color (the current color without one), if the console has colors
+    fn write(&self, foreground: Option<Color>, args: fmt::Arguments);
+    // erase everything and move the cursor to the top left corner
+    fn clear(&self);
+    // the (row, column) where the next character is written, None if the console doesn't know
+    fn cursor(&self) -> Option<(usize, usize)>;
+    fn set_cursor(&self, row: usize, column: usize);
+    // the number of rows and columns
+    fn size(&self) -> (usize, usize);
+}
+
+pub struct VgaConsole;
+
+impl Console for VgaConsole {
+    fn name(&self) -> &'static str {
+        "vga"
+    }
+
+    fn write(&self, foreground: Option<Color>, args: fmt::Arguments) {
+        vga_buffer::with_writer(|writer| match foreground {
+            // the color only applies to this output, colors set by escape sequences stay
+            Some(foreground) => {
+                let background = writer.color_code().background();
+                writer.with_color(foreground, background, |writer| {
+                    writer.write_fmt(args).unwrap()
+                });
+            }
+            None => writer.write_fmt(args).unwrap(),
+        });
+    }
+
+    fn clear(&self) {
+        vga_buffer::with_writer(|writer| writer.clear_screen());
+    }
+
+    fn cursor(&self) -> Option<(usize, usize)> {
+        Some(vga_buffer::with_writer(|writer| writer.cursor_position()))
+    }
+
+    fn set_cursor(&self, row: usize, column: usize) {
+        vga_buffer::with_writer(|writer| writer.set_cursor_position(row, column));
+    }
+
+    fn size(&self) -> (usize, usize) {
+        (BUFFER_HEIGHT, BUFFER_WIDTH)
+    }
+}
+
+// does nothing while the boot code didn't register a framebuffer
+pub struct FramebufferConsole;
+
+impl FramebufferConsole {
+    fn with_writer<R>(&self, f: impl FnOnce(&mut framebuffer::Writer) -> R) -> Option<R> {
+        without_interrupts(|| framebuffer::WRITER.lock().as_mut().map(f))
+    }
+}
+
+impl Console for FramebufferConsole {
+    fn name(&self) -> &'static str {
+        "fb"
+    }
+
+    fn write(&self, foreground: Option<Color>, args: fmt::Arguments) {
+        self.with_writer(|writer| match foreground {
+            Some(foreground) => {
+                let background = writer.color_code().background();
+                writer.with_color(foreground, background, |writer| {
+                    writer.write_fmt(args).unwrap()
+                });
+            }
+            None => writer.write_fmt(args).unwrap(),
+        });
+    }
+
+    fn clear(&self) {
+        self.with_writer(|writer| writer.clear_screen());
+    }
+
+    fn cursor(&self) -> Option<(usize, usize)> {
+        self.with_writer(|writer| writer.cursor_position())
+    }
+
+    fn set_cursor(&self, row: usize, column: usize) {
+        self.with_writer(|writer| writer.set_cursor_position(row, column));
+    }
+
+    fn size(&self) -> (usize, usize) {
+        self.with_writer(|writer| writer.size()).unwrap_or((0, 0))
+    }
+}
+
+// a VT100 compatible terminal, its size and cursor position aren't known so the usual 80x24 is assumed
+pub struct SerialConsole;
+
+// terminals move to the next line with \r\n, so \n is sent as both
+pub(crate) struct Terminal<'a>(pub(crate) &'a mut SerialPort);
+
+impl Write for Terminal<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.0.write_str("\r\n")?;
+            }
+            self.0.write_str(line)?;
+        }
+        Ok(())
+    }
+}
+
+impl SerialConsole {
+    fn write_terminal(&self, args: fmt::Arguments) {
+        without_interrupts(|| {
+            let _ = Terminal(&mut SERIAL1.lock()).write_fmt(args);
+        });
+    }
+}
+
+impl Console for SerialConsole {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn write(&self, _foreground: Option<Color>, args: fmt::Arguments) {
+        self.write_terminal(args);
+    }
+
+    fn clear(&self) {
+        self.write_terminal(format_args!("\x1b[2J\x1b[H"));
+    }
+
+    fn cursor(&self) -> Option<(usize, usize)> {
+        None
+    }
+
+    fn set_cursor(&self, row: usize, column: usize) {
+        // the rows and columns of the terminal start at 1
+        self.write_terminal(format_args!("\x1b[{};{}H", row + 1, column + 1));
+    }
+
+    fn size(&self) -> (usize, usize) {
+        (24, 80)
+    }
+}
+
+pub static VGA: VgaConsole = VgaConsole;
+pub static FRAMEBUFFER: FramebufferConsole = FramebufferConsole;
+pub static SERIAL: SerialConsole = SerialConsole;
+
+static CONSOLES: RwLock<[Option<&'static dyn Console>; MAX_CONSOLES]> =
+    RwLock::new([None; MAX_CONSOLES]);
+
+// add the console to the active ones, a console is only added once
+pub fn register(console: &'static dyn Console) {
+    without_interrupts(|| {
+        let mut consoles = CONSOLES.write();
+        if consoles
+            .iter()
+            .flatten()
+            .any(|active| active.name() == console.name())
+        {
+            return;
+        }
+        if let Some(slot) = consoles.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(console);
+        }
+    });
+}
+
+pub fn unregister(name: &str) {
+    without_interrupts(|| {
+        for slot in CONSOLES.write().iter_mut() {
+            if matches!(slot, Some(console) if console.name() == name) {
+                *slot = None;
+            }
+        }
+    });
+}
+
+// put the console in the place of the active console with the name, does nothing if it isn't active
+pub fn replace(name: &str, console: &'static dyn Console) {
+    without_interrupts(|| {
+        let mut consoles = CONSOLES.write();
+        let already_active = consoles
+            .iter()
+            .flatten()
+            .any(|active| active.name() == console.name());
+        for slot in consoles.iter_mut() {
+            if matches!(slot, Some(active) if active.name() == name) {
+                *slot = if already_active { None } else { Some(console) };
+                return;
+            }
+        }
+    });
+}
+
+pub fn is_active(name: &str) -> bool {
+    CONSOLES
+        .read()
+        .iter()
+        .flatten()
+        .any(|console| console.name() == name)
+}
+
+// the names of the active consoles
+pub fn active() -> Vec<&'static str> {
+    CONSOLES
+        .read()
+        .iter()
+        .flatten()
+        .map(|console| console.name())
+        .collect()
+}
+
+// write to all the active consoles
+pub fn write(foreground: Option<Color>, args: fmt::Arguments) {
+    for console in CONSOLES.read().iter().flatten() {
+        console.write(foreground, args);
+    }
+}
+
+pub fn clear() {
+    for console in CONSOLES.read().iter().flatten() {
+        console.clear();
+    }
+}
+
+// the writer of write, e.g. for dmesg::dump
+pub struct Consoles;
+
+impl Write for Consoles {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write(None, format_args!("{}", s));
+        Ok(())
+    }
+}
+
+// activate the consoles of the console option
+pub fn init() {
+    let option = crate::cmdline::get("console").unwrap_or("vga,serial");
+    for name in option.split(',') {
+        let console: &'static dyn Console = match name {
+            "vga" => &VGA,
+            "fb" => &FRAMEBUFFER,
+            "serial" => &SERIAL,
+            _ => {
+                log::warn!("unknown console {}", name);
+                continue;
+            }
+        };
+        register(console);
+    }
+}
+
+#[test_case]
+fn test_register_and_replace() {
+    let vga_was_active = is_active("vga");
+    register(&VGA);
+    register(&VGA);
+    assert_eq!(active().iter().filter(|&&name| name == "vga").count(), 1);
+    replace("vga", &FRAMEBUFFER);
+    assert!(is_active("fb") && !is_active("vga"));
+    // without a framebuffer the fb console has no cursor
+    assert_eq!(FRAMEBUFFER.cursor(), None);
+    replace("fb", &VGA);
+    assert_eq!(VGA.size(), (BUFFER_HEIGHT, BUFFER_WIDTH));
+    if !vga_was_active {
+        unregister("vga");
+    }
+    assert!(!is_active("fb"));
+    assert_eq!(is_active("vga"), vga_was_active);
+}
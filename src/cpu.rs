@@ -0,0 +1,245 @@
+/*
+* The CPUID instruction tells what the CPU is and what it can do. It takes a LEAF (and for some leaves a
+* subleaf) in EAX (ECX) and returns four registers of information:
+*  * leaf 0: the highest basic leaf and the VENDOR string (EBX, EDX, ECX), "GenuineIntel" or "AuthenticAMD"
+*  * leaf 1: the family, model and stepping in EAX, the original feature flags in EDX and ECX
+*  * leaf 7 subleaf 0: the structured extended features (SMEP, SMAP, AVX2, RDSEED...) in EBX and ECX
+*  * leaf 0x8000_0000 and up: the extended leaves, with the features of AMD64 (NX, 1 GiB pages, SYSCALL)
+*    and the brand string (leaves 0x8000_0002 to 0x8000_0004)
+*
+* The features are read once at boot and then asked with has(Feature::X), so the drivers check what the
+* machine has instead of assuming the CPU QEMU emulates by default.
+* */
+use conquer_once::spin::OnceCell;
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+// the features with the leaf, the register and the bit that has them
+macro_rules! features {
+    ($($feature:ident => $name:literal, $leaf:literal, $register:ident, $bit:literal;)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum Feature {
+            $($feature),*
+        }
+
+        const FEATURES: &[Feature] = &[$(Feature::$feature),*];
+
+        impl Feature {
+            // the name used by Linux in /proc/cpuinfo
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Feature::$feature => $name),*
+                }
+            }
+
+            fn location(self) -> (u32, Register, u32) {
+                match self {
+                    $(Feature::$feature => ($leaf, Register::$register, $bit)),*
+                }
+            }
+        }
+    };
+}
+
+features! {
+    Fpu => "fpu", 1, Edx, 0;
+    Tsc => "tsc", 1, Edx, 4;
+    Msr => "msr", 1, Edx, 5;
+    Pae => "pae", 1, Edx, 6;
+    Apic => "apic", 1, Edx, 9;
+    Pge => "pge", 1, Edx, 13;
+    Pat => "pat", 1, Edx, 16;
+    Fxsr => "fxsr", 1, Edx, 24;
+    Sse => "sse", 1, Edx, 25;
+    Sse2 => "sse2", 1, Edx, 26;
+    Sse3 => "pni", 1, Ecx, 0;
+    Ssse3 => "ssse3", 1, Ecx, 9;
+    Cx16 => "cx16", 1, Ecx, 13;
+    Pcid => "pcid", 1, Ecx, 17;
+    Sse4_1 => "sse4_1", 1, Ecx, 19;
+    Sse4_2 => "sse4_2", 1, Ecx, 20;
+    X2Apic => "x2apic", 1, Ecx, 21;
+    Popcnt => "popcnt", 1, Ecx, 23;
+    TscDeadline => "tsc_deadline_timer", 1, Ecx, 24;
+    Aes => "aes", 1, Ecx, 25;
+    Xsave => "xsave", 1, Ecx, 26;
+    Avx => "avx", 1, Ecx, 28;
+    Rdrand => "rdrand", 1, Ecx, 30;
+    Hypervisor => "hypervisor", 1, Ecx, 31;
+    FsGsBase => "fsgsbase", 7, Ebx, 0;
+    Avx2 => "avx2", 7, Ebx, 5;
+    Smep => "smep", 7, Ebx, 7;
+    Erms => "erms", 7, Ebx, 9;
+    Invpcid => "invpcid", 7, Ebx, 10;
+    Avx512F => "avx512f", 7, Ebx, 16;
+    Rdseed => "rdseed", 7, Ebx, 18;
+    Smap => "smap", 7, Ebx, 20;
+    Umip => "umip", 7, Ecx, 2;
+    Syscall => "syscall", 0x8000_0001, Edx, 11;
+    Nx => "nx", 0x8000_0001, Edx, 20;
+    Page1Gb => "pdpe1gb", 0x8000_0001, Edx, 26;
+    Rdtscp => "rdtscp", 0x8000_0001, Edx, 27;
+    LongMode => "lm", 0x8000_0001, Edx, 29;
+    InvariantTsc => "constant_tsc", 0x8000_0007, Edx, 8;
+}
+
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    // empty if the CPU has no brand string
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    // bit n is set if the CPU has FEATURES[n]
+    features: u64,
+}
+
+impl CpuInfo {
+    pub fn vendor(&self) -> &str {
+        core::str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    pub fn brand(&self) -> &str {
+        let end = self.brand.iter().position(|&byte| byte == 0).unwrap_or(48);
+        core::str::from_utf8(&self.brand[..end])
+            .unwrap_or("")
+            .trim()
+    }
+
+    pub fn has(&self, feature: Feature) -> bool {
+        self.features & (1 << feature as u64) != 0
+    }
+
+    // the features the CPU has
+    pub fn features(&self) -> impl Iterator<Item = Feature> + '_ {
+        FEATURES
+            .iter()
+            .copied()
+            .filter(|&feature| self.has(feature))
+    }
+}
+
+// the features as a space separated list
+impl fmt::Display for CpuInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, feature) in self.features().enumerate() {
+            if index > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(feature.name())?;
+        }
+        Ok(())
+    }
+}
+
+fn detect() -> CpuInfo {
+    let basic = __cpuid(0);
+    let max_leaf = basic.eax;
+    let max_extended_leaf = __cpuid(0x8000_0000).eax;
+    let mut vendor = [0u8; 12];
+    for (chunk, register) in vendor.chunks_mut(4).zip([basic.ebx, basic.edx, basic.ecx]) {
+        chunk.copy_from_slice(&register.to_le_bytes());
+    }
+
+    let mut brand = [0u8; 48];
+    if max_extended_leaf >= 0x8000_0004 {
+        for (index, leaf) in (0x8000_0002..=0x8000_0004).enumerate() {
+            let result = __cpuid(leaf);
+            for (offset, register) in [result.eax, result.ebx, result.ecx, result.edx]
+                .into_iter()
+                .enumerate()
+            {
+                let start = index * 16 + offset * 4;
+                brand[start..start + 4].copy_from_slice(&register.to_le_bytes());
+            }
+        }
+    }
+
+    // the extended family and model only count for some base families
+    let signature = __cpuid(1).eax;
+    let base_family = signature >> 8 & 0xF;
+    let family = match base_family {
+        0xF => base_family + (signature >> 20 & 0xFF),
+        _ => base_family,
+    };
+    let model = match base_family {
+        0x6 | 0xF => (signature >> 4 & 0xF) | (signature >> 16 & 0xF) << 4,
+        _ => signature >> 4 & 0xF,
+    };
+
+    let mut features = 0;
+    for (index, &feature) in FEATURES.iter().enumerate() {
+        let (leaf, register, bit) = feature.location();
+        let available = if leaf >= 0x8000_0000 {
+            leaf <= max_extended_leaf
+        } else {
+            leaf <= max_leaf
+        };
+        if !available {
+            continue;
+        }
+        let result = __cpuid_count(leaf, 0);
+        let value = match register {
+            Register::Ebx => result.ebx,
+            Register::Ecx => result.ecx,
+            Register::Edx => result.edx,
+        };
+        if value & (1 << bit) != 0 {
+            features |= 1 << index;
+        }
+    }
+
+    CpuInfo {
+        vendor,
+        brand,
+        family,
+        model,
+        stepping: signature & 0xF,
+        features,
+    }
+}
+
+static INFO: OnceCell<CpuInfo> = OnceCell::uninit();
+
+// the CPU the kernel runs on, the bootstrap processor (the other CPUs are assumed to be the same)
+pub fn info() -> &'static CpuInfo {
+    INFO.get_or_init(detect)
+}
+
+pub fn has(feature: Feature) -> bool {
+    info().has(feature)
+}
+
+// read the features and log a summary
+pub fn init() {
+    let info = info();
+    log::info!(
+        "cpu: {} ({}) family {:#x} model {:#x} stepping {}",
+        info.brand(),
+        info.vendor(),
+        info.family,
+        info.model,
+        info.stepping
+    );
+    log::info!("cpu: {}", info);
+}
+
+#[test_case]
+fn test_cpu_features() {
+    let info = info();
+    // the kernel runs in long mode, which needs these
+    assert!(has(Feature::LongMode));
+    assert!(has(Feature::Pae));
+    assert!(info.has(Feature::Fpu));
+    assert!(info.vendor().is_ascii() && !info.vendor().is_empty());
+    assert!(info.family > 0);
+    assert!(info.features().any(|feature| feature == Feature::Sse2));
+    assert_eq!(Feature::TscDeadline.name(), "tsc_deadline_timer");
+}
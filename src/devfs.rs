@@ -0,0 +1,201 @@
+/*
+* The DEVICE_FILE_SYSTEM (mounted at /dev) has a file for every device so devices can be read and written
+* with the same calls as files:
+*  * null: reads return nothing, writes are thrown away.
+*  * zero: reads return zeros.
+*  * a file for every block device and partition (ata0, ata0p1, vda...) that can be read and written at any
+*    byte offset. The sectors around the bytes are read into a buffer, a write changes the buffer and
+*    writes the sectors back.
+*
+* The directory is built when it is read, so disks that are registered later show up too.
+* */
+use crate::block::{self, BlockDevice};
+use crate::vfs::{DirEntry, FileSystem, Inode, NodeKind, VfsError};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub struct DevFs;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
+
+struct Root;
+
+impl Root {
+    fn nodes() -> Vec<(String, Arc<dyn Inode>)> {
+        let mut nodes: Vec<(String, Arc<dyn Inode>)> = vec![
+            (String::from("null"), Arc::new(Null)),
+            (String::from("zero"), Arc::new(Zero)),
+        ];
+        for device in block::devices() {
+            nodes.push((device.name(), Arc::new(Block(device))));
+        }
+        nodes
+    }
+}
+
+impl Inode for Root {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Directory
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Ok(Root::nodes()
+            .into_iter()
+            .map(|(name, node)| DirEntry {
+                name,
+                kind: node.kind(),
+                size: node.size(),
+            })
+            .collect())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Root::nodes()
+            .into_iter()
+            .find(|(node_name, _)| node_name == name)
+            .map(|(_, node)| node)
+            .ok_or(VfsError::NotFound)
+    }
+}
+
+struct Null;
+
+impl Inode for Null {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Device
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        Ok(buffer.len())
+    }
+}
+
+struct Zero;
+
+impl Inode for Zero {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Device
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    fn read_at(&self, _offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write_at(&self, _offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        Ok(buffer.len())
+    }
+}
+
+struct Block(&'static dyn BlockDevice);
+
+impl Block {
+    // the first sector and the number of sectors that hold the bytes, limited to the end of the device
+    fn sectors(&self, offset: u64, len: usize) -> Option<(u64, usize, usize)> {
+        let capacity = self.0.capacity();
+        if offset >= capacity {
+            return None;
+        }
+        let len = len.min((capacity - offset) as usize);
+        let sector_size = self.0.sector_size() as u64;
+        let first = offset / sector_size;
+        let last = (offset + len as u64).div_ceil(sector_size);
+        Some((first, (last - first) as usize, len))
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), VfsError> {
+        self.0.read_sectors(lba, buffer).map_err(|error| {
+            log::warn!("devfs: {}: {}", self.0.name(), error);
+            VfsError::Io
+        })
+    }
+}
+
+impl Inode for Block {
+    fn kind(&self) -> NodeKind {
+        NodeKind::Device
+    }
+
+    fn size(&self) -> u64 {
+        self.0.capacity()
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let Some((lba, count, len)) = self.sectors(offset, buffer.len()) else {
+            return Ok(0);
+        };
+        let sector_size = self.0.sector_size();
+        let mut sectors = vec![0; count * sector_size];
+        self.read_sectors(lba, &mut sectors)?;
+        let start = (offset % sector_size as u64) as usize;
+        buffer[..len].copy_from_slice(&sectors[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buffer: &[u8]) -> Result<usize, VfsError> {
+        let Some((lba, count, len)) = self.sectors(offset, buffer.len()) else {
+            return Ok(0);
+        };
+        let sector_size = self.0.sector_size();
+        let mut sectors = vec![0; count * sector_size];
+        let start = (offset % sector_size as u64) as usize;
+        // only the sectors that are partly overwritten have to be read first
+        if start != 0 || !len.is_multiple_of(sector_size) {
+            self.read_sectors(lba, &mut sectors)?;
+        }
+        sectors[start..start + len].copy_from_slice(&buffer[..len]);
+        self.0
+            .write_sectors(lba, &sectors)
+            .map_err(|error| match error {
+                block::BlockError::ReadOnly => VfsError::ReadOnly,
+                error => {
+                    log::warn!("devfs: {}: {}", self.0.name(), error);
+                    VfsError::Io
+                }
+            })?;
+        Ok(len)
+    }
+}
+
+#[test_case]
+fn test_block_device_byte_offsets() {
+    use alloc::boxed::Box;
+    let data = (0..4 * block::SECTOR_SIZE).map(|i| i as u8).collect();
+    let disk = Block(Box::leak(Box::new(block::RamDisk::new("devfs-test", data))));
+    let mut buffer = [0; 4];
+    // the read crosses the end of the first sector
+    assert_eq!(disk.read_at(510, &mut buffer), Ok(4));
+    assert_eq!(buffer, [254, 255, 0, 1]);
+    assert_eq!(disk.write_at(511, &[7, 8]), Ok(2));
+    assert_eq!(disk.read_at(509, &mut buffer), Ok(4));
+    assert_eq!(buffer, [253, 254, 7, 8]);
+    // reads and writes stop at the end of the device
+    assert_eq!(disk.read_at(disk.size() - 2, &mut buffer), Ok(2));
+    assert_eq!(disk.read_at(disk.size(), &mut buffer), Ok(0));
+}
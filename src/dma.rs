@@ -0,0 +1,210 @@
+/*
+* Devices that read and write the memory themselves (DMA) don't use the page tables: the driver gives them
+* physical addresses, and a buffer that spans more than one page must be PHYSICALLY CONTIGUOUS since the
+* device just counts up from its start. Some devices also need the buffer aligned more than to a page (e.g.
+* the rings of some network cards to 64 KiB).
+*
+* A DmaBuffer is a range of contiguous frames from the frame allocator, zeroed, with its physical and virtual
+* address. The virtual address is normally the one in the physical memory mapping (cached, which is fine on
+* x86 since DMA is cache coherent). An uncached buffer is mapped again like device memory (memory::map_mmio),
+* for devices that don't snoop the caches; it must only be accessed through that mapping. The frames are given
+* back to the frame allocator when the buffer is dropped, the device must be done with it by then.
+* */
+use crate::memory;
+use core::mem::size_of;
+use x86_64::structures::paging::{FrameDeallocator, Page, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub struct DmaBuffer {
+    phys: PhysAddr,
+    virt: VirtAddr,
+    // the size that was asked for, the frames are rounded up to whole pages
+    len: usize,
+    frames: usize,
+    uncached: bool,
+}
+
+impl DmaBuffer {
+    // a zeroed buffer of len bytes aligned to align (a power of two), None if there is no such memory
+    pub fn new(len: usize, align: usize) -> Option<DmaBuffer> {
+        DmaBuffer::allocate(len, align, false)
+    }
+
+    // like new, but the CPU doesn't cache the buffer
+    pub fn new_uncached(len: usize, align: usize) -> Option<DmaBuffer> {
+        DmaBuffer::allocate(len, align, true)
+    }
+
+    fn allocate(len: usize, align: usize, uncached: bool) -> Option<DmaBuffer> {
+        assert!(
+            align.is_power_of_two(),
+            "the alignment must be a power of two"
+        );
+        let frames = len.max(1).div_ceil(FRAME_SIZE);
+        // frames are aligned to a page, for more the range is longer and the ends are given back
+        let align_frames = align.div_ceil(FRAME_SIZE).max(1);
+        let first = allocate_aligned(frames, align_frames)?;
+        let phys = first.start_address();
+        let virt = if uncached {
+            // the frames belong to the buffer, so they can be mapped like device memory
+            match unsafe { memory::map_mmio(phys, (frames * FRAME_SIZE) as u64) } {
+                Ok(virt) => virt,
+                Err(_) => {
+                    deallocate(first, frames);
+                    return None;
+                }
+            }
+        } else {
+            memory::phys_to_virt(phys)
+        };
+        unsafe { virt.as_mut_ptr::<u8>().write_bytes(0, frames * FRAME_SIZE) };
+        Some(DmaBuffer {
+            phys,
+            virt,
+            len,
+            frames,
+            uncached,
+        })
+    }
+
+    // the address the device is given
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.phys
+    }
+
+    // the address the CPU accesses the buffer at
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.virt
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_uncached(&self) -> bool {
+        self.uncached
+    }
+
+    // the physical address of the byte at the offset
+    pub fn phys_at(&self, offset: usize) -> PhysAddr {
+        assert!(offset < self.len, "offset outside of the DMA buffer");
+        self.phys + offset as u64
+    }
+
+    // a pointer to a T at the offset, the device may change it so it is read and written volatile
+    pub fn pointer<T>(&self, offset: usize) -> *mut T {
+        assert!(
+            offset + size_of::<T>() <= self.len,
+            "offset outside of the DMA buffer"
+        );
+        (self.virt + offset as u64).as_mut_ptr()
+    }
+
+    // None if the address isn't in the buffer
+    pub fn virt_to_phys(&self, addr: VirtAddr) -> Option<PhysAddr> {
+        let offset = addr.as_u64().checked_sub(self.virt.as_u64())?;
+        (offset < self.len as u64).then(|| self.phys + offset)
+    }
+
+    pub fn phys_to_virt(&self, addr: PhysAddr) -> Option<VirtAddr> {
+        let offset = addr.as_u64().checked_sub(self.phys.as_u64())?;
+        (offset < self.len as u64).then(|| self.virt + offset)
+    }
+
+    // the device must not write the buffer while the slice is used
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.virt.as_ptr(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.virt.as_mut_ptr(), self.len) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if self.uncached {
+            // the virtual addresses aren't used again, like the rest of the MMIO region
+            let first = Page::containing_address(self.virt);
+            for page in Page::range(first, first + self.frames as u64) {
+                unsafe { memory::unmap_page(page) };
+            }
+        }
+        deallocate(PhysFrame::containing_address(self.phys), self.frames);
+    }
+}
+
+// count contiguous frames, the first one aligned to align_frames frames
+fn allocate_aligned(count: usize, align_frames: usize) -> Option<PhysFrame> {
+    memory::with_frame_allocator(|allocator| {
+        let total = count + align_frames - 1;
+        let start = allocator.allocate_contiguous(total)?;
+        let index = start.start_address().as_u64() / FRAME_SIZE as u64;
+        let skipped = (index.next_multiple_of(align_frames as u64) - index) as usize;
+        let first = start + skipped as u64;
+        unsafe {
+            for frame in PhysFrame::range(start, first) {
+                allocator.deallocate_frame(frame);
+            }
+            for frame in PhysFrame::range(first + count as u64, start + total as u64) {
+                allocator.deallocate_frame(frame);
+            }
+        }
+        Some(first)
+    })
+}
+
+fn deallocate(first: PhysFrame, count: usize) {
+    memory::with_frame_allocator(|allocator| {
+        for frame in PhysFrame::range(first, first + count as u64) {
+            unsafe { allocator.deallocate_frame(frame) };
+        }
+    });
+}
+
+#[test_case]
+fn test_dma_buffer_addresses() {
+    let allocated = memory::with_frame_allocator(|allocator| allocator.allocated_frames());
+    {
+        let mut buffer = DmaBuffer::new(3 * FRAME_SIZE, 16 * 1024).expect("out of memory");
+        assert_eq!(buffer.phys_addr().as_u64() % (16 * 1024), 0);
+        assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+        assert_eq!(
+            memory::translate_addr(buffer.virt_addr() + 2 * FRAME_SIZE as u64),
+            Some(buffer.phys_at(2 * FRAME_SIZE))
+        );
+        buffer.as_mut_slice()[FRAME_SIZE + 5] = 0xAB;
+        let phys = buffer.phys_at(FRAME_SIZE + 5);
+        let virt = buffer.phys_to_virt(phys).unwrap();
+        assert_eq!(unsafe { virt.as_ptr::<u8>().read() }, 0xAB);
+        assert_eq!(buffer.virt_to_phys(virt), Some(phys));
+        assert_eq!(
+            buffer.phys_to_virt(buffer.phys_addr() + buffer.len() as u64),
+            None
+        );
+    }
+    // the frames are given back, the ones skipped for the alignment too
+    assert_eq!(
+        memory::with_frame_allocator(|allocator| allocator.allocated_frames()),
+        allocated
+    );
+
+    let uncached = DmaBuffer::new_uncached(100, 64).expect("out of memory");
+    assert!(uncached.is_uncached());
+    assert_eq!(
+        memory::translate_addr(uncached.virt_addr()),
+        Some(uncached.phys_addr())
+    );
+    // only through the uncached mapping, the physical memory mapping would cache the frame
+    unsafe { uncached.pointer::<u32>(96).write_volatile(7) };
+    let virt = uncached.phys_to_virt(uncached.phys_at(96)).unwrap();
+    assert_eq!(virt, uncached.virt_addr() + 96u64);
+    assert_eq!(unsafe { virt.as_ptr::<u32>().read_volatile() }, 7);
+}
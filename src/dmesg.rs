@@ -0,0 +1,178 @@
+/*
+* The screen only shows the last 25 lines (plus the scrollback), so the early boot messages are lost
+* quickly. The kernel message buffer (like dmesg on linux) keeps the last DMESG_SIZE bytes of everything
+* that was printed with print!/println! or logged, every line starts with the uptime when it was written:
+*
+*     [    0.012] [INFO  rust_os] Hello World!
+*
+* It is a fixed size ring in a static so it works before the heap exists, the oldest bytes are overwritten.
+* Interrupt handlers print too, so the ring is only locked with interrupts disabled.
+* */
+use crate::logging::{self, LogSink};
+use core::fmt::{self, Write};
+use log::Record;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub const DMESG_SIZE: usize = 64 * 1024;
+
+// the size is a parameter so the tests can use a small buffer
+pub struct MessageBuffer<const SIZE: usize = DMESG_SIZE> {
+    inner: Mutex<Ring<SIZE>>,
+}
+
+struct Ring<const SIZE: usize> {
+    bytes: [u8; SIZE],
+    // the number of bytes ever written, the ring holds the last SIZE of them
+    written: u64,
+    // the next byte starts a line so it gets a timestamp
+    line_start: bool,
+}
+
+impl<const SIZE: usize> Ring<SIZE> {
+    // the position of the oldest byte that is still stored
+    fn oldest(&self) -> u64 {
+        self.written.saturating_sub(SIZE as u64)
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.bytes[(self.written % SIZE as u64) as usize] = byte;
+        self.written += 1;
+    }
+
+    // copy the stored bytes starting at the position to the buffer, returns the position of the first copied byte
+    fn copy_from(&self, position: u64, buffer: &mut [u8]) -> (u64, usize) {
+        let position = position.max(self.oldest());
+        let len = ((self.written - position) as usize).min(buffer.len());
+        for (index, byte) in buffer[..len].iter_mut().enumerate() {
+            *byte = self.bytes[((position + index as u64) % SIZE as u64) as usize];
+        }
+        (position, len)
+    }
+}
+
+impl<const SIZE: usize> Write for Ring<SIZE> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            if self.line_start {
+                self.line_start = false;
+                let ms = crate::timer::uptime_ms();
+                write!(self, "[{:>5}.{:03}] ", ms / 1000, ms % 1000)?;
+            }
+            self.push(byte);
+            if byte == b'\n' {
+                self.line_start = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const SIZE: usize> MessageBuffer<SIZE> {
+    pub const fn new() -> MessageBuffer<SIZE> {
+        MessageBuffer {
+            inner: Mutex::new(Ring {
+                bytes: [0; SIZE],
+                written: 0,
+                line_start: true,
+            }),
+        }
+    }
+
+    pub fn write_fmt(&self, args: fmt::Arguments) {
+        interrupts::without_interrupts(|| {
+            // writing to the ring can't fail
+            let _ = self.inner.lock().write_fmt(args);
+        });
+    }
+
+    /*
+     * Write the stored messages to the writer, oldest first. The ring is copied in small parts and only
+     * locked while copying, so the writer may print (e.g. to the screen, which writes to the ring again).
+     * Only the messages that were stored when the dump started are written.
+     * */
+    pub fn dump(&self, writer: &mut dyn Write) -> fmt::Result {
+        let mut chunk = [0u8; 256];
+        let (mut position, end) = interrupts::without_interrupts(|| {
+            let ring = self.inner.lock();
+            (ring.oldest(), ring.written)
+        });
+        while position < end {
+            let (start, len) = interrupts::without_interrupts(|| {
+                self.inner.lock().copy_from(position, &mut chunk)
+            });
+            let len = len.min((end - start) as usize);
+            if len == 0 {
+                break;
+            }
+            // a line can be split between two chunks, they are written byte wise so no UTF-8
+            // character can be cut in half (non ASCII bytes are replaced like the VGA writer does)
+            for &byte in &chunk[..len] {
+                writer.write_char(if byte.is_ascii() { byte as char } else { '?' })?;
+            }
+            position = start + len as u64;
+        }
+        Ok(())
+    }
+
+    // the number of bytes currently stored
+    pub fn len(&self) -> usize {
+        interrupts::without_interrupts(|| {
+            let ring = self.inner.lock();
+            (ring.written - ring.oldest()) as usize
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<const SIZE: usize> Default for MessageBuffer<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// the log messages are stored in the same format as on the screen
+impl<const SIZE: usize> LogSink for MessageBuffer<SIZE> {
+    fn write(&self, record: &Record) {
+        self.write_fmt(format_args!("{}", logging::Line(record)));
+    }
+}
+
+pub static DMESG: MessageBuffer = MessageBuffer::new();
+
+// write all the stored kernel messages, e.g. dump(&mut serial) or for a shell command
+pub fn dump(writer: &mut dyn Write) -> fmt::Result {
+    DMESG.dump(writer)
+}
+
+#[test_case]
+fn test_messages_get_timestamps_and_old_ones_are_dropped() {
+    use alloc::string::String;
+
+    const SIZE: usize = 256;
+    let buffer = MessageBuffer::<SIZE>::new();
+    buffer.write_fmt(format_args!("first"));
+    buffer.write_fmt(format_args!(" line\nsecond line\n"));
+    let mut contents = String::new();
+    buffer.dump(&mut contents).unwrap();
+    let lines: alloc::vec::Vec<&str> = contents.lines().collect();
+    assert_eq!(lines.len(), 2);
+    // "[    1.234] " is 12 characters
+    assert!(lines[0].starts_with('[') && &lines[0][10..] == "] first line");
+    assert_eq!(&lines[1][12..], "second line");
+
+    // overflow the ring, only the newest SIZE bytes are kept
+    for _ in 0..SIZE / 16 {
+        buffer.write_fmt(format_args!("{}", "0123456789\n"));
+    }
+    buffer.write_fmt(format_args!("last\n"));
+    assert_eq!(buffer.len(), SIZE);
+    let mut contents = String::new();
+    buffer.dump(&mut contents).unwrap();
+    assert_eq!(contents.len(), SIZE);
+    assert!(!contents.contains("first line"));
+    assert!(contents.ends_with("] last\n"));
+}
@@ -0,0 +1,507 @@
+/*
+* The Intel 82540EM (e1000) is the network card QEMU emulates by default (-nic user,model=e1000), device
+* 8086:100E. Its registers are 32 bit values in the memory BAR 0, the frames are moved by the card itself
+* (DMA) through two RINGS of 16 byte descriptors in physical memory:
+*
+*     receive:  buffer address, length, checksum, status (DD = done, EOP = end of packet), errors
+*     transmit: buffer address, length, checksum offset, command (EOP, IFCS = add the CRC, RS = report
+*               status), status (DD), ...
+*
+* Each ring has a HEAD the card advances over the descriptors it finished and a TAIL the driver writes
+* to hand it new ones, the descriptors from head to tail belong to the card. All receive descriptors get a
+* 2048 byte buffer and are given to the card at once (the tail is the one before the head), the card fills
+* them and raises the receive interrupt. The interrupt handler copies the frames into a queue, clears the
+* status and moves the tail on so the descriptor is used again. A frame is sent by copying it to the buffer
+* of the descriptor at the tail and moving the tail on, the card sets DD once it is on the wire.
+*
+* The card gets its MAC address from its EEPROM and puts it in the first receive address register, the
+* frames for it and the broadcasts are received. One card is driven, the first one found.
+* */
+use crate::dma::DmaBuffer;
+use crate::interrupts;
+use crate::memory;
+use crate::pci::{self, Bar, DeviceMatch, PciDevice, PciDriver};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::marker::PhantomData;
+use core::sync::atomic::{fence, Ordering};
+use core::task::Waker;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+// registers, offsets from BAR 0
+const REG_CTRL: usize = 0x0000;
+const REG_EERD: usize = 0x0014;
+const REG_ICR: usize = 0x00C0;
+const REG_IMS: usize = 0x00D0;
+const REG_IMC: usize = 0x00D8;
+const REG_RCTL: usize = 0x0100;
+const REG_TCTL: usize = 0x0400;
+const REG_TIPG: usize = 0x0410;
+const REG_RDBAL: usize = 0x2800;
+const REG_RDBAH: usize = 0x2804;
+const REG_RDLEN: usize = 0x2808;
+const REG_RDH: usize = 0x2810;
+const REG_RDT: usize = 0x2818;
+const REG_TDBAL: usize = 0x3800;
+const REG_TDBAH: usize = 0x3804;
+const REG_TDLEN: usize = 0x3808;
+const REG_TDH: usize = 0x3810;
+const REG_TDT: usize = 0x3818;
+// the multicast table, 128 registers
+const REG_MTA: usize = 0x5200;
+const REG_RAL: usize = 0x5400;
+const REG_RAH: usize = 0x5404;
+
+const CTRL_SET_LINK_UP: u32 = 1 << 6;
+const CTRL_RESET: u32 = 1 << 26;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+const RCTL_ENABLE: u32 = 1 << 1;
+const RCTL_BROADCAST: u32 = 1 << 15;
+// strip the CRC, the buffer size bits stay 0 for 2048 byte buffers
+const RCTL_STRIP_CRC: u32 = 1 << 26;
+
+const TCTL_ENABLE: u32 = 1 << 1;
+const TCTL_PAD_SHORT: u32 = 1 << 3;
+const TCTL_COLLISION_THRESHOLD: u32 = 0x10 << 4;
+const TCTL_COLLISION_DISTANCE: u32 = 0x40 << 12;
+// the inter packet gap the manual recommends for IEEE 802.3
+const TIPG_DEFAULT: u32 = 0x0060_200A;
+
+// the interrupt causes (ICR, IMS)
+const INT_LINK_CHANGE: u32 = 1 << 2;
+const INT_RX_MIN_THRESHOLD: u32 = 1 << 4;
+const INT_RX_OVERRUN: u32 = 1 << 6;
+const INT_RX_TIMER: u32 = 1 << 7;
+const RX_INTERRUPTS: u32 = INT_RX_MIN_THRESHOLD | INT_RX_OVERRUN | INT_RX_TIMER;
+
+const STATUS_DONE: u8 = 1 << 0;
+const STATUS_END_OF_PACKET: u8 = 1 << 1;
+
+const COMMAND_END_OF_PACKET: u8 = 1 << 0;
+const COMMAND_INSERT_CRC: u8 = 1 << 1;
+const COMMAND_REPORT_STATUS: u8 = 1 << 3;
+
+// the ring lengths must be multiples of 128 bytes (8 descriptors)
+const RX_DESCRIPTORS: usize = 32;
+const TX_DESCRIPTORS: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+// the largest Ethernet frame without the CRC
+pub const MAX_FRAME_SIZE: usize = 1514;
+// the received frames nobody took yet, older ones are dropped
+const RX_QUEUE_SIZE: usize = 64;
+const TX_TIMEOUT_MS: u64 = 100;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RxDescriptor {
+    address: u64,
+    len: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TxDescriptor {
+    address: u64,
+    len: u16,
+    checksum_offset: u8,
+    command: u8,
+    status: u8,
+    checksum_start: u8,
+    special: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    NoDevice,
+    // longer than MAX_FRAME_SIZE
+    TooLarge,
+    // the card didn't send the frames before it in time
+    Timeout,
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SendError::NoDevice => write!(f, "no network card"),
+            SendError::TooLarge => write!(f, "the frame is too large"),
+            SendError::Timeout => write!(f, "the network card doesn't send"),
+        }
+    }
+}
+
+// a ring of descriptors and their buffers, the buffers follow each other in one DMA buffer
+struct Ring<T> {
+    descriptors: DmaBuffer,
+    buffers: DmaBuffer,
+    count: usize,
+    // the next descriptor the driver looks at
+    next: usize,
+    descriptor_type: PhantomData<T>,
+}
+
+impl<T> Ring<T> {
+    // the card wants the descriptors aligned to 16 bytes
+    fn new(count: usize) -> Option<Ring<T>> {
+        Some(Ring {
+            descriptors: DmaBuffer::new(count * size_of::<T>(), 16)?,
+            buffers: DmaBuffer::new(count * BUFFER_SIZE, BUFFER_SIZE)?,
+            count,
+            next: 0,
+            descriptor_type: PhantomData,
+        })
+    }
+
+    fn descriptor(&self, index: usize) -> *mut T {
+        self.descriptors.pointer(index * size_of::<T>())
+    }
+
+    // the virtual and physical address of the buffer of the descriptor
+    fn buffer(&self, index: usize) -> (*mut u8, PhysAddr) {
+        let offset = index * BUFFER_SIZE;
+        (self.buffers.pointer(offset), self.buffers.phys_at(offset))
+    }
+}
+
+pub struct E1000 {
+    address: pci::PciAddress,
+    registers: VirtAddr,
+    mac: [u8; 6],
+    irq: u8,
+    // locked by the interrupt handler, so only with interrupts disabled
+    rx: Mutex<Ring<RxDescriptor>>,
+    tx: Mutex<Ring<TxDescriptor>>,
+}
+
+impl E1000 {
+    fn read(&self, register: usize) -> u32 {
+        unsafe {
+            (self.registers + register as u64)
+                .as_ptr::<u32>()
+                .read_volatile()
+        }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe {
+            (self.registers + register as u64)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value)
+        }
+    }
+
+    // a 16 bit word of the EEPROM, None if the card doesn't answer
+    fn read_eeprom(&self, word: u8) -> Option<u16> {
+        self.write(REG_EERD, EERD_START | (word as u32) << 8);
+        (0..100_000)
+            .map(|_| self.read(REG_EERD))
+            .find(|value| value & EERD_DONE != 0)
+            .map(|value| (value >> 16) as u16)
+    }
+
+    // the address in the first receive address register, or from the EEPROM if it is empty
+    fn read_mac(&self) -> [u8; 6] {
+        let low = self.read(REG_RAL).to_le_bytes();
+        let high = self.read(REG_RAH).to_le_bytes();
+        let mac = [low[0], low[1], low[2], low[3], high[0], high[1]];
+        if mac.iter().any(|&byte| byte != 0) {
+            return mac;
+        }
+        let mut mac = [0; 6];
+        for (word, chunk) in mac.chunks_mut(2).enumerate() {
+            let value = self.read_eeprom(word as u8).unwrap_or(0);
+            chunk.copy_from_slice(&value.to_le_bytes());
+        }
+        mac
+    }
+
+    fn setup(device: &'static PciDevice) -> Option<E1000> {
+        let Some(Bar::Memory { address, size, .. }) = device.bars[0] else {
+            return None;
+        };
+        device.enable(pci::COMMAND_MEMORY_SPACE | pci::COMMAND_BUS_MASTER);
+        let registers = unsafe { memory::map_mmio(PhysAddr::new(address), size) }.ok()?;
+        let mut nic = E1000 {
+            address: device.address,
+            registers,
+            mac: [0; 6],
+            irq: device.interrupt_line,
+            rx: Mutex::new(Ring::new(RX_DESCRIPTORS)?),
+            tx: Mutex::new(Ring::new(TX_DESCRIPTORS)?),
+        };
+
+        // reset the card with its interrupts masked, the reset clears the bit when it is done
+        nic.write(REG_IMC, u32::MAX);
+        nic.write(REG_CTRL, nic.read(REG_CTRL) | CTRL_RESET);
+        crate::timer::sleep_ms(1);
+        if (0..100_000).all(|_| nic.read(REG_CTRL) & CTRL_RESET != 0) {
+            return None;
+        }
+        nic.write(REG_IMC, u32::MAX);
+        nic.read(REG_ICR);
+        nic.write(REG_CTRL, nic.read(REG_CTRL) | CTRL_SET_LINK_UP);
+        nic.mac = nic.read_mac();
+        for index in 0..128 {
+            nic.write(REG_MTA + index * 4, 0);
+        }
+
+        {
+            let rx = nic.rx.lock();
+            for index in 0..rx.count {
+                let (_, phys) = rx.buffer(index);
+                unsafe {
+                    rx.descriptor(index).write_volatile(RxDescriptor {
+                        address: phys.as_u64(),
+                        len: 0,
+                        checksum: 0,
+                        status: 0,
+                        errors: 0,
+                        special: 0,
+                    })
+                };
+            }
+            let tx = nic.tx.lock();
+            for index in 0..tx.count {
+                let (_, phys) = tx.buffer(index);
+                // a done descriptor can be used right away
+                unsafe {
+                    tx.descriptor(index).write_volatile(TxDescriptor {
+                        address: phys.as_u64(),
+                        len: 0,
+                        checksum_offset: 0,
+                        command: 0,
+                        status: STATUS_DONE,
+                        checksum_start: 0,
+                        special: 0,
+                    })
+                };
+            }
+        }
+        let rx_phys = nic.rx.lock().descriptors.phys_addr().as_u64();
+        nic.write(REG_RDBAL, rx_phys as u32);
+        nic.write(REG_RDBAH, (rx_phys >> 32) as u32);
+        nic.write(
+            REG_RDLEN,
+            (RX_DESCRIPTORS * size_of::<RxDescriptor>()) as u32,
+        );
+        nic.write(REG_RDH, 0);
+        // all descriptors belong to the card
+        nic.write(REG_RDT, RX_DESCRIPTORS as u32 - 1);
+        nic.write(REG_RCTL, RCTL_ENABLE | RCTL_BROADCAST | RCTL_STRIP_CRC);
+
+        let tx_phys = nic.tx.lock().descriptors.phys_addr().as_u64();
+        nic.write(REG_TDBAL, tx_phys as u32);
+        nic.write(REG_TDBAH, (tx_phys >> 32) as u32);
+        nic.write(
+            REG_TDLEN,
+            (TX_DESCRIPTORS * size_of::<TxDescriptor>()) as u32,
+        );
+        nic.write(REG_TDH, 0);
+        nic.write(REG_TDT, 0);
+        nic.write(REG_TIPG, TIPG_DEFAULT);
+        nic.write(
+            REG_TCTL,
+            TCTL_ENABLE | TCTL_PAD_SHORT | TCTL_COLLISION_THRESHOLD | TCTL_COLLISION_DISTANCE,
+        );
+        Some(nic)
+    }
+
+    pub fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    // copy the frames the card received to the queue, called with interrupts disabled
+    fn receive(&self) {
+        let mut rx = self.rx.lock();
+        loop {
+            let index = rx.next;
+            let descriptor = unsafe { rx.descriptor(index).read_volatile() };
+            if descriptor.status & STATUS_DONE == 0 {
+                break;
+            }
+            // the buffer is written before the status
+            fence(Ordering::SeqCst);
+            // frames larger than a buffer don't arrive, the card only receives up to 1522 bytes
+            if descriptor.status & STATUS_END_OF_PACKET != 0 && descriptor.errors == 0 {
+                let len = (descriptor.len as usize).min(BUFFER_SIZE);
+                let frame = unsafe { core::slice::from_raw_parts(rx.buffer(index).0, len) };
+                let mut queue = RX_QUEUE.lock();
+                if queue.len() == RX_QUEUE_SIZE {
+                    queue.pop_front();
+                }
+                queue.push_back(frame.to_vec());
+                RX_WAKER.wake();
+            }
+            unsafe {
+                let status = core::ptr::addr_of_mut!((*rx.descriptor(index)).status);
+                status.write_volatile(0);
+            }
+            rx.next = (index + 1) % RX_DESCRIPTORS;
+            // give the descriptor back to the card
+            self.write(REG_RDT, index as u32);
+        }
+    }
+
+    pub fn send_frame(&self, frame: &[u8]) -> Result<(), SendError> {
+        if frame.len() > MAX_FRAME_SIZE {
+            return Err(SendError::TooLarge);
+        }
+        let mut tx = self.tx.lock();
+        let index = tx.next;
+        // the descriptor is free once the card sent the frame it had before
+        let deadline = crate::timer::uptime_ms() + TX_TIMEOUT_MS;
+        loop {
+            let status =
+                unsafe { core::ptr::addr_of!((*tx.descriptor(index)).status).read_volatile() };
+            if status & STATUS_DONE != 0 {
+                break;
+            }
+            if crate::timer::uptime_ms() > deadline {
+                return Err(SendError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        let (buffer, phys) = tx.buffer(index);
+        unsafe {
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), buffer, frame.len());
+            tx.descriptor(index).write_volatile(TxDescriptor {
+                address: phys.as_u64(),
+                len: frame.len() as u16,
+                checksum_offset: 0,
+                command: COMMAND_END_OF_PACKET | COMMAND_INSERT_CRC | COMMAND_REPORT_STATUS,
+                status: 0,
+                checksum_start: 0,
+                special: 0,
+            });
+        }
+        // the card must see the descriptor before the tail
+        fence(Ordering::SeqCst);
+        tx.next = (index + 1) % TX_DESCRIPTORS;
+        self.write(REG_TDT, tx.next as u32);
+        Ok(())
+    }
+}
+
+static NIC: OnceCell<E1000> = OnceCell::uninit();
+// the received frames, the interrupt handler adds to it so it is only locked with interrupts disabled
+static RX_QUEUE: Mutex<VecDeque<Vec<u8>>> = Mutex::new(VecDeque::new());
+// the task waiting for a frame (the net task)
+static RX_WAKER: AtomicWaker = AtomicWaker::new();
+
+// the card, None if there is none
+pub fn nic() -> Option<&'static E1000> {
+    NIC.get()
+}
+
+// send an Ethernet frame (destination, source, type and payload, the card adds the CRC)
+pub fn send_frame(frame: &[u8]) -> Result<(), SendError> {
+    nic().ok_or(SendError::NoDevice)?.send_frame(frame)
+}
+
+// the oldest received frame not taken yet
+pub fn recv_frame() -> Option<Vec<u8>> {
+    let nic = nic()?;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // without a working interrupt line the frames are still found here
+        nic.receive();
+        RX_QUEUE.lock().pop_front()
+    })
+}
+
+// wake the task when the next frame arrives, register before checking recv_frame to not miss it
+pub fn register_waker(waker: &Waker) {
+    RX_WAKER.register(waker);
+}
+
+fn irq_handler() {
+    let Some(nic) = nic() else {
+        return;
+    };
+    // reading the cause acknowledges the interrupt, the line may be shared with other devices
+    let cause = nic.read(REG_ICR);
+    if cause & RX_INTERRUPTS != 0 {
+        nic.receive();
+    }
+    if cause & INT_LINK_CHANGE != 0 {
+        log::info!("e1000: link changed");
+    }
+}
+
+fn probe(device: &'static PciDevice) -> bool {
+    if NIC.get().is_some() {
+        return false;
+    }
+    let Some(nic) = E1000::setup(device) else {
+        log::warn!("e1000: {} can't be set up", device.address);
+        return false;
+    };
+    let mac = nic.mac;
+    log::info!(
+        "e1000: {}, MAC {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} IRQ {}",
+        nic.address,
+        mac[0],
+        mac[1],
+        mac[2],
+        mac[3],
+        mac[4],
+        mac[5],
+        nic.irq
+    );
+    let irq = nic.irq;
+    NIC.init_once(|| nic);
+    // another driver may have the line already, the net task then finds the frames without the interrupt
+    if (irq as usize) < interrupts::IRQ_LINES {
+        if interrupts::try_register_irq_handler(irq, irq_handler) {
+            let nic = NIC.get().unwrap();
+            nic.write(REG_IMS, RX_INTERRUPTS | INT_LINK_CHANGE);
+        } else {
+            log::warn!("e1000: IRQ {} is used by another device, polling", irq);
+        }
+    }
+    true
+}
+
+static DRIVER: PciDriver = PciDriver {
+    name: "e1000",
+    matches: &[DeviceMatch::Id {
+        vendor_id: 0x8086,
+        device_id: 0x100E,
+    }],
+    probe,
+};
+
+// register the driver, the card is set up when the PCI devices are probed
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+#[test_case]
+fn test_descriptors_and_send() {
+    assert_eq!(size_of::<RxDescriptor>(), 16);
+    assert_eq!(size_of::<TxDescriptor>(), 16);
+    assert_eq!(RX_DESCRIPTORS * 16 % 128, 0);
+    let frame = [0u8; MAX_FRAME_SIZE + 1];
+    match nic() {
+        None => assert_eq!(send_frame(&frame), Err(SendError::NoDevice)),
+        Some(nic) => {
+            assert_ne!(nic.mac_address(), [0; 6]);
+            assert_eq!(send_frame(&frame), Err(SendError::TooLarge));
+            // a broadcast frame of an unused Ethernet type from the card's own address
+            let mut frame = [0u8; 60];
+            frame[..6].copy_from_slice(&[0xFF; 6]);
+            frame[6..12].copy_from_slice(&nic.mac_address());
+            frame[12..14].copy_from_slice(&[0x88, 0xB5]);
+            assert_eq!(send_frame(&frame), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,286 @@
+/*
+* A loader for statically linked ELF64 executables. The file starts with the ELF_HEADER:
+*
+*     0 magic "\x7fELF", 4 class (2 = 64 bit), 5 data (1 = little endian), 6 version (1),
+*     16 type (2 = executable), 18 machine (0x3E = x86_64), 24 entry, 32 program header offset,
+*     54 program header size, 56 program header count
+*
+* The PROGRAM_HEADERS describe the segments, the PT_LOAD ones are copied to memory:
+*
+*     0 type (1 = PT_LOAD), 4 flags (1 = execute, 2 = write, 4 = read), 8 offset in the file,
+*     16 virtual address, 32 size in the file, 40 size in memory
+*
+* A segment is mapped to fresh zeroed frames with the permissions of its flags and its part of the file is
+* copied to them, the rest up to the size in memory (the BSS) stays zero. Segments must be in the user part
+* of the address space (memory::USER_START..USER_END), so programs are linked at least at USER_START:
+*
+*     rustc ... -C link-arg=-Ttext-segment=0x200000000000 -C relocation-model=static
+*
+* Position independent executables (type 3) and dynamic linking aren't supported.
+* */
+use crate::memory::{self, AddressSpace};
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXECUTABLE: u16 = 2;
+const MACHINE_X86_64: u16 = 0x3E;
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    // the file is shorter than the headers say
+    Truncated,
+    NotElf,
+    // not a 64 bit little endian x86_64 executable
+    Unsupported,
+    // a segment outside of the user part or with a file size larger than its memory size
+    BadSegment,
+    // the entry point isn't in an executable segment
+    BadEntry,
+    OutOfMemory,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::Truncated => write!(f, "the file is truncated"),
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Unsupported => write!(f, "not a static x86_64 ELF64 executable"),
+            ElfError::BadSegment => write!(f, "invalid segment"),
+            ElfError::BadEntry => write!(f, "the entry point isn't in an executable segment"),
+            ElfError::OutOfMemory => write!(f, "out of memory"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+// a loaded executable, dropping it frees its memory
+pub struct Image {
+    pub address_space: AddressSpace,
+    pub entry: VirtAddr,
+    // the end of the highest segment, the heap of the program can start at the next page
+    pub end: VirtAddr,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+// check the ELF header, returns the entry point and the PT_LOAD segments
+fn parse(data: &[u8]) -> Result<(u64, Vec<Segment>), ElfError> {
+    if data.len() < HEADER_SIZE || &data[..4] != b"\x7fELF" {
+        return Err(ElfError::NotElf);
+    }
+    if data[4] != CLASS_64
+        || data[5] != DATA_LITTLE_ENDIAN
+        || read_u16(data, 16) != TYPE_EXECUTABLE
+        || read_u16(data, 18) != MACHINE_X86_64
+        || (read_u16(data, 54) as usize) < PROGRAM_HEADER_SIZE
+    {
+        return Err(ElfError::Unsupported);
+    }
+    let entry = read_u64(data, 24);
+    let table = read_u64(data, 32) as usize;
+    let entry_size = read_u16(data, 54) as usize;
+    let count = read_u16(data, 56) as usize;
+    let mut segments = Vec::new();
+    for index in 0..count {
+        let start = table
+            .checked_add(index * entry_size)
+            .filter(|start| start + PROGRAM_HEADER_SIZE <= data.len())
+            .ok_or(ElfError::Truncated)?;
+        let header = &data[start..start + PROGRAM_HEADER_SIZE];
+        if read_u32(header, 0) != PT_LOAD {
+            continue;
+        }
+        let segment = Segment {
+            flags: read_u32(header, 4),
+            offset: read_u64(header, 8),
+            vaddr: read_u64(header, 16),
+            file_size: read_u64(header, 32),
+            memory_size: read_u64(header, 40),
+        };
+        if segment.offset.saturating_add(segment.file_size) > data.len() as u64 {
+            return Err(ElfError::Truncated);
+        }
+        let end = segment.vaddr.checked_add(segment.memory_size);
+        if segment.file_size > segment.memory_size
+            || segment.vaddr < memory::USER_START
+            || end.is_none_or(|end| end > memory::USER_END)
+        {
+            return Err(ElfError::BadSegment);
+        }
+        if segment.memory_size > 0 {
+            segments.push(segment);
+        }
+    }
+    let executable = segments.iter().any(|segment| {
+        segment.flags & PF_X != 0
+            && (segment.vaddr..segment.vaddr + segment.memory_size).contains(&entry)
+    });
+    if !executable {
+        return Err(ElfError::BadEntry);
+    }
+    Ok((entry, segments))
+}
+
+// the NO_EXECUTE bit of the page tables is reserved (a page fault) unless EFER.NXE is set
+pub fn nx_enabled() -> bool {
+    Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE)
+}
+
+fn page_flags(segment: &Segment) -> PageTableFlags {
+    let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if segment.flags & PF_W != 0 {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if segment.flags & PF_X == 0 && nx_enabled() {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    flags
+}
+
+fn load_segment(
+    address_space: &mut AddressSpace,
+    data: &[u8],
+    segment: &Segment,
+) -> Result<(), ElfError> {
+    let flags = page_flags(segment);
+    let first = Page::containing_address(VirtAddr::new(segment.vaddr));
+    let last = Page::containing_address(VirtAddr::new(segment.vaddr + segment.memory_size - 1));
+    for page in Page::range_inclusive(first, last) {
+        let page_start = page.start_address().as_u64();
+        let frame = match address_space.translate(page.start_address()) {
+            // two segments in the same page, the page gets the permissions of both
+            Some((frame, old_flags)) => {
+                let mut merged = (old_flags | flags) & !PageTableFlags::NO_EXECUTE;
+                merged |= old_flags & flags & PageTableFlags::NO_EXECUTE;
+                unsafe { address_space.update_flags(page, merged) };
+                frame
+            }
+            None => {
+                let frame = memory::with_frame_allocator(|allocator| allocator.allocate_frame())
+                    .ok_or(ElfError::OutOfMemory)?;
+                let virt = memory::phys_to_virt(frame.start_address());
+                unsafe { virt.as_mut_ptr::<u8>().write_bytes(0, 4096) };
+                if unsafe { address_space.map(page, frame, flags) }.is_err() {
+                    // only the frames that are mapped are freed with the address space
+                    memory::with_frame_allocator(|allocator| unsafe {
+                        allocator.deallocate_frame(frame)
+                    });
+                    return Err(ElfError::OutOfMemory);
+                }
+                frame.start_address()
+            }
+        };
+        // the part of the file that belongs in this page
+        let start = segment.vaddr.max(page_start);
+        let end = (segment.vaddr + segment.file_size).min(page_start + 4096);
+        if start < end {
+            let file_start = (segment.offset + (start - segment.vaddr)) as usize;
+            let bytes = &data[file_start..file_start + (end - start) as usize];
+            let virt = memory::phys_to_virt(frame + (start - page_start));
+            unsafe {
+                core::ptr::copy_nonoverlapping(bytes.as_ptr(), virt.as_mut_ptr(), bytes.len())
+            };
+        }
+    }
+    Ok(())
+}
+
+// load the executable into a new address space
+pub fn load(data: &[u8]) -> Result<Image, ElfError> {
+    let (entry, segments) = parse(data)?;
+    let mut address_space = AddressSpace::new().ok_or(ElfError::OutOfMemory)?;
+    for segment in &segments {
+        // on errors the dropped address space frees the pages of the segments loaded so far
+        load_segment(&mut address_space, data, segment)?;
+    }
+    let end = segments
+        .iter()
+        .map(|segment| segment.vaddr + segment.memory_size)
+        .max()
+        .unwrap_or(memory::USER_START);
+    Ok(Image {
+        address_space,
+        entry: VirtAddr::new(entry),
+        end: VirtAddr::new(end),
+    })
+}
+
+#[test_case]
+fn test_load_segments() {
+    fn program_header(file: &mut [u8], index: usize, flags: u32, segment: (u64, u64, u64, u64)) {
+        let (offset, vaddr, file_size, memory_size) = segment;
+        let start = HEADER_SIZE + index * PROGRAM_HEADER_SIZE;
+        file[start..start + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        file[start + 4..start + 8].copy_from_slice(&flags.to_le_bytes());
+        file[start + 8..start + 16].copy_from_slice(&offset.to_le_bytes());
+        file[start + 16..start + 24].copy_from_slice(&vaddr.to_le_bytes());
+        file[start + 32..start + 40].copy_from_slice(&file_size.to_le_bytes());
+        file[start + 40..start + 48].copy_from_slice(&memory_size.to_le_bytes());
+    }
+    let text = memory::USER_START + 0x1000;
+    let mut file = alloc::vec![0u8; 0x300];
+    file[..4].copy_from_slice(b"\x7fELF");
+    file[4] = CLASS_64;
+    file[5] = DATA_LITTLE_ENDIAN;
+    file[6] = 1;
+    file[16..18].copy_from_slice(&TYPE_EXECUTABLE.to_le_bytes());
+    file[18..20].copy_from_slice(&MACHINE_X86_64.to_le_bytes());
+    file[24..32].copy_from_slice(&(text + 4).to_le_bytes());
+    file[32..40].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+    file[54..56].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    file[56..58].copy_from_slice(&2u16.to_le_bytes());
+    program_header(&mut file, 0, PF_X | 4, (0x200, text, 0x10, 0x10));
+    // the data segment has 8 bytes in the file and a BSS that spans the next page
+    program_header(&mut file, 1, PF_W | 4, (0x210, text + 0x2ffc, 8, 0x1008));
+    file[0x200..0x218].fill(0xCC);
+
+    let allocated = memory::with_frame_allocator(|allocator| allocator.allocated_frames());
+    let mut image = load(&file).unwrap();
+    assert_eq!(image.entry, VirtAddr::new(text + 4));
+    assert_eq!(image.end, VirtAddr::new(text + 0x2ffc + 0x1008));
+    let (phys, flags) = image.address_space.translate(VirtAddr::new(text)).unwrap();
+    assert!(flags.contains(PageTableFlags::USER_ACCESSIBLE));
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+    assert_eq!(unsafe { *memory::phys_to_virt(phys).as_ptr::<u8>() }, 0xCC);
+    let data = VirtAddr::new(text + 0x3000);
+    let (phys, flags) = image.address_space.translate(data).unwrap();
+    assert!(flags.contains(PageTableFlags::WRITABLE));
+    let page = unsafe { core::slice::from_raw_parts(memory::phys_to_virt(phys).as_ptr::<u8>(), 8) };
+    // the last 4 bytes of the file part, then the BSS
+    assert_eq!(page, [0xCC, 0xCC, 0xCC, 0xCC, 0, 0, 0, 0]);
+    drop(image);
+    let freed = memory::with_frame_allocator(|allocator| allocator.allocated_frames());
+    assert_eq!(freed, allocated);
+
+    file[24..32].copy_from_slice(&(text + 0x3000).to_le_bytes());
+    assert_eq!(load(&file).err(), Some(ElfError::BadEntry));
+    assert_eq!(load(b"MZ").err(), Some(ElfError::NotElf));
+}
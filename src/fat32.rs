@@ -0,0 +1,620 @@
+/*
+* A read only driver for FAT32 filesystems. The partition starts with the boot sector whose
+* BIOS_PARAMETER_BLOCK describes the layout:
+*
+*     | reserved sectors (boot sector...) | FAT 1 | FAT 2... | data area: cluster 2, cluster 3... |
+*
+* The data is stored in clusters (a few sectors each), the FILE_ALLOCATION_TABLE (FAT) has a 32 bit entry per
+* cluster (only 28 bits are used) with the number of the next cluster of the same file, so a file is a
+* linked list of clusters that ends with a value >= 0x0FFFFFF8.
+*
+* A directory is a file of 32 byte entries: an 8.3 short name, attributes, first cluster and size. Long
+* names are stored in extra entries before the short entry, each with 13 UTF-16 characters, the last part
+* first. They have the attributes 0x0F (read only, hidden, system, volume) which older systems ignore and a
+* checksum of the short name so a long name left behind by such a system isn't used for another file.
+* Names are compared case insensitively like FAT does.
+* */
+use crate::block::{self, BlockDevice, BlockError};
+use crate::vfs::{self, Inode, NodeKind, VfsError};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+const ATTRIBUTE_VOLUME_ID: u8 = 0x08;
+const ATTRIBUTE_DIRECTORY: u8 = 0x10;
+const ATTRIBUTE_LONG_NAME: u8 = 0x0F;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+const LONG_NAME_LAST: u8 = 0x40;
+
+// FAT values from this one on end the cluster chain, one below is a bad cluster
+const END_OF_CHAIN: u32 = 0x0FFF_FFF8;
+const CLUSTER_MASK: u32 = 0x0FFF_FFFF;
+
+// the flags in the reserved byte of a short entry that Windows uses for all lowercase names
+const LOWERCASE_BASE: u8 = 0x08;
+const LOWERCASE_EXTENSION: u8 = 0x10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatError {
+    Block(BlockError),
+    // the boot sector doesn't describe a FAT32 filesystem
+    NotFat32,
+    // a cluster number or chain that can't be right
+    Corrupt,
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+}
+
+impl From<BlockError> for FatError {
+    fn from(error: BlockError) -> Self {
+        FatError::Block(error)
+    }
+}
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FatError::Block(error) => write!(f, "{}", error),
+            FatError::NotFat32 => write!(f, "not a FAT32 filesystem"),
+            FatError::Corrupt => write!(f, "the filesystem is corrupt"),
+            FatError::NotFound => write!(f, "no such file or directory"),
+            FatError::NotADirectory => write!(f, "not a directory"),
+            FatError::IsADirectory => write!(f, "is a directory"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u32,
+    // the first cluster, 0 for empty files
+    cluster: u32,
+}
+
+pub struct Fat32 {
+    device: &'static dyn BlockDevice,
+    sector_size: usize,
+    sectors_per_cluster: u64,
+    // the first sector of the (first) FAT and of the data area
+    fat_start: u64,
+    data_start: u64,
+    clusters: u32,
+    root_cluster: u32,
+    pub label: String,
+    // the last FAT sector read, following a chain reads the same sector many times
+    fat_cache: Mutex<Option<(u64, Vec<u8>)>>,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+// the checksum of the 11 bytes of a short name stored in its long name entries
+fn short_name_checksum(name: &[u8]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+// "README  TXT" is README.TXT, the flags can make parts of it lowercase
+fn short_name(entry: &[u8]) -> String {
+    let flags = entry[12];
+    let part = |bytes: &[u8], lowercase: bool| -> String {
+        let part = core::str::from_utf8(bytes).unwrap_or("?").trim_end();
+        match lowercase {
+            true => part.to_ascii_lowercase(),
+            false => String::from(part),
+        }
+    };
+    let mut name = part(&entry[0..8], flags & LOWERCASE_BASE != 0);
+    let extension = part(&entry[8..11], flags & LOWERCASE_EXTENSION != 0);
+    // 0x05 stands for a name starting with 0xE5 (which marks deleted entries)
+    if entry[0] == 0x05 {
+        name.replace_range(0..1, "\u{e5}");
+    }
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+// the 13 UTF-16 characters of a long name entry, the name ends with 0 and is padded with 0xFFFF
+fn long_name_part(entry: &[u8]) -> impl Iterator<Item = u16> + '_ {
+    (1..11)
+        .step_by(2)
+        .chain((14..26).step_by(2))
+        .chain((28..32).step_by(2))
+        .map(move |offset| read_u16(entry, offset))
+        .take_while(|&character| character != 0 && character != 0xFFFF)
+}
+
+// the entries of a directory from its raw data
+fn parse_directory(data: &[u8]) -> Vec<DirEntry> {
+    let mut entries = Vec::new();
+    // the parts of the long name, collected in order of their sequence numbers
+    let mut long_name: Vec<Vec<u16>> = Vec::new();
+    let mut long_checksum = None;
+    for entry in data.chunks_exact(ENTRY_SIZE) {
+        match entry[0] {
+            ENTRY_END => break,
+            ENTRY_DELETED => {
+                long_name.clear();
+                long_checksum = None;
+                continue;
+            }
+            _ => {}
+        }
+        let attributes = entry[11];
+        if attributes & ATTRIBUTE_LONG_NAME == ATTRIBUTE_LONG_NAME {
+            // the last part comes first and starts a new name
+            if entry[0] & LONG_NAME_LAST != 0 {
+                long_name.clear();
+                long_checksum = Some(entry[13]);
+            }
+            long_name.push(long_name_part(entry).collect());
+            continue;
+        }
+        if attributes & ATTRIBUTE_VOLUME_ID != 0 {
+            long_name.clear();
+            long_checksum = None;
+            continue;
+        }
+        let name = match long_checksum.take() {
+            Some(checksum) if checksum == short_name_checksum(&entry[0..11]) => {
+                let characters = long_name.iter().rev().flatten().copied();
+                char::decode_utf16(characters)
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect()
+            }
+            _ => short_name(entry),
+        };
+        long_name.clear();
+        if name == "." || name == ".." {
+            continue;
+        }
+        entries.push(DirEntry {
+            name,
+            is_dir: attributes & ATTRIBUTE_DIRECTORY != 0,
+            size: read_u32(entry, 28),
+            cluster: (read_u16(entry, 20) as u32) << 16 | read_u16(entry, 26) as u32,
+        });
+    }
+    entries
+}
+
+impl Fat32 {
+    // read the boot sector of the device and check it is FAT32
+    pub fn mount(device: &'static dyn BlockDevice) -> Result<Fat32, FatError> {
+        let sector_size = device.sector_size();
+        let mut boot = vec![0; sector_size];
+        device.read_sectors(0, &mut boot)?;
+        let bytes_per_sector = read_u16(&boot, 11) as usize;
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved_sectors = read_u16(&boot, 14) as u64;
+        let fat_count = boot[16] as u64;
+        // FAT12/16 have a fixed root directory and 16 bit sizes, FAT32 sets them to 0
+        let root_entries = read_u16(&boot, 17);
+        let sectors_per_fat_16 = read_u16(&boot, 22);
+        let total_sectors = read_u32(&boot, 32) as u64;
+        let sectors_per_fat = read_u32(&boot, 36) as u64;
+        let root_cluster = read_u32(&boot, 44);
+        if boot[510..512] != [0x55, 0xAA]
+            || bytes_per_sector != sector_size
+            || !sectors_per_cluster.is_power_of_two()
+            || fat_count == 0
+            || root_entries != 0
+            || sectors_per_fat_16 != 0
+            || sectors_per_fat == 0
+        {
+            return Err(FatError::NotFat32);
+        }
+        let fat_start = reserved_sectors;
+        let data_start = fat_start + fat_count * sectors_per_fat;
+        let total_sectors = total_sectors.min(device.sectors());
+        let clusters = (total_sectors.saturating_sub(data_start) / sectors_per_cluster) as u32;
+        if root_cluster < 2 || root_cluster >= clusters + 2 {
+            return Err(FatError::NotFat32);
+        }
+        let label = core::str::from_utf8(&boot[71..82]).unwrap_or("").trim_end();
+        Ok(Fat32 {
+            device,
+            sector_size,
+            sectors_per_cluster,
+            fat_start,
+            data_start,
+            clusters,
+            root_cluster,
+            label: String::from(label),
+            fat_cache: Mutex::new(None),
+        })
+    }
+
+    pub fn device(&self) -> &'static dyn BlockDevice {
+        self.device
+    }
+
+    pub fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster as usize * self.sector_size
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), FatError> {
+        if cluster < 2 || cluster >= self.clusters + 2 {
+            return Err(FatError::Corrupt);
+        }
+        Ok(())
+    }
+
+    // the cluster after the given one, None at the end of the chain
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FatError> {
+        self.check_cluster(cluster)?;
+        let offset = cluster as usize * 4;
+        let sector = self.fat_start + (offset / self.sector_size) as u64;
+        let mut cache = self.fat_cache.lock();
+        if cache.as_ref().is_none_or(|(cached, _)| *cached != sector) {
+            let mut data = vec![0; self.sector_size];
+            self.device.read_sectors(sector, &mut data)?;
+            *cache = Some((sector, data));
+        }
+        let (_, data) = cache.as_ref().unwrap();
+        let next = read_u32(data, offset % self.sector_size) & CLUSTER_MASK;
+        if next >= END_OF_CHAIN {
+            return Ok(None);
+        }
+        self.check_cluster(next)?;
+        Ok(Some(next))
+    }
+
+    // the clusters of a file, a chain longer than the filesystem is a loop
+    fn cluster_chain(&self, first: u32) -> Result<Vec<u32>, FatError> {
+        let mut chain = Vec::new();
+        let mut cluster = Some(first).filter(|&cluster| cluster != 0);
+        while let Some(current) = cluster {
+            if chain.len() > self.clusters as usize {
+                return Err(FatError::Corrupt);
+            }
+            chain.push(current);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(chain)
+    }
+
+    fn read_cluster(&self, cluster: u32, buffer: &mut [u8]) -> Result<(), FatError> {
+        self.check_cluster(cluster)?;
+        let sector = self.data_start + (cluster - 2) as u64 * self.sectors_per_cluster;
+        self.device.read_sectors(sector, buffer)?;
+        Ok(())
+    }
+
+    fn read_chain(&self, first: u32) -> Result<Vec<u8>, FatError> {
+        let chain = self.cluster_chain(first)?;
+        let cluster_size = self.cluster_size();
+        let mut data = vec![0; chain.len() * cluster_size];
+        for (cluster, buffer) in chain.iter().zip(data.chunks_exact_mut(cluster_size)) {
+            self.read_cluster(*cluster, buffer)?;
+        }
+        Ok(data)
+    }
+
+    // the entry of the root directory, it has no entry of its own
+    pub fn root(&self) -> DirEntry {
+        DirEntry {
+            name: String::from("/"),
+            is_dir: true,
+            size: 0,
+            cluster: self.root_cluster,
+        }
+    }
+
+    pub fn read_dir(&self, directory: &DirEntry) -> Result<Vec<DirEntry>, FatError> {
+        if !directory.is_dir {
+            return Err(FatError::NotADirectory);
+        }
+        Ok(parse_directory(&self.read_chain(directory.cluster)?))
+    }
+
+    // find the entry of a path like /boot/config.txt, the components are separated by /
+    pub fn lookup(&self, path: &str) -> Result<DirEntry, FatError> {
+        let mut entry = self.root();
+        for name in path.split('/').filter(|name| !name.is_empty()) {
+            entry = self
+                .read_dir(&entry)?
+                .into_iter()
+                .find(|child| child.name.eq_ignore_ascii_case(name))
+                .ok_or(FatError::NotFound)?;
+        }
+        Ok(entry)
+    }
+
+    // read from the file at the offset into the buffer, returns the number of bytes read (0 at the end)
+    pub fn read(&self, file: &DirEntry, offset: u64, buffer: &mut [u8]) -> Result<usize, FatError> {
+        if file.is_dir {
+            return Err(FatError::IsADirectory);
+        }
+        let size = file.size as u64;
+        if offset >= size {
+            return Ok(0);
+        }
+        let len = buffer.len().min((size - offset) as usize);
+        let cluster_size = self.cluster_size() as u64;
+        let mut cluster_data = vec![0; cluster_size as usize];
+        let mut cluster = file.cluster;
+        // follow the chain to the cluster with the offset
+        for _ in 0..offset / cluster_size {
+            cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupt)?;
+        }
+        let mut done = 0;
+        let mut in_cluster = (offset % cluster_size) as usize;
+        while done < len {
+            self.read_cluster(cluster, &mut cluster_data)?;
+            let chunk = (len - done).min(cluster_size as usize - in_cluster);
+            buffer[done..done + chunk]
+                .copy_from_slice(&cluster_data[in_cluster..in_cluster + chunk]);
+            done += chunk;
+            in_cluster = 0;
+            if done < len {
+                cluster = self.next_cluster(cluster)?.ok_or(FatError::Corrupt)?;
+            }
+        }
+        Ok(len)
+    }
+
+    // read the whole file into memory
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, FatError> {
+        let file = self.lookup(path)?;
+        let mut data = vec![0; file.size as usize];
+        let len = self.read(&file, 0, &mut data)?;
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+impl From<FatError> for VfsError {
+    fn from(error: FatError) -> Self {
+        match error {
+            FatError::NotFound => VfsError::NotFound,
+            FatError::NotADirectory => VfsError::NotADirectory,
+            FatError::IsADirectory => VfsError::IsADirectory,
+            error => {
+                log::warn!("fat32: {}", error);
+                VfsError::Io
+            }
+        }
+    }
+}
+
+// a file or directory of a mounted filesystem for the vfs
+struct FatNode {
+    filesystem: &'static Fat32,
+    entry: DirEntry,
+}
+
+fn entry_kind(entry: &DirEntry) -> NodeKind {
+    if entry.is_dir {
+        NodeKind::Directory
+    } else {
+        NodeKind::File
+    }
+}
+
+impl Inode for FatNode {
+    fn kind(&self) -> NodeKind {
+        entry_kind(&self.entry)
+    }
+
+    fn size(&self) -> u64 {
+        self.entry.size as u64
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Ok(self.filesystem.read(&self.entry, offset, buffer)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<vfs::DirEntry>, VfsError> {
+        Ok(self
+            .filesystem
+            .read_dir(&self.entry)?
+            .into_iter()
+            .map(|entry| vfs::DirEntry {
+                kind: entry_kind(&entry),
+                size: entry.size as u64,
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let entry = self
+            .filesystem
+            .read_dir(&self.entry)?
+            .into_iter()
+            .find(|child| child.name.eq_ignore_ascii_case(name))
+            .ok_or(VfsError::NotFound)?;
+        Ok(Arc::new(FatNode {
+            filesystem: self.filesystem,
+            entry,
+        }))
+    }
+}
+
+impl vfs::FileSystem for &'static Fat32 {
+    fn name(&self) -> &str {
+        "fat32"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(FatNode {
+            filesystem: self,
+            entry: Fat32::root(self),
+        })
+    }
+}
+
+static FILESYSTEMS: Mutex<Vec<&'static Fat32>> = Mutex::new(Vec::new());
+
+// the mounted filesystems
+pub fn filesystems() -> Vec<&'static Fat32> {
+    FILESYSTEMS.lock().clone()
+}
+
+// mount the FAT partitions and the disks without partitions that are formatted without a partition table
+pub fn init() {
+    let partitions = block::partitions();
+    let candidates = partitions
+        .iter()
+        .filter(|partition| partition.kind.is_fat())
+        .map(|&partition| partition as &'static dyn BlockDevice)
+        .chain(block::devices().into_iter().filter(|device| {
+            let name = device.name();
+            !partitions
+                .iter()
+                .any(|partition| partition.disk().name() == name)
+                && !partitions.iter().any(|partition| partition.name() == name)
+        }));
+    for device in candidates {
+        match Fat32::mount(device) {
+            Ok(filesystem) => {
+                log::info!("fat32: mounted {} ({})", device.name(), filesystem.label);
+                let filesystem = alloc::boxed::Box::leak(alloc::boxed::Box::new(filesystem));
+                FILESYSTEMS.lock().push(filesystem);
+            }
+            Err(error) => log::debug!("fat32: {}: {}", device.name(), error),
+        }
+    }
+}
+
+#[test_case]
+fn test_read_directories_and_files() {
+    const SECTOR: usize = block::SECTOR_SIZE;
+    // 1 reserved sector, 1 FAT sector and one sector per cluster: cluster n is sector n
+    let mut image = vec![0u8; 16 * SECTOR];
+    image[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+    image[13] = 1;
+    image[14..16].copy_from_slice(&1u16.to_le_bytes());
+    image[16] = 1;
+    image[32..36].copy_from_slice(&16u32.to_le_bytes());
+    image[36..40].copy_from_slice(&1u32.to_le_bytes());
+    image[44..48].copy_from_slice(&2u32.to_le_bytes());
+    image[71..82].copy_from_slice(b"TEST       ");
+    image[510] = 0x55;
+    image[511] = 0xAA;
+    // the FAT: the root and the subdirectory have one cluster, the long file two (3 -> 4)
+    let fat = |cluster: usize| SECTOR + cluster * 4;
+    for (cluster, next) in [
+        (2, 0x0FFF_FFFF),
+        (3, 4),
+        (4, 0x0FFF_FFF8),
+        (5, 0x0FFF_FFFF),
+        (6, 0x0FFF_FFFF),
+    ] {
+        image[fat(cluster)..fat(cluster) + 4].copy_from_slice(&(next as u32).to_le_bytes());
+    }
+    let short_entry =
+        |image: &mut [u8], at: usize, name: &[u8; 11], attributes: u8, cluster: u16, size: u32| {
+            image[at..at + 11].copy_from_slice(name);
+            image[at + 11] = attributes;
+            image[at + 26..at + 28].copy_from_slice(&cluster.to_le_bytes());
+            image[at + 28..at + 32].copy_from_slice(&size.to_le_bytes());
+        };
+    // the root directory: a long name entry for "Long file name.txt" (needs 2 parts), its short entry and a directory
+    let root = 2 * SECTOR;
+    let name: Vec<u16> = "Long file name.txt".encode_utf16().collect();
+    let checksum = short_name_checksum(b"LONGFI~1TXT");
+    for (index, part) in name.chunks(13).enumerate().rev() {
+        let entry = root + (1 - index) * ENTRY_SIZE;
+        image[entry] = (index as u8 + 1) | if index == 1 { LONG_NAME_LAST } else { 0 };
+        image[entry + 11] = ATTRIBUTE_LONG_NAME;
+        image[entry + 13] = checksum;
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (slot, offset) in offsets.enumerate() {
+            let character =
+                part.get(slot)
+                    .copied()
+                    .unwrap_or(if slot == part.len() { 0 } else { 0xFFFF });
+            image[entry + offset..entry + offset + 2].copy_from_slice(&character.to_le_bytes());
+        }
+    }
+    short_entry(&mut image, root + 2 * ENTRY_SIZE, b"LONGFI~1TXT", 0, 3, 600);
+    short_entry(
+        &mut image,
+        root + 3 * ENTRY_SIZE,
+        b"DIR        ",
+        ATTRIBUTE_DIRECTORY,
+        5,
+        0,
+    );
+    // the subdirectory with the dot entries and a lowercase short name
+    let dir = 5 * SECTOR;
+    short_entry(&mut image, dir, b".          ", ATTRIBUTE_DIRECTORY, 5, 0);
+    short_entry(
+        &mut image,
+        dir + ENTRY_SIZE,
+        b"..         ",
+        ATTRIBUTE_DIRECTORY,
+        0,
+        0,
+    );
+    short_entry(&mut image, dir + 2 * ENTRY_SIZE, b"A       TXT", 0, 6, 3);
+    image[dir + 2 * ENTRY_SIZE + 12] = LOWERCASE_BASE | LOWERCASE_EXTENSION;
+    // a long name part whose short entry was deleted, the next short entry has the same checksum
+    let orphan = dir + 3 * ENTRY_SIZE;
+    image[orphan] = 1 | LONG_NAME_LAST;
+    image[orphan + 11] = ATTRIBUTE_LONG_NAME;
+    image[orphan + 13] = short_name_checksum(b"B       TXT");
+    short_entry(&mut image, orphan + ENTRY_SIZE, b"B       TXT", 0, 0, 0);
+    image[orphan + ENTRY_SIZE] = ENTRY_DELETED;
+    short_entry(&mut image, orphan + 2 * ENTRY_SIZE, b"B       TXT", 0, 0, 0);
+    image[6 * SECTOR..6 * SECTOR + 3].copy_from_slice(b"abc");
+    for (index, byte) in image[3 * SECTOR..3 * SECTOR + 600].iter_mut().enumerate() {
+        *byte = index as u8;
+    }
+
+    let disk: &'static block::RamDisk =
+        alloc::boxed::Box::leak(alloc::boxed::Box::new(block::RamDisk::new("fat", image)));
+    let filesystem = Fat32::mount(disk).unwrap();
+    assert_eq!(filesystem.label, "TEST");
+    let names: Vec<String> = filesystem
+        .read_dir(&filesystem.root())
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, ["Long file name.txt", "DIR"]);
+    assert_eq!(filesystem.read_file("/dir/a.txt").unwrap(), b"abc");
+    let dir = filesystem.lookup("/dir").unwrap();
+    let names: Vec<String> = filesystem
+        .read_dir(&dir)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, ["a.txt", "B.TXT"]);
+    // the long file spans two clusters
+    let data = filesystem.read_file("/LONG FILE NAME.TXT").unwrap();
+    assert_eq!(data.len(), 600);
+    assert!(data
+        .iter()
+        .enumerate()
+        .all(|(index, &byte)| byte == index as u8));
+    let file = filesystem.lookup("long file name.txt").unwrap();
+    let mut buffer = [0; 4];
+    assert_eq!(filesystem.read(&file, 510, &mut buffer), Ok(4));
+    assert_eq!(buffer, [254, 255, 0, 1]);
+    assert_eq!(filesystem.lookup("/missing"), Err(FatError::NotFound));
+    assert_eq!(filesystem.read_dir(&file), Err(FatError::NotADirectory));
+}
@@ -0,0 +1,112 @@
+/*
+* The x87 FPU and the SSE unit have their own registers (st0-st7, xmm0-xmm15, the control words FCW and
+* MXCSR) that the general purpose context switch doesn't touch. The kernel is compiled with soft-float so
+* it never uses them itself, interrupt handlers leave them alone, but user programs do and two threads
+* running them would see each other's values.
+*
+* The CPU only allows the SSE instructions once the OS says it saves their state:
+*  * CR0.EM must be clear (no emulation, otherwise every FPU instruction raises #NM) and CR0.MP set
+*  * CR0.NE reports the x87 errors as #MF exceptions instead of the legacy IRQ 13
+*  * CR4.OSFXSR enables SSE and fxsave/fxrstor, CR4.OSXMMEXCPT reports the SIMD errors as #XM
+*
+* The state is saved EAGERLY: every thread has a 512 byte area in the fxsave format, the scheduler saves the
+* registers of the old thread into it and loads the ones of the new thread on every switch. Lazy switching
+* with CR0.TS would save some of the work but needs the #NM handler to find the owner of the registers.
+*
+*     0 FCW, 2 FSW, 4 abridged tag word, 24 MXCSR, 28 MXCSR mask, 32 st0-st7, 160 xmm0-xmm15
+* */
+use crate::cpu::{self, Feature};
+use core::arch::asm;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+// all exceptions masked, round to nearest, 64 bit precision (what fninit sets)
+const DEFAULT_FCW: u16 = 0x037F;
+// all SIMD exceptions masked, round to nearest
+const DEFAULT_MXCSR: u32 = 0x1F80;
+
+// the fxsave area, it must be 16 byte aligned
+#[repr(C, align(16))]
+pub struct FpuState([u8; 512]);
+
+impl FpuState {
+    // the state after fninit with the default MXCSR, what a new thread starts with
+    pub fn new() -> FpuState {
+        let mut area = [0u8; 512];
+        area[0..2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        area[24..28].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        FpuState(area)
+    }
+
+    // store the registers of the CPU
+    pub fn save(&mut self) {
+        unsafe { asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack)) };
+    }
+
+    // load the registers into the CPU
+    pub fn restore(&self) {
+        unsafe { asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack)) };
+    }
+}
+
+impl Default for FpuState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// enable the FPU and SSE on the running CPU
+pub fn init() {
+    assert!(
+        cpu::has(Feature::Fxsr) && cpu::has(Feature::Sse2),
+        "the CPU has no fxsave or SSE2"
+    );
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        });
+    }
+    FpuState::new().restore();
+}
+
+#[test_case]
+fn test_fpu_state_is_saved_per_thread() {
+    use crate::scheduler;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static DONE: AtomicBool = AtomicBool::new(false);
+    static KEPT: AtomicBool = AtomicBool::new(false);
+
+    fn set_xmm0(value: u64) {
+        unsafe { asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+    }
+    fn xmm0() -> u64 {
+        let value;
+        unsafe { asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+        value
+    }
+
+    let mut state = FpuState::new();
+    set_xmm0(0x1234);
+    state.save();
+    set_xmm0(0);
+    state.restore();
+    assert_eq!(xmm0(), 0x1234);
+
+    scheduler::spawn("fpu test", || {
+        set_xmm0(0xAAAA);
+        for _ in 0..10 {
+            scheduler::yield_now();
+        }
+        KEPT.store(xmm0() == 0xAAAA, Ordering::Relaxed);
+        DONE.store(true, Ordering::Release);
+    });
+    set_xmm0(0x5555);
+    while !DONE.load(Ordering::Acquire) {
+        scheduler::yield_now();
+    }
+    assert!(KEPT.load(Ordering::Relaxed));
+    assert_eq!(xmm0(), 0x5555);
+}
@@ -0,0 +1,342 @@
+/*
+* Newer boot environments (UEFI, multiboot2, the newer versions of the bootloader crate) don't set up
+* VGA text mode, instead they hand over a LINEAR_FRAMEBUFFER: a block of memory where every pixel of the
+* screen is a few bytes of color, row after row. There is no hardware font so every character is drawn
+* pixel by pixel from a bitmap font (8x16 pixels per character, the same size as the VGA text mode font).
+*
+* The writer offers the same interface as the VGA text writer, the fb console (console::FramebufferConsole)
+* takes the place of the vga console when the boot code registered a framebuffer with init, otherwise the
+* output stays in VGA text mode.
+*
+* Only the multiboot2 boot path (GRUB) registers a framebuffer. The bootloader crate version we use (0.9)
+* always sets up VGA text mode and its BootInfo has no framebuffer, so when booting the bootimage the
+* framebuffer is never used.
+* */
+use crate::vga_buffer::{cp437, Color, ColorCode};
+use core::fmt;
+use spin::Mutex;
+
+mod font;
+
+pub use font::{CHAR_HEIGHT, CHAR_WIDTH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // red in the lowest byte
+    Rgb,
+    // blue in the lowest byte (the usual UEFI format)
+    Bgr,
+    // one byte of brightness per pixel
+    Grayscale,
+}
+
+// the layout of the framebuffer, as reported by the bootloader
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBufferInfo {
+    // visible pixels per row and number of rows
+    pub width: usize,
+    pub height: usize,
+    // pixels per row in memory, can be larger than the width because of padding
+    pub stride: usize,
+    pub bytes_per_pixel: usize,
+    pub pixel_format: PixelFormat,
+}
+
+impl FrameBufferInfo {
+    pub fn size(&self) -> usize {
+        self.stride * self.height * self.bytes_per_pixel
+    }
+}
+
+// the RGB values of the 16 VGA colors, in the order of the Color enum
+const PALETTE: [[u8; 3]; 16] = [
+    [0x00, 0x00, 0x00],
+    [0x00, 0x00, 0xAA],
+    [0x00, 0xAA, 0x00],
+    [0x00, 0xAA, 0xAA],
+    [0xAA, 0x00, 0x00],
+    [0xAA, 0x00, 0xAA],
+    [0xAA, 0x55, 0x00],
+    [0xAA, 0xAA, 0xAA],
+    [0x55, 0x55, 0x55],
+    [0x55, 0x55, 0xFF],
+    [0x55, 0xFF, 0x55],
+    [0x55, 0xFF, 0xFF],
+    [0xFF, 0x55, 0x55],
+    [0xFF, 0x55, 0xFF],
+    [0xFF, 0xFF, 0x55],
+    [0xFF, 0xFF, 0xFF],
+];
+
+pub struct Writer {
+    buffer: &'static mut [u8],
+    info: FrameBufferInfo,
+    color_code: ColorCode,
+    // the text grid that fits on the screen
+    columns: usize,
+    rows: usize,
+    column_position: usize,
+    row_position: usize,
+    // escape sequences are parsed so they don't show up as garbage, only the printed bytes are drawn
+    ansi_parser: crate::vga_buffer::ansi::Parser,
+}
+
+impl Writer {
+    pub fn new(buffer: &'static mut [u8], info: FrameBufferInfo, color_code: ColorCode) -> Writer {
+        assert!(
+            buffer.len() >= info.size(),
+            "framebuffer smaller than its info"
+        );
+        assert!(
+            (1..=4).contains(&info.bytes_per_pixel),
+            "unsupported framebuffer pixel size"
+        );
+        let mut writer = Writer {
+            columns: info.width / CHAR_WIDTH,
+            rows: info.height / CHAR_HEIGHT,
+            buffer,
+            info,
+            color_code,
+            column_position: 0,
+            row_position: 0,
+            ansi_parser: crate::vga_buffer::ansi::Parser::new(),
+        };
+        writer.clear_screen();
+        writer
+    }
+
+    pub fn info(&self) -> FrameBufferInfo {
+        self.info
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.column_position = 0,
+            byte => self.write_glyph(byte),
+        }
+    }
+
+    // draw the code page 437 glyph, 0x0A and 0x0D are glyphs too
+    fn write_glyph(&mut self, glyph: u8) {
+        if self.column_position >= self.columns {
+            self.new_line();
+        }
+        self.draw_char(self.row_position, self.column_position, glyph);
+        self.column_position += 1;
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        // like the VGA writer, the font has the same code page 437 glyphs
+        for character in s.chars() {
+            if !character.is_ascii() {
+                self.write_glyph(cp437::encode(character));
+                continue;
+            }
+            if let Some(crate::vga_buffer::ansi::Action::Print(byte)) =
+                self.ansi_parser.advance(character as u8)
+            {
+                match byte {
+                    0x20..=0x7e | b'\n' | b'\r' => self.write_byte(byte),
+                    _ => self.write_glyph(cp437::UNKNOWN),
+                }
+            }
+        }
+    }
+
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
+
+    // run the closure with the given color and restore the previous color afterwards
+    pub fn with_color<R>(
+        &mut self,
+        foreground: Color,
+        background: Color,
+        f: impl FnOnce(&mut Writer) -> R,
+    ) -> R {
+        let previous = self.color_code;
+        self.set_color(foreground, background);
+        let result = f(self);
+        self.color_code = previous;
+        result
+    }
+
+    // fill the screen with the background color and move the cursor to the top left corner
+    pub fn clear_screen(&mut self) {
+        let background = self.pixel_bytes(self.color_code.background());
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+        for pixel in self.buffer[..self.info.size()].chunks_exact_mut(bytes_per_pixel) {
+            pixel.copy_from_slice(&background[..bytes_per_pixel]);
+        }
+        self.column_position = 0;
+        self.row_position = 0;
+    }
+
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    // move the cursor, the next character is drawn at this position
+    pub fn set_cursor_position(&mut self, row: usize, col: usize) {
+        self.row_position = row.min(self.rows - 1);
+        self.column_position = col.min(self.columns - 1);
+    }
+
+    // the number of text rows and columns that fit on the screen
+    pub fn size(&self) -> (usize, usize) {
+        (self.rows, self.columns)
+    }
+
+    fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position + 1 < self.rows {
+            self.row_position += 1;
+            return;
+        }
+
+        // move all the pixel rows of the text up one character row and clear the last one
+        let text_row_bytes = self.info.stride * self.info.bytes_per_pixel * CHAR_HEIGHT;
+        let text_bytes = text_row_bytes * self.rows;
+        self.buffer.copy_within(text_row_bytes..text_bytes, 0);
+        for col in 0..self.columns {
+            self.draw_char(self.rows - 1, col, b' ');
+        }
+    }
+
+    fn draw_char(&mut self, row: usize, col: usize, character: u8) {
+        let foreground = self.pixel_bytes(self.color_code.foreground());
+        let background = self.pixel_bytes(self.color_code.background());
+        let bytes_per_pixel = self.info.bytes_per_pixel;
+
+        for (y, bits) in font::glyph(character).iter().enumerate() {
+            let pixel_row = row * CHAR_HEIGHT + y;
+            let start = (pixel_row * self.info.stride + col * CHAR_WIDTH) * bytes_per_pixel;
+            let line = &mut self.buffer[start..start + CHAR_WIDTH * bytes_per_pixel];
+            for (x, pixel) in line.chunks_exact_mut(bytes_per_pixel).enumerate() {
+                // the highest bit is the leftmost pixel
+                let color = if bits & (0x80 >> x) != 0 {
+                    &foreground
+                } else {
+                    &background
+                };
+                pixel.copy_from_slice(&color[..bytes_per_pixel]);
+            }
+        }
+    }
+
+    // the bytes of one pixel with the color, pixels with more than 3 bytes leave the rest 0
+    fn pixel_bytes(&self, color: Color) -> [u8; 4] {
+        let [r, g, b] = PALETTE[color as usize];
+        match self.info.pixel_format {
+            PixelFormat::Rgb => [r, g, b, 0],
+            PixelFormat::Bgr => [b, g, r, 0],
+            PixelFormat::Grayscale => {
+                let gray = ((r as u16 * 3 + g as u16 * 6 + b as u16) / 10) as u8;
+                [gray, 0, 0, 0]
+            }
+        }
+    }
+}
+
+impl fmt::Write for Writer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+// None while the kernel uses VGA text mode
+pub static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+
+/*
+* Called by multiboot2::init when GRUB set up a graphics mode, from then on print! draws to the framebuffer
+* instead of the VGA text buffer. Nothing calls it when booting with the bootloader crate (VGA text mode only).
+*
+* Safety: the buffer must be the memory of the framebuffer described by the info and nothing else
+* may use it.
+* */
+pub unsafe fn init(buffer: &'static mut [u8], info: FrameBufferInfo) {
+    use x86_64::instructions::interrupts;
+
+    let writer = Writer::new(buffer, info, ColorCode::new(Color::Yellow, Color::Black));
+    interrupts::without_interrupts(|| *WRITER.lock() = Some(writer));
+    crate::console::replace("vga", &crate::console::FRAMEBUFFER);
+}
+
+pub fn is_active() -> bool {
+    // interrupt handlers print to the writer (vga_buffer::with_writer)
+    x86_64::instructions::interrupts::without_interrupts(|| WRITER.lock().is_some())
+}
+
+#[cfg(test)]
+fn test_writer(columns: usize, rows: usize) -> Writer {
+    use alloc::vec;
+
+    let info = FrameBufferInfo {
+        width: columns * CHAR_WIDTH,
+        height: rows * CHAR_HEIGHT,
+        // padding at the end of every row
+        stride: columns * CHAR_WIDTH + 3,
+        bytes_per_pixel: 4,
+        pixel_format: PixelFormat::Bgr,
+    };
+    let buffer = vec![0xAB; info.size()].leak();
+    Writer::new(buffer, info, ColorCode::new(Color::White, Color::Blue))
+}
+
+#[cfg(test)]
+fn pixel(writer: &Writer, x: usize, y: usize) -> [u8; 4] {
+    let start = (y * writer.info.stride + x) * writer.info.bytes_per_pixel;
+    writer.buffer[start..start + 4].try_into().unwrap()
+}
+
+#[cfg(test)]
+const TEST_FOREGROUND: [u8; 4] = [0xFF, 0xFF, 0xFF, 0];
+#[cfg(test)]
+const TEST_BACKGROUND: [u8; 4] = [0xAA, 0x00, 0x00, 0];
+
+// check that every pixel of the character cell shows the glyph in the test colors
+#[cfg(test)]
+fn assert_glyph(writer: &Writer, row: usize, col: usize, character: u8) {
+    for (y, bits) in font::glyph(character).iter().enumerate() {
+        for x in 0..CHAR_WIDTH {
+            let expected = if bits & (0x80 >> x) != 0 {
+                TEST_FOREGROUND
+            } else {
+                TEST_BACKGROUND
+            };
+            let pixel = pixel(writer, col * CHAR_WIDTH + x, row * CHAR_HEIGHT + y);
+            assert_eq!(pixel, expected);
+        }
+    }
+}
+
+#[test_case]
+fn test_framebuffer_draws_glyphs() {
+    let mut writer = test_writer(4, 2);
+    // the screen is cleared to the background color
+    assert_glyph(&writer, 0, 0, b' ');
+
+    // the escape sequence isn't drawn, the underscore is the first character
+    writer.write_string("\x1b[31m_|");
+    assert_eq!(writer.cursor_position(), (0, 2));
+    assert_glyph(&writer, 0, 0, b'_');
+    assert_glyph(&writer, 0, 1, b'|');
+}
+
+#[test_case]
+fn test_framebuffer_wraps_and_scrolls() {
+    let mut writer = test_writer(4, 2);
+    // the first line wraps after 4 characters, the newline on the last row scrolls "____" off the screen
+    writer.write_string("____ab\n");
+    assert_eq!(writer.cursor_position(), (1, 0));
+    assert_glyph(&writer, 0, 0, b'a');
+    assert_glyph(&writer, 0, 1, b'b');
+    assert_glyph(&writer, 0, 2, b' ');
+    (0..4).for_each(|col| assert_glyph(&writer, 1, col, b' '));
+}
@@ -0,0 +1,99 @@
+/*
+* QEMU's firmware configuration device is two I/O ports: a 16 bit selector port and an 8 bit data port
+* that reads the selected item byte by byte. Item 0 is the signature "QEMU", item 0x19 the directory of the
+* files, a big endian u32 count followed by the entries:
+*     u32 size, u16 selector, u16 reserved, 56 bytes name (NUL terminated), all big endian
+*
+* The files are given on the QEMU command line with -fw_cfg name=<name>,string=<text> or
+* name=<name>,file=<host path>, the kernel reads its command line and its initrd from them.
+* */
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const SELECTOR_PORT: u16 = 0x510;
+const DATA_PORT: u16 = 0x511;
+const SIGNATURE: u16 = 0x0000;
+const FILE_DIR: u16 = 0x0019;
+const NAME_LEN: usize = 56;
+
+// selecting an item and reading it are two steps, another CPU must not select an item in between
+static LOCK: Mutex<()> = Mutex::new(());
+
+fn select(item: u16) {
+    unsafe { Port::<u16>::new(SELECTOR_PORT).write(item) };
+}
+
+fn read(buffer: &mut [u8]) {
+    let mut data = Port::<u8>::new(DATA_PORT);
+    for byte in buffer {
+        *byte = unsafe { data.read() };
+    }
+}
+
+fn read_u32() -> u32 {
+    let mut bytes = [0; 4];
+    read(&mut bytes);
+    u32::from_be_bytes(bytes)
+}
+
+fn read_u16() -> u16 {
+    let mut bytes = [0; 2];
+    read(&mut bytes);
+    u16::from_be_bytes(bytes)
+}
+
+// the port reads 0xFF on machines without the device, so the signature tells if it is there
+fn signature_matches() -> bool {
+    let mut signature = [0; 4];
+    select(SIGNATURE);
+    read(&mut signature);
+    &signature == b"QEMU"
+}
+
+// the selector and size of the file
+fn find(name: &str) -> Option<(u16, usize)> {
+    if !signature_matches() {
+        return None;
+    }
+    select(FILE_DIR);
+    let count = read_u32();
+    for _ in 0..count {
+        let size = read_u32() as usize;
+        let selector = read_u16();
+        read_u16(); // reserved
+        let mut entry_name = [0; NAME_LEN];
+        read(&mut entry_name);
+        let len = entry_name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+        if &entry_name[..len] == name.as_bytes() {
+            return Some((selector, size));
+        }
+    }
+    None
+}
+
+pub fn is_present() -> bool {
+    let _lock = LOCK.lock();
+    signature_matches()
+}
+
+// read the file to the buffer, returns how many bytes were read or None if there is no such file
+pub fn read_file(name: &str, buffer: &mut [u8]) -> Option<usize> {
+    let _lock = LOCK.lock();
+    let (selector, size) = find(name)?;
+    let len = size.min(buffer.len());
+    select(selector);
+    read(&mut buffer[..len]);
+    Some(len)
+}
+
+// read the whole file to the heap
+pub fn read_file_to_vec(name: &str) -> Option<Vec<u8>> {
+    let _lock = LOCK.lock();
+    let (selector, size) = find(name)?;
+    let mut data = vec![0; size];
+    select(selector);
+    read(&mut data);
+    Some(data)
+}
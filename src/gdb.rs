@@ -0,0 +1,665 @@
+/*
+* A stub for the GDB REMOTE SERIAL PROTOCOL, so gdb can debug the kernel over a serial cable on real
+* hardware (QEMU has its own stub, -s):
+*
+*     (gdb) target remote /dev/ttyS0       or with QEMU: -serial mon:stdio -serial pty
+*
+* It is enabled with the gdb option of the command line (gdb=com1 uses the first serial port, which
+* shares it with the log messages, the default is COM2), the kernel then stops in init_devices until gdb
+* attaches. The messages are PACKETS, $<data>#<checksum> with the checksum the sum of the data bytes modulo
+* 256 in hex, every packet is acknowledged with + (or - to ask for it again). The stub answers the commands
+* for reading and writing the registers (g, G, p, P) and the memory (m, M), inserting breakpoints (Z0, z0),
+* continuing (c) and single stepping (s).
+*
+* The debug exception (#DB) and the breakpoint exception (#BP, int3) enter the stub with all the general
+* purpose registers saved in a Registers frame by an assembly entry, the registers gdb writes are restored
+* from it when the CPU continues. A software breakpoint replaces the byte at its address with int3 (the
+* write protection of the kernel code is disabled while the stub writes), single stepping sets the trap flag
+* (TF) in RFLAGS which raises #DB after the next instruction. Ctrl-C in gdb sends the byte 3, the interrupt
+* handler of the port executes int3 so the kernel stops in it. The fatal exceptions enter the stub before the
+* panic with the registers of their stack frame, so the cause can be inspected.
+*
+* Only the CPU that stopped waits for gdb, the other CPUs keep running.
+* */
+use crate::interrupts;
+use crate::memory;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+// the longest packet gdb sends, told to gdb in the answer to qSupported
+const PACKET_SIZE: usize = 1024;
+const MAX_BREAKPOINTS: usize = 32;
+const TRAP_FLAG: u64 = 1 << 8;
+const INT3: u8 = 0xCC;
+
+// the signals reported to gdb
+pub const SIGILL: u8 = 4;
+pub const SIGTRAP: u8 = 5;
+pub const SIGFPE: u8 = 8;
+pub const SIGSEGV: u8 = 11;
+
+// the general purpose registers pushed by the entry, then the frame the CPU pushed for the exception
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+// the registers in the order of gdb's amd64 description, the first 17 have 8 bytes and the rest 4 bytes
+const REGISTER_COUNT: usize = 24;
+
+impl Registers {
+    fn get(&self, index: usize) -> Option<u64> {
+        Some(match index {
+            0 => self.rax,
+            1 => self.rbx,
+            2 => self.rcx,
+            3 => self.rdx,
+            4 => self.rsi,
+            5 => self.rdi,
+            6 => self.rbp,
+            7 => self.rsp,
+            8 => self.r8,
+            9 => self.r9,
+            10 => self.r10,
+            11 => self.r11,
+            12 => self.r12,
+            13 => self.r13,
+            14 => self.r14,
+            15 => self.r15,
+            16 => self.rip,
+            17 => self.rflags,
+            18 => self.cs,
+            19 => self.ss,
+            // ds, es, fs and gs are unused in long mode
+            20..REGISTER_COUNT => 0,
+            _ => return None,
+        })
+    }
+
+    // the segment registers can't be changed, returning to another segment would crash
+    fn set(&mut self, index: usize, value: u64) -> bool {
+        let register = match index {
+            0 => &mut self.rax,
+            1 => &mut self.rbx,
+            2 => &mut self.rcx,
+            3 => &mut self.rdx,
+            4 => &mut self.rsi,
+            5 => &mut self.rdi,
+            6 => &mut self.rbp,
+            7 => &mut self.rsp,
+            8 => &mut self.r8,
+            9 => &mut self.r9,
+            10 => &mut self.r10,
+            11 => &mut self.r11,
+            12 => &mut self.r12,
+            13 => &mut self.r13,
+            14 => &mut self.r14,
+            15 => &mut self.r15,
+            16 => &mut self.rip,
+            17 => &mut self.rflags,
+            18..REGISTER_COUNT => return true,
+            _ => return false,
+        };
+        *register = value;
+        true
+    }
+
+    fn size(index: usize) -> usize {
+        if index <= 16 {
+            8
+        } else {
+            4
+        }
+    }
+}
+
+global_asm!(
+    ".global gdb_trap_entry",
+    "gdb_trap_entry:",
+    // a trap in user mode runs with the GS base of the program, load the kernel's (see percpu)
+    "test byte ptr [rsp + 8], 3",
+    "jz 2f",
+    "swapgs",
+    "2:",
+    // #DB and #BP push no error code, the push order makes the Registers layout
+    "push r15",
+    "push r14",
+    "push r13",
+    "push r12",
+    "push r11",
+    "push r10",
+    "push r9",
+    "push r8",
+    "push rbp",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push rcx",
+    "push rbx",
+    "push rax",
+    // the CPU aligned the stack to 16 bytes before pushing its 5 words, with the 15 registers it is aligned again
+    "mov rdi, rsp",
+    "cld",
+    "call {trap}",
+    "pop rax",
+    "pop rbx",
+    "pop rcx",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop rbp",
+    "pop r8",
+    "pop r9",
+    "pop r10",
+    "pop r11",
+    "pop r12",
+    "pop r13",
+    "pop r14",
+    "pop r15",
+    "test byte ptr [rsp + 8], 3",
+    "jz 3f",
+    "swapgs",
+    "3:",
+    "iretq",
+    trap = sym trap,
+);
+
+extern "C" {
+    fn gdb_trap_entry();
+}
+
+// the address of the entry for the #DB and #BP entries of the IDT
+pub fn trap_entry() -> VirtAddr {
+    VirtAddr::new(gdb_trap_entry as *const () as u64)
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+// set once gdb let the kernel run, gdb then waits for the stop reply of the next stop
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static PORT: Mutex<Option<SerialPort>> = Mutex::new(None);
+// the address and the replaced byte of every breakpoint
+static BREAKPOINTS: Mutex<[Option<(u64, u8)>; MAX_BREAKPOINTS]> =
+    Mutex::new([None; MAX_BREAKPOINTS]);
+
+// the port given with the gdb option, None without it (read before the IDT is built)
+fn port_base() -> Option<(u16, u8)> {
+    if !crate::cmdline::flag("gdb") {
+        return None;
+    }
+    match crate::cmdline::get("gdb")? {
+        "com1" => Some((0x3F8, 4)),
+        _ => Some((0x2F8, 3)),
+    }
+}
+
+pub fn is_enabled() -> bool {
+    port_base().is_some()
+}
+
+// the I/O port of the serial port gdb uses
+pub fn port() -> Option<u16> {
+    port_base().map(|(base, _)| base)
+}
+
+// open the port and wait for gdb to attach, does nothing without the gdb option
+pub fn init() {
+    let Some((base, irq)) = port_base() else {
+        return;
+    };
+    let mut port = unsafe { SerialPort::new(base) };
+    port.init();
+    x86_64::instructions::interrupts::without_interrupts(|| *PORT.lock() = Some(port));
+    ENABLED.store(true, Ordering::SeqCst);
+    interrupts::register_irq_handler(irq, serial_interrupt_handler);
+    log::info!("gdb: waiting for the debugger on the port {:#x}", base);
+    x86_64::instructions::interrupts::int3();
+}
+
+// a byte received while the kernel runs, gdb only sends Ctrl-C then
+fn serial_interrupt_handler() {
+    // the byte is there, receive doesn't wait
+    let byte = PORT.lock().as_mut().map(SerialPort::receive);
+    if byte == Some(0x03) {
+        x86_64::instructions::interrupts::int3();
+    }
+}
+
+extern "C" fn trap(registers: &mut Registers) {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+    let step = session(registers, SIGTRAP);
+    if step {
+        registers.rflags |= TRAP_FLAG;
+    } else {
+        registers.rflags &= !TRAP_FLAG;
+    }
+}
+
+// called by the fatal exception handlers before they panic, gdb can look at the state but not continue
+pub fn exception(signal: u8, stack_frame: &InterruptStackFrame) {
+    if !ENABLED.load(Ordering::SeqCst) {
+        return;
+    }
+    let mut registers = Registers {
+        rip: stack_frame.instruction_pointer.as_u64(),
+        cs: stack_frame.code_segment,
+        rflags: stack_frame.cpu_flags,
+        rsp: stack_frame.stack_pointer.as_u64(),
+        ss: stack_frame.stack_segment,
+        ..Registers::default()
+    };
+    session(&mut registers, signal);
+}
+
+/*
+* Talk to gdb until it lets the kernel run again, returns whether it asked for a single step. Runs with
+* interrupts disabled in the exception handler, so it polls the port.
+* */
+fn session(registers: &mut Registers, signal: u8) -> bool {
+    let Some(mut port) = PORT.try_lock() else {
+        return false;
+    };
+    let Some(port) = port.as_mut() else {
+        return false;
+    };
+    let mut packet = [0u8; PACKET_SIZE];
+    let mut reply = Reply::new();
+    if RUNNING.swap(false, Ordering::SeqCst) {
+        reply.stop_reply(signal);
+        send_packet(port, &reply);
+    }
+    loop {
+        let len = receive_packet(port, &mut packet);
+        reply.clear();
+        match handle(&packet[..len], registers, signal, &mut reply) {
+            Action::Reply => send_packet(port, &reply),
+            Action::Continue => {
+                RUNNING.store(true, Ordering::SeqCst);
+                return false;
+            }
+            Action::Step => {
+                RUNNING.store(true, Ordering::SeqCst);
+                return true;
+            }
+            Action::Detach => {
+                if !reply.as_bytes().is_empty() {
+                    send_packet(port, &reply);
+                }
+                return false;
+            }
+        }
+    }
+}
+
+fn receive_packet(port: &mut SerialPort, buffer: &mut [u8; PACKET_SIZE]) -> usize {
+    'packet: loop {
+        // everything before the start of a packet (acknowledgements, Ctrl-C) is skipped
+        while port.receive() != b'$' {}
+        let mut len = 0;
+        let mut checksum: u8 = 0;
+        loop {
+            match port.receive() {
+                b'#' => break,
+                b'$' => continue 'packet,
+                byte if len < buffer.len() => {
+                    buffer[len] = byte;
+                    len += 1;
+                    checksum = checksum.wrapping_add(byte);
+                }
+                _ => continue 'packet,
+            }
+        }
+        let expected = [port.receive(), port.receive()];
+        if parse_hex(&expected) == Some(checksum as u64) {
+            port.send_raw(b'+');
+            return len;
+        }
+        port.send_raw(b'-');
+    }
+}
+
+fn send_packet(port: &mut SerialPort, reply: &Reply) {
+    let data = reply.as_bytes();
+    let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+    loop {
+        port.send_raw(b'$');
+        for &byte in data {
+            port.send_raw(byte);
+        }
+        port.send_raw(b'#');
+        port.send_raw(HEX[(checksum >> 4) as usize]);
+        port.send_raw(HEX[(checksum & 0xF) as usize]);
+        // send again until gdb acknowledges it
+        if port.receive() == b'+' {
+            return;
+        }
+    }
+}
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+struct Reply {
+    bytes: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn new() -> Self {
+        Reply {
+            bytes: [0; PACKET_SIZE],
+            len: 0,
+        }
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let len = bytes.len().min(PACKET_SIZE - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&bytes[..len]);
+        self.len += len;
+    }
+
+    fn push_hex_byte(&mut self, byte: u8) {
+        self.push(&[HEX[(byte >> 4) as usize], HEX[(byte & 0xF) as usize]]);
+    }
+
+    // the values are sent in the byte order of the target (little endian)
+    fn push_register(&mut self, value: u64, size: usize) {
+        for byte in &value.to_le_bytes()[..size] {
+            self.push_hex_byte(*byte);
+        }
+    }
+
+    fn stop_reply(&mut self, signal: u8) {
+        self.push(b"S");
+        self.push_hex_byte(signal);
+    }
+}
+
+enum Action {
+    Reply,
+    Continue,
+    Step,
+    // the kernel runs without gdb, a new gdb attaches with Ctrl-C
+    Detach,
+}
+
+fn hex_digit(byte: u8) -> Option<u64> {
+    (byte as char).to_digit(16).map(u64::from)
+}
+
+fn parse_hex(bytes: &[u8]) -> Option<u64> {
+    if bytes.is_empty() || bytes.len() > 16 {
+        return None;
+    }
+    bytes
+        .iter()
+        .try_fold(0, |value, &byte| Some(value << 4 | hex_digit(byte)?))
+}
+
+// a value in the little endian hex of the register packets
+fn parse_le_hex(bytes: &[u8]) -> Option<u64> {
+    if !bytes.len().is_multiple_of(2) || bytes.len() > 16 {
+        return None;
+    }
+    bytes
+        .chunks(2)
+        .rev()
+        .try_fold(0, |value, pair| Some(value << 8 | parse_hex(pair)?))
+}
+
+// the address and the length of the m, M, Z and z packets (addr,length)
+fn parse_address_length(arguments: &[u8]) -> Option<(u64, u64)> {
+    let comma = arguments.iter().position(|&byte| byte == b',')?;
+    let (address, length) = (&arguments[..comma], &arguments[comma + 1..]);
+    let length = match length.iter().position(|&byte| byte == b',' || byte == b':') {
+        Some(end) => &length[..end],
+        None => length,
+    };
+    Some((parse_hex(address)?, parse_hex(length)?))
+}
+
+fn handle(packet: &[u8], registers: &mut Registers, signal: u8, reply: &mut Reply) -> Action {
+    let Some((&command, arguments)) = packet.split_first() else {
+        return Action::Reply;
+    };
+    match command {
+        b'?' => reply.stop_reply(signal),
+        b'g' => {
+            for index in 0..REGISTER_COUNT {
+                reply.push_register(registers.get(index).unwrap(), Registers::size(index));
+            }
+        }
+        b'G' => {
+            let mut offset = 0;
+            for index in 0..REGISTER_COUNT {
+                let size = Registers::size(index) * 2;
+                let Some(value) = arguments.get(offset..offset + size).and_then(parse_le_hex)
+                else {
+                    break;
+                };
+                registers.set(index, value);
+                offset += size;
+            }
+            reply.push(b"OK");
+        }
+        b'p' => match parse_hex(arguments)
+            .and_then(|index| registers.get(index as usize).map(|value| (index, value)))
+        {
+            Some((index, value)) => reply.push_register(value, Registers::size(index as usize)),
+            None => reply.push(b"E01"),
+        },
+        b'P' => {
+            let parsed = arguments
+                .iter()
+                .position(|&byte| byte == b'=')
+                .and_then(|equals| {
+                    Some((
+                        parse_hex(&arguments[..equals])?,
+                        parse_le_hex(&arguments[equals + 1..])?,
+                    ))
+                });
+            match parsed {
+                Some((index, value)) if registers.set(index as usize, value) => reply.push(b"OK"),
+                _ => reply.push(b"E01"),
+            }
+        }
+        b'm' => match parse_address_length(arguments) {
+            Some((address, length)) => {
+                // the reply has two hex digits per byte
+                let length = length.min(PACKET_SIZE as u64 / 2);
+                for address in address..address.saturating_add(length) {
+                    match read_byte(address) {
+                        Some(byte) => reply.push_hex_byte(byte),
+                        // the bytes up to an unmapped page are returned
+                        None => break,
+                    }
+                }
+                if reply.len == 0 && length > 0 {
+                    reply.push(b"E14");
+                }
+            }
+            None => reply.push(b"E01"),
+        },
+        b'M' => {
+            let data = arguments
+                .iter()
+                .position(|&byte| byte == b':')
+                .map(|colon| &arguments[colon + 1..]);
+            match (parse_address_length(arguments), data) {
+                (Some((address, length)), Some(data)) if data.len() as u64 == length * 2 => {
+                    let written = data.chunks(2).enumerate().all(|(offset, pair)| {
+                        parse_hex(pair)
+                            .is_some_and(|byte| write_byte(address + offset as u64, byte as u8))
+                    });
+                    reply.push(if written { b"OK" } else { b"E14" });
+                }
+                _ => reply.push(b"E01"),
+            }
+        }
+        b'c' | b's' => {
+            if let Some(address) = parse_hex(arguments) {
+                registers.rip = address;
+            }
+            return if command == b'c' {
+                Action::Continue
+            } else {
+                Action::Step
+            };
+        }
+        // detach, and kill which can't kill the kernel so it detaches too (without a reply, gdb is gone)
+        b'D' | b'k' => {
+            remove_all_breakpoints();
+            if command == b'D' {
+                reply.push(b"OK");
+            }
+            return Action::Detach;
+        }
+        b'Z' | b'z' if arguments.starts_with(b"0,") => {
+            let ok = match parse_address_length(&arguments[2..]) {
+                Some((address, _)) if command == b'Z' => insert_breakpoint(address),
+                Some((address, _)) => remove_breakpoint(address),
+                None => false,
+            };
+            reply.push(if ok { b"OK" } else { b"E01" });
+        }
+        b'q' if arguments.starts_with(b"Supported") => reply.push(b"PacketSize=400"),
+        b'q' if arguments == b"Attached" => reply.push(b"1"),
+        b'q' if arguments == b"C" => reply.push(b"QC1"),
+        b'H' => reply.push(b"OK"),
+        // an empty reply means the command isn't supported
+        _ => {}
+    }
+    Action::Reply
+}
+
+// None if the page isn't mapped, or the page tables are locked by the code that stopped
+fn read_byte(address: u64) -> Option<u8> {
+    let address = VirtAddr::try_new(address).ok()?;
+    memory::try_translate_addr(address)?;
+    Some(unsafe { address.as_ptr::<u8>().read_volatile() })
+}
+
+// the kernel code is mapped read only, the write protection is disabled for the write
+fn write_byte(address: u64, byte: u8) -> bool {
+    let Ok(address) = VirtAddr::try_new(address) else {
+        return false;
+    };
+    if memory::try_translate_addr(address).is_none() {
+        return false;
+    }
+    let cr0 = Cr0::read();
+    unsafe {
+        Cr0::write(cr0 - Cr0Flags::WRITE_PROTECT);
+        address.as_mut_ptr::<u8>().write_volatile(byte);
+        Cr0::write(cr0);
+    }
+    true
+}
+
+fn insert_breakpoint(address: u64) -> bool {
+    let mut breakpoints = BREAKPOINTS.lock();
+    if breakpoints.iter().flatten().any(|&(at, _)| at == address) {
+        return true;
+    }
+    let Some(slot) = breakpoints.iter_mut().find(|slot| slot.is_none()) else {
+        return false;
+    };
+    let Some(original) = read_byte(address) else {
+        return false;
+    };
+    if !write_byte(address, INT3) {
+        return false;
+    }
+    *slot = Some((address, original));
+    true
+}
+
+fn remove_breakpoint(address: u64) -> bool {
+    let mut breakpoints = BREAKPOINTS.lock();
+    let Some(slot) = breakpoints
+        .iter_mut()
+        .find(|slot| slot.is_some_and(|(at, _)| at == address))
+    else {
+        return false;
+    };
+    let (address, original) = slot.take().unwrap();
+    write_byte(address, original)
+}
+
+fn remove_all_breakpoints() {
+    for (address, original) in BREAKPOINTS.lock().iter_mut().filter_map(Option::take) {
+        write_byte(address, original);
+    }
+}
+
+#[test_case]
+fn test_register_and_memory_packets() {
+    fn run(packet: &[u8], registers: &mut Registers) -> Reply {
+        let mut reply = Reply::new();
+        assert!(matches!(
+            handle(packet, registers, SIGTRAP, &mut reply),
+            Action::Reply
+        ));
+        reply
+    }
+
+    let mut registers = Registers {
+        rax: 0x1122_3344_5566_7788,
+        rip: 0x20_1000,
+        rflags: 0x202,
+        ..Registers::default()
+    };
+    assert_eq!(run(b"?", &mut registers).as_bytes(), b"S05");
+    let all = run(b"g", &mut registers);
+    assert_eq!(all.len, (17 * 8 + 7 * 4) * 2);
+    assert!(all.as_bytes().starts_with(b"8877665544332211"));
+    assert_eq!(run(b"p10", &mut registers).as_bytes(), b"0010200000000000");
+    assert_eq!(
+        run(b"P3=0100000000000000", &mut registers).as_bytes(),
+        b"OK"
+    );
+    assert_eq!(registers.rdx, 1);
+
+    let value: u32 = 0xdead_beef;
+    let address = &value as *const u32 as u64;
+    let packet = alloc::format!("m{:x},4", address);
+    assert_eq!(
+        run(packet.as_bytes(), &mut registers).as_bytes(),
+        b"efbeadde"
+    );
+    assert_eq!(parse_hex(b"9a"), Some(0x9a));
+    assert_eq!(parse_address_length(b"1000,4:ab"), Some((0x1000, 4)));
+}
@@ -0,0 +1,127 @@
+/*
+* When the CPU fails to invoke an exception handler it raises a DOUBLE_FAULT. But if the double fault is caused
+* by a kernel stack overflow the CPU can't invoke the double fault handler either since it would push
+* the interrupt stack frame to the same (overflowed) guard page, that causes a TRIPLE_FAULT which resets the machine.
+*
+* To fix this the CPU can switch to a known good stack before invoking a handler using the
+* INTERRUPT_STACK_TABLE (IST), a table of 7 pointers to stacks stored in the TASK_STATE_SEGMENT (TSS).
+* The TSS is loaded through the GLOBAL_DESCRIPTOR_TABLE (GDT), a leftover of memory segmentation
+* that is still used in 64 bit mode for switching between kernel/user space and loading the TSS.
+*
+* User mode (ring 3) needs code and data segments with privilege level 3. syscall and sysret don't read the
+* GDT, they compute the selectors from the STAR register which requires this order:
+*
+*     0x08 kernel code, 0x10 kernel data, 0x18 user data, 0x20 user code, 0x28 TSS (two entries)
+*
+* An interrupt or exception in user mode switches to the stack in privilege_stack_table[0] of the TSS (RSP0),
+* set to the kernel stack of the running program by set_kernel_stack.
+* */
+use core::cell::UnsafeCell;
+use lazy_static::lazy_static;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+// the index of the stack used by the double fault handler in the interrupt stack table
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5;
+
+// the selectors with the requested privilege level in the low bits, init checks the GDT matches them
+pub const KERNEL_CODE_SELECTOR: u16 = 0x08;
+pub const KERNEL_DATA_SELECTOR: u16 = 0x10;
+pub const USER_DATA_SELECTOR: u16 = 0x18 | 3;
+pub const USER_CODE_SELECTOR: u16 = 0x20 | 3;
+
+// RSP0 changes while the TSS is loaded, the CPU only reads it when it enters the kernel from user mode
+struct Tss(UnsafeCell<TaskStateSegment>);
+
+unsafe impl Sync for Tss {}
+
+lazy_static! {
+    static ref TSS: Tss = {
+        let mut tss = TaskStateSegment::new();
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+            // we don't have memory management yet so the stack is a static array
+            // it is mut so the bootloader maps it to a writable page
+            // there is no guard page so we must not do anything stack intensive in the double fault handler
+            static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(&raw const STACK);
+            // stacks on x86 grow downwards so the top of the stack is its highest address
+            stack_start + DOUBLE_FAULT_STACK_SIZE
+        };
+        Tss(UnsafeCell::new(tss))
+    };
+}
+
+// the selectors are needed to reload the code segment register and load the TSS after loading the new GDT
+struct Selectors {
+    code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    tss_selector: SegmentSelector,
+}
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        let mut gdt = GlobalDescriptorTable::new();
+        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+        let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+        let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*TSS.0.get() }));
+        (
+            gdt,
+            Selectors {
+                code_selector,
+                data_selector,
+                user_data_selector,
+                user_code_selector,
+                tss_selector,
+            },
+        )
+    };
+}
+
+pub fn init() {
+    use x86_64::instructions::segmentation::{Segment, CS};
+    use x86_64::instructions::tables::load_tss;
+
+    let selectors = &GDT.1;
+    assert_eq!(selectors.code_selector.0, KERNEL_CODE_SELECTOR);
+    assert_eq!(selectors.data_selector.0, KERNEL_DATA_SELECTOR);
+    assert_eq!(selectors.user_data_selector.0, USER_DATA_SELECTOR);
+    assert_eq!(selectors.user_code_selector.0, USER_CODE_SELECTOR);
+    GDT.0.load();
+    unsafe {
+        // the old code segment selector may point to a different GDT entry so reload it
+        CS::set_reg(GDT.1.code_selector);
+        // tell the CPU which TSS to use
+        load_tss(GDT.1.tss_selector);
+    }
+
+    // panics in the double fault handler can be backtraced too
+    let stack_top =
+        unsafe { (*TSS.0.get()).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize].as_u64() };
+    crate::backtrace::register_stack(stack_top - DOUBLE_FAULT_STACK_SIZE as u64..stack_top);
+}
+
+/*
+* The other CPUs (smp) load the same GDT but not the TSS: a TSS can only be loaded by one CPU (loading marks
+* it busy) so they have no double fault stack yet and must not fault.
+* */
+pub fn init_ap() {
+    use x86_64::instructions::segmentation::{Segment, CS};
+
+    GDT.0.load();
+    unsafe { CS::set_reg(GDT.1.code_selector) };
+}
+
+/*
+* The stack the CPU switches to when an interrupt arrives in user mode. Only the TSS of the bootstrap
+* processor is loaded, so user programs run there.
+* */
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (*TSS.0.get()).privilege_stack_table[0] = top };
+}
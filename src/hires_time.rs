@@ -0,0 +1,166 @@
+/*
+* The timer ticks only count milliseconds, too coarse to measure how long a driver call or an allocation
+* takes. The TIME_STAMP_COUNTER (TSC) of the CPU counts much faster (at about the clock rate of the CPU) and
+* is read with a single instruction (rdtsc), so Instant measures time with it instead.
+*
+* The number of counts per second is found at boot:
+*  * CPUID leaf 0x15 gives the ratio of the TSC to the crystal clock and the crystal frequency
+*  * CPUID leaf 0x16 gives the base frequency of the CPU in MHz, which the TSC runs at
+*  * otherwise (e.g. on AMD CPUs and most virtual machines) the TSC is counted for CALIBRATION_TICKS timer ticks
+* Older CPUs change the rate of the TSC with the CPU frequency, only an invariant TSC (cpu feature
+* constant_tsc) gives the right durations all the time. The TSCs of the CPUs are assumed to be synchronized
+* so the Instants taken on different CPUs can be compared.
+* */
+use crate::cpu::{self, Feature};
+use crate::timer::{self, TICKS_PER_SECOND};
+use core::arch::x86_64::{__cpuid, _mm_lfence, _rdtsc};
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+// 50 ms, the error of the calibration is about one tick
+const CALIBRATION_TICKS: u64 = 50;
+
+// the TSC counts per second, 0 until init ran
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+fn read_tsc() -> u64 {
+    // the fence waits for the instructions before it, so the measured code can't overlap with the read
+    unsafe {
+        _mm_lfence();
+        _rdtsc()
+    }
+}
+
+// the frequency CPUID reports, None if the CPU doesn't report it
+fn cpuid_frequency() -> Option<(u64, &'static str)> {
+    let max_leaf = __cpuid(0).eax;
+    if max_leaf >= 0x15 {
+        // TSC frequency = crystal frequency * EBX / EAX, a field is 0 if it isn't reported
+        let leaf = __cpuid(0x15);
+        if leaf.eax != 0 && leaf.ebx != 0 && leaf.ecx != 0 {
+            return Some((
+                leaf.ecx as u64 * leaf.ebx as u64 / leaf.eax as u64,
+                "cpuid leaf 0x15",
+            ));
+        }
+    }
+    if max_leaf >= 0x16 {
+        let base_mhz = __cpuid(0x16).eax & 0xFFFF;
+        if base_mhz != 0 {
+            return Some((base_mhz as u64 * 1_000_000, "cpuid leaf 0x16"));
+        }
+    }
+    None
+}
+
+// count the TSC for CALIBRATION_TICKS timer ticks, interrupts must be enabled
+fn calibrate() -> u64 {
+    // start at the beginning of a tick so we measure whole ticks, like apic::calibrate_timer
+    let start = timer::ticks();
+    while timer::ticks() == start {
+        x86_64::instructions::hlt();
+    }
+    let start_tsc = read_tsc();
+    let end = timer::ticks() + CALIBRATION_TICKS;
+    while timer::ticks() < end {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = read_tsc() - start_tsc;
+    elapsed * TICKS_PER_SECOND as u64 / CALIBRATION_TICKS
+}
+
+// find the TSC frequency, called after the timer is initialized and interrupts are enabled
+pub fn init() {
+    let (frequency, source) = cpuid_frequency().unwrap_or_else(|| (calibrate(), "calibrated"));
+    FREQUENCY.store(frequency.max(1), Ordering::Relaxed);
+    log::info!(
+        "hires_time: TSC {}.{:03} MHz ({})",
+        frequency / 1_000_000,
+        frequency / 1000 % 1000,
+        source
+    );
+    if !cpu::has(Feature::InvariantTsc) {
+        log::warn!("hires_time: the TSC isn't invariant, durations may be wrong when the CPU frequency changes");
+    }
+}
+
+// the TSC counts per second, 0 before init
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+fn counts_to_duration(counts: u64, frequency: u64) -> Duration {
+    if frequency == 0 {
+        return Duration::ZERO;
+    }
+    // u128 since counts * 10^9 overflows a u64 after a few seconds
+    let nanos = counts as u128 * 1_000_000_000 / frequency as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+fn duration_to_counts(duration: Duration, frequency: u64) -> u64 {
+    (duration.as_nanos() * frequency as u128 / 1_000_000_000) as u64
+}
+
+// a point in time with the resolution of the TSC, only meaningful compared with another Instant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(read_tsc())
+    }
+
+    // the time since the earlier instant, zero if it is actually later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        counts_to_duration(self.0.saturating_sub(earlier.0), frequency())
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    // the TSC value
+    pub fn counts(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0 + duration_to_counts(duration, frequency()))
+    }
+}
+
+#[test_case]
+fn test_instant_measures_sleeps() {
+    assert_eq!(
+        counts_to_duration(3_000_000_000, 2_000_000_000),
+        Duration::from_millis(1500)
+    );
+    assert_eq!(
+        duration_to_counts(Duration::from_micros(1), 2_000_000_000),
+        2000
+    );
+    assert_eq!(counts_to_duration(1, 0), Duration::ZERO);
+
+    let start = Instant::now();
+    timer::sleep_ms(10);
+    let elapsed = start.elapsed();
+    // the timer ticks and the TSC don't agree exactly, but they are close
+    assert!(elapsed >= Duration::from_millis(8), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    assert!(start + Duration::from_millis(5) > start);
+    assert_eq!(start - Instant::now(), Duration::ZERO);
+}
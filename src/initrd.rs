@@ -0,0 +1,281 @@
+/*
+* The initial ramdisk is a tar archive that is loaded with the kernel and mounted read only at /, so there
+* are programs and configuration files before any disk driver is trusted. bootloader 0.9 can't load
+* anything but the kernel, so QEMU passes the archive as a fw_cfg file like the command line:
+*
+*     tar --format=ustar -cf initrd.tar -C rootfs .
+*     cargo run -- -fw_cfg name=opt/rust_os/initrd,file=initrd.tar
+*
+* fw_cfg is read a byte at a time through an I/O port, which is fine for a few MiB. Booted by GRUB the
+* archive is a multiboot2 module instead (module2 /boot/initrd.tar initrd), it is mounted where GRUB loaded
+* it. Other boot protocols that load modules can hand the archive to init_with.
+*
+* A tar archive is a list of 512 byte headers, each followed by the data of the file rounded up to 512
+* bytes, and ends with two blocks of zeros. The USTAR header has (offsets in bytes):
+*     0 name[100], 124 size[12] (octal ASCII), 156 type ('0' or NUL file, '5' directory),
+*     257 magic "ustar", 345 prefix[155] (the directory of long names)
+* Other types (links, devices) are skipped. The archive stays in memory and the files point into it, the
+* directories are built when it is mounted, so the parents of a file exist even if the archive doesn't
+* list them.
+* */
+use crate::vfs::{self, DirEntry, FileSystem, Inode, NodeKind, VfsError};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub const FW_CFG_FILE: &str = "opt/rust_os/initrd";
+
+const BLOCK_SIZE: usize = 512;
+const TYPE_FILE: u8 = b'0';
+const TYPE_DIRECTORY: u8 = b'5';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    // a header or file extends past the end of the archive
+    Truncated,
+    // a header without the ustar magic or with a size that isn't octal
+    BadHeader,
+}
+
+enum Node {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, Arc<Node>>),
+}
+
+// a field of the header, terminated by NUL or the end of the field
+fn field(header: &[u8], offset: usize, len: usize) -> &[u8] {
+    let field = &header[offset..offset + len];
+    let end = field.iter().position(|&b| b == 0).unwrap_or(len);
+    &field[..end]
+}
+
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let digits = core::str::from_utf8(field).ok()?.trim_matches([' ', '\0']);
+    if digits.is_empty() {
+        return Some(0);
+    }
+    usize::from_str_radix(digits, 8).ok()
+}
+
+// add the node at the path, the missing directories on the way are created
+fn insert(directory: &mut BTreeMap<String, Arc<Node>>, path: &[&str], node: Node) {
+    let Some((&name, rest)) = path.split_first() else {
+        return;
+    };
+    if rest.is_empty() {
+        // a directory that was created for a file below it keeps its children
+        if let Node::File(_) = node {
+            directory.insert(String::from(name), Arc::new(node));
+        } else {
+            directory
+                .entry(String::from(name))
+                .or_insert_with(|| Arc::new(Node::Directory(BTreeMap::new())));
+        }
+        return;
+    }
+    let child = directory
+        .entry(String::from(name))
+        .or_insert_with(|| Arc::new(Node::Directory(BTreeMap::new())));
+    // the tree is only changed while it is built, so the directories have no other owner yet
+    if let Some(Node::Directory(children)) = Arc::get_mut(child) {
+        insert(children, rest, node);
+    }
+}
+
+pub struct TarFs {
+    root: Arc<Node>,
+}
+
+impl TarFs {
+    pub fn parse(archive: &'static [u8]) -> Result<TarFs, TarError> {
+        let mut root = BTreeMap::new();
+        let mut offset = 0;
+        while offset + BLOCK_SIZE <= archive.len() {
+            let header = &archive[offset..offset + BLOCK_SIZE];
+            // the archive ends with zero blocks
+            if header.iter().all(|&b| b == 0) {
+                break;
+            }
+            if &header[257..262] != b"ustar" {
+                return Err(TarError::BadHeader);
+            }
+            let size = parse_octal(field(header, 124, 12)).ok_or(TarError::BadHeader)?;
+            let start = offset + BLOCK_SIZE;
+            let data = archive
+                .get(start..start + size)
+                .ok_or(TarError::Truncated)?;
+            offset = start + size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+
+            let mut path = String::from_utf8_lossy(field(header, 345, 155)).into_owned();
+            if !path.is_empty() {
+                path.push('/');
+            }
+            path.push_str(&String::from_utf8_lossy(field(header, 0, 100)));
+            // tar -C dir . stores the names as ./name
+            let components: Vec<&str> = path
+                .split('/')
+                .filter(|component| !component.is_empty() && *component != ".")
+                .collect();
+            match header[156] {
+                TYPE_FILE | 0 => insert(&mut root, &components, Node::File(data)),
+                TYPE_DIRECTORY => insert(&mut root, &components, Node::Directory(BTreeMap::new())),
+                kind => log::debug!("initrd: skipping {} of type {}", path, kind as char),
+            }
+        }
+        Ok(TarFs {
+            root: Arc::new(Node::Directory(root)),
+        })
+    }
+}
+
+// the vfs inode of a node, it keeps the node alive while the file is open
+struct TarNode(Arc<Node>);
+
+impl TarNode {
+    fn entry_of(name: &str, node: &Node) -> DirEntry {
+        match node {
+            Node::File(data) => DirEntry {
+                name: String::from(name),
+                kind: NodeKind::File,
+                size: data.len() as u64,
+            },
+            Node::Directory(_) => DirEntry {
+                name: String::from(name),
+                kind: NodeKind::Directory,
+                size: 0,
+            },
+        }
+    }
+}
+
+impl Inode for TarNode {
+    fn kind(&self) -> NodeKind {
+        match *self.0 {
+            Node::File(_) => NodeKind::File,
+            Node::Directory(_) => NodeKind::Directory,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        match *self.0 {
+            Node::File(data) => data.len() as u64,
+            Node::Directory(_) => 0,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let Node::File(data) = *self.0 else {
+            return Err(VfsError::IsADirectory);
+        };
+        let start = (offset as usize).min(data.len());
+        let len = buffer.len().min(data.len() - start);
+        buffer[..len].copy_from_slice(&data[start..start + len]);
+        Ok(len)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        let Node::Directory(children) = &*self.0 else {
+            return Err(VfsError::NotADirectory);
+        };
+        Ok(children
+            .iter()
+            .map(|(name, node)| TarNode::entry_of(name, node))
+            .collect())
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        let Node::Directory(children) = &*self.0 else {
+            return Err(VfsError::NotADirectory);
+        };
+        let node = children.get(name).ok_or(VfsError::NotFound)?;
+        Ok(Arc::new(TarNode(node.clone())))
+    }
+}
+
+impl FileSystem for TarFs {
+    fn name(&self) -> &str {
+        "initrd"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(TarNode(self.root.clone()))
+    }
+}
+
+// mount the archive at /, it is kept in memory for the rest of the kernel's life
+pub fn init_with(archive: Vec<u8>) {
+    mount(Box::leak(archive.into_boxed_slice()));
+}
+
+fn mount(archive: &'static [u8]) {
+    match TarFs::parse(archive) {
+        Ok(filesystem) => {
+            log::info!("initrd: {} KiB", archive.len() / 1024);
+            if let Err(error) = vfs::mount("/", Arc::new(filesystem)) {
+                log::warn!("initrd: can't mount at /: {}", error);
+            }
+        }
+        Err(error) => log::warn!("initrd: not a tar archive: {:?}", error),
+    }
+}
+
+// load the archive from the multiboot2 module or fw_cfg, without one / only has the mount points of the
+// other filesystems
+pub fn init() {
+    if let Some(archive) = crate::multiboot2::initrd() {
+        mount(archive);
+    } else if let Some(archive) = crate::fw_cfg::read_file_to_vec(FW_CFG_FILE) {
+        init_with(archive);
+    }
+}
+
+#[test_case]
+fn test_parse_tar_archive() {
+    fn header(archive: &mut Vec<u8>, name: &str, kind: u8, data: &[u8]) {
+        let mut header = [0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = alloc::format!("{:011o}", data.len());
+        header[124..135].copy_from_slice(size.as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(data);
+        archive.resize(archive.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+    }
+    let mut archive = Vec::new();
+    header(&mut archive, "./etc/", TYPE_DIRECTORY, b"");
+    header(
+        &mut archive,
+        "./etc/motd",
+        TYPE_FILE,
+        b"hello from the initrd\n",
+    );
+    // the parent directory isn't in the archive
+    header(&mut archive, "bin/init", TYPE_FILE, &[0x7f; 600]);
+    header(&mut archive, "etc/link", b'2', b"");
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+
+    let filesystem = TarFs::parse(Box::leak(archive.into_boxed_slice())).unwrap();
+    let root = filesystem.root();
+    let names: Vec<String> = root
+        .read_dir()
+        .unwrap()
+        .into_iter()
+        .map(|e| e.name)
+        .collect();
+    assert_eq!(names, ["bin", "etc"]);
+    let etc = root.lookup("etc").unwrap();
+    assert_eq!(etc.read_dir().unwrap().len(), 1);
+    let motd = etc.lookup("motd").unwrap();
+    let mut buffer = [0; 64];
+    assert_eq!(motd.read_at(11, &mut buffer), Ok(11));
+    assert_eq!(&buffer[..11], b"the initrd\n");
+    assert_eq!(
+        root.lookup("bin").unwrap().lookup("init").unwrap().size(),
+        600
+    );
+    assert_eq!(etc.write_at(0, b"x"), Err(VfsError::ReadOnly));
+    assert!(TarFs::parse(&[1; BLOCK_SIZE]).is_err());
+}
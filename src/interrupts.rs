@@ -0,0 +1,462 @@
+/*
+* When a CPU exception occurs (dividing by zero, executing an invalid opcode, accessing an unmapped page...)
+* the CPU interrupts the current work and calls the handler function registered for that exception
+* in the INTERRUPT_DESCRIPTOR_TABLE (IDT). If there is no handler the CPU raises a double fault and
+* if that can't be handled either it raises a triple fault which resets (reboots) the machine.
+*
+* The IDT has 256 entries, the first 32 are reserved for CPU exceptions. Instead of building the entries
+* ourselves we use the InterruptDescriptorTable type of the x86_64 crate.
+* */
+use crate::gdb;
+use crate::gdt;
+use crate::panic_screen::{self, ExceptionState};
+use crate::{per_cpu, percpu};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use pic8259::ChainedPics;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+/*
+* Hardware devices (timer, keyboard, disks...) don't talk to the CPU directly, they are connected
+* to an interrupt controller that forwards their interrupts. The classic one is the INTEL_8259 PIC
+* which has 8 interrupt lines (IRQs), two of them are chained (the secondary PIC is connected to
+* IRQ 2 of the primary) which gives us 15 usable lines.
+*
+* By default the PICs send the interrupt vectors 0-15 which are already used by the CPU exceptions
+* (e.g. 8 is the double fault) so we remap them to the first free vectors after the 32 exception slots
+* */
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+// the number of IRQ lines of the two chained PICs
+pub const IRQ_LINES: usize = 16;
+
+// the PICs are accessed through port mapped I/O, ChainedPics wraps the command and data ports
+// of both of them. It is unsafe since wrong offsets would cause undefined behavior
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+// the indexes of the hardware interrupts in the IDT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET, // IRQ 0
+    Keyboard,             // IRQ 1
+}
+
+impl InterruptIndex {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    // the IRQ line the interrupt arrives on
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+}
+
+/*
+* Drivers register a handler for the IRQ line of their device instead of editing the IDT directly.
+* Every IRQ line has an entry in the IDT pointing to a small stub that looks up the registered
+* handler, calls it and then notifies the PIC that the interrupt was handled.
+* */
+pub type IrqHandler = fn();
+
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; IRQ_LINES]> = Mutex::new([None; IRQ_LINES]);
+
+// register the handler called when the given IRQ line fires, returns the handler it replaced
+pub fn register_irq_handler(irq: u8, handler: IrqHandler) -> Option<IrqHandler> {
+    set_irq_handler(irq, Some(handler))
+}
+
+/*
+* For a line that may be shared (PCI devices): the handler is only registered if the line has none (or the
+* same handler, e.g. for the second disk of a driver), otherwise the other device's handler is kept and false
+* is returned, the driver then has to do without the interrupt.
+* */
+pub fn try_register_irq_handler(irq: u8, handler: IrqHandler) -> bool {
+    assert!((irq as usize) < IRQ_LINES, "invalid IRQ line {}", irq);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let slot = &mut IRQ_HANDLERS.lock()[irq as usize];
+        if slot.is_some_and(|other| !core::ptr::fn_addr_eq(other, handler)) {
+            return false;
+        }
+        *slot = Some(handler);
+        true
+    })
+}
+
+pub fn unregister_irq_handler(irq: u8) -> Option<IrqHandler> {
+    set_irq_handler(irq, None)
+}
+
+fn set_irq_handler(irq: u8, handler: Option<IrqHandler>) -> Option<IrqHandler> {
+    assert!((irq as usize) < IRQ_LINES, "invalid IRQ line {}", irq);
+    // the interrupt handler locks IRQ_HANDLERS too, if it fired while we hold the lock
+    // it would spin forever so interrupts are disabled while the table is updated
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        core::mem::replace(&mut IRQ_HANDLERS.lock()[irq as usize], handler)
+    })
+}
+
+// the IN_SERVICE_REGISTER (ISR) of a PIC has a bit set for every IRQ it delivered that wasn't ended yet
+fn pic_in_service(irq: u8) -> bool {
+    // the command port of the PIC the line belongs to, the secondary one has IRQ 8-15
+    let (port, line) = if irq < 8 {
+        (0x20, irq)
+    } else {
+        (0xA0, irq - 8)
+    };
+    let mut command: Port<u8> = Port::new(port);
+    unsafe {
+        // OCW3: the next read of the command port returns the ISR
+        command.write(0x0B);
+        command.read() & (1 << line) != 0
+    }
+}
+
+/*
+* Every handler counts its vector on the CPU it runs on. A vector that counts up very fast is an interrupt
+* storm, an IRQ line that stopped counting while its device is busy may be missing an end of interrupt.
+* The entry of the debugger (breakpoint and debug exceptions with gdb enabled) isn't counted.
+* */
+pub const VECTORS: usize = 256;
+
+per_cpu! {
+    static VECTOR_COUNTS: [AtomicU64; VECTORS] = [const { AtomicU64::new(0) }; VECTORS];
+}
+
+fn count_vector(vector: u8) {
+    // an exception can happen before the per-CPU data is set up
+    if percpu::is_initialized() {
+        VECTOR_COUNTS.get()[vector as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// how often every vector was raised on the CPU
+pub fn vector_counts(cpu: usize) -> [u64; VECTORS] {
+    let counts = VECTOR_COUNTS.get_for(cpu);
+    core::array::from_fn(|vector| counts[vector].load(Ordering::Relaxed))
+}
+
+// the name of the exception or interrupt, None for the vectors without a handler and the IRQ lines without a name
+pub fn vector_name(vector: u8) -> Option<&'static str> {
+    Some(match vector {
+        0 => "divide error",
+        3 => "breakpoint",
+        6 => "invalid opcode",
+        8 => "double fault",
+        13 => "general protection fault",
+        14 => "page fault",
+        16 => "x87 floating point",
+        19 => "simd floating point",
+        vector if vector == InterruptIndex::Timer.as_u8() => "timer",
+        vector if vector == InterruptIndex::Keyboard.as_u8() => "keyboard",
+        crate::apic::SPURIOUS_VECTOR => "spurious",
+        _ => return None,
+    })
+}
+
+fn dispatch_irq(irq: u8, stack_frame: &InterruptStackFrame) {
+    count_vector(PIC_1_OFFSET + irq);
+    {
+        let _interrupt = crate::percpu::InterruptGuard::enter();
+        // the profiler samples the code the timer interrupted
+        if irq == InterruptIndex::Timer.irq() {
+            crate::profiler::sample(stack_frame.instruction_pointer.as_u64());
+        }
+        // copy the handler out so the lock isn't held while it runs
+        let handler = IRQ_HANDLERS.lock()[irq as usize];
+        if let Some(handler) = handler {
+            handler();
+        }
+
+        // the PIC (or APIC) waits for an END_OF_INTERRUPT (EOI) signal before sending the next interrupt
+        // it is sent even when there is no handler otherwise the line would be blocked forever,
+        // but not for a vector raised with int since the EOI would end another interrupt of the controller
+        if crate::apic::is_enabled() {
+            if crate::apic::in_service(PIC_1_OFFSET + irq) {
+                crate::apic::end_of_interrupt();
+            }
+        } else if pic_in_service(irq) {
+            unsafe {
+                PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq);
+            }
+        }
+    }
+    // the interrupt is over (EOI sent, no longer counted), another thread can run now if the time slice ended
+    crate::scheduler::preempt();
+}
+
+// the IDT needs a separate x86-interrupt function per vector, this generates one stub per IRQ line
+macro_rules! irq_stubs {
+    ($($name:ident => $irq:expr),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+                let _gs = percpu::UserGs::enter(&stack_frame);
+                dispatch_irq($irq, &stack_frame);
+            }
+        )*
+
+        const IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_LINES] = [$($name),*];
+    };
+}
+
+irq_stubs! {
+    irq0 => 0, irq1 => 1, irq2 => 2, irq3 => 3, irq4 => 4, irq5 => 5, irq6 => 6, irq7 => 7,
+    irq8 => 8, irq9 => 9, irq10 => 10, irq11 => 11, irq12 => 12, irq13 => 13, irq14 => 14, irq15 => 15,
+}
+
+/*
+* The CPU keeps a pointer to the IDT (loaded with the lidt instruction) and uses it whenever an exception
+* occurs, so the table has to live for the whole runtime of the kernel ('static). It can't be a normal
+* static because we need to modify it to add our handlers so we use lazy_static like the VGA WRITER
+* */
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        if gdb::is_enabled() {
+            // the debugger needs all the registers, its entry saves them (unsafe since it must be a
+            // valid handler, which gdb_trap_entry is)
+            unsafe {
+                idt.breakpoint.set_handler_addr(gdb::trap_entry());
+                idt.debug.set_handler_addr(gdb::trap_entry());
+            }
+        } else {
+            idt.breakpoint.set_handler_fn(breakpoint_handler);
+        }
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            // switch to the dedicated double fault stack from the TSS before invoking the handler
+            // unsafe because the index must be valid and not used by another exception
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        for (irq, stub) in IRQ_STUBS.iter().enumerate() {
+            idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(*stub);
+        }
+        idt[usize::from(crate::apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        idt
+    };
+}
+
+// load the IDT into the CPU (lidt instruction)
+pub fn init_idt() {
+    IDT.load();
+}
+
+// remap the PICs to PIC_1_OFFSET/PIC_2_OFFSET and unmask all of their lines
+// interrupts still have to be enabled on the CPU (sti instruction) before they arrive
+pub fn init_pics() {
+    unsafe { PICS.lock().initialize() };
+}
+
+/*
+* Exception handlers can't be normal functions, the CPU pushes an INTERRUPT_STACK_FRAME
+* (instruction pointer, stack pointer, cpu flags...) to the stack and expects the handler to
+* preserve all the registers and return with the iretq instruction instead of ret.
+* The x86-interrupt calling convention (unstable feature abi_x86_interrupt) makes the compiler take care of that
+* */
+
+// the breakpoint exception is raised by the int3 instruction, debuggers use it to pause a program.
+// It is harmless so we log the stack frame and continue the execution
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::UserGs::enter(&stack_frame);
+    count_vector(3);
+    log::warn!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+}
+
+// the local APIC raises the spurious vector for an interrupt that went away before the CPU took it,
+// nothing is in service so no EOI is sent
+extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::UserGs::enter(&stack_frame);
+    count_vector(crate::apic::SPURIOUS_VECTOR);
+}
+
+/*
+* Returning from the following handlers would execute the faulting instruction again and fault forever
+* so they save the CPU state for the panic screen and panic, or stop the user program that caused them.
+* */
+fn exception_panic(
+    name: &'static str,
+    signal: u8,
+    error_code: Option<u64>,
+    stack_frame: &InterruptStackFrame,
+) -> ! {
+    // an exception in a user program stops the program, not the kernel
+    if stack_frame.code_segment & 3 == 3 {
+        crate::usermode::kill(name);
+    }
+    // an attached debugger sees the exception first
+    gdb::exception(signal, stack_frame);
+    panic_screen::record_exception(ExceptionState {
+        name,
+        error_code,
+        stack_frame: **stack_frame,
+    });
+    panic!("{}", name);
+}
+
+/*
+* A kernel stack overflow hits the guard page below the stack, pushing the exception frame onto it fails
+* too and the CPU raises a double fault instead (on its own stack). The address is in CR2 in both cases.
+* The boot stack isn't in the stack region, an access right below the stack pointer is taken as an
+* overflow of it.
+* */
+fn is_stack_overflow(stack_frame: &InterruptStackFrame) -> bool {
+    let address = Cr2::read();
+    let stack_pointer = stack_frame.stack_pointer.as_u64();
+    crate::stack::is_guard_page(address)
+        || (address.as_u64() < stack_pointer && stack_pointer - address.as_u64() <= 4096)
+}
+
+// like exception_panic, but names the thread whose stack overflowed
+fn stack_overflow_panic(error_code: Option<u64>, stack_frame: &InterruptStackFrame) -> ! {
+    gdb::exception(gdb::SIGSEGV, stack_frame);
+    panic_screen::record_exception(ExceptionState {
+        name: "EXCEPTION: STACK OVERFLOW",
+        error_code,
+        stack_frame: **stack_frame,
+    });
+    panic!("stack overflow in task {}", crate::scheduler::CurrentThread);
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::UserGs::enter(&stack_frame);
+    count_vector(0);
+    exception_panic("EXCEPTION: DIVIDE ERROR", gdb::SIGFPE, None, &stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::UserGs::enter(&stack_frame);
+    count_vector(6);
+    exception_panic("EXCEPTION: INVALID OPCODE", gdb::SIGILL, None, &stack_frame);
+}
+
+// an unmasked FPU or SSE error (division by zero, invalid operation...) of the instruction before
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::UserGs::enter(&stack_frame);
+    count_vector(16);
+    exception_panic(
+        "EXCEPTION: X87 FLOATING POINT",
+        gdb::SIGFPE,
+        None,
+        &stack_frame,
+    );
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    let _gs = percpu::UserGs::enter(&stack_frame);
+    count_vector(19);
+    exception_panic(
+        "EXCEPTION: SIMD FLOATING POINT",
+        gdb::SIGFPE,
+        None,
+        &stack_frame,
+    );
+}
+
+// a double fault is raised when the CPU fails to invoke an exception handler (e.g. a page fault
+// without a registered handler or a kernel stack overflow). The error code is always 0 and
+// returning from a double fault is not allowed so the handler is diverging
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) -> ! {
+    let _gs = percpu::UserGs::enter(&stack_frame);
+    count_vector(8);
+    if stack_frame.code_segment & 3 == 0 && is_stack_overflow(&stack_frame) {
+        stack_overflow_panic(Some(error_code), &stack_frame);
+    }
+    exception_panic(
+        "EXCEPTION: DOUBLE FAULT",
+        gdb::SIGSEGV,
+        Some(error_code),
+        &stack_frame,
+    );
+}
+
+// the error code of a general protection fault is the index of the segment selector that caused it
+// (or 0 if the fault is not segment related)
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    let _gs = percpu::UserGs::enter(&stack_frame);
+    count_vector(13);
+    exception_panic(
+        "EXCEPTION: GENERAL PROTECTION FAULT",
+        gdb::SIGSEGV,
+        Some(error_code),
+        &stack_frame,
+    );
+}
+
+// the interrupt flag in RFLAGS
+const INTERRUPT_FLAG: u64 = 1 << 9;
+
+// a page fault occurs when accessing a page that is not mapped or violating its permissions
+// (writing to a read only page for example)
+extern "x86-interrupt" fn page_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let _gs = percpu::UserGs::enter(&stack_frame);
+    count_vector(14);
+    // the CPU stores the virtual address that caused the page fault in the CR2 register (shown on
+    // the panic screen), the error code tells us the type of access (read/write, user/kernel, present/not present)
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        // a page of a lazy region is mapped and the instruction runs again (demand paging, memory module),
+        // waiting for the memory locks is only possible if the faulting code could be interrupted
+        let can_wait = stack_frame.cpu_flags & INTERRUPT_FLAG != 0;
+        // read before another thread can run and fault
+        let address = Cr2::read();
+        if can_wait {
+            x86_64::instructions::interrupts::enable();
+        }
+        let user = error_code.contains(PageFaultErrorCode::USER_MODE);
+        let mapped = crate::memory::handle_page_fault(address, can_wait, user);
+        x86_64::instructions::interrupts::disable();
+        if mapped {
+            return;
+        }
+    }
+    if stack_frame.code_segment & 3 == 0 && is_stack_overflow(&stack_frame) {
+        stack_overflow_panic(Some(error_code.bits()), &stack_frame);
+    }
+    exception_panic(
+        "EXCEPTION: PAGE FAULT",
+        gdb::SIGSEGV,
+        Some(error_code.bits()),
+        &stack_frame,
+    );
+}
+
+// if the breakpoint handler works the execution continues after the int3 instruction
+#[test_case]
+fn test_breakpoint_exception() {
+    x86_64::instructions::interrupts::int3();
+}
+
+// a registered handler is called when its IRQ vector is raised, here we raise it manually with int
+// IRQ 5 is usually unused (it was the second parallel port) so no real driver is replaced
+#[test_case]
+fn test_irq_handler_dispatch() {
+    use core::sync::atomic::AtomicBool;
+    static CALLED: AtomicBool = AtomicBool::new(false);
+
+    let count = vector_counts(0)[usize::from(PIC_1_OFFSET) + 5];
+    let previous = register_irq_handler(5, || CALLED.store(true, Ordering::SeqCst));
+    unsafe { core::arch::asm!("int {}", const PIC_1_OFFSET + 5) };
+    set_irq_handler(5, previous);
+    assert!(CALLED.load(Ordering::SeqCst));
+    assert_eq!(vector_counts(0)[usize::from(PIC_1_OFFSET) + 5], count + 1);
+}
@@ -0,0 +1,384 @@
+/*
+* The PS/2 controller sends a SCANCODE for every key press and release through the data port 0x60
+* and raises IRQ 1 to tell us it's available. Keyboards default to SCANCODE_SET_1 (the IBM XT set)
+* which the PS/2 controller translates to even if the keyboard uses another one:
+*  * pressing a key sends its make code (one byte in the range 0x01-0x58)
+*  * releasing it sends the break code which is the make code with the highest bit set (make | 0x80)
+*  * keys added later (arrows, page up/down, right ctrl...) are prefixed with an extra 0xE0 byte
+*
+* The scancodes only identify the physical key, turning them into characters depends on the keyboard
+* layout (keymap module) and the state of the modifier keys (shift, caps lock, AltGr) so the decoder keeps
+* track of them.
+* */
+use crate::interrupts::{self, InterruptIndex};
+use crate::keymap::{self, Keymap};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+// the scancode byte that prefixes the extended keys
+const EXTENDED_PREFIX: u8 = 0xE0;
+// the bit that is set in the break code of a key
+const RELEASE_BIT: u8 = 0x80;
+
+// keys that don't produce a character
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCode {
+    Escape,
+    ArrowUp,
+    ArrowDown,
+    ArrowLeft,
+    ArrowRight,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Insert,
+    Delete,
+    F(u8), // F1 - F12
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodedKey {
+    Unicode(char),
+    RawKey(KeyCode),
+}
+
+// turns a stream of scancodes into keys while tracking the state of the modifier keys
+pub struct Keyboard {
+    left_shift: bool,
+    right_shift: bool,
+    ctrl: bool,
+    alt: bool,
+    // the right alt key
+    alt_gr: bool,
+    caps_lock: bool,
+    // the previous byte was the 0xE0 prefix
+    extended: bool,
+    // the accent of the dead key pressed before, it changes the next character
+    dead_key: Option<char>,
+    // None uses the current keymap (keymap::current)
+    keymap: Option<&'static Keymap>,
+}
+
+impl Keyboard {
+    pub const fn new() -> Keyboard {
+        Keyboard {
+            left_shift: false,
+            right_shift: false,
+            ctrl: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            extended: false,
+            dead_key: None,
+            keymap: None,
+        }
+    }
+
+    // a decoder that always uses the keymap instead of the current one
+    pub const fn with_keymap(keymap: &'static Keymap) -> Keyboard {
+        Keyboard {
+            keymap: Some(keymap),
+            ..Keyboard::new()
+        }
+    }
+
+    pub fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.ctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.alt
+    }
+
+    // feed one scancode byte to the decoder, returns a key once a complete key press was received
+    pub fn process_scancode(&mut self, scancode: u8) -> Option<DecodedKey> {
+        if scancode == EXTENDED_PREFIX {
+            self.extended = true;
+            return None;
+        }
+        let extended = core::mem::replace(&mut self.extended, false);
+        let released = scancode & RELEASE_BIT != 0;
+        let code = scancode & !RELEASE_BIT;
+
+        // the modifier keys change the state on both press and release
+        match (extended, code) {
+            (false, 0x2A) => self.left_shift = !released,
+            (false, 0x36) => self.right_shift = !released,
+            (_, 0x1D) => self.ctrl = !released, // left ctrl or right ctrl (extended)
+            (false, 0x38) => self.alt = !released,
+            (true, 0x38) => self.alt_gr = !released, // right alt
+            (false, 0x3A) if !released => self.caps_lock = !self.caps_lock,
+            _ if released => {}
+            (true, code) => return Self::decode_extended(code),
+            (false, code) => return self.decode(code),
+        }
+        None
+    }
+
+    fn decode(&mut self, code: u8) -> Option<DecodedKey> {
+        match code {
+            0x01 => return Some(DecodedKey::RawKey(KeyCode::Escape)),
+            0x3B..=0x44 => return Some(DecodedKey::RawKey(KeyCode::F(code - 0x3B + 1))),
+            0x57 => return Some(DecodedKey::RawKey(KeyCode::F(11))),
+            0x58 => return Some(DecodedKey::RawKey(KeyCode::F(12))),
+            _ => {}
+        }
+
+        let keymap = self.keymap.unwrap_or_else(keymap::current);
+        // ctrl + left alt works like AltGr for keyboards without a right alt key
+        let alt_gr = self.alt_gr || (self.ctrl && self.alt);
+        // caps lock only affects letters, shift + caps lock gives lower case letters again
+        let upper = if keymap.has_caps(code) {
+            self.shift() != self.caps_lock
+        } else {
+            self.shift()
+        };
+        let character = keymap.char(code, upper, alt_gr)?;
+
+        if let Some(accent) = self.dead_key.take() {
+            if character == ' ' || character == accent {
+                return Some(DecodedKey::Unicode(accent));
+            }
+            return Some(DecodedKey::Unicode(
+                keymap::compose(accent, character).unwrap_or(character),
+            ));
+        }
+        if keymap.is_dead(character) {
+            self.dead_key = Some(character);
+            return None;
+        }
+        Some(DecodedKey::Unicode(character))
+    }
+
+    fn decode_extended(code: u8) -> Option<DecodedKey> {
+        let key = match code {
+            0x1C => return Some(DecodedKey::Unicode('\n')), // keypad enter
+            0x35 => return Some(DecodedKey::Unicode('/')),  // keypad slash
+            0x47 => KeyCode::Home,
+            0x48 => KeyCode::ArrowUp,
+            0x49 => KeyCode::PageUp,
+            0x4B => KeyCode::ArrowLeft,
+            0x4D => KeyCode::ArrowRight,
+            0x4F => KeyCode::End,
+            0x50 => KeyCode::ArrowDown,
+            0x51 => KeyCode::PageDown,
+            0x52 => KeyCode::Insert,
+            0x53 => KeyCode::Delete,
+            _ => return None,
+        };
+        Some(DecodedKey::RawKey(key))
+    }
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/*
+* The decoded keys are stored in a fixed size ring buffer until the kernel reads them
+* (we have no heap to allocate a growable queue). When the queue is full new keys are dropped.
+* */
+const QUEUE_SIZE: usize = 128;
+
+struct KeyQueue {
+    keys: [Option<DecodedKey>; QUEUE_SIZE],
+    head: usize, // index of the oldest key
+    len: usize,
+}
+
+impl KeyQueue {
+    const fn new() -> KeyQueue {
+        KeyQueue {
+            keys: [None; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, key: DecodedKey) -> bool {
+        if self.len == QUEUE_SIZE {
+            return false;
+        }
+        self.keys[(self.head + self.len) % QUEUE_SIZE] = Some(key);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<DecodedKey> {
+        if self.len == 0 {
+            return None;
+        }
+        let key = self.keys[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        key
+    }
+}
+
+static KEYBOARD: Mutex<Keyboard> = Mutex::new(Keyboard::new());
+static KEY_QUEUE: Mutex<KeyQueue> = Mutex::new(KeyQueue::new());
+// the keys dropped since the last read, the interrupt handler only counts them since printing
+// there deadlocks if the interrupted code holds the screen lock, the next read prints the warning
+static DROPPED_KEYS: AtomicUsize = AtomicUsize::new(0);
+
+pub fn init() {
+    if let Some(name) = crate::cmdline::get("keymap") {
+        if !keymap::set(name) {
+            log::warn!("unknown keymap {}", name);
+        }
+    }
+    interrupts::register_irq_handler(InterruptIndex::Keyboard.irq(), keyboard_interrupt_handler);
+}
+
+// called by the interrupt dispatcher on IRQ 1
+// the PS/2 controller won't send another interrupt until the scancode is read from the data port
+fn keyboard_interrupt_handler() {
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe { port.read() };
+
+    // once an async task listens for scancodes they are decoded there (task::keyboard)
+    // otherwise they are decoded here and stored for read_key/wait_key
+    if crate::task::keyboard::add_scancode(scancode) {
+        return;
+    }
+    if let Some(key) = KEYBOARD.lock().process_scancode(scancode) {
+        if !KEY_QUEUE.lock().push(key) {
+            DROPPED_KEYS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+fn report_dropped_keys() {
+    let dropped = DROPPED_KEYS.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        log::warn!("keyboard queue full; dropped {} keys", dropped);
+    }
+}
+
+// returns the oldest key that wasn't read yet
+pub fn read_key() -> Option<DecodedKey> {
+    report_dropped_keys();
+    // the keyboard interrupt handler locks the queue too so interrupts are disabled while it's locked
+    x86_64::instructions::interrupts::without_interrupts(|| KEY_QUEUE.lock().pop())
+}
+
+// halts the CPU until a key is pressed
+pub fn wait_key() -> DecodedKey {
+    use x86_64::instructions::interrupts;
+    loop {
+        report_dropped_keys();
+        // if the key arrived between checking the queue and the hlt instruction we would sleep
+        // until the next interrupt, enable_and_hlt enables the interrupts and halts atomically
+        interrupts::disable();
+        if let Some(key) = KEY_QUEUE.lock().pop() {
+            interrupts::enable();
+            return key;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+#[test_case]
+fn test_decode_letters_and_shift() {
+    let mut keyboard = Keyboard::new();
+    assert_eq!(
+        keyboard.process_scancode(0x1E),
+        Some(DecodedKey::Unicode('a'))
+    );
+    assert_eq!(keyboard.process_scancode(0x1E | RELEASE_BIT), None);
+    // hold left shift
+    assert_eq!(keyboard.process_scancode(0x2A), None);
+    assert_eq!(
+        keyboard.process_scancode(0x1E),
+        Some(DecodedKey::Unicode('A'))
+    );
+    assert_eq!(
+        keyboard.process_scancode(0x02),
+        Some(DecodedKey::Unicode('!'))
+    );
+    assert_eq!(keyboard.process_scancode(0x2A | RELEASE_BIT), None);
+    assert_eq!(
+        keyboard.process_scancode(0x02),
+        Some(DecodedKey::Unicode('1'))
+    );
+}
+
+#[test_case]
+fn test_decode_caps_lock_and_extended_keys() {
+    let mut keyboard = Keyboard::new();
+    keyboard.process_scancode(0x3A);
+    keyboard.process_scancode(0x3A | RELEASE_BIT);
+    assert_eq!(
+        keyboard.process_scancode(0x10),
+        Some(DecodedKey::Unicode('Q'))
+    );
+    // caps lock doesn't affect digits
+    assert_eq!(
+        keyboard.process_scancode(0x03),
+        Some(DecodedKey::Unicode('2'))
+    );
+    // page up is E0 49, without the prefix 0x49 is keypad 9
+    assert_eq!(keyboard.process_scancode(EXTENDED_PREFIX), None);
+    assert_eq!(
+        keyboard.process_scancode(0x49),
+        Some(DecodedKey::RawKey(KeyCode::PageUp))
+    );
+    assert_eq!(
+        keyboard.process_scancode(0x49),
+        Some(DecodedKey::Unicode('9'))
+    );
+}
+
+#[test_case]
+fn test_key_queue_is_fifo_and_bounded() {
+    let mut queue = KeyQueue::new();
+    for _ in 0..QUEUE_SIZE {
+        assert!(queue.push(DecodedKey::Unicode('x')));
+    }
+    assert!(!queue.push(DecodedKey::Unicode('y')));
+    assert_eq!(queue.pop(), Some(DecodedKey::Unicode('x')));
+    assert!(queue.push(DecodedKey::Unicode('z')));
+    for _ in 1..QUEUE_SIZE {
+        assert_eq!(queue.pop(), Some(DecodedKey::Unicode('x')));
+    }
+    assert_eq!(queue.pop(), Some(DecodedKey::Unicode('z')));
+    assert_eq!(queue.pop(), None);
+}
+
+#[test_case]
+fn test_decode_alt_gr_and_dead_keys() {
+    let mut keyboard = Keyboard::with_keymap(&keymap::DE);
+    // the y and z keys are swapped
+    assert_eq!(
+        keyboard.process_scancode(0x15),
+        Some(DecodedKey::Unicode('z'))
+    );
+    // AltGr (right alt) + q
+    keyboard.process_scancode(EXTENDED_PREFIX);
+    keyboard.process_scancode(0x38);
+    assert_eq!(
+        keyboard.process_scancode(0x10),
+        Some(DecodedKey::Unicode('@'))
+    );
+    keyboard.process_scancode(EXTENDED_PREFIX);
+    keyboard.process_scancode(0x38 | RELEASE_BIT);
+    // the dead key ^ then a, then ^ then space
+    assert_eq!(keyboard.process_scancode(0x29), None);
+    assert_eq!(
+        keyboard.process_scancode(0x1E),
+        Some(DecodedKey::Unicode('â'))
+    );
+    assert_eq!(keyboard.process_scancode(0x29), None);
+    assert_eq!(
+        keyboard.process_scancode(0x39),
+        Some(DecodedKey::Unicode('^'))
+    );
+}
@@ -0,0 +1,214 @@
+/*
+* A KEYMAP (keyboard layout) maps the physical keys (their make codes, keyboard module) to characters. The
+* same key types 'y' on a US keyboard and 'z' on a German one, so the decoder asks the current keymap for
+* the character of a key instead of hard coding one layout. Every keymap has a table for the keys without
+* and with shift, and a short list of the keys that type a third character with ALT_GR (the right alt key,
+* or ctrl + left alt).
+*
+* DEAD_KEYS type accents: they don't type anything themselves but change the next key, '^' then 'a' types
+* 'â'. The accent followed by space (or the dead key again) types the accent itself, keys that can't take
+* the accent type their own character and the accent is dropped.
+*
+* The keymap is chosen with keymap=us|uk|de on the kernel command line or the keymap shell command.
+* */
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// the make codes up to the extra key of ISO keyboards (0x56, between left shift and z)
+const KEYS: usize = 0x57;
+// the key doesn't type a character
+const NONE: char = '\0';
+
+pub struct Keymap {
+    pub name: &'static str,
+    lower: [char; KEYS],
+    upper: [char; KEYS],
+    alt_gr: &'static [(u8, char)],
+    // the characters typed by the dead keys
+    dead_keys: &'static [char],
+}
+
+impl Keymap {
+    // the character of the key without caps lock applied, None if the key doesn't type one
+    pub fn char(&self, code: u8, shift: bool, alt_gr: bool) -> Option<char> {
+        let character = if alt_gr {
+            self.alt_gr
+                .iter()
+                .find(|(key, _)| *key == code)
+                .map(|(_, character)| *character)?
+        } else if shift {
+            *self.upper.get(code as usize)?
+        } else {
+            *self.lower.get(code as usize)?
+        };
+        (character != NONE).then_some(character)
+    }
+
+    // caps lock only affects the letters that have an upper case character on the shifted key
+    pub fn has_caps(&self, code: u8) -> bool {
+        match (self.char(code, false, false), self.char(code, true, false)) {
+            (Some(lower), Some(upper)) => lower.is_lowercase() && upper.is_uppercase(),
+            _ => false,
+        }
+    }
+
+    pub fn is_dead(&self, character: char) -> bool {
+        self.dead_keys.contains(&character)
+    }
+}
+
+pub static US: Keymap = Keymap {
+    name: "us",
+    lower: US_LOWER,
+    upper: US_UPPER,
+    alt_gr: &[],
+    dead_keys: &[],
+};
+
+pub static UK: Keymap = Keymap {
+    name: "uk",
+    lower: UK_LOWER,
+    upper: UK_UPPER,
+    alt_gr: UK_ALT_GR,
+    dead_keys: &[],
+};
+
+pub static DE: Keymap = Keymap {
+    name: "de",
+    lower: DE_LOWER,
+    upper: DE_UPPER,
+    alt_gr: DE_ALT_GR,
+    dead_keys: &['^', '´', '`'],
+};
+
+pub static KEYMAPS: [&Keymap; 3] = [&US, &UK, &DE];
+
+// the index of the current keymap in KEYMAPS
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn current() -> &'static Keymap {
+    KEYMAPS[CURRENT.load(Ordering::Relaxed)]
+}
+
+pub fn find(name: &str) -> Option<&'static Keymap> {
+    KEYMAPS.iter().copied().find(|keymap| keymap.name == name)
+}
+
+// switch to the keymap with the name, false if there is none
+pub fn set(name: &str) -> bool {
+    match KEYMAPS.iter().position(|keymap| keymap.name == name) {
+        Some(index) => {
+            CURRENT.store(index, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+// the accent of a dead key combined with the next character, e.g. ('^', 'a') => 'â'
+pub fn compose(accent: char, base: char) -> Option<char> {
+    const VOWELS: [char; 10] = ['a', 'e', 'i', 'o', 'u', 'A', 'E', 'I', 'O', 'U'];
+    let composed: [char; 10] = match accent {
+        '^' => ['â', 'ê', 'î', 'ô', 'û', 'Â', 'Ê', 'Î', 'Ô', 'Û'],
+        '´' => ['á', 'é', 'í', 'ó', 'ú', 'Á', 'É', 'Í', 'Ó', 'Ú'],
+        '`' => ['à', 'è', 'ì', 'ò', 'ù', 'À', 'È', 'Ì', 'Ò', 'Ù'],
+        _ => return None,
+    };
+    let index = VOWELS.iter().position(|&vowel| vowel == base)?;
+    Some(composed[index])
+}
+
+#[rustfmt::skip]
+const US_LOWER: [char; KEYS] = [
+    NONE, NONE, '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', '-', '=', '\x08', '\t', // 0x00 - 0x0F
+    'q', 'w', 'e', 'r', 't', 'y', 'u', 'i', 'o', 'p', '[', ']', '\n', NONE, 'a', 's', // 0x10 - 0x1F
+    'd', 'f', 'g', 'h', 'j', 'k', 'l', ';', '\'', '`', NONE, '\\', 'z', 'x', 'c', 'v', // 0x20 - 0x2F
+    'b', 'n', 'm', ',', '.', '/', NONE, '*', NONE, ' ', NONE, NONE, NONE, NONE, NONE, NONE, // 0x30 - 0x3F
+    NONE, NONE, NONE, NONE, NONE, NONE, NONE, '7', '8', '9', '-', '4', '5', '6', '+', '1', // 0x40 - 0x4F
+    '2', '3', '0', '.', NONE, NONE, NONE, // 0x50 - 0x56
+];
+
+#[rustfmt::skip]
+const US_UPPER: [char; KEYS] = [
+    NONE, NONE, '!', '@', '#', '$', '%', '^', '&', '*', '(', ')', '_', '+', '\x08', '\t', // 0x00 - 0x0F
+    'Q', 'W', 'E', 'R', 'T', 'Y', 'U', 'I', 'O', 'P', '{', '}', '\n', NONE, 'A', 'S', // 0x10 - 0x1F
+    'D', 'F', 'G', 'H', 'J', 'K', 'L', ':', '"', '~', NONE, '|', 'Z', 'X', 'C', 'V', // 0x20 - 0x2F
+    'B', 'N', 'M', '<', '>', '?', NONE, '*', NONE, ' ', NONE, NONE, NONE, NONE, NONE, NONE, // 0x30 - 0x3F
+    NONE, NONE, NONE, NONE, NONE, NONE, NONE, '7', '8', '9', '-', '4', '5', '6', '+', '1', // 0x40 - 0x4F
+    '2', '3', '0', '.', NONE, NONE, NONE, // 0x50 - 0x56
+];
+
+#[rustfmt::skip]
+const UK_LOWER: [char; KEYS] = [
+    NONE, NONE, '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', '-', '=', '\x08', '\t', // 0x00 - 0x0F
+    'q', 'w', 'e', 'r', 't', 'y', 'u', 'i', 'o', 'p', '[', ']', '\n', NONE, 'a', 's', // 0x10 - 0x1F
+    'd', 'f', 'g', 'h', 'j', 'k', 'l', ';', '\'', '`', NONE, '#', 'z', 'x', 'c', 'v', // 0x20 - 0x2F
+    'b', 'n', 'm', ',', '.', '/', NONE, '*', NONE, ' ', NONE, NONE, NONE, NONE, NONE, NONE, // 0x30 - 0x3F
+    NONE, NONE, NONE, NONE, NONE, NONE, NONE, '7', '8', '9', '-', '4', '5', '6', '+', '1', // 0x40 - 0x4F
+    '2', '3', '0', '.', NONE, NONE, '\\', // 0x50 - 0x56
+];
+
+#[rustfmt::skip]
+const UK_UPPER: [char; KEYS] = [
+    NONE, NONE, '!', '"', '£', '$', '%', '^', '&', '*', '(', ')', '_', '+', '\x08', '\t', // 0x00 - 0x0F
+    'Q', 'W', 'E', 'R', 'T', 'Y', 'U', 'I', 'O', 'P', '{', '}', '\n', NONE, 'A', 'S', // 0x10 - 0x1F
+    'D', 'F', 'G', 'H', 'J', 'K', 'L', ':', '@', '¬', NONE, '~', 'Z', 'X', 'C', 'V', // 0x20 - 0x2F
+    'B', 'N', 'M', '<', '>', '?', NONE, '*', NONE, ' ', NONE, NONE, NONE, NONE, NONE, NONE, // 0x30 - 0x3F
+    NONE, NONE, NONE, NONE, NONE, NONE, NONE, '7', '8', '9', '-', '4', '5', '6', '+', '1', // 0x40 - 0x4F
+    '2', '3', '0', '.', NONE, NONE, '|', // 0x50 - 0x56
+];
+
+#[rustfmt::skip]
+const DE_LOWER: [char; KEYS] = [
+    NONE, NONE, '1', '2', '3', '4', '5', '6', '7', '8', '9', '0', 'ß', '´', '\x08', '\t', // 0x00 - 0x0F
+    'q', 'w', 'e', 'r', 't', 'z', 'u', 'i', 'o', 'p', 'ü', '+', '\n', NONE, 'a', 's', // 0x10 - 0x1F
+    'd', 'f', 'g', 'h', 'j', 'k', 'l', 'ö', 'ä', '^', NONE, '#', 'y', 'x', 'c', 'v', // 0x20 - 0x2F
+    'b', 'n', 'm', ',', '.', '-', NONE, '*', NONE, ' ', NONE, NONE, NONE, NONE, NONE, NONE, // 0x30 - 0x3F
+    NONE, NONE, NONE, NONE, NONE, NONE, NONE, '7', '8', '9', '-', '4', '5', '6', '+', '1', // 0x40 - 0x4F
+    '2', '3', '0', '.', NONE, NONE, '<', // 0x50 - 0x56
+];
+
+#[rustfmt::skip]
+const DE_UPPER: [char; KEYS] = [
+    NONE, NONE, '!', '"', '§', '$', '%', '&', '/', '(', ')', '=', '?', '`', '\x08', '\t', // 0x00 - 0x0F
+    'Q', 'W', 'E', 'R', 'T', 'Z', 'U', 'I', 'O', 'P', 'Ü', '*', '\n', NONE, 'A', 'S', // 0x10 - 0x1F
+    'D', 'F', 'G', 'H', 'J', 'K', 'L', 'Ö', 'Ä', '°', NONE, '\'', 'Y', 'X', 'C', 'V', // 0x20 - 0x2F
+    'B', 'N', 'M', ';', ':', '_', NONE, '*', NONE, ' ', NONE, NONE, NONE, NONE, NONE, NONE, // 0x30 - 0x3F
+    NONE, NONE, NONE, NONE, NONE, NONE, NONE, '7', '8', '9', '-', '4', '5', '6', '+', '1', // 0x40 - 0x4F
+    '2', '3', '0', '.', NONE, NONE, '>', // 0x50 - 0x56
+];
+
+const UK_ALT_GR: &[(u8, char)] = &[(0x05, '€')];
+
+const DE_ALT_GR: &[(u8, char)] = &[
+    (0x03, '²'),
+    (0x04, '³'),
+    (0x08, '{'),
+    (0x09, '['),
+    (0x0A, ']'),
+    (0x0B, '}'),
+    (0x0C, '\\'),
+    (0x10, '@'),
+    (0x12, '€'),
+    (0x1B, '~'),
+    (0x32, 'µ'),
+    (0x56, '|'),
+];
+
+#[test_case]
+fn test_layouts_alt_gr_and_caps() {
+    // the key right of t
+    assert_eq!(US.char(0x15, false, false), Some('y'));
+    assert_eq!(DE.char(0x15, false, false), Some('z'));
+    assert_eq!(UK.char(0x04, true, false), Some('£'));
+    assert_eq!(US.char(0x04, true, false), Some('#'));
+    assert_eq!(DE.char(0x10, false, true), Some('@'));
+    assert_eq!(US.char(0x10, false, true), None);
+    assert!(
+        DE.has_caps(0x1A) && !DE.has_caps(0x0C),
+        "ü has caps, ß doesn't"
+    );
+    assert_eq!(compose('^', 'o'), Some('ô'));
+    assert_eq!(compose('^', 'x'), None);
+    assert_eq!(find("de").map(|keymap| keymap.name), Some("de"));
+    assert!(!set("dvorak"));
+}
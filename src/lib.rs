@@ -0,0 +1,354 @@
+/*
+* The kernel is split in a library and a small binary (main.rs) so the integration tests in `tests/`
+* can reuse the drivers, the test runner and the QEMU exit helpers. Every integration test is its own
+* executable with its own entry point and panic handler, it links this library like main.rs does.
+*
+* `cargo test --lib` builds the library as a test executable, so it needs its own _start and panic
+* handler when compiled for tests, normal builds of the library don't have an entry point.
+* */
+#![no_std]
+#![cfg_attr(test, no_main)]
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+// the x86-interrupt calling convention used by the CPU exception handlers is still unstable
+#![feature(abi_x86_interrupt)]
+// the safety requirements of the unsafe functions are explained in the comment above each of them
+// instead of a `# Safety` doc section
+#![allow(clippy::missing_safety_doc)]
+
+// the alloc crate is not linked by default in no_std crates, it is built by build-std (.cargo/config.toml)
+// and needs the global allocator defined in the allocator module
+extern crate alloc;
+
+#[cfg(test)]
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+// Define a module to print things to the screen through VGA text buffer
+pub mod vga_buffer;
+// Define a module to draw text to a pixel framebuffer when there is no VGA text mode
+pub mod framebuffer;
+// Define a module to send output to the host through the serial port (COM1)
+pub mod serial;
+// Define a module for the consoles print! writes to (VGA, framebuffer, serial)
+pub mod console;
+// Define a module to handle CPU exceptions through the Interrupt Descriptor Table
+pub mod interrupts;
+// Define a module to set up the Global Descriptor Table and the Task State Segment
+pub mod gdt;
+// Define a module to read and decode the key presses of the PS/2 keyboard
+pub mod keyboard;
+// Define a module with the keyboard layouts (US, UK, DE) the key presses are decoded with
+pub mod keymap;
+// Define a module to decode the movements and button presses of the PS/2 mouse
+pub mod mouse;
+// Define a module to count the timer interrupts of the programmable interval timer
+pub mod timer;
+// Define a module to panic when the kernel main loop is stuck
+pub mod watchdog;
+// Define a module to inspect and modify the page tables
+pub mod memory;
+// Define a module to enforce the page permissions (NX, write protect, SMEP, SMAP)
+pub mod protection;
+// Define a module for the heap allocator used by Box, Vec, String...
+pub mod allocator;
+// Define a module to run cooperative async tasks
+pub mod task;
+// Define a module to log messages with levels to the consoles and memory
+pub mod logging;
+// Define a module to keep the printed and logged messages in memory (dmesg)
+pub mod dmesg;
+// Define a module for the vendor, model and features of the CPU (CPUID)
+pub mod cpu;
+// Define a module to enable the FPU and SSE and save their registers per thread
+pub mod fpu;
+// Define a module for debugging the kernel with gdb over a serial port
+pub mod gdb;
+// Define a module to list the return addresses on the stack by following the frame pointers
+pub mod backtrace;
+// Define a module to find the kernel function that contains an address
+pub mod symbols;
+// Define a module to find the functions the kernel spends its time in by sampling the timer interrupts
+pub mod profiler;
+// Define a module to show the panic message and the CPU state on the whole screen
+pub mod panic_screen;
+// Define a module to halt, reboot or exit QEMU after a panic
+pub mod panic_policy;
+// Define a module for the interactive shell task
+pub mod shell;
+// Define a module to parse the kernel command line
+pub mod cmdline;
+// Define a module to boot with GRUB through the multiboot2 protocol
+pub mod multiboot2;
+// Define a module to read the files QEMU passes to the guest
+pub mod fw_cfg;
+// Define a module to read the date and time of the CMOS real time clock
+pub mod rtc;
+// Define a module for the wall clock time and the uptime
+pub mod time;
+// Define a module to measure short durations with the time stamp counter
+pub mod hires_time;
+// Define a module to find and parse the ACPI tables of the firmware
+pub mod acpi;
+// Define a module to deliver the interrupts with the local APIC and the IO APIC
+pub mod apic;
+// Define a module to turn the machine off or restart it
+pub mod power;
+// Define a module to start the other CPUs
+pub mod smp;
+// Define a module for the data of every CPU
+pub mod percpu;
+// Define a module for the buffers devices read and write directly (DMA)
+pub mod dma;
+// Define a module to find the devices on the PCI bus
+pub mod pci;
+// Define a module for the disks and their partitions
+pub mod block;
+// Define a module for the ATA hard disks
+pub mod ata;
+// Define a module for the virtio disks of QEMU
+pub mod virtio_blk;
+// Define a module for the Intel e1000 network card
+pub mod e1000;
+// Define a module for the network stack (ARP, IPv4, ICMP, UDP, TCP)
+pub mod net;
+// Define a module to read FAT32 filesystems
+pub mod fat32;
+// Define a module for the tree of paths all filesystems are mounted in
+pub mod vfs;
+// Define a module for the files of the devices in /dev
+pub mod devfs;
+// Define a module to mount the initial ramdisk as the root filesystem
+pub mod initrd;
+// Define a module to load ELF executables into their own address space
+pub mod elf;
+// Define a module to run programs in user mode (ring 3)
+pub mod usermode;
+// Define a module for the system calls of the user programs
+pub mod syscall;
+// Define a module for kernel stacks with a guard page below them
+pub mod stack;
+// Define a module to run kernel threads that take turns on the CPU
+pub mod scheduler;
+// Define a module for user programs running as processes with their own address space
+pub mod process;
+// Define a module for random numbers from RDRAND/RDSEED or a ChaCha20 generator
+pub mod rand;
+
+// initialize the CPU tables before doing anything that can raise an exception
+pub fn init() {
+    // the per-CPU data is used by the interrupt handlers and the executor
+    percpu::init();
+    // the command line configures the logger (and other subsystems) so it is read first
+    cmdline::init();
+    logging::init();
+    panic_policy::init();
+    // the consoles of the console option show the output of print! and the log messages
+    console::init();
+    // booted by GRUB in a graphics mode, its framebuffer takes the place of the VGA text screen
+    multiboot2::init();
+    cpu::init();
+    // user programs may use floating point and SIMD instructions
+    fpu::init();
+    // remember where the kernel stack is so panics can walk it
+    backtrace::init();
+    // load the GDT first since the double fault handler entry references a stack from its TSS
+    gdt::init();
+    syscall::init();
+    // register the CPU exception handlers so exceptions don't reboot the machine
+    interrupts::init_idt();
+    // the hardware interrupts can only be enabled once the PICs are remapped
+    // otherwise the timer interrupt would be mistaken for a double fault
+    interrupts::init_pics();
+    keyboard::init();
+    mouse::init();
+    timer::init();
+    // the wall clock starts at the RTC time and advances with the timer ticks
+    time::init();
+    watchdog::init();
+    x86_64::instructions::interrupts::enable();
+    // the time stamp counter may be calibrated against the timer ticks, which need interrupts
+    hires_time::init();
+}
+
+// the part of the initialization that needs the page tables (call memory::init first)
+pub fn init_devices() {
+    // the kernel code becomes read only and the data no execute before anything else runs
+    protection::init();
+    // with the gdb option the kernel waits for the debugger here, the memory it reads is mapped now
+    gdb::init();
+    // the running code becomes the first thread, the threads are allocated on the heap
+    scheduler::init();
+    // the APIC and power management registers are described in the ACPI tables
+    acpi::init();
+    // replace the PICs and the PIT with the APIC if the CPU has one
+    apic::init();
+    // the other CPUs are started with APIC interrupts
+    smp::init();
+    // find the devices for the drivers
+    pci::init();
+    ata::init();
+    virtio_blk::init();
+    e1000::init();
+    net::init();
+    // mount the filesystems on the disks found above
+    fat32::init();
+    vfs::init();
+    initrd::init();
+}
+
+// halt the CPU until the next interrupt arrives instead of spinning in an endless loop
+// used when there is nothing left to do (e.g. after an unrecoverable exception)
+pub fn hlt_loop() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/*
+* Every test prints its name before it runs and [ok] once it returned, if it panics the panic handler
+* prints [failed] instead. The name is the path of the function (e.g. rust_os::memory::test_...)
+* given by type_name, so the tests don't need to print anything themselves.
+* */
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T {
+    fn run(&self) {
+        let name = core::any::type_name::<T>();
+        serial_print!("{}...\t", name);
+        start_test_watchdog(name);
+        self();
+        stop_test_watchdog();
+        serial_println!("[ok]");
+    }
+}
+
+/*
+* A test stuck in an endless loop would hang `cargo test` forever, so the timer interrupt checks if the
+* running test exceeded its time and fails the run. This only works once the PIT is initialized (init)
+* and while the test doesn't disable interrupts.
+* */
+pub const TEST_TIMEOUT_TICKS: u64 = 10 * timer::TICKS_PER_SECOND as u64;
+
+// the test_timeout option of the command line overrides the default (in seconds)
+fn test_timeout_ticks() -> u64 {
+    cmdline::parse::<u64>("test_timeout").map_or(TEST_TIMEOUT_TICKS, |seconds| {
+        seconds * timer::TICKS_PER_SECOND as u64
+    })
+}
+
+// the tick at which the running test times out, 0 while no test is running
+static TEST_DEADLINE: AtomicU64 = AtomicU64::new(0);
+static CURRENT_TEST: Mutex<&str> = Mutex::new("");
+
+fn start_test_watchdog(name: &'static str) {
+    // the timer interrupt reads the name so it must not arrive while it is locked
+    x86_64::instructions::interrupts::without_interrupts(|| *CURRENT_TEST.lock() = name);
+    TEST_DEADLINE.store(timer::ticks() + test_timeout_ticks(), Ordering::SeqCst);
+}
+
+fn stop_test_watchdog() {
+    TEST_DEADLINE.store(0, Ordering::SeqCst);
+}
+
+// called by the timer interrupt handler on every tick
+pub(crate) fn check_test_timeout(ticks: u64) {
+    let deadline = TEST_DEADLINE.load(Ordering::SeqCst);
+    if deadline == 0 || ticks < deadline {
+        return;
+    }
+    serial_println!("[timeout]\n");
+    serial_println!(
+        "Error: {} did not finish within {} ticks\n",
+        *CURRENT_TEST.lock(),
+        test_timeout_ticks()
+    );
+    exit_qemu(QemuExitCode::Failed);
+}
+
+// a custom test runner
+// the output goes to the serial port so it shows up in the host terminal
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+// when testing the panic message is sent to the host through the serial port
+// and QEMU is terminated with a failure code so a failing test doesn't hang `cargo test`
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    serial_println!("Backtrace:\n{}", backtrace::Backtrace::capture());
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+// the entry point of `cargo test --lib`, the tests of the modules need the same setup as the kernel
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init();
+    unsafe {
+        memory::init(
+            x86_64::VirtAddr::new(boot_info.physical_memory_offset),
+            &boot_info.memory_map,
+        )
+    };
+    allocator::init_heap().expect("heap initialization failed");
+    init_devices();
+    test_main();
+    hlt_loop();
+}
+
+// the runner arms the watchdog with the name of the running test
+#[test_case]
+fn test_watchdog_is_armed_while_test_runs() {
+    let deadline = TEST_DEADLINE.load(Ordering::SeqCst);
+    assert!(deadline > timer::ticks() && deadline <= timer::ticks() + test_timeout_ticks());
+    assert!(CURRENT_TEST
+        .lock()
+        .ends_with("test_watchdog_is_armed_while_test_runs"));
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
+
+/*
+* After running the tests we need a way to exit
+* we can send an exit instruction to QEMU to terminate the machine
+* QEMU supports a special isa-debug-exit device, which provides an easy way to exit QEMU from the guest system
+* isa-debug-exit uses a port mapped I/O interface
+* we use the x86_64 crate to write to the port
+* 0xf4 is the iobase of the isa-debug-exit device.
+* */
+
+// The actual exit codes don’t matter much, as long as they don’t clash with the default exit codes of QEMU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        let mut port = Port::new(0xf4);
+        port.write(exit_code as u32);
+    }
+}
@@ -0,0 +1,200 @@
+/*
+* The kernel logs through the `log` crate facade: log::error!, warn!, info!, debug! and trace! record the
+* level and the module (target) of every message, and the logger registered here decides where it goes.
+*
+* Messages are filtered by level per module, the most specific module prefix wins, e.g. with
+* `set_level("rust_os::keyboard", LevelFilter::Trace)` the keyboard driver logs everything while the
+* other modules stay at the default level. Messages that pass the filter are written to every sink
+* (the active consoles, the kernel message buffer in dmesg, or anything else implementing LogSink).
+*
+* The logger can be called from interrupt handlers, so the tables are read with a RwLock (an interrupt
+* that logs while the kernel is logging only needs another read lock) and are only changed with
+* interrupts disabled.
+* */
+use crate::vga_buffer::Color;
+use core::fmt;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use spin::RwLock;
+use x86_64::instructions::interrupts;
+
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+const MAX_FILTERS: usize = 16;
+const MAX_SINKS: usize = 8;
+
+// a destination for the log messages that passed the filter
+pub trait LogSink: Sync {
+    fn write(&self, record: &Record);
+}
+
+struct Filters {
+    default: LevelFilter,
+    modules: [Option<(&'static str, LevelFilter)>; MAX_FILTERS],
+}
+
+impl Filters {
+    // the level of the longest module prefix that matches the target
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .flatten()
+            .filter(|(module, _)| is_module_prefix(module, target))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    // the most verbose level of all the filters, the log macros skip everything above it
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .flatten()
+            .map(|&(_, level)| level)
+            .fold(self.default, core::cmp::max)
+    }
+}
+
+// "rust_os::task" matches rust_os::task and rust_os::task::keyboard but not rust_os::tasks
+fn is_module_prefix(module: &str, target: &str) -> bool {
+    match target.strip_prefix(module) {
+        Some(rest) => rest.is_empty() || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+static FILTERS: RwLock<Filters> = RwLock::new(Filters {
+    default: DEFAULT_LEVEL,
+    modules: [None; MAX_FILTERS],
+});
+static SINKS: RwLock<[Option<&'static dyn LogSink>; MAX_SINKS]> = RwLock::new([None; MAX_SINKS]);
+
+struct KernelLogger;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= FILTERS.read().level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        for sink in SINKS.read().iter().flatten() {
+            sink.write(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/*
+* Register the logger with the consoles and the kernel message buffer as sinks. The console option of the
+* command line selects the consoles (console module), log_level sets the default level.
+* */
+pub fn init() {
+    // set_logger fails if a logger is already registered, init is only called once
+    if log::set_logger(&LOGGER).is_err() {
+        return;
+    }
+    add_sink(&ConsoleSink);
+    add_sink(&crate::dmesg::DMESG);
+    update_max_level();
+
+    if let Some(level) = crate::cmdline::parse("log_level") {
+        set_default_level(level);
+    }
+}
+
+pub fn add_sink(sink: &'static dyn LogSink) {
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.write();
+        if let Some(slot) = sinks.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(sink);
+        }
+    });
+}
+
+// set the level of the messages of the module and its submodules, e.g. "rust_os::memory"
+pub fn set_level(module: &'static str, level: LevelFilter) {
+    interrupts::without_interrupts(|| {
+        let mut filters = FILTERS.write();
+        let existing = filters
+            .modules
+            .iter()
+            .position(|filter| matches!(filter, Some((name, _)) if *name == module));
+        let free = filters.modules.iter().position(Option::is_none);
+        if let Some(index) = existing.or(free) {
+            filters.modules[index] = Some((module, level));
+        }
+    });
+    update_max_level();
+}
+
+// the level of the modules without their own filter
+pub fn set_default_level(level: LevelFilter) {
+    interrupts::without_interrupts(|| FILTERS.write().default = level);
+    update_max_level();
+}
+
+fn update_max_level() {
+    log::set_max_level(FILTERS.read().max_level());
+}
+
+// the line written by the sinks, e.g. "[WARN  rust_os::keyboard] keyboard queue full"
+pub(crate) struct Line<'a, 'b>(pub &'a Record<'b>);
+
+impl fmt::Display for Line<'_, '_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "[{:<5} {}] {}",
+            self.0.level(),
+            self.0.target(),
+            self.0.args()
+        )
+    }
+}
+
+// errors and warnings stand out on the screen, debug messages are dimmed
+fn level_color(level: Level) -> Option<Color> {
+    match level {
+        Level::Error => Some(Color::LightRed),
+        Level::Warn => Some(Color::Yellow),
+        Level::Info => None,
+        Level::Debug | Level::Trace => Some(Color::DarkGray),
+    }
+}
+
+pub struct ConsoleSink;
+
+impl LogSink for ConsoleSink {
+    fn write(&self, record: &Record) {
+        // only to the consoles, the message buffer gets the record from its own sink
+        crate::console::write(
+            level_color(record.level()),
+            format_args!("{}", Line(record)),
+        );
+    }
+}
+
+#[test_case]
+fn test_module_filters_use_longest_prefix() {
+    let mut filters = Filters {
+        default: LevelFilter::Info,
+        modules: [None; MAX_FILTERS],
+    };
+    filters.modules[0] = Some(("rust_os::task", LevelFilter::Debug));
+    filters.modules[1] = Some(("rust_os::task::keyboard", LevelFilter::Error));
+    assert_eq!(filters.level_for("rust_os::memory"), LevelFilter::Info);
+    assert_eq!(filters.level_for("rust_os::task"), LevelFilter::Debug);
+    assert_eq!(
+        filters.level_for("rust_os::task::executor"),
+        LevelFilter::Debug
+    );
+    assert_eq!(
+        filters.level_for("rust_os::task::keyboard"),
+        LevelFilter::Error
+    );
+    assert_eq!(filters.level_for("rust_os::tasks"), LevelFilter::Info);
+    assert_eq!(filters.max_level(), LevelFilter::Debug);
+}
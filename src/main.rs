@@ -19,32 +19,70 @@
 * we use the custom_test_frameworks feature to define our own test runner
 * */
 #![feature(custom_test_frameworks)]
-#![test_runner(crate::test_runner)]
+#![test_runner(rust_os::test_runner)]
 /*
 * The custom_test_framewrok feature generates it's own main function that calls the test runner
 * we need to specify a custom name for the generated function and then call it our self in the
-* _start function (our entry point)
+* kernel_main function (our entry point)
 * */
 #![reexport_test_harness_main = "test_main"]
-// this forces the compilar to not mangle the name of this function aka give it a
-// random cryptic name ex: asdfaasdf  to avoid conflicts
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
-    // extern "C" tells the compiler to use the C CALLING_CONVENTION for this function
-    // _start is the entry point for most systems
+/*
+* The bootloader passes a BootInfo struct (memory map, physical memory offset...) to the kernel.
+* Instead of the raw `extern "C" fn _start()` we use the entry_point macro of the bootloader crate
+* which defines the real _start (with #[no_mangle] so the linker finds it) and calls our function
+* with a type checked signature. Booted by GRUB the multiboot2 module builds the same BootInfo and calls
+* the same _start.
+* */
+use bootloader::{entry_point, BootInfo};
+// the drivers, the test runner etc. are in the rust_os library (lib.rs) so the integration tests can use them too
+use rust_os::task::{self, executor::Executor, Task};
+use rust_os::{allocator, memory, shell};
+entry_point!(kernel_main);
+
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
+    // _start (defined by entry_point) is the entry point for most systems
     // ! return type means that this function never returns (it is a DIVERGING_FUNCTION)
     // that makes since because an OS is not called by another function but by a bootloader
     // so it should never return and instead it should invoke the EXIT_SYSCALL to terminate the OS
     // (shutdown the machine)
     // panic!("Some panic");
-    println!("Hello World{}", "!");
+    rust_os::init();
+    log::info!("Hello World{}", "!");
+    // the bootloader mapped the complete physical memory at this offset (map_physical_memory feature)
+    // and marked the frames that are used by the kernel, page tables etc. in the memory map
+    unsafe {
+        memory::init(
+            x86_64::VirtAddr::new(boot_info.physical_memory_offset),
+            &boot_info.memory_map,
+        )
+    };
+    // the heap needs the page tables and the frame allocator from the memory module
+    allocator::init_heap().expect("heap initialization failed");
+    // the drivers that access device memory need the page tables
+    rust_os::init_devices();
 
     // call the test runner if compiling for tests
     #[cfg(test)]
     test_main();
 
-    loop {}
+    // tasks can also be spawned later from anywhere with task::spawn
+    task::spawn(example_task());
+    // the network stack handles the received frames in its own task
+    task::spawn(rust_os::net::run());
+    task::spawn(rust_os::net::dhcp::run());
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(shell::run()));
+    executor.run();
+}
+
+async fn async_number() -> u32 {
+    42
+}
+
+async fn example_task() {
+    let number = async_number().await;
+    log::info!("async number: {}", number);
 }
 
 /*
@@ -52,55 +90,23 @@ pub extern "C" fn _start() -> ! {
 * the ! return type means that this function never returns (it is a DIVERGING_FUNCTION)
 * */
 use core::panic::PanicInfo;
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    loop {}
+    rust_os::panic_screen::show(info);
+    // halt, reboot or exit QEMU depending on the panic option
+    rust_os::panic_policy::apply(info);
 }
 
-// Define a module to print things to the screen through VGA text buffer
-mod vga_buffer;
-
-// a custom test runner
+// when testing the panic message is sent to the host through the serial port
+// and QEMU is terminated with a failure code so a failing test doesn't hang `cargo test`
 #[cfg(test)]
-pub fn test_runner(tests: &[&dyn Fn()]) {
-    println!("Running {} tests", tests.len());
-    for test in tests {
-        test();
-    }
-
-    exit_qemu(QemuExitCode::Success);
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
 }
 
 #[test_case]
 fn trivial_assertion() {
-    print!("trivial assertion... ");
     assert_eq!(1, 1);
-    println!("[ok]");
-}
-
-/*
-* After running the tests we need a way to exit
-* we can send an exit instruction to QEMU to terminate the machine
-* QEMU supports a special isa-debug-exit device, which provides an easy way to exit QEMU from the guest system
-* isa-debug-exit uses a port mapped I/O interface
-* we use the x86_64 crate to write to the port
-* 0xf4 is the iobase of the isa-debug-exit device.
-* */
-
-// The actual exit codes don’t matter much, as long as they don’t clash with the default exit codes of QEMU
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
-pub enum QemuExitCode {
-    Success = 0x10,
-    Failed = 0x11,
-}
-
-pub fn exit_qemu(exit_code: QemuExitCode) {
-    use x86_64::instructions::port::Port;
-
-    unsafe {
-        let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
-    }
 }
@@ -0,0 +1,828 @@
+/*
+* On x86_64 the kernel doesn't access the physical memory directly, every address goes through PAGING:
+* the memory is divided into 4KiB PAGES (virtual) that are mapped to FRAMES (physical) using a
+* 4 level PAGE_TABLE hierarchy. The address of the level 4 table is stored in the CR3 register.
+*
+* The page tables store physical addresses but the kernel can only access virtual addresses, so to
+* read or modify a page table we need a virtual address that maps to its frame. The bootloader
+* (map_physical_memory feature) maps the complete physical memory at a virtual offset, so the
+* virtual address of physical address X is simply physical_memory_offset + X.
+* The OffsetPageTable type of the x86_64 crate uses this offset to walk and modify the page tables.
+* */
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ops::Range;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MapToError, Translate, TranslateResult};
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// the page tables of the kernel, None until init is called
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+// the allocator of the unused physical frames, None until init is called
+// when both locks are needed FRAME_ALLOCATOR must be locked first to avoid deadlocks
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/*
+* Unsafe because the caller must guarantee that the complete physical memory is mapped at the
+* passed offset, that the memory map is valid and that it is only called once (creating two
+* OffsetPageTables would create aliased &mut references to the same level 4 table)
+* */
+pub unsafe fn init(physical_memory_offset: VirtAddr, memory_map: &'static MemoryMap) {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    *MAPPER.lock() = Some(OffsetPageTable::new(level_4_table, physical_memory_offset));
+    *FRAME_ALLOCATOR.lock() = Some(BootInfoFrameAllocator::new(
+        memory_map,
+        physical_memory_offset,
+    ));
+}
+
+// returns a mutable reference to the active level 4 table (the one CR3 points to)
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
+
+// run a closure with the kernel page tables, panics if the memory module isn't initialized
+pub fn with_mapper<R>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> R) -> R {
+    let mut mapper = MAPPER.lock();
+    f(mapper.as_mut().expect("memory::init must be called first"))
+}
+
+// the virtual address through which the kernel can access the given physical address
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    with_mapper(|mapper| mapper.phys_offset() + addr.as_u64())
+}
+
+// translate a virtual address to the mapped physical address, None if it isn't mapped
+pub fn translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    with_mapper(|mapper| mapper.translate_addr(addr))
+}
+
+// like translate_addr but None if the page tables are locked, for the debugger which may stop anywhere
+pub fn try_translate_addr(addr: VirtAddr) -> Option<PhysAddr> {
+    MAPPER.try_lock()?.as_ref()?.translate_addr(addr)
+}
+
+/*
+* Map the given page to the given frame in the kernel page tables. The frame allocator is used
+* to allocate frames for the page tables that don't exist yet.
+*
+* Unsafe because the caller must make sure the frame isn't already in use otherwise mapping it again
+* creates two mutable views of the same memory (undefined behavior).
+* */
+pub unsafe fn map_page(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    with_mapper(|mapper| {
+        mapper
+            .map_to(page, frame, flags, frame_allocator)
+            // the CPU caches the translations in the TRANSLATION_LOOKASIDE_BUFFER (TLB)
+            // flush removes the old entry of the page from it
+            .map(|flush| flush.flush())
+    })
+}
+
+// remove the page from the kernel page tables, returns the frame it was mapped to
+pub unsafe fn unmap_page(page: Page) -> Option<PhysFrame> {
+    with_mapper(|mapper| {
+        let (frame, flush) = mapper.unmap(page).ok()?;
+        flush.flush();
+        Some(frame)
+    })
+}
+
+/*
+* The registers of devices (APIC, PCI cards...) are accessed through physical addresses outside of the RAM
+* (MEMORY_MAPPED_IO). The physical memory mapping of the bootloader only covers the memory map and is cached,
+* but the CPU must not cache device registers: reads must reach the device every time and writes must not
+* be delayed. So device memory is mapped to its own virtual region with caching disabled.
+* */
+const MMIO_START: u64 = 0x_5555_0000_0000;
+// the start of the unused part of the MMIO region, mappings are never removed
+static NEXT_MMIO: Mutex<u64> = Mutex::new(MMIO_START);
+
+/*
+* Map size bytes of device memory starting at the physical address, returns the virtual address of phys.
+* Unsafe because the range must be device memory, mapping RAM that is in use uncached would alias it.
+* */
+pub unsafe fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let pages = last.start_address() - first.start_address() + 4096;
+    let start = {
+        let mut next = NEXT_MMIO.lock();
+        let start = *next;
+        *next += pages;
+        start
+    };
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | crate::protection::no_execute();
+    with_frame_allocator(|frame_allocator| {
+        for (index, frame) in PhysFrame::range_inclusive(first, last).enumerate() {
+            let page = Page::containing_address(VirtAddr::new(start + index as u64 * 4096));
+            map_page(page, frame, flags, frame_allocator)?;
+        }
+        Ok(VirtAddr::new(start + (phys - first.start_address())))
+    })
+}
+
+/*
+* Every process has its own ADDRESS_SPACE, a level 4 table of its own. The kernel isn't in the higher half
+* (bootloader 0.9 loads it at its link address in the first 512 GiB), so the kernel part of an address space
+* is every used entry of the kernel's level 4 table: the entries are copied and the tables below them are
+* shared by all address spaces, a page the kernel maps later shows up everywhere. A level 4 entry the kernel
+* adds later is copied when the address space is activated.
+*
+* The user part is USER_START..USER_END (level 4 entries 64 to 127, unused by the kernel), the tables and
+* frames mapped below these entries belong to the address space and are freed with it.
+* */
+pub const USER_START: u64 = 0x0000_2000_0000_0000;
+pub const USER_END: u64 = 0x0000_4000_0000_0000;
+
+// the level 4 entries of the user part
+const USER_ENTRIES: core::ops::Range<usize> =
+    (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    (USER_START..USER_END).contains(&addr.as_u64())
+}
+
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+// the table in the frame, through the physical memory mapping
+unsafe fn table_at(offset: VirtAddr, addr: PhysAddr) -> &'static mut PageTable {
+    &mut *(offset + addr.as_u64()).as_mut_ptr()
+}
+
+// copy the kernel part of the kernel's level 4 table
+fn copy_kernel_entries(table: &mut PageTable) {
+    with_mapper(|mapper| {
+        for (index, entry) in mapper.level_4_table().iter().enumerate() {
+            if USER_ENTRIES.contains(&index) {
+                assert!(
+                    entry.is_unused(),
+                    "the kernel uses the user part of the address space"
+                );
+            } else if entry.is_unused() {
+                table[index].set_unused();
+            } else {
+                table[index].set_addr(entry.addr(), entry.flags());
+            }
+        }
+    });
+}
+
+/*
+* Free the frame the entry points to, the entries of a level 1 table point to the mapped frames, the entries
+* of the other levels to the table of the level below whose frames are freed first.
+* */
+unsafe fn free_entry(
+    entry: &mut PageTableEntry,
+    level: u8,
+    offset: VirtAddr,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) {
+    if entry.is_unused() {
+        return;
+    }
+    if level > 1 {
+        for child in table_at(offset, entry.addr()).iter_mut() {
+            free_entry(child, level - 1, offset, frame_allocator);
+        }
+    }
+    frame_allocator.deallocate_frame(PhysFrame::containing_address(entry.addr()));
+    entry.set_unused();
+}
+
+impl AddressSpace {
+    // an address space with the kernel mappings and an empty user part, None if there is no free frame
+    pub fn new() -> Option<AddressSpace> {
+        let frame = with_frame_allocator(|frame_allocator| frame_allocator.allocate_frame())?;
+        let table = unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() };
+        table.zero();
+        copy_kernel_entries(table);
+        Some(AddressSpace {
+            level_4_frame: frame,
+        })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let offset = with_mapper(|mapper| mapper.phys_offset());
+        unsafe {
+            OffsetPageTable::new(table_at(offset, self.level_4_frame.start_address()), offset)
+        }
+    }
+
+    /*
+     * Map a page of the user part to the frame, the frame belongs to the address space from now on.
+     * Unsafe like map_page, the frame must not be in use anywhere else.
+     * */
+    pub unsafe fn map(
+        &mut self,
+        page: Page,
+        frame: PhysFrame,
+        flags: PageTableFlags,
+    ) -> Result<(), MapToError<Size4KiB>> {
+        assert!(is_user_address(page.start_address()), "not a user page");
+        let mut mapper = self.mapper();
+        // the tables above a page restrict it, so they allow everything and the page's own flags decide
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        with_frame_allocator(|frame_allocator| {
+            mapper
+                .map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator)
+                .map(|flush| flush.flush())
+        })
+    }
+
+    // change the flags of a mapped user page, unsafe because the code using the page may rely on them
+    pub unsafe fn update_flags(&mut self, page: Page, flags: PageTableFlags) -> bool {
+        assert!(is_user_address(page.start_address()), "not a user page");
+        self.mapper()
+            .update_flags(page, flags)
+            .map(|flush| flush.flush())
+            .is_ok()
+    }
+
+    // unmap a user page and free its frame, unsafe because the code using the page must be done with it
+    pub unsafe fn unmap(&mut self, page: Page) -> bool {
+        assert!(is_user_address(page.start_address()), "not a user page");
+        let Ok((frame, flush)) = self.mapper().unmap(page) else {
+            return false;
+        };
+        flush.flush();
+        with_frame_allocator(|frame_allocator| frame_allocator.deallocate_frame(frame));
+        true
+    }
+
+    // register a lazy user region (demand paging below), its pages are mapped with the flags when touched
+    pub fn map_lazy(&mut self, range: Range<VirtAddr>, flags: PageTableFlags) {
+        assert!(
+            is_user_address(range.start) && range.end.as_u64() <= USER_END,
+            "not a user range"
+        );
+        add_lazy(Some(self.level_4_frame), range, flags);
+    }
+
+    // remove the range from the lazy regions and free the pages of it that were touched
+    pub unsafe fn unmap_lazy(&mut self, range: Range<VirtAddr>) {
+        remove_lazy(Some(self.level_4_frame), range.clone());
+        let first = Page::containing_address(range.start);
+        let end = Page::containing_address(range.end.align_up(4096u64));
+        for page in Page::range(first, end) {
+            self.unmap(page);
+        }
+    }
+
+    // the physical address and the flags of the page the address is in
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped {
+                frame,
+                offset,
+                flags,
+            } => Some((frame.start_address() + offset, flags)),
+            _ => None,
+        }
+    }
+
+    /*
+     * Switch the CPU to the address space. Unsafe because the code and stack that run must be mapped in it,
+     * which they are if they are in the kernel part.
+     * */
+    pub unsafe fn activate(&mut self) {
+        let table = table_at(
+            with_mapper(|mapper| mapper.phys_offset()),
+            self.level_4_frame.start_address(),
+        );
+        copy_kernel_entries(table);
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+}
+
+// the level 4 table the kernel started with
+pub fn kernel_level_4_frame() -> PhysFrame {
+    with_mapper(|mapper| {
+        let table = mapper.level_4_table() as *const PageTable as u64;
+        PhysFrame::containing_address(PhysAddr::new(table - mapper.phys_offset().as_u64()))
+    })
+}
+
+// switch back to the kernel's own page tables, the kernel part is the same in every address space
+pub fn activate_kernel() {
+    let (_, flags) = Cr3::read();
+    unsafe { Cr3::write(kernel_level_4_frame(), flags) };
+}
+
+/*
+* Check that the user program may access the bytes in the active address space before the kernel reads or
+* writes them for it, a page fault in the kernel would panic. The tables are walked by hand since the kernel
+* mapper only sees the kernel's level 4 table.
+* */
+pub fn is_user_accessible(start: VirtAddr, len: u64, write: bool) -> bool {
+    let Some(end) = start.as_u64().checked_add(len) else {
+        return false;
+    };
+    if len == 0 {
+        return true;
+    }
+    if start.as_u64() < USER_START || end > USER_END {
+        return false;
+    }
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let offset = with_mapper(|mapper| mapper.phys_offset());
+    let (level_4_frame, _) = Cr3::read();
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    Page::range_inclusive(first, last).all(|page| {
+        // a lazy page is mapped by the page fault when the kernel touches it
+        if lazy_flags(level_4_frame, page.start_address())
+            .is_some_and(|flags| flags.contains(required))
+        {
+            return true;
+        }
+        let indexes = [
+            page.p4_index(),
+            page.p3_index(),
+            page.p2_index(),
+            page.p1_index(),
+        ];
+        let mut table = level_4_frame.start_address();
+        for (level, index) in indexes.into_iter().enumerate() {
+            let entry = &unsafe { table_at(offset, table) }[index];
+            if !entry.flags().contains(required) {
+                return false;
+            }
+            // the user part has no huge pages
+            if level < 3 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return false;
+            }
+            table = entry.addr();
+        }
+        true
+    })
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        let (active, _) = Cr3::read();
+        assert_ne!(
+            active, self.level_4_frame,
+            "dropping the active address space"
+        );
+        let offset = with_mapper(|mapper| mapper.phys_offset());
+        with_frame_allocator(|frame_allocator| unsafe {
+            let table = table_at(offset, self.level_4_frame.start_address());
+            for index in USER_ENTRIES {
+                free_entry(&mut table[index], 4, offset, frame_allocator);
+            }
+            frame_allocator.deallocate_frame(self.level_4_frame);
+        });
+        without_interrupts(|| {
+            LAZY_REGIONS
+                .lock()
+                .retain(|region| region.level_4_frame != Some(self.level_4_frame))
+        });
+    }
+}
+
+/*
+* DEMAND PAGING: a LAZY region is registered instead of mapped, its pages get a zeroed frame when they are
+* first touched. The access is a page fault (the page isn't present) and the page fault handler maps the
+* page in handle_page_fault, then the faulting instruction runs again. Memory that is reserved but never
+* used (most of a large stack or heap) costs no frames this way.
+*
+* A kernel region is mapped in the kernel page tables and seen by every address space, a user region only
+* in the address space it was registered for. The regions are locked with interrupts disabled since the
+* page fault handler reads them.
+* */
+struct LazyRegion {
+    // None for a kernel region
+    level_4_frame: Option<PhysFrame>,
+    start: VirtAddr,
+    end: VirtAddr,
+    flags: PageTableFlags,
+}
+
+static LAZY_REGIONS: Mutex<Vec<LazyRegion>> = Mutex::new(Vec::new());
+
+fn add_lazy(level_4_frame: Option<PhysFrame>, range: Range<VirtAddr>, flags: PageTableFlags) {
+    let region = LazyRegion {
+        level_4_frame,
+        start: range.start.align_down(4096u64),
+        end: range.end.align_up(4096u64),
+        flags: flags | PageTableFlags::PRESENT,
+    };
+    without_interrupts(|| LAZY_REGIONS.lock().push(region));
+}
+
+// remove the range from the regions, the parts of a region before and after it stay lazy
+fn remove_lazy(level_4_frame: Option<PhysFrame>, range: Range<VirtAddr>) {
+    let (start, end) = (range.start.align_down(4096u64), range.end.align_up(4096u64));
+    without_interrupts(|| {
+        let mut regions = LAZY_REGIONS.lock();
+        let mut rest = Vec::new();
+        regions.retain(|region| {
+            if region.level_4_frame != level_4_frame || region.end <= start || region.start >= end {
+                return true;
+            }
+            for (from, to) in [(region.start, start), (end, region.end)] {
+                if from < to {
+                    rest.push(LazyRegion {
+                        start: from,
+                        end: to,
+                        ..*region
+                    });
+                }
+            }
+            false
+        });
+        regions.append(&mut rest);
+    });
+}
+
+// the flags of the lazy region the address is in, for the address space with the level 4 frame
+fn lazy_flags(level_4_frame: PhysFrame, addr: VirtAddr) -> Option<PageTableFlags> {
+    without_interrupts(|| {
+        LAZY_REGIONS
+            .lock()
+            .iter()
+            .find(|region| {
+                region
+                    .level_4_frame
+                    .is_none_or(|frame| frame == level_4_frame)
+                    && (region.start..region.end).contains(&addr)
+            })
+            .map(|region| region.flags)
+    })
+}
+
+/*
+* Register a lazy region in the kernel page tables, the flags are those of the mapped pages. Unsafe because
+* the range must not overlap anything else the kernel maps.
+* */
+pub unsafe fn map_lazy(range: Range<VirtAddr>, flags: PageTableFlags) {
+    assert!(!is_user_address(range.start), "not a kernel range");
+    add_lazy(None, range, flags);
+}
+
+// remove a lazy kernel region and free the pages of it that were touched
+pub unsafe fn unmap_lazy(range: Range<VirtAddr>) {
+    remove_lazy(None, range.clone());
+    let first = Page::<Size4KiB>::containing_address(range.start);
+    let end = Page::containing_address(range.end.align_up(4096u64));
+    for page in Page::range(first, end) {
+        if let Some(frame) = unmap_page(page) {
+            with_frame_allocator(|allocator| allocator.deallocate_frame(frame));
+        }
+    }
+}
+
+/*
+* Called by the page fault handler for a page that isn't present, maps a zeroed frame if the address is in a
+* lazy region of the active address space and returns whether it did. The locks of the page tables and the
+* frame allocator may be held by a preempted thread, so the handler enables interrupts while waiting for
+* them; when the faulting code had interrupts disabled (can_wait is false) a held lock is a failure instead.
+* A fault of user mode code (user is true) only maps pages of regions that are user accessible, touching a
+* kernel region is a failure like touching an address that isn't mapped at all.
+* */
+pub fn handle_page_fault(addr: VirtAddr, can_wait: bool, user: bool) -> bool {
+    let (active, _) = Cr3::read();
+    let Some(flags) = lazy_flags(active, addr) else {
+        return false;
+    };
+    if user && !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return false;
+    }
+    let (mut frame_allocator, mut kernel_mapper) = if can_wait {
+        (FRAME_ALLOCATOR.lock(), MAPPER.lock())
+    } else {
+        let Some(frame_allocator) = FRAME_ALLOCATOR.try_lock() else {
+            return false;
+        };
+        let Some(kernel_mapper) = MAPPER.try_lock() else {
+            return false;
+        };
+        (frame_allocator, kernel_mapper)
+    };
+    let (Some(frame_allocator), Some(kernel_mapper)) =
+        (frame_allocator.as_mut(), kernel_mapper.as_mut())
+    else {
+        return false;
+    };
+    let Some(frame) = frame_allocator.allocate_frame() else {
+        return false;
+    };
+    let offset = kernel_mapper.phys_offset();
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let result = unsafe {
+        (offset + frame.start_address().as_u64())
+            .as_mut_ptr::<u8>()
+            .write_bytes(0, 4096);
+        if is_user_address(addr) {
+            let table_flags = PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::USER_ACCESSIBLE;
+            OffsetPageTable::new(table_at(offset, active.start_address()), offset)
+                .map_to_with_table_flags(page, frame, flags, table_flags, frame_allocator)
+                .map(|flush| flush.flush())
+        } else {
+            let result = kernel_mapper
+                .map_to(page, frame, flags, frame_allocator)
+                .map(|flush| flush.flush());
+            // a level 4 entry the kernel just added isn't in the copy of the active address space yet
+            let index = usize::from(page.p4_index());
+            let entry = &kernel_mapper.level_4_table()[index];
+            let (entry_addr, entry_flags) = (entry.addr(), entry.flags());
+            table_at(offset, active.start_address())[index].set_addr(entry_addr, entry_flags);
+            result
+        }
+    };
+    if result.is_err() {
+        unsafe { frame_allocator.deallocate_frame(frame) };
+        return false;
+    }
+    true
+}
+
+// run a closure with the kernel frame allocator, panics if the memory module isn't initialized
+pub fn with_frame_allocator<R>(f: impl FnOnce(&mut BootInfoFrameAllocator) -> R) -> R {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    f(allocator
+        .as_mut()
+        .expect("memory::init must be called first"))
+}
+
+/*
+* The bootloader passes a MEMORY_MAP that lists the physical memory regions and what they are used for
+* (kernel image, page tables, reserved by the BIOS...). The frames of the Usable regions are free memory
+* that we can hand out.
+*
+* Frames are handed out in order from the usable regions, the allocator remembers the current region
+* and the next frame so allocating is O(1). Deallocated frames are kept in a FREE_LIST and reused first,
+* we have no heap to store the list so each free frame stores the address of the next free frame in
+* its first 8 bytes (an intrusive linked list), the frames are reachable through the physical memory mapping.
+* */
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    physical_memory_offset: VirtAddr,
+    // index of the memory map region the next frame is taken from
+    region: usize,
+    // start address of the next never allocated frame
+    next: u64,
+    // first frame of the free list
+    free_list: Option<PhysFrame>,
+    allocated: usize,
+    // a frame below 1 MiB kept for real mode code (the SMP trampoline), never handed out by allocate_frame
+    low_frame: Option<PhysFrame>,
+    low_frame_taken: bool,
+}
+
+// stored in the last frame of the free list
+const FREE_LIST_END: u64 = u64::MAX;
+
+impl BootInfoFrameAllocator {
+    /*
+     * Unsafe because the caller must guarantee that the memory map is valid, all frames marked as
+     * Usable must really be unused, and the physical memory must be mapped at the given offset
+     * */
+    pub unsafe fn new(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        BootInfoFrameAllocator {
+            memory_map,
+            physical_memory_offset,
+            region: 0,
+            next: 0,
+            free_list: None,
+            allocated: 0,
+            low_frame: Self::last_low_frame(memory_map),
+            low_frame_taken: false,
+        }
+    }
+
+    // the highest usable frame below 1 MiB, the addresses real mode code can run at
+    fn last_low_frame(memory_map: &MemoryMap) -> Option<PhysFrame> {
+        memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+            .filter(|region| region.range.end_addr() <= 0x10_0000)
+            .filter(|region| region.range.end_addr() - region.range.start_addr() >= 4096)
+            .map(|region| {
+                PhysFrame::containing_address(PhysAddr::new(region.range.end_addr() - 4096))
+            })
+            .max()
+    }
+
+    // the reserved frame below 1 MiB, can only be taken once
+    pub fn take_low_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.low_frame.filter(|_| !self.low_frame_taken)?;
+        self.low_frame_taken = true;
+        self.allocated += 1;
+        Some(frame)
+    }
+
+    // the number of frames currently allocated (allocated - deallocated)
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated
+    }
+
+    // the end of the highest region of the memory map, the bootloader maps the physical memory up to it
+    pub fn physical_memory_end(&self) -> PhysAddr {
+        let end = self
+            .memory_map
+            .iter()
+            .map(|region| region.range.end_addr())
+            .max();
+        PhysAddr::new(end.unwrap_or(0))
+    }
+
+    // the total number of frames in the usable regions of the memory map
+    pub fn usable_frames(&self) -> usize {
+        self.memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+            .map(|region| {
+                (region.range.end_frame_number - region.range.start_frame_number) as usize
+            })
+            .sum()
+    }
+
+    // a pointer to the first 8 bytes of a free frame that hold the address of the next free frame
+    fn free_list_link(&self, frame: PhysFrame) -> *mut u64 {
+        (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+
+    fn next_unused_frame(&mut self) -> Option<PhysFrame> {
+        while let Some(region) = self.memory_map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable {
+                let start = region.range.start_addr();
+                let end = region.range.end_addr();
+                if self.next < start {
+                    self.next = start;
+                }
+                if self.next + 4096 <= end {
+                    let frame = PhysFrame::containing_address(PhysAddr::new(self.next));
+                    self.next += 4096;
+                    if Some(frame) == self.low_frame {
+                        continue;
+                    }
+                    return Some(frame);
+                }
+            }
+            self.region += 1;
+        }
+        None
+    }
+
+    // unsafe because the frame must be unused, it is handed out again
+    unsafe fn push_free_list(&mut self, frame: PhysFrame) {
+        let next = match self.free_list {
+            Some(next) => next.start_address().as_u64(),
+            None => FREE_LIST_END,
+        };
+        self.free_list_link(frame).write(next);
+        self.free_list = Some(frame);
+    }
+
+    /*
+     * Allocate count frames that follow each other in physical memory, for devices that read and write
+     * the memory directly (DMA) without the page tables. They are taken from the never allocated frames
+     * since the free list isn't ordered, the frames skipped at the end of a region go to the free list.
+     * */
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut first = self.next_unused_frame()?;
+        let mut len = 1;
+        while len < count {
+            let frame = self.next_unused_frame()?;
+            if frame == first + len as u64 {
+                len += 1;
+            } else {
+                for skipped in PhysFrame::range(first, first + len as u64) {
+                    unsafe { self.push_free_list(skipped) };
+                }
+                first = frame;
+                len = 1;
+            }
+        }
+        self.allocated += count;
+        Some(first)
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = match self.free_list {
+            Some(frame) => {
+                let next = unsafe { self.free_list_link(frame).read() };
+                self.free_list = match next {
+                    FREE_LIST_END => None,
+                    addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+                };
+                frame
+            }
+            None => self.next_unused_frame()?,
+        };
+        self.allocated += 1;
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    // unsafe because the caller must guarantee that the frame is unused (and not mapped anywhere)
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        self.push_free_list(frame);
+        self.allocated -= 1;
+    }
+}
+
+#[test_case]
+fn test_frame_allocator_reuses_deallocated_frames() {
+    with_frame_allocator(|allocator| {
+        let allocated = allocator.allocated_frames();
+        let first = allocator.allocate_frame().expect("out of frames");
+        let second = allocator.allocate_frame().expect("out of frames");
+        assert_ne!(first, second);
+        assert_eq!(allocator.allocated_frames(), allocated + 2);
+
+        unsafe {
+            allocator.deallocate_frame(first);
+            allocator.deallocate_frame(second);
+        }
+        assert_eq!(allocator.allocated_frames(), allocated);
+        // the free list is last in first out
+        assert_eq!(allocator.allocate_frame(), Some(second));
+        assert_eq!(allocator.allocate_frame(), Some(first));
+        unsafe {
+            allocator.deallocate_frame(first);
+            allocator.deallocate_frame(second);
+        }
+    });
+}
+
+#[test_case]
+fn test_translate_identity_mapped_vga_buffer() {
+    // the bootloader identity maps the VGA text buffer
+    let vga = VirtAddr::new(0xb8000);
+    assert_eq!(translate_addr(vga), Some(PhysAddr::new(0xb8000)));
+}
+
+#[test_case]
+fn test_map_mmio_keeps_the_offset_in_the_page() {
+    // the local APIC registers, every machine the kernel runs on has them at this address
+    let phys = PhysAddr::new(0xfee0_0020);
+    let virt = unsafe { map_mmio(phys, 8) }.expect("mapping failed");
+    assert_eq!(virt.as_u64() % 4096, 0x20);
+    assert_eq!(translate_addr(virt), Some(phys));
+}
+
+#[test_case]
+fn test_translate_physical_memory_mapping() {
+    let phys = PhysAddr::new(0x1234);
+    assert_eq!(translate_addr(phys_to_virt(phys)), Some(phys));
+}
+
+// only the touched page of a lazy region gets a frame, it is zeroed
+#[test_case]
+fn test_lazy_region_is_mapped_on_first_touch() {
+    let start = VirtAddr::new(0x_6666_0000_0000);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    unsafe { map_lazy(start..start + 2 * 4096u64, flags) };
+    assert_eq!(translate_addr(start), None);
+    let second = start + 4096u64;
+    unsafe {
+        assert_eq!(second.as_ptr::<u64>().read_volatile(), 0);
+        second.as_mut_ptr::<u64>().write_volatile(42);
+        assert_eq!(second.as_ptr::<u64>().read_volatile(), 42);
+    }
+    assert!(translate_addr(second).is_some());
+    assert_eq!(translate_addr(start), None);
+    unsafe { unmap_lazy(start..start + 2 * 4096u64) };
+    assert_eq!(translate_addr(second), None);
+}
@@ -0,0 +1,219 @@
+/*
+* The PS/2 mouse is the second (AUXILIARY) device of the PS/2 controller that also serves the keyboard. It
+* shares the data port 0x60 with the keyboard, the controller raises IRQ 12 instead of IRQ 1 for its bytes.
+* Bytes for the mouse are sent by writing 0xD4 to the command port 0x64 first, the mouse answers every
+* command with an ACK (0xFA).
+*
+* Once data reporting is enabled the mouse sends a 3 byte PACKET for every movement or button change:
+*  * byte 0: the buttons (bit 0 left, 1 right, 2 middle), bit 3 always set, the sign bits of the movement
+*    (bit 4 x, bit 5 y) and the overflow bits (bit 6 x, bit 7 y)
+*  * byte 1 and 2: the low 8 bits of the x and y movement, the movement is a 9 bit two's complement value
+*    and y grows upwards (unlike screen coordinates)
+*
+* The interrupt handler decodes the packets and queues the events in a channel (task::channel), tasks read
+* them from the receiving side as an async stream.
+* */
+use crate::interrupts;
+use crate::task::channel::{self, Receiver, Sender, TrySendError};
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+// reading gives the status, writing sends a command to the controller
+const COMMAND_PORT: u16 = 0x64;
+pub const IRQ: u8 = 12;
+
+// status bits
+const OUTPUT_FULL: u8 = 1 << 0;
+const INPUT_FULL: u8 = 1 << 1;
+
+// controller commands
+const ENABLE_AUX: u8 = 0xA8;
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const WRITE_AUX: u8 = 0xD4;
+// configuration bits
+const CONFIG_AUX_INTERRUPT: u8 = 1 << 1;
+const CONFIG_AUX_CLOCK_DISABLED: u8 = 1 << 5;
+
+// mouse commands
+const SET_DEFAULTS: u8 = 0xF6;
+const ENABLE_REPORTING: u8 = 0xF4;
+const ACK: u8 = 0xFA;
+
+// the status polls before the controller is considered absent
+const TIMEOUT: usize = 100_000;
+const EVENT_QUEUE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+// the movement since the previous event (y grows upwards) and the buttons held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    pub dy: i16,
+    pub buttons: Buttons,
+}
+
+// collects the bytes of a packet
+pub struct PacketDecoder {
+    bytes: [u8; 3],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub const fn new() -> PacketDecoder {
+        PacketDecoder {
+            bytes: [0; 3],
+            len: 0,
+        }
+    }
+
+    // feed one byte from the mouse, returns the event once the packet is complete
+    pub fn process_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // bit 3 of the first byte is always set, a byte without it can't start a packet (lost bytes)
+        if self.len == 0 && byte & 0x08 == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < 3 {
+            return None;
+        }
+        self.len = 0;
+        let [flags, x, y] = self.bytes;
+        // the movement of an overflowed packet is meaningless
+        if flags & 0xC0 != 0 {
+            return None;
+        }
+        let sign_extend = |low: u8, negative: bool| low as i16 - if negative { 0x100 } else { 0 };
+        Some(MouseEvent {
+            dx: sign_extend(x, flags & 0x10 != 0),
+            dy: sign_extend(y, flags & 0x20 != 0),
+            buttons: Buttons {
+                left: flags & 0x01 != 0,
+                right: flags & 0x02 != 0,
+                middle: flags & 0x04 != 0,
+            },
+        })
+    }
+}
+
+impl Default for PacketDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static DECODER: Mutex<PacketDecoder> = Mutex::new(PacketDecoder::new());
+// the sending side of the stream, set once by events
+static EVENTS: OnceCell<Sender<MouseEvent>> = OnceCell::uninit();
+
+fn wait_status(bit: u8, set: bool) -> Option<()> {
+    let mut status: Port<u8> = Port::new(COMMAND_PORT);
+    (0..TIMEOUT)
+        .any(|_| (unsafe { status.read() } & bit != 0) == set)
+        .then_some(())
+}
+
+fn write_command(command: u8) -> Option<()> {
+    wait_status(INPUT_FULL, false)?;
+    unsafe { Port::new(COMMAND_PORT).write(command) };
+    Some(())
+}
+
+fn write_data(byte: u8) -> Option<()> {
+    wait_status(INPUT_FULL, false)?;
+    unsafe { Port::new(DATA_PORT).write(byte) };
+    Some(())
+}
+
+fn read_data() -> Option<u8> {
+    wait_status(OUTPUT_FULL, true)?;
+    Some(unsafe { Port::new(DATA_PORT).read() })
+}
+
+fn mouse_command(command: u8) -> Option<()> {
+    write_command(WRITE_AUX)?;
+    write_data(command)?;
+    (read_data()? == ACK).then_some(())
+}
+
+fn enable() -> Option<()> {
+    write_command(ENABLE_AUX)?;
+    write_command(READ_CONFIG)?;
+    let config = read_data()?;
+    write_command(WRITE_CONFIG)?;
+    write_data((config | CONFIG_AUX_INTERRUPT) & !CONFIG_AUX_CLOCK_DISABLED)?;
+    mouse_command(SET_DEFAULTS)?;
+    mouse_command(ENABLE_REPORTING)
+}
+
+// enable the mouse and its interrupt, without a mouse (or controller) the driver stays off
+pub fn init() {
+    // the answers are polled, the keyboard interrupt handler would read them from the data port otherwise
+    let enabled = x86_64::instructions::interrupts::without_interrupts(enable);
+    if enabled.is_none() {
+        log::info!("no PS/2 mouse");
+        return;
+    }
+    interrupts::register_irq_handler(IRQ, mouse_interrupt_handler);
+}
+
+fn mouse_interrupt_handler() {
+    let byte: u8 = unsafe { Port::new(DATA_PORT).read() };
+    let Some(event) = DECODER.lock().process_byte(byte) else {
+        return;
+    };
+    // the events are dropped until a task listens
+    if let Ok(sender) = EVENTS.try_get() {
+        if let Err(TrySendError::Full(_)) = sender.try_send(event) {
+            log::warn!("mouse event queue full; dropping mouse input");
+        }
+    }
+}
+
+// the async stream of mouse events, there can only be one
+pub fn events() -> Receiver<MouseEvent> {
+    let (sender, receiver) = channel::channel(EVENT_QUEUE_SIZE);
+    EVENTS
+        .try_init_once(|| sender)
+        .expect("mouse::events should only be called once");
+    receiver
+}
+
+#[test_case]
+fn test_decode_packets() {
+    let mut decoder = PacketDecoder::new();
+    // a byte without bit 3 is skipped until a packet starts
+    assert_eq!(decoder.process_byte(0x00), None);
+    assert_eq!(decoder.process_byte(0x09), None);
+    assert_eq!(decoder.process_byte(5), None);
+    assert_eq!(
+        decoder.process_byte(3),
+        Some(MouseEvent {
+            dx: 5,
+            dy: 3,
+            buttons: Buttons {
+                left: true,
+                ..Buttons::default()
+            },
+        })
+    );
+    // negative movement, the right button
+    decoder.process_byte(0x08 | 0x10 | 0x20 | 0x02);
+    decoder.process_byte(0xFF);
+    let event = decoder.process_byte(0xF6).unwrap();
+    assert_eq!((event.dx, event.dy), (-1, -10));
+    assert!(event.buttons.right && !event.buttons.left);
+    // an overflowed packet is dropped
+    decoder.process_byte(0x08 | 0x40);
+    decoder.process_byte(0);
+    assert_eq!(decoder.process_byte(0), None);
+}
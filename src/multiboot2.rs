@@ -0,0 +1,663 @@
+/*
+* MULTIBOOT2 is the boot protocol of GRUB, so the kernel can be booted on real machines without the disk
+* image of the bootloader crate. The same kernel ELF file (with the symbols embedded, tools/embed_symbols.py)
+* is copied to /boot and booted with:
+*
+*     menuentry "rust_os" {
+*         multiboot2 /boot/rust_os log_level=debug
+*         module2 /boot/initrd.tar initrd
+*     }
+*
+* GRUB looks for the HEADER in the first 32 KiB of the file. It is a note section since the linker puts the
+* notes right after the ELF headers. GRUB loads the segments at their physical addresses (the same as the
+* virtual ones, the kernel is linked at 2 MiB) and jumps to multiboot2_start in 32 bit protected mode without
+* paging, with the magic in EAX and the physical address of the boot INFORMATION in EBX. Like the smp
+* trampoline the entry switches to long mode itself, with page tables in .bss:
+*  * the kernel (__ehdr_start.._end) and the VGA text buffer identity mapped with 4 KiB pages in the first
+*    LOW_MAPPING_SIZE bytes, so the protection module can set the permissions of the kernel pages like with
+*    the bootloader crate. Nothing else is mapped there: null pointers fault, and so does an overflow of the
+*    boot stack into the guard page below it
+*  * the first 4 GiB mapped at PHYSICAL_MEMORY_OFFSET with 2 MiB pages, the physical memory mapping of the
+*    memory module. The memory above it isn't used
+*
+* multiboot2_main turns the information into what bootloader 0.9 passes: a BootInfo with the memory map
+* (the kernel, the information and the modules are taken out of the usable memory) and the offset of the
+* physical memory, then calls the same _start as the bootloader does. The rest is handed to the modules
+* that already take it from other boot protocols: the command line to cmdline::init_with, the framebuffer
+* to framebuffer::init (init), the module named initrd (or the first one) to the initrd module.
+* acpi::init still searches the BIOS areas for the RSDP, so ACPI is only found when GRUB was booted by a BIOS.
+* */
+use crate::framebuffer::{self, FrameBufferInfo, PixelFormat};
+use bootloader::bootinfo::{FrameRange, MemoryMap, MemoryRegion, MemoryRegionType};
+use bootloader::BootInfo;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const HEADER_MAGIC: u32 = 0xE852_50D6;
+// the value of EAX when a multiboot2 bootloader started the kernel
+const BOOTLOADER_MAGIC: u32 = 0x36D7_6289;
+
+pub const LOW_MAPPING_SIZE: u64 = 32 * 1024 * 1024;
+// identity mapped like the bootloader crate does, the VGA writer uses it at its physical address
+const VGA_BUFFER: core::ops::Range<u64> = 0xB8000..0xC0000;
+pub const PHYSICAL_MEMORY_OFFSET: u64 = 0xFFFF_8000_0000_0000;
+// the size of the physical memory mapping
+pub const MAPPED_MEMORY: u64 = 4 * 1024 * 1024 * 1024;
+// the capacity of the MemoryMap of bootloader 0.9
+const MAX_REGIONS: usize = 64;
+const MAX_MODULES: usize = 8;
+
+const TAG_END: u32 = 0;
+const TAG_CMDLINE: u32 = 1;
+const TAG_BOOTLOADER_NAME: u32 = 2;
+const TAG_MODULE: u32 = 3;
+const TAG_MEMORY_MAP: u32 = 6;
+const TAG_FRAMEBUFFER: u32 = 8;
+
+const MEMORY_AVAILABLE: u32 = 1;
+const MEMORY_ACPI_RECLAIMABLE: u32 = 3;
+const MEMORY_ACPI_NVS: u32 = 4;
+const MEMORY_BAD: u32 = 5;
+
+const FRAMEBUFFER_RGB: u8 = 1;
+
+core::arch::global_asm!(
+    r#"
+.section .note.multiboot2, "a", @note
+.align 8
+multiboot2_header:
+    .long {header_magic}
+    # architecture 0, i386 protected mode
+    .long 0
+    .long multiboot2_header_end - multiboot2_header
+    # the checksum makes the sum of the first four fields 0
+    .long 0x100000000 - ({header_magic} + (multiboot2_header_end - multiboot2_header))
+    # the entry address tag, the ELF entry is the _start of the bootloader crate
+    .word 3
+    .word 0
+    .long 12
+    .long multiboot2_start
+    .align 8
+    # the end tag
+    .word 0
+    .word 0
+    .long 8
+multiboot2_header_end:
+
+.section .text.multiboot2, "ax"
+.code32
+.global multiboot2_start
+multiboot2_start:
+    cli
+    cld
+    mov $multiboot2_stack_top, %esp
+    # the arguments of multiboot2_main, cpuid doesn't change them
+    mov %eax, %edi
+    mov %ebx, %esi
+    mov $0x80000000, %eax
+    cpuid
+    cmp $0x80000001, %eax
+    jb multiboot2_no_long_mode
+    mov $0x80000001, %eax
+    cpuid
+    test $(1 << 29), %edx
+    jz multiboot2_no_long_mode
+
+    # the low page tables, entry i maps frame i (present, writable) for the pages of the kernel
+    mov $__ehdr_start, %eax
+    and $~0xFFF, %eax
+1:
+    mov %eax, %edx
+    shr $12, %edx
+    lea 3(%eax), %ecx
+    mov %ecx, multiboot2_low_pt(, %edx, 8)
+    add $4096, %eax
+    cmp $_end, %eax
+    jb 1b
+    # the stack guard page is part of the kernel's .bss
+    mov $multiboot2_stack_guard, %edx
+    shr $12, %edx
+    movl $0, multiboot2_low_pt(, %edx, 8)
+    # the VGA text buffer
+    mov $({vga_start} + 3), %eax
+    mov $({vga_start} / 4096), %edx
+7:
+    mov %eax, multiboot2_low_pt(, %edx, 8)
+    add $4096, %eax
+    inc %edx
+    cmp $({vga_end} / 4096), %edx
+    jb 7b
+    mov $multiboot2_low_pd, %edx
+    mov $(multiboot2_low_pt + 3), %eax
+    mov $({low_mapping_size} / 0x200000), %ecx
+2:
+    mov %eax, (%edx)
+    add $4096, %eax
+    add $8, %edx
+    loop 2b
+    movl $(multiboot2_low_pd + 3), multiboot2_low_pdpt
+
+    # the physical memory mapping, 2 MiB pages (present, writable, huge)
+    mov $multiboot2_high_pd, %edx
+    mov $0x83, %eax
+    mov $2048, %ecx
+3:
+    mov %eax, (%edx)
+    add $0x200000, %eax
+    add $8, %edx
+    loop 3b
+    mov $multiboot2_high_pdpt, %edx
+    mov $(multiboot2_high_pd + 3), %eax
+    mov $4, %ecx
+4:
+    mov %eax, (%edx)
+    add $4096, %eax
+    add $8, %edx
+    loop 4b
+    movl $(multiboot2_low_pdpt + 3), multiboot2_pml4
+    movl $(multiboot2_high_pdpt + 3), multiboot2_pml4 + ({physical_memory_offset_index} * 8)
+
+    # PAE
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+    mov $multiboot2_pml4, %eax
+    mov %eax, %cr3
+    # EFER.LME
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $(1 << 8), %eax
+    wrmsr
+    # paging
+    mov %cr0, %eax
+    or $(1 << 31), %eax
+    mov %eax, %cr0
+    lgdt multiboot2_gdt_pointer
+    ljmp $0x08, $multiboot2_long
+
+# "NO LM" in white on red, there is no way to go on without long mode
+multiboot2_no_long_mode:
+    movl $0x4F4F4F4E, 0xB8000
+    movl $0x4F4C4F20, 0xB8004
+    movw $0x4F4D, 0xB8008
+5:
+    hlt
+    jmp 5b
+
+.code64
+multiboot2_long:
+    xor %ax, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %ax, %fs
+    mov %ax, %gs
+    # the upper half of the registers is undefined after the mode switch
+    mov %edi, %edi
+    mov %esi, %esi
+    # a zero frame pointer ends the backtraces
+    xor %ebp, %ebp
+    call multiboot2_main
+6:
+    hlt
+    jmp 6b
+
+.align 8
+multiboot2_gdt:
+    .quad 0
+    .quad 0x00AF9A000000FFFF
+multiboot2_gdt_pointer:
+    .word 15
+    .long multiboot2_gdt
+
+.section .bss.multiboot2, "aw", @nobits
+.align 4096
+multiboot2_pml4:
+    .space 4096
+multiboot2_low_pdpt:
+    .space 4096
+multiboot2_low_pd:
+    .space 4096
+multiboot2_low_pt:
+    .space {low_mapping_size} / 512
+multiboot2_high_pdpt:
+    .space 4096
+multiboot2_high_pd:
+    .space 4 * 4096
+# not mapped, an overflow of the stack faults instead of writing the page tables
+.global multiboot2_stack_guard
+multiboot2_stack_guard:
+    .space 4096
+multiboot2_stack:
+    .space {stack_size}
+multiboot2_stack_top:
+
+.section .text
+"#,
+    header_magic = const HEADER_MAGIC,
+    low_mapping_size = const LOW_MAPPING_SIZE,
+    vga_start = const VGA_BUFFER.start,
+    vga_end = const VGA_BUFFER.end,
+    physical_memory_offset_index = const (PHYSICAL_MEMORY_OFFSET >> 39) & 511,
+    stack_size = const crate::backtrace::KERNEL_STACK_SIZE,
+    options(att_syntax)
+);
+
+extern "C" {
+    // defined by the entry_point macro of the bootloader crate in every kernel executable
+    fn _start(boot_info: &'static BootInfo) -> !;
+    // the start and the end of the loaded kernel, defined by the linker
+    static __ehdr_start: u8;
+    static _end: u8;
+    static multiboot2_stack_guard: u8;
+}
+
+// the physical address of the boot information, 0 if the kernel wasn't booted with multiboot2
+static INFO_ADDRESS: AtomicU64 = AtomicU64::new(0);
+static BOOT_INFO: OnceCell<BootInfo> = OnceCell::uninit();
+
+fn read_u8(bytes: &[u8], offset: usize) -> u8 {
+    bytes[offset]
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// the NUL terminated string at the offset
+fn read_str(bytes: &[u8], offset: usize) -> &str {
+    let bytes = bytes.get(offset..).unwrap_or(&[]);
+    let len = bytes
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(bytes.len());
+    core::str::from_utf8(&bytes[..len]).unwrap_or("")
+}
+
+// a file GRUB loaded with module2, the addresses are physical
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Module<'a> {
+    pub start: u64,
+    pub end: u64,
+    // the arguments after the path of the module2 command
+    pub name: &'a str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryArea {
+    pub base: u64,
+    pub length: u64,
+    pub kind: u32,
+}
+
+// the boot information: the total size and a reserved field, then the tags (type, size, data) 8 byte aligned
+pub struct BootInformation<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> BootInformation<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        let size = read_u32(bytes, 0) as usize;
+        BootInformation {
+            bytes: &bytes[..size.clamp(8, bytes.len())],
+        }
+    }
+
+    /*
+     * Unsafe because the address must be the boot information GRUB passed, it is read through the physical
+     * memory mapping.
+     * */
+    unsafe fn from_address(address: u64) -> BootInformation<'static> {
+        let pointer = (PHYSICAL_MEMORY_OFFSET + address) as *const u8;
+        let size = (pointer as *const u32).read() as usize;
+        BootInformation::new(core::slice::from_raw_parts(pointer, size))
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    // the type and the data of every tag
+    fn tags(&self) -> impl Iterator<Item = (u32, &'a [u8])> {
+        let bytes = self.bytes;
+        let mut offset = 8;
+        core::iter::from_fn(move || {
+            let header = bytes.get(offset..offset + 8)?;
+            let kind = read_u32(header, 0);
+            let size = read_u32(header, 4) as usize;
+            if kind == TAG_END || size < 8 {
+                return None;
+            }
+            let data = bytes.get(offset + 8..offset + size)?;
+            offset = (offset + size).next_multiple_of(8);
+            Some((kind, data))
+        })
+    }
+
+    fn tag(&self, kind: u32) -> Option<&'a [u8]> {
+        self.tags()
+            .find(|&(tag, _)| tag == kind)
+            .map(|(_, data)| data)
+    }
+
+    pub fn cmdline(&self) -> Option<&'a str> {
+        self.tag(TAG_CMDLINE).map(|data| read_str(data, 0))
+    }
+
+    pub fn bootloader_name(&self) -> Option<&'a str> {
+        self.tag(TAG_BOOTLOADER_NAME).map(|data| read_str(data, 0))
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = Module<'a>> {
+        self.tags()
+            .filter(|&(kind, data)| kind == TAG_MODULE && data.len() >= 8)
+            .map(|(_, data)| Module {
+                start: read_u32(data, 0) as u64,
+                end: read_u32(data, 4) as u64,
+                name: read_str(data, 8),
+            })
+    }
+
+    pub fn memory_areas(&self) -> impl Iterator<Item = MemoryArea> + 'a {
+        let data = self.tag(TAG_MEMORY_MAP).unwrap_or(&[]);
+        let entry_size = data.get(..4).map_or(0, |bytes| read_u32(bytes, 0) as usize);
+        data.get(8..)
+            .unwrap_or(&[])
+            .chunks_exact(entry_size.max(24))
+            .map(|entry| MemoryArea {
+                base: read_u64(entry, 0),
+                length: read_u64(entry, 8),
+                kind: read_u32(entry, 16),
+            })
+    }
+
+    // the physical address and the layout of a linear framebuffer, None in EGA text mode
+    pub fn framebuffer(&self) -> Option<(u64, FrameBufferInfo)> {
+        let data = self.tag(TAG_FRAMEBUFFER)?;
+        if data.len() < 30 || read_u8(data, 21) != FRAMEBUFFER_RGB {
+            return None;
+        }
+        let bytes_per_pixel = (read_u8(data, 20) as usize).div_ceil(8);
+        // the positions of the lowest bit of red and blue
+        let pixel_format = match (read_u8(data, 24), read_u8(data, 28)) {
+            (0, 16) => PixelFormat::Rgb,
+            (16, 0) => PixelFormat::Bgr,
+            _ => return None,
+        };
+        if !(3..=4).contains(&bytes_per_pixel) {
+            return None;
+        }
+        Some((
+            read_u64(data, 0),
+            FrameBufferInfo {
+                width: read_u32(data, 12) as usize,
+                height: read_u32(data, 16) as usize,
+                stride: read_u32(data, 8) as usize / bytes_per_pixel,
+                bytes_per_pixel,
+                pixel_format,
+            },
+        ))
+    }
+}
+
+fn region_type(kind: u32) -> MemoryRegionType {
+    match kind {
+        MEMORY_AVAILABLE => MemoryRegionType::Usable,
+        MEMORY_ACPI_RECLAIMABLE => MemoryRegionType::AcpiReclaimable,
+        MEMORY_ACPI_NVS => MemoryRegionType::AcpiNvs,
+        MEMORY_BAD => MemoryRegionType::BadMemory,
+        _ => MemoryRegionType::Reserved,
+    }
+}
+
+fn add_region(map: &mut MemoryMap, start: u64, end: u64, region_type: MemoryRegionType) {
+    let end = end.min(MAPPED_MEMORY);
+    // the map has no room for more, the memory is left out
+    if start < end && map.len() < MAX_REGIONS {
+        map.add_region(MemoryRegion {
+            range: FrameRange::new(start, end),
+            region_type,
+        });
+    }
+}
+
+/*
+* The memory map of the BootInfo: the available areas without the reserved ranges (the kernel, the
+* information, the modules and frame 0 with the real mode data of the BIOS) become usable regions, the
+* reserved ranges and the other areas get their own regions. Usable regions only contain whole frames,
+* reserved ranges are extended to whole frames. Nothing above MAPPED_MEMORY is in the map.
+* */
+fn memory_map(
+    areas: impl Iterator<Item = MemoryArea>,
+    reserved: &mut [(u64, u64, MemoryRegionType)],
+) -> MemoryMap {
+    let mut map = MemoryMap::new();
+    for range in reserved.iter_mut() {
+        range.0 = range.0 / 4096 * 4096;
+        range.1 = range.1.next_multiple_of(4096);
+    }
+    reserved.sort_unstable_by_key(|range| range.0);
+    for area in areas {
+        let end = area.base.saturating_add(area.length);
+        if area.kind != MEMORY_AVAILABLE {
+            add_region(
+                &mut map,
+                area.base / 4096 * 4096,
+                end.next_multiple_of(4096),
+                region_type(area.kind),
+            );
+            continue;
+        }
+        let (mut start, end) = (area.base.next_multiple_of(4096), end / 4096 * 4096);
+        for &(reserved_start, reserved_end, _) in reserved.iter() {
+            if reserved_end <= start || reserved_start >= end {
+                continue;
+            }
+            add_region(&mut map, start, reserved_start, MemoryRegionType::Usable);
+            start = start.max(reserved_end);
+        }
+        add_region(&mut map, start, end, MemoryRegionType::Usable);
+    }
+    for &(start, end, region_type) in reserved.iter() {
+        add_region(&mut map, start, end, region_type);
+    }
+    map
+}
+
+// called by multiboot2_start in long mode with the page tables of the entry
+#[no_mangle]
+extern "C" fn multiboot2_main(magic: u32, info_address: u32) -> ! {
+    // jumped to by something that isn't a multiboot2 bootloader, there is no information to boot with
+    if magic != BOOTLOADER_MAGIC {
+        crate::hlt_loop();
+    }
+    INFO_ADDRESS.store(info_address as u64, Ordering::Relaxed);
+    let info = unsafe { BootInformation::from_address(info_address as u64) };
+    // lib::init reads the command line first, before that init_with sets it
+    if let Some(cmdline) = info.cmdline() {
+        crate::cmdline::init_with(cmdline);
+    }
+
+    let mut reserved = [(0, 0, MemoryRegionType::Empty); 3 + MAX_MODULES];
+    reserved[0] = (0, 4096, MemoryRegionType::FrameZero);
+    reserved[1] = (
+        (&raw const __ehdr_start) as u64,
+        (&raw const _end) as u64,
+        MemoryRegionType::Kernel,
+    );
+    reserved[2] = (
+        info_address as u64,
+        info_address as u64 + info.size() as u64,
+        MemoryRegionType::BootInfo,
+    );
+    for (slot, module) in reserved[3..].iter_mut().zip(info.modules()) {
+        *slot = (module.start, module.end, MemoryRegionType::Package);
+    }
+    let memory_map = memory_map(info.memory_areas(), &mut reserved);
+    let boot_info = BOOT_INFO
+        .try_get_or_init(|| BootInfo::new(memory_map, None, 0, PHYSICAL_MEMORY_OFFSET))
+        .expect("multiboot2_main runs only once");
+    unsafe { _start(boot_info) }
+}
+
+// the information GRUB passed, None if the kernel was booted by the bootloader crate
+pub fn boot_information() -> Option<BootInformation<'static>> {
+    let address = INFO_ADDRESS.load(Ordering::Relaxed);
+    (address != 0).then(|| unsafe { BootInformation::from_address(address) })
+}
+
+// the unmapped page below the boot stack, None if the kernel was booted by the bootloader crate
+pub fn stack_guard() -> Option<u64> {
+    boot_information().map(|_| (&raw const multiboot2_stack_guard) as u64)
+}
+
+// the initrd module (the one named initrd or else the first one) through the physical memory mapping
+pub fn initrd() -> Option<&'static [u8]> {
+    let info = boot_information()?;
+    let module = info
+        .modules()
+        .find(|module| module.name == "initrd")
+        .or_else(|| info.modules().next())?;
+    if module.end <= module.start || module.end > MAPPED_MEMORY {
+        log::warn!("multiboot2: the module {} isn't usable", module.name);
+        return None;
+    }
+    let start = (PHYSICAL_MEMORY_OFFSET + module.start) as *const u8;
+    // the module frames are reserved in the memory map, nothing else uses them
+    Some(unsafe { core::slice::from_raw_parts(start, (module.end - module.start) as usize) })
+}
+
+// switch to the framebuffer GRUB set up, called by lib::init after the consoles are registered
+pub fn init() {
+    let Some(info) = boot_information() else {
+        return;
+    };
+    log::info!(
+        "multiboot2: booted by {}, {} modules",
+        info.bootloader_name().unwrap_or("an unknown bootloader"),
+        info.modules().count()
+    );
+    let ignored: u64 = info
+        .memory_areas()
+        .filter(|area| area.kind == MEMORY_AVAILABLE)
+        .map(|area| (area.base + area.length).saturating_sub(area.base.max(MAPPED_MEMORY)))
+        .sum();
+    if ignored > 0 {
+        log::warn!(
+            "multiboot2: {} MiB of memory above 4 GiB are not used",
+            ignored / (1024 * 1024)
+        );
+    }
+    if let Some((address, framebuffer_info)) = info.framebuffer() {
+        let size = framebuffer_info.size();
+        if address + size as u64 > MAPPED_MEMORY {
+            log::warn!("multiboot2: the framebuffer at {:#x} isn't mapped", address);
+            return;
+        }
+        // GRUB doesn't use the framebuffer anymore and it isn't in the usable memory
+        unsafe {
+            let buffer = (PHYSICAL_MEMORY_OFFSET + address) as *mut u8;
+            framebuffer::init(
+                core::slice::from_raw_parts_mut(buffer, size),
+                framebuffer_info,
+            );
+        }
+    }
+}
+
+#[test_case]
+fn test_parse_boot_information() {
+    use alloc::vec::Vec;
+
+    fn tag(info: &mut Vec<u8>, kind: u32, data: &[u8]) {
+        info.extend_from_slice(&kind.to_le_bytes());
+        info.extend_from_slice(&(8 + data.len() as u32).to_le_bytes());
+        info.extend_from_slice(data);
+        info.resize(info.len().next_multiple_of(8), 0);
+    }
+    let mut info = Vec::from([0u8; 8]);
+    tag(&mut info, TAG_CMDLINE, b"log_level=debug\0");
+    tag(&mut info, TAG_BOOTLOADER_NAME, b"GRUB 2.12\0");
+    let mut module = Vec::new();
+    module.extend_from_slice(&0x90_0000u32.to_le_bytes());
+    module.extend_from_slice(&0x90_1800u32.to_le_bytes());
+    module.extend_from_slice(b"initrd\0");
+    tag(&mut info, TAG_MODULE, &module);
+    let mut memory = Vec::new();
+    memory.extend_from_slice(&24u32.to_le_bytes());
+    memory.extend_from_slice(&0u32.to_le_bytes());
+    for (base, length, kind) in [
+        (0u64, 0x9_FC00u64, MEMORY_AVAILABLE),
+        (0x9_FC00, 0x6_0400, 2),
+        (0x10_0000, 0x7F0_0000, MEMORY_AVAILABLE),
+        (0x1_0000_0000, 0x4000_0000, MEMORY_AVAILABLE),
+    ] {
+        memory.extend_from_slice(&base.to_le_bytes());
+        memory.extend_from_slice(&length.to_le_bytes());
+        memory.extend_from_slice(&kind.to_le_bytes());
+        memory.extend_from_slice(&0u32.to_le_bytes());
+    }
+    tag(&mut info, TAG_MEMORY_MAP, &memory);
+    // 1024x768, 32 bits per pixel, blue in the lowest byte
+    let mut framebuffer = Vec::new();
+    framebuffer.extend_from_slice(&0xFD00_0000u64.to_le_bytes());
+    for value in [4096u32, 1024, 768] {
+        framebuffer.extend_from_slice(&value.to_le_bytes());
+    }
+    framebuffer.extend_from_slice(&[32, FRAMEBUFFER_RGB, 0, 0, 16, 8, 8, 8, 0, 8]);
+    tag(&mut info, TAG_FRAMEBUFFER, &framebuffer);
+    tag(&mut info, TAG_END, &[]);
+    let size = info.len() as u32;
+    info[..4].copy_from_slice(&size.to_le_bytes());
+
+    let info = BootInformation::new(&info);
+    assert_eq!(info.cmdline(), Some("log_level=debug"));
+    assert_eq!(info.bootloader_name(), Some("GRUB 2.12"));
+    let modules: Vec<Module> = info.modules().collect();
+    assert_eq!(
+        modules,
+        [Module {
+            start: 0x90_0000,
+            end: 0x90_1800,
+            name: "initrd"
+        }]
+    );
+    let (address, framebuffer) = info.framebuffer().unwrap();
+    assert_eq!(address, 0xFD00_0000);
+    assert_eq!((framebuffer.width, framebuffer.stride), (1024, 1024));
+    assert_eq!(framebuffer.pixel_format, PixelFormat::Bgr);
+
+    let mut reserved = [
+        (0, 4096, MemoryRegionType::FrameZero),
+        (0x20_0000, 0x80_0000, MemoryRegionType::Kernel),
+        (0x90_0000, 0x90_1800, MemoryRegionType::Package),
+    ];
+    let map = memory_map(info.memory_areas(), &mut reserved);
+    let regions: Vec<(u64, u64, MemoryRegionType)> = map
+        .iter()
+        .map(|region| {
+            (
+                region.range.start_addr(),
+                region.range.end_addr(),
+                region.region_type,
+            )
+        })
+        .collect();
+    assert_eq!(
+        regions,
+        [
+            (0, 0x1000, MemoryRegionType::FrameZero),
+            (0x1000, 0x9_F000, MemoryRegionType::Usable),
+            (0x9_F000, 0x10_0000, MemoryRegionType::Reserved),
+            (0x10_0000, 0x20_0000, MemoryRegionType::Usable),
+            (0x20_0000, 0x80_0000, MemoryRegionType::Kernel),
+            (0x80_0000, 0x90_0000, MemoryRegionType::Usable),
+            (0x90_0000, 0x90_2000, MemoryRegionType::Package),
+            (0x90_2000, 0x800_0000, MemoryRegionType::Usable),
+        ]
+    );
+}
@@ -0,0 +1,281 @@
+/*
+* A small network stack on top of the e1000 card, the layers wrap each other in the frames:
+*
+*     Ethernet (destination MAC, source MAC, type)
+*       ARP                            the MAC address of an IPv4 address on the local network (arp)
+*       IPv4 (addresses, protocol)     routing to the local network or the gateway (ipv4)
+*         ICMP                         echo requests (ping) get a reply
+*         UDP (ports, length)          datagram sockets (udp), the DHCP client (dhcp)
+*         TCP (ports, sequence numbers) listening sockets and connections (tcp)
+*
+* The net task (run) takes the frames the card received and hands them up the layers, the data for a socket
+* is kept in its buffer and the task waiting for it is woken. Sending happens in the calling task, the packet
+* is built and given to the card right away (a packet to an address whose MAC isn't known yet waits in the
+* ARP module until the answer arrives).
+*
+* The interface gets its configuration from a DHCP server (the DHCP task, QEMU's user network has one and
+* hands out 10.0.2.15/24 with the host as the gateway 10.0.2.2), or from the command line:
+*  * ip=<dhcp|<address>/<prefix length>>  the address and the size of the local network (dhcp)
+*  * gateway=<address>  where packets for other networks are sent
+*  * dns=<address>  the name server
+* QEMU forwards host ports to the guest with hostfwd, e.g. hostfwd=tcp::5555-:7 for the tcpecho server.
+* */
+use crate::cmdline;
+use crate::e1000::{self, SendError};
+use crate::task::timer::timeout;
+use alloc::vec::Vec;
+use core::fmt;
+use core::future::poll_fn;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::Poll;
+use spin::Mutex;
+
+pub use core::net::{Ipv4Addr, SocketAddrV4};
+
+pub mod arp;
+pub mod dhcp;
+pub mod ipv4;
+pub mod tcp;
+pub mod udp;
+
+pub type MacAddress = [u8; 6];
+pub const BROADCAST_MAC: MacAddress = [0xFF; 6];
+
+const ETHERNET_HEADER_SIZE: usize = 14;
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_ARP: u16 = 0x0806;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    NoDevice,
+    // the packet doesn't fit in a frame
+    TooLarge,
+    // a socket is already bound to the port
+    AddressInUse,
+    // no route to the address (no gateway)
+    Unreachable,
+    // the connection was closed or reset
+    NotConnected,
+    Device(SendError),
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::NoDevice => write!(f, "no network card"),
+            NetError::TooLarge => write!(f, "the packet is too large"),
+            NetError::AddressInUse => write!(f, "the port is in use"),
+            NetError::Unreachable => write!(f, "the network is unreachable"),
+            NetError::NotConnected => write!(f, "not connected"),
+            NetError::Device(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Config {
+    pub mac: MacAddress,
+    // unspecified (0.0.0.0) until the interface is configured
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+}
+
+impl Config {
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(
+            u32::MAX
+                .checked_shl(32 - self.prefix_len as u32)
+                .unwrap_or(0),
+        )
+    }
+
+    // the address is on the local network, it is reached without the gateway
+    pub fn is_local(&self, address: Ipv4Addr) -> bool {
+        (u32::from(address) ^ u32::from(self.address)) & u32::from(self.netmask()) == 0
+    }
+
+    // the broadcast address of the local network
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.address) | !u32::from(self.netmask()))
+    }
+}
+
+static CONFIG: Mutex<Config> = Mutex::new(Config {
+    mac: [0; 6],
+    address: Ipv4Addr::UNSPECIFIED,
+    prefix_len: 0,
+    gateway: None,
+    dns: None,
+});
+
+// the interface is configured by the DHCP task
+static DHCP: AtomicBool = AtomicBool::new(false);
+
+pub fn config() -> Config {
+    *CONFIG.lock()
+}
+
+// set the address of the interface, the MAC address stays the card's
+pub fn configure(
+    address: Ipv4Addr,
+    prefix_len: u8,
+    gateway: Option<Ipv4Addr>,
+    dns: Option<Ipv4Addr>,
+) {
+    let mut config = CONFIG.lock();
+    config.address = address;
+    config.prefix_len = prefix_len.min(32);
+    config.gateway = gateway;
+    config.dns = dns;
+}
+
+pub fn uses_dhcp() -> bool {
+    DHCP.load(Ordering::Relaxed)
+}
+
+// the internet checksum (RFC 1071): the ones' complement of the ones' complement sum of the 16 bit words
+pub fn checksum(parts: &[&[u8]]) -> u16 {
+    let mut sum = 0u64;
+    // the high byte of a word split between two parts
+    let mut high: Option<u8> = None;
+    for &byte in parts.iter().flat_map(|part| part.iter()) {
+        match high.take() {
+            Some(first) => sum += u16::from_be_bytes([first, byte]) as u64,
+            None => high = Some(byte),
+        }
+    }
+    if let Some(first) = high {
+        sum += (first as u64) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+pub(crate) fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn read_address(data: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::from(read_u32(data, offset))
+}
+
+// send an Ethernet frame from the card's address
+fn send_ethernet(destination: MacAddress, ethertype: u16, payload: &[u8]) -> Result<(), NetError> {
+    let nic = e1000::nic().ok_or(NetError::NoDevice)?;
+    if ETHERNET_HEADER_SIZE + payload.len() > e1000::MAX_FRAME_SIZE {
+        return Err(NetError::TooLarge);
+    }
+    let mut frame = Vec::with_capacity(ETHERNET_HEADER_SIZE + payload.len());
+    frame.extend_from_slice(&destination);
+    frame.extend_from_slice(&nic.mac_address());
+    frame.extend_from_slice(&ethertype.to_be_bytes());
+    frame.extend_from_slice(payload);
+    nic.send_frame(&frame).map_err(NetError::Device)
+}
+
+// hand a received frame to its protocol, frames the stack doesn't understand are dropped
+fn handle_frame(frame: &[u8]) {
+    if frame.len() < ETHERNET_HEADER_SIZE {
+        return;
+    }
+    let payload = &frame[ETHERNET_HEADER_SIZE..];
+    match read_u16(frame, 12) {
+        ETHERTYPE_ARP => arp::handle(payload),
+        ETHERTYPE_IPV4 => ipv4::handle(payload),
+        _ => {}
+    }
+}
+
+// the net task also looks for frames this often, the card may have no working interrupt line
+const RX_POLL_MS: u64 = 10;
+
+// the net task, it handles the received frames forever
+pub async fn run() {
+    loop {
+        let frame = timeout(
+            RX_POLL_MS,
+            poll_fn(|context| {
+                if let Some(frame) = e1000::recv_frame() {
+                    return Poll::Ready(frame);
+                }
+                e1000::register_waker(context.waker());
+                match e1000::recv_frame() {
+                    Some(frame) => Poll::Ready(frame),
+                    None => Poll::Pending,
+                }
+            }),
+        )
+        .await;
+        if let Some(frame) = frame {
+            handle_frame(&frame);
+        }
+    }
+}
+
+// <address>/<prefix length>, the prefix length is 32 without it
+fn parse_cidr(value: &str) -> Option<(Ipv4Addr, u8)> {
+    let (address, prefix_len) = value.split_once('/').unwrap_or((value, "32"));
+    let prefix_len = prefix_len.parse().ok().filter(|&len| len <= 32)?;
+    Some((address.parse().ok()?, prefix_len))
+}
+
+// configure the interface, without a network card there is no network
+// the kernel spawns the net and DHCP tasks with its other tasks, the tests don't run them
+pub fn init() {
+    let Some(nic) = e1000::nic() else {
+        return;
+    };
+    CONFIG.lock().mac = nic.mac_address();
+    let value = cmdline::get("ip").unwrap_or("dhcp");
+    if value == "dhcp" {
+        DHCP.store(true, Ordering::Relaxed);
+        return;
+    }
+    let Some((address, prefix_len)) = parse_cidr(value) else {
+        log::warn!("net: invalid ip={}", value);
+        return;
+    };
+    let gateway = cmdline::parse("gateway");
+    configure(address, prefix_len, gateway, cmdline::parse("dns"));
+    log::info!(
+        "net: {}/{} gateway {}",
+        address,
+        prefix_len,
+        gateway.unwrap_or(Ipv4Addr::UNSPECIFIED)
+    );
+}
+
+#[test_case]
+fn test_checksum_and_config() {
+    // the example of RFC 1071 section 3
+    let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+    assert_eq!(checksum(&[&data]), !0xddf2);
+    // the sum doesn't depend on where the parts are split
+    assert_eq!(checksum(&[&data[..3], &data[3..]]), !0xddf2);
+    assert_eq!(checksum(&[&data[..7]]), !0xddf2u16.wrapping_sub(0xf7));
+
+    let config = Config {
+        mac: [0; 6],
+        address: Ipv4Addr::new(10, 0, 2, 15),
+        prefix_len: 24,
+        gateway: None,
+        dns: None,
+    };
+    assert_eq!(config.netmask(), Ipv4Addr::new(255, 255, 255, 0));
+    assert_eq!(config.broadcast(), Ipv4Addr::new(10, 0, 2, 255));
+    assert!(config.is_local(Ipv4Addr::new(10, 0, 2, 2)));
+    assert!(!config.is_local(Ipv4Addr::new(10, 0, 3, 2)));
+    assert_eq!(
+        parse_cidr("192.168.1.2/16"),
+        Some((Ipv4Addr::new(192, 168, 1, 2), 16))
+    );
+    assert_eq!(parse_cidr("1.2.3.4/33"), None);
+}
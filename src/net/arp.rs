@@ -0,0 +1,141 @@
+/*
+* The address resolution protocol (RFC 826) finds the MAC address of an IPv4 address on the local network.
+* A request is broadcast to all machines ("who has 10.0.2.2? tell 10.0.2.15"), the one with the address
+* answers with a reply sent directly to the asker. The packet for Ethernet and IPv4 is 28 bytes:
+*
+*     0 hardware type (1), 2 protocol type (0x0800), 4 hardware size (6), 5 protocol size (4),
+*     6 operation (1 request, 2 reply), 8 sender MAC, 14 sender IP, 18 target MAC, 24 target IP
+*
+* The answers are kept in the CACHE. A packet to an address that isn't in it waits in the pending list while
+* the request is out and is sent once the reply arrives, the oldest ones are dropped if too many wait.
+* */
+use super::{config, read_address, read_u16, send_ethernet, Ipv4Addr, MacAddress, NetError};
+use super::{BROADCAST_MAC, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const PACKET_SIZE: usize = 28;
+const HARDWARE_ETHERNET: u16 = 1;
+const OPERATION_REQUEST: u16 = 1;
+const OPERATION_REPLY: u16 = 2;
+const MAX_PENDING: usize = 16;
+
+struct Arp {
+    cache: BTreeMap<Ipv4Addr, MacAddress>,
+    // the IPv4 packets waiting for the MAC address of their next hop
+    pending: Vec<(Ipv4Addr, Vec<u8>)>,
+}
+
+static ARP: Mutex<Arp> = Mutex::new(Arp {
+    cache: BTreeMap::new(),
+    pending: Vec::new(),
+});
+
+fn build(operation: u16, target_mac: MacAddress, target_address: Ipv4Addr) -> [u8; PACKET_SIZE] {
+    let config = config();
+    let mut packet = [0u8; PACKET_SIZE];
+    packet[0..2].copy_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+    packet[2..4].copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+    packet[4] = 6;
+    packet[5] = 4;
+    packet[6..8].copy_from_slice(&operation.to_be_bytes());
+    packet[8..14].copy_from_slice(&config.mac);
+    packet[14..18].copy_from_slice(&config.address.octets());
+    packet[18..24].copy_from_slice(&target_mac);
+    packet[24..28].copy_from_slice(&target_address.octets());
+    packet
+}
+
+// the MAC address of the address if it is known
+pub fn lookup(address: Ipv4Addr) -> Option<MacAddress> {
+    ARP.lock().cache.get(&address).copied()
+}
+
+// the known addresses
+pub fn entries() -> Vec<(Ipv4Addr, MacAddress)> {
+    let arp = ARP.lock();
+    arp.cache.iter().map(|(&ip, &mac)| (ip, mac)).collect()
+}
+
+// broadcast a request for the address
+pub fn request(address: Ipv4Addr) -> Result<(), NetError> {
+    send_ethernet(
+        BROADCAST_MAC,
+        ETHERTYPE_ARP,
+        &build(OPERATION_REQUEST, [0; 6], address),
+    )
+}
+
+// send an IPv4 packet to the next hop, it waits for the MAC address if it isn't known yet
+pub(crate) fn send(next_hop: Ipv4Addr, packet: Vec<u8>) -> Result<(), NetError> {
+    if let Some(mac) = lookup(next_hop) {
+        return send_ethernet(mac, ETHERTYPE_IPV4, &packet);
+    }
+    {
+        let mut arp = ARP.lock();
+        if arp.pending.len() == MAX_PENDING {
+            arp.pending.remove(0);
+        }
+        arp.pending.push((next_hop, packet));
+    }
+    request(next_hop)
+}
+
+pub(crate) fn handle(packet: &[u8]) {
+    if packet.len() < PACKET_SIZE
+        || read_u16(packet, 0) != HARDWARE_ETHERNET
+        || read_u16(packet, 2) != ETHERTYPE_IPV4
+        || packet[4] != 6
+        || packet[5] != 4
+    {
+        return;
+    }
+    let operation = read_u16(packet, 6);
+    let sender_mac: MacAddress = packet[8..14].try_into().unwrap();
+    let sender = read_address(packet, 14);
+    let target = read_address(packet, 24);
+    let config = config();
+    let for_us = !config.address.is_unspecified() && target == config.address;
+
+    let waiting = {
+        let mut arp = ARP.lock();
+        // the sender is remembered if it talks to us or is known already (RFC 826 merge rule)
+        if !sender.is_unspecified() && (for_us || arp.cache.contains_key(&sender)) {
+            arp.cache.insert(sender, sender_mac);
+        }
+        let (waiting, rest) = core::mem::take(&mut arp.pending)
+            .into_iter()
+            .partition(|(next_hop, _)| *next_hop == sender);
+        arp.pending = rest;
+        waiting
+    };
+    for (_, packet) in waiting {
+        let _ = send_ethernet(sender_mac, ETHERTYPE_IPV4, &packet);
+    }
+
+    if operation == OPERATION_REQUEST && for_us {
+        let reply = build(OPERATION_REPLY, sender_mac, sender);
+        let _ = send_ethernet(sender_mac, ETHERTYPE_ARP, &reply);
+    }
+}
+
+#[test_case]
+fn test_reply_fills_the_cache() {
+    let address = Ipv4Addr::new(192, 0, 2, 1);
+    let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+    // a reply for someone else from an unknown sender isn't cached
+    let mut reply = build(OPERATION_REPLY, [0; 6], Ipv4Addr::new(192, 0, 2, 99));
+    reply[8..14].copy_from_slice(&mac);
+    reply[14..18].copy_from_slice(&address.octets());
+    handle(&reply);
+    assert_eq!(lookup(address), None);
+    // a reply to our address is
+    let ours = config().address;
+    if !ours.is_unspecified() {
+        reply[24..28].copy_from_slice(&ours.octets());
+        handle(&reply);
+        assert_eq!(lookup(address), Some(mac));
+        ARP.lock().cache.remove(&address);
+    }
+}
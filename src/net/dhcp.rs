@@ -0,0 +1,398 @@
+/*
+* DHCP (RFC 2131) lets a server on the local network configure the interface. The client without an address
+* broadcasts from 0.0.0.0:68 to 255.255.255.255:67 and the exchange goes:
+*
+*     DISCOVER  ->            the client looks for servers
+*               <-  OFFER     a server proposes an address (yiaddr) and the other parameters
+*     REQUEST   ->            the client takes the offer of one server (options 50 and 54)
+*               <-  ACK       the server confirms the lease (or NAK, the client starts again)
+*
+* The address is leased for a time (option 51). At half of it (T1, option 58) the client renews the lease
+* with a REQUEST directly to the server, if the server doesn't answer it broadcasts the REQUEST to any server
+* from 7/8 of the time on (T2, option 59, rebinding). A lease that expires takes the address away and the
+* client starts with DISCOVER again. The messages are BOOTP messages (RFC 951) with the DHCP options after
+* a magic cookie:
+*
+*     0 op (1 request, 2 reply), 1 hardware type (1), 2 hardware address length (6), 3 hops, 4 transaction id,
+*     8 seconds, 10 flags (broadcast), 12 ciaddr (the client's address), 16 yiaddr (the offered address),
+*     20 siaddr, 24 giaddr, 28 chaddr (the client's MAC), 44 server name, 108 boot file, 236 magic cookie,
+*     240 options (code, length, data, up to the end option 255)
+*
+* The request and the replies are matched by the transaction id, a request without an answer is sent again
+* after 2, 4 and 8 seconds (the backoff of RFC 2131 section 4.1, without the random part).
+* */
+use super::udp::UdpSocket;
+use super::{config, configure, read_address, read_u32, Ipv4Addr, SocketAddrV4};
+use crate::task::timer::{sleep_ms, timeout};
+use crate::timer;
+use alloc::vec::Vec;
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+
+const OP_REQUEST: u8 = 1;
+const OP_REPLY: u8 = 2;
+const HARDWARE_ETHERNET: u8 = 1;
+// the server answers with a broadcast, the client takes no unicast before it has an address
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_OFFSET: usize = 240;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+
+// the waits for a reply before the request is sent again, in seconds
+const RETRIES: [u64; 3] = [2, 4, 8];
+// the wait before starting again when no server answered, in seconds
+const RESTART_DELAY: u64 = 30;
+
+// the configuration a server leased to the interface, the times are in seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Option<Ipv4Addr>,
+    pub server: Ipv4Addr,
+    pub lease_time: u32,
+    pub renewal_time: u32,
+    pub rebinding_time: u32,
+}
+
+#[derive(Debug, Default)]
+struct Message {
+    op: u8,
+    transaction: u32,
+    your_address: Option<Ipv4Addr>,
+    message_type: u8,
+    server: Option<Ipv4Addr>,
+    subnet_mask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    dns: Option<Ipv4Addr>,
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+impl Message {
+    // the lease of an ACK, the times the server didn't give default to the ones of RFC 2131
+    fn lease(&self) -> Option<Lease> {
+        let lease_time = self.lease_time?;
+        Some(Lease {
+            address: self.your_address?,
+            prefix_len: self
+                .subnet_mask
+                .map_or(24, |mask| u32::from(mask).leading_ones() as u8),
+            gateway: self.router,
+            dns: self.dns,
+            server: self.server?,
+            lease_time,
+            renewal_time: self.renewal_time.unwrap_or(lease_time / 2),
+            rebinding_time: self
+                .rebinding_time
+                .unwrap_or((lease_time as u64 * 7 / 8) as u32),
+        })
+    }
+}
+
+// a client message, ciaddr is the address while renewing
+fn build(
+    message_type: u8,
+    transaction: u32,
+    client_address: Ipv4Addr,
+    options: &[(u8, &[u8])],
+) -> Vec<u8> {
+    let mut message = alloc::vec![0u8; OPTIONS_OFFSET];
+    message[0] = OP_REQUEST;
+    message[1] = HARDWARE_ETHERNET;
+    message[2] = 6;
+    message[4..8].copy_from_slice(&transaction.to_be_bytes());
+    if client_address.is_unspecified() {
+        message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    }
+    message[12..16].copy_from_slice(&client_address.octets());
+    message[28..34].copy_from_slice(&config().mac);
+    message[236..240].copy_from_slice(&MAGIC_COOKIE);
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+    let parameters = [
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS,
+        OPTION_LEASE_TIME,
+        OPTION_RENEWAL_TIME,
+        OPTION_REBINDING_TIME,
+    ];
+    for &(code, data) in [(OPTION_PARAMETERS, &parameters[..])].iter().chain(options) {
+        message.extend_from_slice(&[code, data.len() as u8]);
+        message.extend_from_slice(data);
+    }
+    message.push(OPTION_END);
+    message
+}
+
+fn parse(data: &[u8]) -> Option<Message> {
+    if data.len() < OPTIONS_OFFSET || data[236..240] != MAGIC_COOKIE {
+        return None;
+    }
+    let mut message = Message {
+        op: data[0],
+        transaction: read_u32(data, 4),
+        your_address: Some(read_address(data, 16)).filter(|address| !address.is_unspecified()),
+        ..Message::default()
+    };
+    let mut options = &data[OPTIONS_OFFSET..];
+    while let [code, rest @ ..] = options {
+        match *code {
+            OPTION_END => break,
+            OPTION_PAD => options = rest,
+            code => {
+                let (&len, rest) = rest.split_first()?;
+                let value = rest.get(..len as usize)?;
+                let address = (len >= 4).then(|| read_address(value, 0));
+                let seconds = (len == 4).then(|| read_u32(value, 0));
+                match code {
+                    OPTION_MESSAGE_TYPE if len == 1 => message.message_type = value[0],
+                    OPTION_SERVER_ID => message.server = address,
+                    OPTION_SUBNET_MASK => message.subnet_mask = address,
+                    // the first router and name server of the lists
+                    OPTION_ROUTER => message.router = address,
+                    OPTION_DNS => message.dns = address,
+                    OPTION_LEASE_TIME => message.lease_time = seconds,
+                    OPTION_RENEWAL_TIME => message.renewal_time = seconds,
+                    OPTION_REBINDING_TIME => message.rebinding_time = seconds,
+                    _ => {}
+                }
+                options = &rest[len as usize..];
+            }
+        }
+    }
+    Some(message)
+}
+
+/*
+* Send the message and wait for a reply to it that accept takes, the message is sent again if none arrives.
+* Other messages (e.g. the offers of other servers or replies to an earlier request) are skipped.
+* */
+async fn exchange(
+    socket: &UdpSocket,
+    message: &[u8],
+    destination: Ipv4Addr,
+    accept: impl Fn(&Message) -> bool,
+) -> Option<Message> {
+    let transaction = read_u32(message, 4);
+    for seconds in RETRIES {
+        let _ = socket.send_to(message, SocketAddrV4::new(destination, SERVER_PORT));
+        let reply = timeout(seconds * 1000, async {
+            loop {
+                let (data, _) = socket.recv_from().await;
+                match parse(&data) {
+                    Some(reply)
+                        if reply.op == OP_REPLY
+                            && reply.transaction == transaction
+                            && accept(&reply) =>
+                    {
+                        return reply
+                    }
+                    _ => {}
+                }
+            }
+        })
+        .await;
+        if reply.is_some() {
+            return reply;
+        }
+    }
+    None
+}
+
+enum Reply {
+    Ack(Lease),
+    Nak,
+    None,
+}
+
+// request the address from the server (or any server if it is the broadcast address)
+async fn request(socket: &UdpSocket, address: Ipv4Addr, server: Ipv4Addr, renewing: bool) -> Reply {
+    let transaction = crate::rand::next_u64() as u32;
+    // a client renewing its lease puts the address in ciaddr instead of the options
+    let message = if renewing {
+        build(REQUEST, transaction, address, &[])
+    } else {
+        build(
+            REQUEST,
+            transaction,
+            Ipv4Addr::UNSPECIFIED,
+            &[
+                (OPTION_REQUESTED_ADDRESS, &address.octets()),
+                (OPTION_SERVER_ID, &server.octets()),
+            ],
+        )
+    };
+    let reply = exchange(socket, &message, server, |reply| {
+        matches!(reply.message_type, ACK | NAK)
+    })
+    .await;
+    match reply {
+        Some(reply) if reply.message_type == NAK => Reply::Nak,
+        // the server may leave out its id in a renewal
+        Some(reply) => match (Message {
+            server: reply.server.or(Some(server)),
+            ..reply
+        })
+        .lease()
+        {
+            Some(lease) => Reply::Ack(lease),
+            None => Reply::None,
+        },
+        None => Reply::None,
+    }
+}
+
+// DISCOVER, OFFER, REQUEST, ACK
+async fn acquire(socket: &UdpSocket) -> Option<Lease> {
+    let discover = build(
+        DISCOVER,
+        crate::rand::next_u64() as u32,
+        Ipv4Addr::UNSPECIFIED,
+        &[],
+    );
+    let offer = exchange(socket, &discover, Ipv4Addr::BROADCAST, |reply| {
+        reply.message_type == OFFER && reply.your_address.is_some() && reply.server.is_some()
+    })
+    .await?;
+    let (address, server) = (offer.your_address?, offer.server?);
+    match request(socket, address, server, false).await {
+        Reply::Ack(lease) => Some(lease),
+        Reply::Nak | Reply::None => None,
+    }
+}
+
+fn apply(lease: &Lease) {
+    configure(lease.address, lease.prefix_len, lease.gateway, lease.dns);
+    log::info!(
+        "dhcp: {}/{} gateway {} dns {} for {}s from {}",
+        lease.address,
+        lease.prefix_len,
+        lease.gateway.unwrap_or(Ipv4Addr::UNSPECIFIED),
+        lease.dns.unwrap_or(Ipv4Addr::UNSPECIFIED),
+        lease.lease_time,
+        lease.server
+    );
+}
+
+// wait until the given number of seconds passed since the lease was acquired
+async fn sleep_until(acquired_ms: u64, seconds: u32) {
+    let deadline = acquired_ms + seconds as u64 * 1000;
+    let now = timer::uptime_ms();
+    if deadline > now {
+        sleep_ms(deadline - now).await;
+    }
+}
+
+// the DHCP task, it keeps the interface configured while the kernel runs
+pub async fn run() {
+    if !super::uses_dhcp() {
+        return;
+    }
+    let socket = match UdpSocket::bind(CLIENT_PORT) {
+        Ok(socket) => socket,
+        Err(error) => return log::warn!("dhcp: {}", error),
+    };
+    loop {
+        let Some(mut lease) = acquire(&socket).await else {
+            log::warn!(
+                "dhcp: no server answered, trying again in {}s",
+                RESTART_DELAY
+            );
+            sleep_ms(RESTART_DELAY * 1000).await;
+            continue;
+        };
+        apply(&lease);
+        // keep the lease until a server refuses it or it expires
+        loop {
+            let acquired = timer::uptime_ms();
+            let elapsed = || ((timer::uptime_ms() - acquired) / 1000) as u32;
+            sleep_until(acquired, lease.renewal_time).await;
+            let mut reply = Reply::None;
+            while matches!(reply, Reply::None) && elapsed() < lease.rebinding_time {
+                reply = request(&socket, lease.address, lease.server, true).await;
+            }
+            while matches!(reply, Reply::None) && elapsed() < lease.lease_time {
+                reply = request(&socket, lease.address, Ipv4Addr::BROADCAST, true).await;
+            }
+            match reply {
+                Reply::Ack(renewed) => {
+                    if renewed != lease {
+                        apply(&renewed);
+                    }
+                    lease = renewed;
+                }
+                Reply::Nak | Reply::None => {
+                    log::warn!("dhcp: lost the lease of {}", lease.address);
+                    configure(Ipv4Addr::UNSPECIFIED, 0, None, None);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_parse_reply() {
+    use super::read_u16;
+
+    let request = build(DISCOVER, 0x1234_5678, Ipv4Addr::UNSPECIFIED, &[]);
+    assert_eq!(read_u16(&request, 10), FLAG_BROADCAST);
+    assert_eq!(
+        request[OPTIONS_OFFSET..OPTIONS_OFFSET + 3],
+        [OPTION_MESSAGE_TYPE, 1, DISCOVER]
+    );
+    assert_eq!(request.last(), Some(&OPTION_END));
+
+    // the ACK of QEMU's user network
+    let mut reply = request.clone();
+    reply[0] = OP_REPLY;
+    reply[16..20].copy_from_slice(&[10, 0, 2, 15]);
+    reply.truncate(OPTIONS_OFFSET);
+    reply.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, ACK, OPTION_PAD]);
+    reply.extend_from_slice(&[OPTION_SERVER_ID, 4, 10, 0, 2, 2]);
+    reply.extend_from_slice(&[OPTION_LEASE_TIME, 4, 0, 0, 0x0e, 0x10]);
+    reply.extend_from_slice(&[OPTION_SUBNET_MASK, 4, 255, 255, 255, 0]);
+    reply.extend_from_slice(&[OPTION_ROUTER, 4, 10, 0, 2, 2]);
+    reply.extend_from_slice(&[OPTION_DNS, 8, 10, 0, 2, 3, 8, 8, 8, 8, OPTION_END]);
+    let message = parse(&reply).unwrap();
+    assert_eq!(message.transaction, 0x1234_5678);
+    assert_eq!(message.message_type, ACK);
+    assert_eq!(
+        message.lease(),
+        Some(Lease {
+            address: Ipv4Addr::new(10, 0, 2, 15),
+            prefix_len: 24,
+            gateway: Some(Ipv4Addr::new(10, 0, 2, 2)),
+            dns: Some(Ipv4Addr::new(10, 0, 2, 3)),
+            server: Ipv4Addr::new(10, 0, 2, 2),
+            lease_time: 3600,
+            renewal_time: 1800,
+            rebinding_time: 3150,
+        })
+    );
+    // an option longer than the message
+    reply.truncate(reply.len() - 4);
+    assert!(parse(&reply).is_none());
+}
@@ -0,0 +1,153 @@
+/*
+* IPv4 (RFC 791) carries the packets of the transport protocols between addresses. The header without
+* options is 20 bytes:
+*
+*     0 version (4) and header length in words, 1 type of service, 2 total length, 4 identification,
+*     6 flags (DF = don't fragment, MF = more fragments) and fragment offset, 8 time to live, 9 protocol
+*     (1 ICMP, 6 TCP, 17 UDP), 10 header checksum, 12 source address, 16 destination address
+*
+* A packet goes directly to the destination if it is on the local network, to the gateway otherwise, and to
+* all machines (the broadcast MAC) for a broadcast address. The packets the kernel sends are never larger
+* than a frame, so they are sent with DF. Received fragments are dropped, nothing sends them to us.
+*
+* ICMP (RFC 792) reports errors and answers pings: an echo request (type 8) is sent back as an echo reply
+* (type 0) with the same identifier, sequence number and data.
+* */
+use super::{arp, checksum, config, read_address, read_u16, tcp, udp, Ipv4Addr, NetError};
+use super::{BROADCAST_MAC, ETHERNET_HEADER_SIZE, ETHERTYPE_IPV4};
+use crate::e1000;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU16, Ordering};
+
+pub const PROTOCOL_ICMP: u8 = 1;
+pub const PROTOCOL_TCP: u8 = 6;
+pub const PROTOCOL_UDP: u8 = 17;
+
+const HEADER_SIZE: usize = 20;
+const DONT_FRAGMENT: u16 = 1 << 14;
+const MORE_FRAGMENTS: u16 = 1 << 13;
+const FRAGMENT_OFFSET: u16 = 0x1FFF;
+const TIME_TO_LIVE: u8 = 64;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+
+// the largest payload of a packet that fits in a frame
+pub const MAX_PAYLOAD: usize = e1000::MAX_FRAME_SIZE - ETHERNET_HEADER_SIZE - HEADER_SIZE;
+
+// the addresses and the protocol TCP and UDP add to their checksums
+pub(crate) fn pseudo_header(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    len: usize,
+) -> [u8; 12] {
+    let mut header = [0u8; 12];
+    header[0..4].copy_from_slice(&source.octets());
+    header[4..8].copy_from_slice(&destination.octets());
+    header[9] = protocol;
+    header[10..12].copy_from_slice(&(len as u16).to_be_bytes());
+    header
+}
+
+fn build(source: Ipv4Addr, destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Vec<u8> {
+    static IDENTIFICATION: AtomicU16 = AtomicU16::new(0);
+    let mut packet = Vec::with_capacity(HEADER_SIZE + payload.len());
+    packet.extend_from_slice(&[0x45, 0]);
+    packet.extend_from_slice(&((HEADER_SIZE + payload.len()) as u16).to_be_bytes());
+    packet.extend_from_slice(&IDENTIFICATION.fetch_add(1, Ordering::Relaxed).to_be_bytes());
+    packet.extend_from_slice(&DONT_FRAGMENT.to_be_bytes());
+    packet.extend_from_slice(&[TIME_TO_LIVE, protocol, 0, 0]);
+    packet.extend_from_slice(&source.octets());
+    packet.extend_from_slice(&destination.octets());
+    let sum = checksum(&[&packet]);
+    packet[10..12].copy_from_slice(&sum.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+// send a packet from the address of the interface (0.0.0.0 before it has one)
+pub fn send(destination: Ipv4Addr, protocol: u8, payload: &[u8]) -> Result<(), NetError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(NetError::TooLarge);
+    }
+    let config = config();
+    let packet = build(config.address, destination, protocol, payload);
+    if destination.is_broadcast() || destination == config.broadcast() {
+        return super::send_ethernet(BROADCAST_MAC, ETHERTYPE_IPV4, &packet);
+    }
+    let next_hop = if config.is_local(destination) {
+        destination
+    } else {
+        config.gateway.ok_or(NetError::Unreachable)?
+    };
+    arp::send(next_hop, packet)
+}
+
+fn handle_icmp(source: Ipv4Addr, message: &[u8]) {
+    if message.len() < 8 || checksum(&[message]) != 0 {
+        return;
+    }
+    if message[0] == ICMP_ECHO_REQUEST && message[1] == 0 {
+        let mut reply = message.to_vec();
+        reply[0] = ICMP_ECHO_REPLY;
+        reply[2..4].copy_from_slice(&[0, 0]);
+        let sum = checksum(&[&reply]);
+        reply[2..4].copy_from_slice(&sum.to_be_bytes());
+        let _ = send(source, PROTOCOL_ICMP, &reply);
+    }
+}
+
+pub(crate) fn handle(packet: &[u8]) {
+    if packet.len() < HEADER_SIZE || packet[0] >> 4 != 4 {
+        return;
+    }
+    let header_len = (packet[0] & 0xF) as usize * 4;
+    let total_len = read_u16(packet, 2) as usize;
+    if header_len < HEADER_SIZE
+        || total_len < header_len
+        || total_len > packet.len()
+        || checksum(&[&packet[..header_len]]) != 0
+    {
+        return;
+    }
+    let fragment = read_u16(packet, 6);
+    if fragment & (MORE_FRAGMENTS | FRAGMENT_OFFSET) != 0 {
+        return;
+    }
+    let source = read_address(packet, 12);
+    let destination = read_address(packet, 16);
+    let config = config();
+    // before the interface has an address everything is taken (the DHCP answers)
+    let for_us = destination == config.address
+        || destination.is_broadcast()
+        || destination == config.broadcast()
+        || config.address.is_unspecified();
+    if !for_us {
+        return;
+    }
+    // the frame may be padded after the packet
+    let payload = &packet[header_len..total_len];
+    match packet[9] {
+        PROTOCOL_ICMP => handle_icmp(source, payload),
+        PROTOCOL_UDP => udp::handle(source, destination, payload),
+        PROTOCOL_TCP => tcp::handle(source, destination, payload),
+        _ => {}
+    }
+}
+
+#[test_case]
+fn test_header_checksum() {
+    let source = Ipv4Addr::new(10, 0, 2, 15);
+    let destination = Ipv4Addr::new(10, 0, 2, 2);
+    let packet = build(source, destination, PROTOCOL_UDP, &[1, 2, 3]);
+    assert_eq!(packet.len(), HEADER_SIZE + 3);
+    // a header with its checksum sums to zero
+    assert_eq!(checksum(&[&packet[..HEADER_SIZE]]), 0);
+    assert_eq!(read_address(&packet, 12), source);
+    assert_eq!(read_u16(&packet, 2), 23);
+    assert_eq!(
+        send(destination, PROTOCOL_UDP, &[0; MAX_PAYLOAD + 1]),
+        Err(NetError::TooLarge)
+    );
+}
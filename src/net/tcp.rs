@@ -0,0 +1,638 @@
+/*
+* TCP (RFC 793) turns the packets into reliable byte streams between ports. The header is 20 bytes and options:
+*
+*     0 source port, 2 destination port, 4 sequence number, 8 acknowledgment number, 12 header length in
+*     words, 13 flags (FIN, SYN, RST, PSH, ACK), 14 window, 16 checksum (with the pseudo header), 18 urgent
+*
+* Every byte has a sequence number, the SYN and FIN flags count as one byte too. A side acknowledges the next
+* sequence number it expects and advertises how many bytes it can still buffer (the window). A connection
+* is opened with a handshake and every side closes its direction with FIN:
+*
+*     peer                kernel (listening)
+*      SYN seq=x    ->                       SynReceived
+*                   <-    SYN ACK seq=y ack=x+1
+*      ACK ack=y+1  ->                       Established, the connection waits in the backlog for accept
+*      data, FIN    ->                       CloseWait, read returns 0 at the end of the data
+*                   <-    FIN                LastAck (after close or dropping the stream)
+*      ACK          ->                       Closed
+*
+* The kernel closing first goes through FinWait1 (FIN sent) and FinWait2 (FIN acknowledged) to Closed once
+* the peer's FIN arrives, without waiting in TIME_WAIT. This is a basic implementation for the local network:
+* nothing is retransmitted, segments that don't arrive in order are dropped (the acknowledgment tells the
+* peer where to resume) and the peer's window is assumed to be large enough. Only listening sockets are
+* supported, the kernel doesn't open connections itself.
+* */
+use super::ipv4::{self, PROTOCOL_TCP};
+use super::{checksum, config, read_u16, read_u32, Ipv4Addr, NetError, SocketAddrV4};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use spin::Mutex;
+
+const HEADER_SIZE: usize = 20;
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+const OPTION_END: u8 = 0;
+const OPTION_NOP: u8 = 1;
+const OPTION_MSS: u8 = 2;
+
+// the bytes a connection buffers for the reader, also the largest window advertised
+const RECEIVE_BUFFER: usize = 8192;
+// the maximum segment size if the peer doesn't say (RFC 879) and the largest that fits in a frame
+const DEFAULT_MSS: usize = 536;
+const MAX_MSS: usize = ipv4::MAX_PAYLOAD - HEADER_SIZE;
+// the established connections waiting for accept
+const BACKLOG: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    LastAck,
+    Closed,
+}
+
+// the local port and the peer
+type Key = (u16, SocketAddrV4);
+
+struct Connection {
+    state: State,
+    // the next sequence number to send and the oldest one the peer didn't acknowledge
+    send_next: u32,
+    send_unacknowledged: u32,
+    // the next sequence number expected from the peer
+    receive_next: u32,
+    // the largest segment the peer takes
+    mss: usize,
+    received: VecDeque<u8>,
+    // the peer sent FIN, read returns 0 once the buffer is empty
+    peer_closed: bool,
+    reset: bool,
+    // the stream was dropped, the entry is removed once the connection is closed
+    orphan: bool,
+    // the task waiting in read
+    waker: Option<Waker>,
+}
+
+struct Listener {
+    backlog: VecDeque<Key>,
+    waker: Option<Waker>,
+}
+
+struct Tcp {
+    listeners: BTreeMap<u16, Listener>,
+    connections: BTreeMap<Key, Connection>,
+}
+
+static TCP: Mutex<Tcp> = Mutex::new(Tcp {
+    listeners: BTreeMap::new(),
+    connections: BTreeMap::new(),
+});
+
+struct Segment<'a> {
+    source_port: u16,
+    destination_port: u16,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    mss: Option<usize>,
+    data: &'a [u8],
+}
+
+impl Segment<'_> {
+    // the sequence numbers the segment takes, SYN and FIN count as one
+    fn len(&self) -> u32 {
+        self.data.len() as u32 + (self.flags & SYN != 0) as u32 + (self.flags & FIN != 0) as u32
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build(
+    source: Ipv4Addr,
+    destination: SocketAddrV4,
+    source_port: u16,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    window: u16,
+    data: &[u8],
+) -> Vec<u8> {
+    // the SYN tells the peer the largest segment we take
+    let options: &[u8] = if flags & SYN != 0 {
+        &[OPTION_MSS, 4, (MAX_MSS >> 8) as u8, MAX_MSS as u8]
+    } else {
+        &[]
+    };
+    let header_len = HEADER_SIZE + options.len();
+    let mut segment = Vec::with_capacity(header_len + data.len());
+    segment.extend_from_slice(&source_port.to_be_bytes());
+    segment.extend_from_slice(&destination.port().to_be_bytes());
+    segment.extend_from_slice(&sequence.to_be_bytes());
+    segment.extend_from_slice(&acknowledgment.to_be_bytes());
+    segment.extend_from_slice(&[((header_len / 4) as u8) << 4, flags]);
+    segment.extend_from_slice(&window.to_be_bytes());
+    segment.extend_from_slice(&[0, 0, 0, 0]);
+    segment.extend_from_slice(options);
+    segment.extend_from_slice(data);
+    let pseudo = ipv4::pseudo_header(source, *destination.ip(), PROTOCOL_TCP, segment.len());
+    let sum = checksum(&[&pseudo, &segment]);
+    segment[16..18].copy_from_slice(&sum.to_be_bytes());
+    segment
+}
+
+fn parse(source: Ipv4Addr, destination: Ipv4Addr, segment: &[u8]) -> Option<Segment<'_>> {
+    if segment.len() < HEADER_SIZE {
+        return None;
+    }
+    let header_len = (segment[12] >> 4) as usize * 4;
+    let pseudo = ipv4::pseudo_header(source, destination, PROTOCOL_TCP, segment.len());
+    if header_len < HEADER_SIZE || header_len > segment.len() || checksum(&[&pseudo, segment]) != 0
+    {
+        return None;
+    }
+    let mut mss = None;
+    let mut options = &segment[HEADER_SIZE..header_len];
+    while let [kind, rest @ ..] = options {
+        match *kind {
+            OPTION_END => break,
+            OPTION_NOP => options = rest,
+            _ => {
+                let len = *rest.first()? as usize;
+                if len < 2 || len > options.len() {
+                    break;
+                }
+                if *kind == OPTION_MSS && len == 4 {
+                    mss = Some(read_u16(options, 2) as usize);
+                }
+                options = &options[len..];
+            }
+        }
+    }
+    Some(Segment {
+        source_port: read_u16(segment, 0),
+        destination_port: read_u16(segment, 2),
+        sequence: read_u32(segment, 4),
+        acknowledgment: read_u32(segment, 8),
+        flags: segment[13],
+        mss,
+        data: &segment[header_len..],
+    })
+}
+
+fn send_segment(
+    key: Key,
+    sequence: u32,
+    acknowledgment: u32,
+    flags: u8,
+    window: u16,
+    data: &[u8],
+) -> Result<(), NetError> {
+    let (port, remote) = key;
+    let segment = build(
+        config().address,
+        remote,
+        port,
+        sequence,
+        acknowledgment,
+        flags,
+        window,
+        data,
+    );
+    ipv4::send(*remote.ip(), PROTOCOL_TCP, &segment)
+}
+
+// answer a segment that belongs to no connection (RFC 793 "reset generation")
+fn reset(key: Key, segment: &Segment) {
+    let _ = if segment.flags & ACK != 0 {
+        send_segment(key, segment.acknowledgment, 0, RST, 0, &[])
+    } else {
+        let acknowledgment = segment.sequence.wrapping_add(segment.len());
+        send_segment(key, 0, acknowledgment, RST | ACK, 0, &[])
+    };
+}
+
+impl Connection {
+    fn window(&self) -> u16 {
+        (RECEIVE_BUFFER - self.received.len()) as u16
+    }
+
+    // send a segment at send_next that takes len sequence numbers
+    fn send(&mut self, key: Key, flags: u8, data: &[u8], len: u32) -> Result<(), NetError> {
+        let result = send_segment(
+            key,
+            self.send_next,
+            self.receive_next,
+            flags,
+            self.window(),
+            data,
+        );
+        self.send_next = self.send_next.wrapping_add(len);
+        result
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    // send our FIN, the peer may still send until it closes too
+    fn close(&mut self, key: Key) {
+        self.state = match self.state {
+            State::SynReceived | State::Established => State::FinWait1,
+            State::CloseWait => State::LastAck,
+            _ => return,
+        };
+        let _ = self.send(key, FIN | ACK, &[], 1);
+    }
+
+    // process a segment of the connection, returns true if it completed the handshake
+    fn segment_arrives(&mut self, key: Key, segment: &Segment) -> bool {
+        if segment.flags & RST != 0 {
+            // a reset must be in the window, an old one could close a new connection
+            let offset = segment.sequence.wrapping_sub(self.receive_next);
+            if offset <= RECEIVE_BUFFER as u32 {
+                self.state = State::Closed;
+                self.reset = true;
+                self.wake();
+            }
+            return false;
+        }
+        if segment.flags & SYN != 0 {
+            // the peer didn't get our SYN ACK and sends its SYN again
+            if self.state == State::SynReceived
+                && segment.sequence.wrapping_add(1) == self.receive_next
+            {
+                let _ = send_segment(
+                    key,
+                    self.send_unacknowledged,
+                    self.receive_next,
+                    SYN | ACK,
+                    self.window(),
+                    &[],
+                );
+            }
+            return false;
+        }
+        if segment.flags & ACK == 0 {
+            return false;
+        }
+
+        let mut established = false;
+        let acknowledged = segment.acknowledgment;
+        let in_flight = self.send_next.wrapping_sub(self.send_unacknowledged);
+        if acknowledged.wrapping_sub(self.send_unacknowledged) <= in_flight {
+            self.send_unacknowledged = acknowledged;
+        }
+        let all_acknowledged = acknowledged == self.send_next;
+        match self.state {
+            State::SynReceived if all_acknowledged => {
+                self.state = State::Established;
+                established = true;
+            }
+            // an ACK that doesn't complete the handshake
+            State::SynReceived => return false,
+            State::FinWait1 if all_acknowledged => self.state = State::FinWait2,
+            State::LastAck if all_acknowledged => {
+                self.state = State::Closed;
+                self.wake();
+            }
+            _ => {}
+        }
+
+        let mut acknowledge = false;
+        if !segment.data.is_empty() {
+            acknowledge = true;
+            let receiving = matches!(
+                self.state,
+                State::Established | State::FinWait1 | State::FinWait2
+            );
+            if receiving && segment.sequence == self.receive_next {
+                let space = RECEIVE_BUFFER - self.received.len();
+                let data = &segment.data[..segment.data.len().min(space)];
+                self.received.extend(data);
+                self.receive_next = self.receive_next.wrapping_add(data.len() as u32);
+                if !data.is_empty() {
+                    self.wake();
+                }
+            }
+        }
+        // the FIN counts once all the data before it is taken
+        let end = segment.sequence.wrapping_add(segment.data.len() as u32);
+        if segment.flags & FIN != 0 && end == self.receive_next && !self.peer_closed {
+            self.receive_next = self.receive_next.wrapping_add(1);
+            self.peer_closed = true;
+            acknowledge = true;
+            self.state = match self.state {
+                State::Established => State::CloseWait,
+                State::FinWait1 | State::FinWait2 => State::Closed,
+                state => state,
+            };
+            self.wake();
+        }
+        if acknowledge {
+            let _ = self.send(key, ACK, &[], 0);
+        }
+        established
+    }
+}
+
+pub(crate) fn handle(source: Ipv4Addr, destination: Ipv4Addr, bytes: &[u8]) {
+    let Some(segment) = parse(source, destination, bytes) else {
+        return;
+    };
+    let key = (
+        segment.destination_port,
+        SocketAddrV4::new(source, segment.source_port),
+    );
+    let mut tcp = TCP.lock();
+    let tcp = &mut *tcp;
+
+    if let Some(connection) = tcp.connections.get_mut(&key) {
+        if connection.segment_arrives(key, &segment) {
+            match tcp.listeners.get_mut(&key.0) {
+                Some(listener) if listener.backlog.len() < BACKLOG => {
+                    listener.backlog.push_back(key);
+                    if let Some(waker) = listener.waker.take() {
+                        waker.wake();
+                    }
+                }
+                // the listener is gone or too many connections wait
+                _ => {
+                    let _ = send_segment(key, connection.send_next, 0, RST, 0, &[]);
+                    connection.state = State::Closed;
+                    connection.orphan = true;
+                }
+            }
+        }
+        if connection.state == State::Closed && connection.orphan {
+            tcp.connections.remove(&key);
+        }
+        return;
+    }
+
+    if segment.flags & RST != 0 {
+        return;
+    }
+    if segment.flags & (SYN | ACK) == SYN && tcp.listeners.contains_key(&key.0) {
+        let initial = crate::rand::next_u64() as u32;
+        let mut connection = Connection {
+            state: State::SynReceived,
+            send_next: initial,
+            send_unacknowledged: initial,
+            receive_next: segment.sequence.wrapping_add(1),
+            mss: segment.mss.unwrap_or(DEFAULT_MSS).clamp(1, MAX_MSS),
+            received: VecDeque::new(),
+            peer_closed: false,
+            reset: false,
+            // accept makes a stream for it
+            orphan: true,
+            waker: None,
+        };
+        let _ = connection.send(key, SYN | ACK, &[], 1);
+        tcp.connections.insert(key, connection);
+        return;
+    }
+    reset(key, &segment);
+}
+
+// a socket waiting for connections on a port
+pub struct TcpListener {
+    port: u16,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener, NetError> {
+        let mut tcp = TCP.lock();
+        if port == 0 || tcp.listeners.contains_key(&port) {
+            return Err(NetError::AddressInUse);
+        }
+        tcp.listeners.insert(
+            port,
+            Listener {
+                backlog: VecDeque::new(),
+                waker: None,
+            },
+        );
+        Ok(TcpListener { port })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    // wait for the next established connection
+    pub async fn accept(&self) -> TcpStream {
+        poll_fn(|context| {
+            let mut tcp = TCP.lock();
+            let tcp = &mut *tcp;
+            let listener = tcp
+                .listeners
+                .get_mut(&self.port)
+                .expect("the listener is bound");
+            while let Some(key) = listener.backlog.pop_front() {
+                // a connection reset while it waited is skipped
+                match tcp.connections.get_mut(&key) {
+                    Some(connection) if !connection.reset => {
+                        connection.orphan = false;
+                        return Poll::Ready(TcpStream { key });
+                    }
+                    Some(_) => {
+                        tcp.connections.remove(&key);
+                    }
+                    None => {}
+                }
+            }
+            listener.waker = Some(context.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl Drop for TcpListener {
+    // the connections nobody accepted are reset
+    fn drop(&mut self) {
+        let mut tcp = TCP.lock();
+        if let Some(listener) = tcp.listeners.remove(&self.port) {
+            for key in listener.backlog {
+                if let Some(connection) = tcp.connections.remove(&key) {
+                    let _ = send_segment(key, connection.send_next, 0, RST, 0, &[]);
+                }
+            }
+        }
+    }
+}
+
+// an established connection
+pub struct TcpStream {
+    key: Key,
+}
+
+impl TcpStream {
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        self.key.1
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.key.0
+    }
+
+    pub fn state(&self) -> State {
+        TCP.lock()
+            .connections
+            .get(&self.key)
+            .map_or(State::Closed, |connection| connection.state)
+    }
+
+    // wait for data, returns the number of bytes read and 0 once the peer closed the connection
+    pub async fn read(&self, buffer: &mut [u8]) -> Result<usize, NetError> {
+        poll_fn(|context| {
+            let mut tcp = TCP.lock();
+            let Some(connection) = tcp.connections.get_mut(&self.key) else {
+                return Poll::Ready(Err(NetError::NotConnected));
+            };
+            if !connection.received.is_empty() {
+                let was_full = connection.window() == 0;
+                let len = buffer.len().min(connection.received.len());
+                for (byte, received) in buffer.iter_mut().zip(connection.received.drain(..len)) {
+                    *byte = received;
+                }
+                // tell the peer it can send again
+                if was_full {
+                    let _ = connection.send(self.key, ACK, &[], 0);
+                }
+                return Poll::Ready(Ok(len));
+            }
+            if connection.reset {
+                return Poll::Ready(Err(NetError::NotConnected));
+            }
+            if connection.peer_closed || connection.state == State::Closed {
+                return Poll::Ready(Ok(0));
+            }
+            connection.waker = Some(context.waker().clone());
+            Poll::Pending
+        })
+        .await
+    }
+
+    // send the data in segments of the peer's size
+    pub fn write(&self, data: &[u8]) -> Result<usize, NetError> {
+        let mut tcp = TCP.lock();
+        let connection = tcp
+            .connections
+            .get_mut(&self.key)
+            .filter(|connection| matches!(connection.state, State::Established | State::CloseWait))
+            .ok_or(NetError::NotConnected)?;
+        for chunk in data.chunks(connection.mss) {
+            connection.send(self.key, ACK | PSH, chunk, chunk.len() as u32)?;
+        }
+        Ok(data.len())
+    }
+
+    // close our direction of the connection, the peer can still send
+    pub fn close(&self) {
+        if let Some(connection) = TCP.lock().connections.get_mut(&self.key) {
+            connection.close(self.key);
+        }
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut tcp = TCP.lock();
+        if let Some(connection) = tcp.connections.get_mut(&self.key) {
+            connection.close(self.key);
+            connection.orphan = true;
+            if connection.state == State::Closed {
+                tcp.connections.remove(&self.key);
+            }
+        }
+    }
+}
+
+// the echo service (RFC 862), every connection gets its data back
+pub async fn echo_server(listener: TcpListener) {
+    loop {
+        let stream = listener.accept().await;
+        crate::task::spawn(async move {
+            let mut buffer = [0u8; 512];
+            while let Ok(len @ 1..) = stream.read(&mut buffer).await {
+                if stream.write(&buffer[..len]).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[test_case]
+fn test_connection_lifecycle() {
+    let listener = TcpListener::bind(7777).unwrap();
+    assert_eq!(TcpListener::bind(7777).err(), Some(NetError::AddressInUse));
+    let local = config().address;
+    let peer = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 2), 40000);
+    let key = (7777, peer);
+    let segment = |sequence: u32, acknowledgment: u32, flags: u8, data: &[u8]| {
+        let bytes = build(
+            *peer.ip(),
+            SocketAddrV4::new(local, 7777),
+            peer.port(),
+            sequence,
+            acknowledgment,
+            flags,
+            1024,
+            data,
+        );
+        handle(*peer.ip(), local, &bytes);
+    };
+    let connection = |f: &dyn Fn(&Connection)| f(&TCP.lock().connections[&key]);
+
+    // the handshake
+    segment(1000, 0, SYN, &[]);
+    connection(&|connection| assert_eq!(connection.state, State::SynReceived));
+    let ours = TCP.lock().connections[&key].send_next;
+    segment(1001, ours, ACK, &[]);
+    connection(&|connection| assert_eq!(connection.state, State::Established));
+    assert_eq!(TCP.lock().listeners[&7777].backlog.front(), Some(&key));
+    let stream = {
+        let mut tcp = TCP.lock();
+        tcp.listeners.get_mut(&7777).unwrap().backlog.clear();
+        tcp.connections.get_mut(&key).unwrap().orphan = false;
+        TcpStream { key }
+    };
+
+    // data out of order is dropped, in order it is buffered
+    segment(1006, ours, ACK, b"world");
+    segment(1001, ours, ACK | PSH, b"hello");
+    connection(&|connection| {
+        assert_eq!(
+            connection.received.iter().copied().collect::<Vec<u8>>(),
+            b"hello"
+        );
+        assert_eq!(connection.receive_next, 1006);
+    });
+    // the peer closes, then the kernel
+    segment(1006, ours, FIN | ACK, &[]);
+    connection(&|connection| {
+        assert_eq!(connection.state, State::CloseWait);
+        assert!(connection.peer_closed);
+    });
+    drop(stream);
+    connection(&|connection| assert_eq!(connection.state, State::LastAck));
+    segment(1007, ours.wrapping_add(1), ACK, &[]);
+    assert!(!TCP.lock().connections.contains_key(&key));
+
+    // a port without a listener doesn't create connections
+    drop(listener);
+    segment(2000, 0, SYN, &[]);
+    assert!(!TCP.lock().connections.contains_key(&key));
+}
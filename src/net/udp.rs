@@ -0,0 +1,163 @@
+/*
+* UDP (RFC 768) adds ports to IPv4, every datagram is delivered on its own (or lost). The header is 8 bytes:
+*
+*     0 source port, 2 destination port, 4 length (header and data), 6 checksum
+*
+* The checksum covers a pseudo header with the addresses too, 0 means the sender didn't compute one.
+*
+* A UdpSocket is bound to a local port, the datagrams for the port are queued in its entry of SOCKETS until
+* the owner takes them (recv_from waits for one). Port 0 picks a free port from the dynamic range.
+* Dropping the socket unbinds the port.
+* */
+use super::ipv4::{self, PROTOCOL_UDP};
+use super::{checksum, config, read_u16, Ipv4Addr, NetError, SocketAddrV4};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use spin::Mutex;
+
+const HEADER_SIZE: usize = 8;
+// the received datagrams a socket keeps, newer ones are dropped
+const QUEUE_SIZE: usize = 64;
+pub const MAX_PAYLOAD: usize = ipv4::MAX_PAYLOAD - HEADER_SIZE;
+pub(crate) const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Default)]
+struct Socket {
+    queue: VecDeque<(Vec<u8>, SocketAddrV4)>,
+    waker: Option<Waker>,
+}
+
+static SOCKETS: Mutex<BTreeMap<u16, Socket>> = Mutex::new(BTreeMap::new());
+
+pub struct UdpSocket {
+    port: u16,
+}
+
+impl UdpSocket {
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let mut sockets = SOCKETS.lock();
+        let port = match port {
+            0 => EPHEMERAL_PORTS
+                .clone()
+                .find(|port| !sockets.contains_key(port))
+                .ok_or(NetError::AddressInUse)?,
+            port if sockets.contains_key(&port) => return Err(NetError::AddressInUse),
+            port => port,
+        };
+        sockets.insert(port, Socket::default());
+        Ok(UdpSocket { port })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn send_to(&self, data: &[u8], destination: SocketAddrV4) -> Result<(), NetError> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(NetError::TooLarge);
+        }
+        let len = HEADER_SIZE + data.len();
+        let mut datagram = Vec::with_capacity(len);
+        datagram.extend_from_slice(&self.port.to_be_bytes());
+        datagram.extend_from_slice(&destination.port().to_be_bytes());
+        datagram.extend_from_slice(&(len as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(data);
+        let pseudo = ipv4::pseudo_header(config().address, *destination.ip(), PROTOCOL_UDP, len);
+        // a computed 0 is sent as 0xFFFF, 0 means no checksum
+        let sum = match checksum(&[&pseudo, &datagram]) {
+            0 => 0xFFFF,
+            sum => sum,
+        };
+        datagram[6..8].copy_from_slice(&sum.to_be_bytes());
+        ipv4::send(*destination.ip(), PROTOCOL_UDP, &datagram)
+    }
+
+    // the oldest received datagram and its sender, None if none is queued
+    pub fn try_recv_from(&self) -> Option<(Vec<u8>, SocketAddrV4)> {
+        SOCKETS.lock().get_mut(&self.port)?.queue.pop_front()
+    }
+
+    // wait for a datagram
+    pub async fn recv_from(&self) -> (Vec<u8>, SocketAddrV4) {
+        poll_fn(|context| {
+            let mut sockets = SOCKETS.lock();
+            let socket = sockets.get_mut(&self.port).expect("the socket is bound");
+            match socket.queue.pop_front() {
+                Some(datagram) => Poll::Ready(datagram),
+                None => {
+                    socket.waker = Some(context.waker().clone());
+                    Poll::Pending
+                }
+            }
+        })
+        .await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        SOCKETS.lock().remove(&self.port);
+    }
+}
+
+pub(crate) fn handle(source: Ipv4Addr, destination: Ipv4Addr, datagram: &[u8]) {
+    if datagram.len() < HEADER_SIZE {
+        return;
+    }
+    let len = read_u16(datagram, 4) as usize;
+    if len < HEADER_SIZE || len > datagram.len() {
+        return;
+    }
+    let datagram = &datagram[..len];
+    if read_u16(datagram, 6) != 0 {
+        let pseudo = ipv4::pseudo_header(source, destination, PROTOCOL_UDP, len);
+        if checksum(&[&pseudo, datagram]) != 0 {
+            return;
+        }
+    }
+    let sender = SocketAddrV4::new(source, read_u16(datagram, 0));
+    let mut sockets = SOCKETS.lock();
+    let Some(socket) = sockets.get_mut(&read_u16(datagram, 2)) else {
+        return;
+    };
+    if socket.queue.len() < QUEUE_SIZE {
+        socket
+            .queue
+            .push_back((datagram[HEADER_SIZE..].to_vec(), sender));
+    }
+    if let Some(waker) = socket.waker.take() {
+        waker.wake();
+    }
+}
+
+#[test_case]
+fn test_bind_and_receive() {
+    let socket = UdpSocket::bind(0).unwrap();
+    assert!(EPHEMERAL_PORTS.contains(&socket.port()));
+    assert_eq!(
+        UdpSocket::bind(socket.port()).err(),
+        Some(NetError::AddressInUse)
+    );
+    // a datagram to the socket without a checksum
+    let source = Ipv4Addr::new(10, 0, 2, 2);
+    let mut datagram = Vec::new();
+    datagram.extend_from_slice(&1234u16.to_be_bytes());
+    datagram.extend_from_slice(&socket.port().to_be_bytes());
+    datagram.extend_from_slice(&11u16.to_be_bytes());
+    datagram.extend_from_slice(&[0, 0, b'h', b'i', b'!']);
+    handle(source, config().address, &datagram);
+    assert_eq!(
+        socket.try_recv_from(),
+        Some((
+            alloc::vec![b'h', b'i', b'!'],
+            SocketAddrV4::new(source, 1234)
+        ))
+    );
+    assert_eq!(socket.try_recv_from(), None);
+    let port = socket.port();
+    drop(socket);
+    assert!(UdpSocket::bind(port).is_ok());
+}
@@ -0,0 +1,116 @@
+/*
+* What the kernel does after a panic, set with the panic option of the command line:
+*  * halt       stop with the panic screen shown, the default
+*  * reboot     restart the machine after REBOOT_DELAY_MS so the panic screen can be read
+*  * qemu-exit  terminate QEMU with a failure code (isa-debug-exit device), halts without the device
+* Automated runs use qemu-exit so a panic fails the run right away instead of stalling forever.
+*
+* The panic can happen with any lock held, so the option is read at boot and the panic handler only
+* loads it. Automated runs usually have no screen, so the panic message also goes to the serial port (forced
+* open like the screen) when the serial console didn't get the report of the panic screen already.
+* */
+use crate::hires_time::Instant;
+use crate::serial::SERIAL1;
+use crate::{console, exit_qemu, hlt_loop, power, QemuExitCode};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::str::FromStr;
+use core::sync::atomic::{AtomicU8, Ordering};
+use core::time::Duration;
+
+const REBOOT_DELAY_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum PanicPolicy {
+    Halt,
+    Reboot,
+    QemuExit,
+}
+
+impl PanicPolicy {
+    pub fn name(self) -> &'static str {
+        match self {
+            PanicPolicy::Halt => "halt",
+            PanicPolicy::Reboot => "reboot",
+            PanicPolicy::QemuExit => "qemu-exit",
+        }
+    }
+}
+
+impl FromStr for PanicPolicy {
+    type Err = ();
+
+    fn from_str(name: &str) -> Result<PanicPolicy, ()> {
+        [
+            PanicPolicy::Halt,
+            PanicPolicy::Reboot,
+            PanicPolicy::QemuExit,
+        ]
+        .into_iter()
+        .find(|policy| policy.name() == name)
+        .ok_or(())
+    }
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(PanicPolicy::Halt as u8);
+
+// read the panic option, called after the logger is initialized
+pub fn init() {
+    if let Some(policy) = crate::cmdline::parse::<PanicPolicy>("panic") {
+        set(policy);
+    }
+}
+
+pub fn set(policy: PanicPolicy) {
+    POLICY.store(policy as u8, Ordering::SeqCst);
+}
+
+pub fn current() -> PanicPolicy {
+    match POLICY.load(Ordering::SeqCst) {
+        1 => PanicPolicy::Reboot,
+        2 => PanicPolicy::QemuExit,
+        _ => PanicPolicy::Halt,
+    }
+}
+
+// called by the panic handler after the panic screen, never returns
+pub fn apply(info: &PanicInfo) -> ! {
+    x86_64::instructions::interrupts::disable();
+    let policy = current();
+    unsafe { SERIAL1.force_unlock() };
+    let mut serial = SERIAL1.lock();
+    if !console::is_active("serial") {
+        let _ = writeln!(serial, "KERNEL PANIC: {}", info);
+    }
+    let _ = writeln!(serial, "panic={}", policy.name());
+    drop(serial);
+    match policy {
+        PanicPolicy::Halt => hlt_loop(),
+        PanicPolicy::Reboot => {
+            // the TSC keeps counting with the interrupts disabled, before hires_time::init this doesn't wait
+            let end = Instant::now() + Duration::from_millis(REBOOT_DELAY_MS);
+            while Instant::now() < end {
+                core::hint::spin_loop();
+            }
+            power::reset()
+        }
+        PanicPolicy::QemuExit => {
+            exit_qemu(QemuExitCode::Failed);
+            hlt_loop()
+        }
+    }
+}
+
+#[test_case]
+fn test_parse_panic_policy() {
+    assert_eq!("halt".parse(), Ok(PanicPolicy::Halt));
+    assert_eq!("reboot".parse(), Ok(PanicPolicy::Reboot));
+    assert_eq!("qemu-exit".parse(), Ok(PanicPolicy::QemuExit));
+    assert_eq!("exit".parse::<PanicPolicy>(), Err(()));
+
+    let policy = current();
+    set(PanicPolicy::QemuExit);
+    assert_eq!(current(), PanicPolicy::QemuExit);
+    set(policy);
+}
@@ -0,0 +1,172 @@
+/*
+* When the kernel panics it takes over the whole screen so the message can't be missed or scrolled
+* away between the normal output: the screen is cleared to red on white and shows the panic message,
+* its location and, if the panic was caused by a CPU exception, the state the exception handler saved.
+*
+* The panic can happen while the writer is locked (e.g. a panic in the middle of a println!) so the
+* lock is forced open, nothing else runs after a panic so the interrupted writer never continues.
+* The serial console (if it is active) gets the same report, its port is forced open too.
+* */
+use crate::backtrace::Backtrace;
+use crate::console;
+use crate::framebuffer;
+use crate::serial::SERIAL1;
+use crate::vga_buffer::{self, Color};
+use core::fmt::{self, Write};
+use core::panic::{Location, PanicInfo};
+use spin::Mutex;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::InterruptStackFrameValue;
+
+// what an exception handler saves before it panics
+#[derive(Clone, Copy)]
+pub struct ExceptionState {
+    pub name: &'static str,
+    pub error_code: Option<u64>,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+static EXCEPTION: Mutex<Option<ExceptionState>> = Mutex::new(None);
+
+// called by the exception handlers right before they panic so the panic screen can show the state
+pub fn record_exception(state: ExceptionState) {
+    *EXCEPTION.lock() = Some(state);
+}
+
+// the state recorded by the exception that caused the panic, if any
+pub fn exception() -> Option<ExceptionState> {
+    // the exception handler may have panicked while holding the lock
+    unsafe { EXCEPTION.force_unlock() };
+    *EXCEPTION.lock()
+}
+
+pub fn show(info: &PanicInfo) {
+    // captured before drawing so the drawing functions aren't part of it
+    let backtrace = Backtrace::capture();
+    with_screen(|screen| {
+        // the screen was taken over so there is nobody to report a write error to
+        let _ = write_report(
+            screen,
+            &info.message(),
+            info.location(),
+            exception(),
+            &backtrace,
+        );
+    });
+    if console::is_active("serial") {
+        unsafe { SERIAL1.force_unlock() };
+        let _ = write_report(
+            &mut console::Terminal(&mut SERIAL1.lock()),
+            &info.message(),
+            info.location(),
+            exception(),
+            &backtrace,
+        );
+    }
+}
+
+fn write_report(
+    screen: &mut dyn Write,
+    message: &dyn fmt::Display,
+    location: Option<&Location>,
+    exception: Option<ExceptionState>,
+    backtrace: &Backtrace,
+) -> fmt::Result {
+    writeln!(screen, "KERNEL PANIC\n")?;
+    writeln!(screen, "{}", message)?;
+    if let Some(location) = location {
+        writeln!(
+            screen,
+            "at {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )?;
+    }
+
+    if let Some(exception) = exception {
+        let frame = exception.stack_frame;
+        writeln!(screen, "\n{}", exception.name)?;
+        if let Some(error_code) = exception.error_code {
+            writeln!(screen, "error code: {:#x}", error_code)?;
+        }
+        writeln!(
+            screen,
+            "RIP: {:#018x}  CS: {:#06x}  RFLAGS: {:#018x}",
+            frame.instruction_pointer.as_u64(),
+            frame.code_segment,
+            frame.cpu_flags
+        )?;
+        writeln!(
+            screen,
+            "RSP: {:#018x}  SS: {:#06x}",
+            frame.stack_pointer.as_u64(),
+            frame.stack_segment
+        )?;
+    }
+
+    // CR2 is the address of the last page fault, CR3 the physical address of the level 4 page table
+    let (level_4_frame, cr3_flags) = Cr3::read();
+    writeln!(screen, "\nCR0: {:#018x}", Cr0::read_raw())?;
+    writeln!(screen, "CR2: {:#018x}", Cr2::read().as_u64())?;
+    writeln!(
+        screen,
+        "CR3: {:#018x}",
+        level_4_frame.start_address().as_u64() | cr3_flags.bits()
+    )?;
+    writeln!(screen, "CR4: {:#018x}", Cr4::read_raw())?;
+
+    // the oldest frames scroll off the top, they are still in the scrollback (page up)
+    write!(screen, "\nbacktrace:\n{}", backtrace)
+}
+
+// clear the active screen (framebuffer or VGA text buffer) to red on white and write to it
+fn with_screen(f: impl FnOnce(&mut dyn Write)) {
+    unsafe {
+        framebuffer::WRITER.force_unlock();
+        vga_buffer::WRITER.force_unlock();
+    }
+    if let Some(writer) = framebuffer::WRITER.lock().as_mut() {
+        writer.set_color(Color::Red, Color::White);
+        writer.clear_screen();
+        f(writer);
+        return;
+    }
+    let mut writer = vga_buffer::WRITER.lock();
+    writer.set_color(Color::Red, Color::White);
+    writer.clear_screen();
+    f(&mut *writer);
+}
+
+#[test_case]
+fn test_panic_report_contains_exception_state() {
+    use alloc::string::String;
+    use x86_64::VirtAddr;
+
+    let exception = ExceptionState {
+        name: "EXCEPTION: PAGE FAULT",
+        error_code: Some(0x2),
+        stack_frame: InterruptStackFrameValue {
+            instruction_pointer: VirtAddr::new(0x1234),
+            code_segment: 0x8,
+            cpu_flags: 0x2,
+            stack_pointer: VirtAddr::new(0x5678),
+            stack_segment: 0,
+        },
+    };
+    let mut report = String::new();
+    write_report(
+        &mut report,
+        &"test panic",
+        Some(Location::caller()),
+        Some(exception),
+        &Backtrace::capture(),
+    )
+    .unwrap();
+    assert!(report.starts_with("KERNEL PANIC\n\ntest panic\nat src/panic_screen.rs:"));
+    assert!(report.contains("EXCEPTION: PAGE FAULT\nerror code: 0x2\n"));
+    assert!(report.contains("RIP: 0x0000000000001234"));
+    assert!(report.contains("RSP: 0x0000000000005678"));
+    assert!(report.contains("CR3: "));
+    assert!(report.contains("\nbacktrace:\n 0: 0x"));
+}
@@ -0,0 +1,438 @@
+/*
+* Devices on the PERIPHERAL_COMPONENT_INTERCONNECT (PCI) bus are found through their configuration space:
+* 256 bytes per function describing the device (vendor, device, class) and its resources. A function is
+* addressed by bus (0-255), device (0-31) and function (0-7) and its configuration space is read through two
+* I/O ports: the address of a 32 bit register is written to CONFIG_ADDRESS (0xCF8) and its value is then
+* read from or written to CONFIG_DATA (0xCFC).
+*
+* init checks every bus and device, a vendor id of 0xFFFF means nothing is there. Function 0 tells in its
+* header type whether the device has more functions. The devices found are kept in a list and the
+* drivers that registered for one of them get it passed to their probe function, either during init or
+* when they register later. Every device is bound to at most one driver.
+*
+* The BASE_ADDRESS_REGISTERS (BARs) tell where the registers of the device are, either memory or I/O
+* ports. The size of a BAR is found by writing all ones to it: the bits that stay zero are the ones the
+* device decodes itself (the size is the lowest bit that reads back as one).
+* */
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// offsets of the registers in the configuration space header
+const VENDOR_ID: u8 = 0x00;
+const DEVICE_ID: u8 = 0x02;
+const COMMAND: u8 = 0x04;
+const REVISION: u8 = 0x08;
+const PROG_IF: u8 = 0x09;
+const SUBCLASS: u8 = 0x0A;
+const CLASS: u8 = 0x0B;
+const HEADER_TYPE: u8 = 0x0E;
+const BAR0: u8 = 0x10;
+const INTERRUPT_LINE: u8 = 0x3C;
+const INTERRUPT_PIN: u8 = 0x3D;
+
+const HEADER_TYPE_MULTI_FUNCTION: u8 = 0x80;
+// only general devices (type 0) have 6 BARs, bridges (type 1) use the space for bus numbers
+const HEADER_TYPE_GENERAL: u8 = 0x00;
+
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    // the value for CONFIG_ADDRESS, bit 31 enables the access and the offset must be 4 byte aligned
+    fn config_address(self, offset: u8) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+}
+
+// the notation of lspci: bus:device.function in hex
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+// the port pair is shared by all accesses so an interrupt handler using it in between would mix them up
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+pub fn read_u32(address: PciAddress, offset: u8) -> u32 {
+    interrupts::without_interrupts(|| {
+        let _lock = CONFIG_LOCK.lock();
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(address.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).read()
+        }
+    })
+}
+
+pub fn write_u32(address: PciAddress, offset: u8, value: u32) {
+    interrupts::without_interrupts(|| {
+        let _lock = CONFIG_LOCK.lock();
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(address.config_address(offset));
+            Port::<u32>::new(CONFIG_DATA).write(value);
+        }
+    })
+}
+
+pub fn read_u16(address: PciAddress, offset: u8) -> u16 {
+    (read_u32(address, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_u8(address: PciAddress, offset: u8) -> u8 {
+    (read_u32(address, offset) >> ((offset & 3) * 8)) as u8
+}
+
+// writes the 16 bits by reading and writing the whole register
+pub fn write_u16(address: PciAddress, offset: u8, value: u16) {
+    let shift = (offset & 2) * 8;
+    let register = read_u32(address, offset) & !(0xFFFF << shift);
+    write_u32(address, offset, register | (value as u32) << shift);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+    },
+    Io {
+        port: u16,
+        size: u16,
+    },
+}
+
+// decode a BAR from its value and the value read back after writing all ones (high halves for 64 bit BARs)
+fn decode_bar(value: u64, mask: u64) -> Option<Bar> {
+    if value & 1 == 1 {
+        // I/O BARs have 2 flag bits and are at most 16 bits wide
+        let mask = (mask & !0x3) as u16;
+        if mask == 0 {
+            return None;
+        }
+        return Some(Bar::Io {
+            port: (value & !0x3) as u16,
+            size: (!mask).wrapping_add(1),
+        });
+    }
+    let mask = mask & !0xF;
+    if mask == 0 {
+        return None;
+    }
+    let is_64bit = value >> 1 & 0x3 == 0x2;
+    // a 32 bit BAR reads back only 32 bits, the upper ones are all decoded
+    let mask = if is_64bit {
+        mask
+    } else {
+        mask | 0xFFFF_FFFF_0000_0000
+    };
+    Some(Bar::Memory {
+        address: value & !0xF,
+        size: (!mask).wrapping_add(1),
+        prefetchable: value & 0x8 != 0,
+    })
+}
+
+// read the BARs of a general device, a 64 bit BAR uses the next one for its high 32 bits
+fn read_bars(address: PciAddress) -> [Option<Bar>; 6] {
+    // an interrupt handler printing to the VGA device while its memory is disabled would be lost
+    interrupts::without_interrupts(|| {
+        let mut bars = [None; 6];
+        let command = read_u16(address, COMMAND);
+        // the device must not decode the all ones address while the BARs are sized
+        write_u16(
+            address,
+            COMMAND,
+            command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE),
+        );
+        let mut index = 0;
+        while index < 6 {
+            let offset = BAR0 + index as u8 * 4;
+            let low = read_u32(address, offset);
+            write_u32(address, offset, 0xFFFF_FFFF);
+            let low_mask = read_u32(address, offset);
+            write_u32(address, offset, low);
+            let is_64bit = low & 0x7 == 0x4 && index < 5;
+            let (value, mask) = if is_64bit {
+                let high = read_u32(address, offset + 4);
+                write_u32(address, offset + 4, 0xFFFF_FFFF);
+                let high_mask = read_u32(address, offset + 4);
+                write_u32(address, offset + 4, high);
+                (
+                    (high as u64) << 32 | low as u64,
+                    (high_mask as u64) << 32 | low_mask as u64,
+                )
+            } else {
+                (low as u64, low_mask as u64)
+            };
+            bars[index] = decode_bar(value, mask);
+            index += if is_64bit { 2 } else { 1 };
+        }
+        write_u16(address, COMMAND, command);
+        bars
+    })
+}
+
+#[derive(Debug, Clone)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    // the PIC IRQ line the firmware routed the device's interrupt to and which pin (1-4, 0 is none) it uses
+    pub interrupt_line: u8,
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+}
+
+impl PciDevice {
+    fn read(address: PciAddress) -> Option<PciDevice> {
+        let vendor_id = read_u16(address, VENDOR_ID);
+        if vendor_id == 0xFFFF {
+            return None;
+        }
+        let header_type = read_u8(address, HEADER_TYPE);
+        let bars = if header_type & !HEADER_TYPE_MULTI_FUNCTION == HEADER_TYPE_GENERAL {
+            read_bars(address)
+        } else {
+            [None; 6]
+        };
+        Some(PciDevice {
+            address,
+            vendor_id,
+            device_id: read_u16(address, DEVICE_ID),
+            class: read_u8(address, CLASS),
+            subclass: read_u8(address, SUBCLASS),
+            prog_if: read_u8(address, PROG_IF),
+            revision: read_u8(address, REVISION),
+            header_type,
+            interrupt_line: read_u8(address, INTERRUPT_LINE),
+            interrupt_pin: read_u8(address, INTERRUPT_PIN),
+            bars,
+        })
+    }
+
+    // set bits in the command register, e.g. COMMAND_BUS_MASTER for devices that use DMA
+    pub fn enable(&self, bits: u16) {
+        let command = read_u16(self.address, COMMAND);
+        write_u16(self.address, COMMAND, command | bits);
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        class_name(self.class, self.subclass)
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x} {}",
+            self.address,
+            self.vendor_id,
+            self.device_id,
+            self.class_name()
+        )
+    }
+}
+
+// the names of the common classes, from the PCI code and ID assignment specification
+fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVM controller",
+        (0x01, 0x00) => "SCSI controller",
+        (0x01, _) => "mass storage controller",
+        (0x02, 0x00) => "ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x0C, 0x03) => "USB controller",
+        (0x0C, 0x05) => "SMBus controller",
+        (0x0C, _) => "serial bus controller",
+        _ => "unknown device",
+    }
+}
+
+// which devices a driver handles
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceMatch {
+    Id { vendor_id: u16, device_id: u16 },
+    Class { class: u8, subclass: u8 },
+}
+
+impl DeviceMatch {
+    fn matches(&self, device: &PciDevice) -> bool {
+        match *self {
+            DeviceMatch::Id {
+                vendor_id,
+                device_id,
+            } => device.vendor_id == vendor_id && device.device_id == device_id,
+            DeviceMatch::Class { class, subclass } => {
+                device.class == class && device.subclass == subclass
+            }
+        }
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [DeviceMatch],
+    // called once for every matching device, returns false if it can't drive the device
+    pub probe: fn(&'static PciDevice) -> bool,
+}
+
+static DEVICES: OnceCell<Vec<PciDevice>> = OnceCell::uninit();
+
+static DRIVERS: Mutex<Vec<&'static PciDriver>> = Mutex::new(Vec::new());
+// the driver bound to the device with the same index in DEVICES
+static BOUND: Mutex<Vec<Option<&'static str>>> = Mutex::new(Vec::new());
+
+fn scan() -> Vec<PciDevice> {
+    let mut devices = Vec::new();
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let address = PciAddress {
+                bus,
+                device,
+                function: 0,
+            };
+            let Some(first) = PciDevice::read(address) else {
+                continue;
+            };
+            let functions = if first.header_type & HEADER_TYPE_MULTI_FUNCTION != 0 {
+                8
+            } else {
+                1
+            };
+            devices.push(first);
+            for function in 1..functions {
+                let address = PciAddress {
+                    function,
+                    ..address
+                };
+                devices.extend(PciDevice::read(address));
+            }
+        }
+    }
+    devices
+}
+
+// offer the unbound devices to the driver, the locks aren't held while a probe function runs
+fn probe(driver: &'static PciDriver, devices: &'static [PciDevice]) {
+    for (index, device) in devices.iter().enumerate() {
+        if BOUND.lock()[index].is_some() || !driver.matches.iter().any(|m| m.matches(device)) {
+            continue;
+        }
+        if (driver.probe)(device) {
+            log::info!("pci: {} bound to {}", device.address, driver.name);
+            BOUND.lock()[index] = Some(driver.name);
+        }
+    }
+}
+
+// scan the buses and probe the drivers registered so far, called by init_devices (needs the heap)
+pub fn init() {
+    let devices = DEVICES.get_or_init(scan);
+    for device in devices {
+        log::info!("pci: {}", device);
+    }
+    BOUND.lock().resize(devices.len(), None);
+    let drivers = DRIVERS.lock().clone();
+    for driver in drivers {
+        probe(driver, devices);
+    }
+}
+
+// register a driver, it is probed right away if the buses were already scanned
+pub fn register_driver(driver: &'static PciDriver) {
+    DRIVERS.lock().push(driver);
+    if let Some(devices) = DEVICES.get() {
+        probe(driver, devices);
+    }
+}
+
+// the devices found by init with the name of their driver
+pub fn devices() -> impl Iterator<Item = (&'static PciDevice, Option<&'static str>)> {
+    let devices: &'static [PciDevice] = DEVICES.get().map_or(&[], |devices| devices.as_slice());
+    let bound = BOUND.lock().clone();
+    devices
+        .iter()
+        .enumerate()
+        .map(move |(index, device)| (device, bound.get(index).copied().flatten()))
+}
+
+// the first device matching, for drivers that only handle one device
+pub fn find(matches: DeviceMatch) -> Option<&'static PciDevice> {
+    devices()
+        .map(|(device, _)| device)
+        .find(|device| matches.matches(device))
+}
+
+#[test_case]
+fn test_decode_bars_and_find_the_host_bridge() {
+    // a 32 bit memory BAR of 4 KiB and an I/O BAR of 32 ports
+    assert_eq!(
+        decode_bar(0xFEBF_0000, 0xFFFF_F000),
+        Some(Bar::Memory {
+            address: 0xFEBF_0000,
+            size: 0x1000,
+            prefetchable: false
+        })
+    );
+    assert_eq!(
+        decode_bar(0xC041, 0xFFFF_FFE1),
+        Some(Bar::Io {
+            port: 0xC040,
+            size: 32
+        })
+    );
+    // a prefetchable 64 bit BAR of 16 KiB above 4 GiB and an unused BAR
+    assert_eq!(
+        decode_bar(0x8_0000_000C, 0xFFFF_FFFF_FFFF_C00C),
+        Some(Bar::Memory {
+            address: 0x8_0000_0000,
+            size: 0x4000,
+            prefetchable: true
+        })
+    );
+    assert_eq!(decode_bar(0, 0), None);
+    // QEMU's chipsets have a host bridge on bus 0
+    assert!(find(DeviceMatch::Class {
+        class: 0x06,
+        subclass: 0x00
+    })
+    .is_some_and(|device| device.address.bus == 0));
+}
@@ -0,0 +1,268 @@
+/*
+* Every CPU needs some data of its own: its id, the task it is running, how deep it is nested in interrupt
+* handlers... A global variable is shared by all CPUs, so each CPU gets a CpuBlock and the address of its
+* block is stored in the GS base MSR. An access through the gs segment (gs:[0]) reads from the current
+* CPU's block without knowing which CPU runs the code, and it can't be interrupted halfway like looking up
+* the CPU id and then indexing a table.
+*
+* The blocks are a static array (one per CPU id, no heap needed) and the first field of a block is its own
+* address so the block can be found with a single gs relative load. User programs can load the gs segment
+* register too, which sets the GS base to the base of the descriptor, so the kernel can't trust the GS base
+* it finds when it is entered from user mode. The model specific register KERNEL_GS_BASE holds the GS base of
+* the user program while the kernel runs, and every entry from ring 3 (the syscall entry, the interrupt and
+* exception handlers with UserGs) exchanges the two with swapgs and swaps them back before returning to the
+* program; enter_user swaps before starting it. The syscall entry finds its stack in the block
+* (SYSCALL_STACK_OFFSET).
+*
+* Other modules declare their own per-CPU variables with the per_cpu! macro, it creates an array with one
+* value per CPU that is indexed with the current CPU id. The values are only handed out as shared references
+* since an interrupt handler on the same CPU can access the variable too, so they are atomics or locks.
+* */
+use core::mem::offset_of;
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::segmentation::GS;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+// the maximum number of CPUs the kernel runs on, smp doesn't start the others
+pub const MAX_CPUS: usize = 64;
+
+// the value of current_task while a CPU runs no task
+const NO_TASK: u64 = u64::MAX;
+
+#[repr(C)]
+pub struct CpuBlock {
+    // the address of the block itself, must be the first field (read with gs:[0])
+    this: AtomicU64,
+    id: AtomicUsize,
+    apic_id: AtomicU32,
+    interrupt_depth: AtomicUsize,
+    current_task: AtomicU64,
+    // the top of the kernel stack the syscall entry switches to
+    syscall_stack: AtomicU64,
+    // the stack pointer of the user program saved by the syscall entry
+    user_stack: AtomicU64,
+    // the kernel stack pointer to go back to when the user program exits
+    user_return: AtomicU64,
+}
+
+// the offsets of the fields the syscall entry accesses with gs relative moves
+pub const SYSCALL_STACK_OFFSET: usize = offset_of!(CpuBlock, syscall_stack);
+pub const USER_STACK_OFFSET: usize = offset_of!(CpuBlock, user_stack);
+
+impl CpuBlock {
+    const fn new() -> Self {
+        CpuBlock {
+            this: AtomicU64::new(0),
+            id: AtomicUsize::new(0),
+            apic_id: AtomicU32::new(0),
+            interrupt_depth: AtomicUsize::new(0),
+            current_task: AtomicU64::new(NO_TASK),
+            syscall_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            user_return: AtomicU64::new(0),
+        }
+    }
+
+    // the index of the CPU, 0 is the bootstrap processor and the others are numbered in the order they start
+    pub fn id(&self) -> usize {
+        self.id.load(Ordering::Relaxed)
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    // the number of interrupt handlers the CPU is currently running (nested ones count too)
+    pub fn interrupt_depth(&self) -> usize {
+        self.interrupt_depth.load(Ordering::Relaxed)
+    }
+
+    // the id of the task the executor on this CPU is polling
+    pub fn current_task(&self) -> Option<u64> {
+        match self.current_task.load(Ordering::Relaxed) {
+            NO_TASK => None,
+            task => Some(task),
+        }
+    }
+
+    pub fn set_current_task(&self, task: Option<u64>) {
+        self.current_task
+            .store(task.unwrap_or(NO_TASK), Ordering::Relaxed);
+    }
+
+    pub fn syscall_stack(&self) -> VirtAddr {
+        VirtAddr::new(self.syscall_stack.load(Ordering::Relaxed))
+    }
+
+    pub fn set_syscall_stack(&self, top: VirtAddr) {
+        self.syscall_stack.store(top.as_u64(), Ordering::Relaxed);
+    }
+
+    // where usermode::enter_user saves the kernel stack pointer
+    pub fn user_return(&self) -> *mut u64 {
+        self.user_return.as_ptr()
+    }
+}
+
+static BLOCKS: [CpuBlock; MAX_CPUS] = [const { CpuBlock::new() }; MAX_CPUS];
+
+// install the block of the CPU with the id in the GS base registers of the running CPU
+fn install(id: usize, apic_id: u32) {
+    let block = &BLOCKS[id];
+    let address = block as *const CpuBlock as u64;
+    block.this.store(address, Ordering::Relaxed);
+    block.id.store(id, Ordering::Relaxed);
+    block.apic_id.store(apic_id, Ordering::Relaxed);
+    GsBase::write(VirtAddr::new(address));
+    // the GS base user programs start with
+    KernelGsBase::write(VirtAddr::new(0));
+}
+
+// called first by lib::init on the bootstrap processor, its APIC id is filled in by apic::init
+pub fn init() {
+    install(0, 0);
+}
+
+// called by every application processor when it starts, before it can take interrupts
+pub fn init_ap(id: usize, apic_id: u32) {
+    assert!(id < MAX_CPUS, "CPU id {} out of range", id);
+    install(id, apic_id);
+}
+
+pub fn set_apic_id(apic_id: u32) {
+    current().apic_id.store(apic_id, Ordering::Relaxed);
+}
+
+// true once init installed a block on this CPU, before that gs:[0] isn't a valid address
+pub fn is_initialized() -> bool {
+    GsBase::read().as_u64() != 0
+}
+
+// the block of the running CPU
+pub fn current() -> &'static CpuBlock {
+    assert!(is_initialized(), "percpu::init must be called first");
+    let address: u64;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) address, options(nostack, readonly, preserves_flags));
+        &*(address as *const CpuBlock)
+    }
+}
+
+pub fn cpu_id() -> usize {
+    current().id()
+}
+
+// count the interrupt handler that is running until the guard is dropped, used by interrupts::dispatch_irq
+pub struct InterruptGuard(&'static CpuBlock);
+
+impl InterruptGuard {
+    pub fn enter() -> Self {
+        let block = current();
+        block.interrupt_depth.fetch_add(1, Ordering::Relaxed);
+        InterruptGuard(block)
+    }
+}
+
+impl Drop for InterruptGuard {
+    fn drop(&mut self) {
+        self.0.interrupt_depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/*
+* Called first by the interrupt and exception handlers: if the interrupted code ran in user mode the GS base is
+* the one of the program, swapgs loads the kernel's (the block of this CPU) until the guard is dropped. A
+* handler that doesn't return to the program (usermode::kill) stays in the kernel and keeps the kernel's.
+* */
+pub struct UserGs(bool);
+
+impl UserGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        let from_user = stack_frame.code_segment & 3 == 3;
+        if from_user {
+            unsafe { GS::swap() };
+        }
+        UserGs(from_user)
+    }
+}
+
+impl Drop for UserGs {
+    fn drop(&mut self) {
+        if self.0 {
+            unsafe { GS::swap() };
+        }
+    }
+}
+
+// true while the CPU is running an interrupt handler
+pub fn in_interrupt() -> bool {
+    is_initialized() && current().interrupt_depth() > 0
+}
+
+/*
+* A variable with one value per CPU, declared with the per_cpu! macro:
+*
+*     per_cpu! {
+*         static EVENTS: AtomicU64 = AtomicU64::new(0);
+*     }
+*
+*     EVENTS.get().fetch_add(1, Ordering::Relaxed);
+* */
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        PerCpu { values }
+    }
+
+    // the value of the running CPU
+    pub fn get(&self) -> &T {
+        &self.values[cpu_id()]
+    }
+
+    // the value of another CPU, e.g. to sum up statistics
+    pub fn get_for(&self, cpu: usize) -> &T {
+        &self.values[cpu]
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.values.iter()
+    }
+}
+
+#[macro_export]
+macro_rules! per_cpu {
+    ($($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::percpu::PerCpu<$ty> =
+                $crate::percpu::PerCpu::new([const { $init }; $crate::percpu::MAX_CPUS]);
+        )*
+    };
+}
+
+#[test_case]
+fn test_per_cpu_variable() {
+    per_cpu! {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    }
+    assert_eq!(cpu_id(), 0);
+    COUNTER.get().fetch_add(2, Ordering::Relaxed);
+    assert_eq!(COUNTER.get_for(0).load(Ordering::Relaxed), 2);
+    assert_eq!(
+        COUNTER
+            .iter()
+            .map(|value| value.load(Ordering::Relaxed))
+            .sum::<usize>(),
+        2
+    );
+    {
+        let _guard = InterruptGuard::enter();
+        assert!(in_interrupt());
+    }
+    assert!(!in_interrupt());
+}
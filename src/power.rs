@@ -0,0 +1,210 @@
+/*
+* Turning the machine off or restarting it without the isa-debug-exit device of the tests.
+*
+* shutdown enters the ACPI sleep state S5 (soft off): the value for the SLP_TYP field of the PM1 control
+* registers is the first element of the \_S5 package in the DSDT, written together with the SLP_EN bit.
+* The DSDT is AML bytecode, instead of an interpreter we search for the bytes of the definition:
+*
+*     NameOp(0x08) "_S5_" PackageOp(0x12) PkgLength NumElements <SLP_TYPa> <SLP_TYPb> ...
+*
+* which is how every firmware we know of encodes it. If that fails the ports that QEMU (0x604), older
+* QEMU versions and Bochs (0xB004) and VirtualBox (0x4004) use for their ACPI PM1a control register are
+* tried directly.
+*
+* reboot uses the ACPI reset register if the FADT has one, then the keyboard controller which can pulse
+* the reset line of the CPU (command 0xFE) and if that doesn't work either loads an empty IDT and triggers
+* an interrupt: the CPU can't find a handler for it or for the double fault, this triple fault resets the
+* machine too.
+* */
+use crate::acpi::{self, ADDRESS_SPACE_IO, ADDRESS_SPACE_MEMORY};
+use crate::memory;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+const SLP_EN: u16 = 1 << 13;
+const SCI_EN: u16 = 1 << 0;
+const KEYBOARD_CONTROLLER_PORT: u16 = 0x64;
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xFE;
+
+// (port, value) of the emulators' PM1a control registers
+const EMULATOR_SHUTDOWN_PORTS: [(u16, u16); 3] =
+    [(0x604, 0x2000), (0xB004, 0x2000), (0x4004, 0x3400)];
+
+// a rough delay that works with interrupts disabled, reading a port takes about a microsecond
+fn io_delay(microseconds: u32) {
+    let mut port: Port<u8> = Port::new(0x80);
+    for _ in 0..microseconds {
+        unsafe { port.read() };
+    }
+}
+
+// the SLP_TYPa and SLP_TYPb values of the \_S5 object in the AML code
+fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    let start = aml.windows(4).position(|window| window == b"_S5_")?;
+    // the name must be defined by a NameOp, optionally with a root prefix (\_S5_)
+    let name_op = match start.checked_sub(1).map(|index| aml[index]) {
+        Some(b'\\') => start.checked_sub(2).map(|index| aml[index]),
+        byte => byte,
+    };
+    if name_op != Some(0x08) || aml.get(start + 4) != Some(&0x12) {
+        return None;
+    }
+    // the top 2 bits of the first PkgLength byte are the number of bytes that follow it
+    let pkg_length_bytes = (*aml.get(start + 5)? >> 6) as usize + 1;
+    // skip the PkgLength and NumElements
+    let mut offset = start + 5 + pkg_length_bytes + 1;
+    let mut element = || {
+        let byte = *aml.get(offset)?;
+        offset += 1;
+        // BytePrefix followed by the value, otherwise ZeroOp (0) or OneOp (1) which are their value
+        if byte == 0x0A {
+            let value = *aml.get(offset)?;
+            offset += 1;
+            Some(value)
+        } else {
+            Some(byte)
+        }
+    };
+    let a = element()?;
+    let b = element()?;
+    Some((a, b))
+}
+
+// switch to ACPI mode if the firmware is still in legacy mode (the PM registers are ignored until then)
+fn enable_acpi(fadt: &acpi::Fadt) {
+    let mut control: Port<u16> = Port::new(fadt.pm1a_control_block as u16);
+    if fadt.smi_command_port == 0
+        || fadt.acpi_enable == 0
+        || unsafe { control.read() } & SCI_EN != 0
+    {
+        return;
+    }
+    unsafe { Port::<u8>::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+    for _ in 0..300 {
+        if unsafe { control.read() } & SCI_EN != 0 {
+            return;
+        }
+        io_delay(1000);
+    }
+}
+
+fn acpi_shutdown() {
+    let Some(fadt) = acpi::fadt() else {
+        return;
+    };
+    let Some(dsdt) = acpi::table(b"DSDT") else {
+        return;
+    };
+    // the AML starts after the table header
+    let Some((slp_typa, slp_typb)) = parse_s5(&dsdt[36..]) else {
+        log::warn!("power: no \\_S5 object in the DSDT");
+        return;
+    };
+    if fadt.pm1a_control_block == 0 {
+        return;
+    }
+    enable_acpi(fadt);
+    unsafe {
+        Port::<u16>::new(fadt.pm1a_control_block as u16).write((slp_typa as u16) << 10 | SLP_EN);
+        if fadt.pm1b_control_block != 0 {
+            Port::<u16>::new(fadt.pm1b_control_block as u16)
+                .write((slp_typb as u16) << 10 | SLP_EN);
+        }
+    }
+    io_delay(100_000);
+}
+
+// turn the machine off, halts forever if no method works
+pub fn shutdown() -> ! {
+    log::info!("power: shutting down");
+    x86_64::instructions::interrupts::disable();
+    acpi_shutdown();
+    for (port, value) in EMULATOR_SHUTDOWN_PORTS {
+        unsafe { Port::<u16>::new(port).write(value) };
+        io_delay(10_000);
+    }
+    log::error!("power: shutdown failed, it is now safe to turn off the machine");
+    crate::hlt_loop();
+}
+
+fn acpi_reset() {
+    let Some(fadt) = acpi::fadt() else {
+        return;
+    };
+    let Some(register) = fadt.reset_register else {
+        return;
+    };
+    match register.address_space {
+        ADDRESS_SPACE_IO => unsafe {
+            Port::<u8>::new(register.address as u16).write(fadt.reset_value);
+        },
+        ADDRESS_SPACE_MEMORY => unsafe {
+            if let Ok(address) = memory::map_mmio(PhysAddr::new(register.address), 1) {
+                address.as_mut_ptr::<u8>().write_volatile(fadt.reset_value);
+            }
+        },
+        // the reset register can also be in the PCI configuration space, we don't support that
+        _ => return,
+    }
+    io_delay(10_000);
+}
+
+fn keyboard_controller_reset() {
+    let mut port: Port<u8> = Port::new(KEYBOARD_CONTROLLER_PORT);
+    unsafe {
+        // wait until the input buffer of the controller is empty
+        for _ in 0..10_000 {
+            if port.read() & 0x02 == 0 {
+                break;
+            }
+        }
+        port.write(KEYBOARD_CONTROLLER_RESET);
+    }
+    io_delay(10_000);
+}
+
+fn triple_fault() {
+    use x86_64::structures::DescriptorTablePointer;
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: x86_64::VirtAddr::zero(),
+    };
+    unsafe {
+        x86_64::instructions::tables::lidt(&empty);
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+}
+
+// restart the machine
+pub fn reboot() -> ! {
+    log::info!("power: rebooting");
+    reset();
+}
+
+// restart without logging, for the panic handler where the logger may be locked
+pub fn reset() -> ! {
+    x86_64::instructions::interrupts::disable();
+    acpi_reset();
+    keyboard_controller_reset();
+    triple_fault();
+    crate::hlt_loop();
+}
+
+#[test_case]
+fn test_parse_s5_package() {
+    // DefName \_S5_ Package (4) { 0x05, Zero, Zero, Zero } with other code around it
+    let aml = [
+        0x10, 0x20, 0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 0x05, 0x00, 0x00,
+        0x00, 0x5B,
+    ];
+    assert_eq!(parse_s5(&aml), Some((5, 0)));
+    // a long PkgLength and OneOp elements
+    let aml = [
+        0x08, b'_', b'S', b'5', b'_', 0x12, 0x40, 0x01, 0x02, 0x01, 0x0A, 0x07,
+    ];
+    assert_eq!(parse_s5(&aml), Some((1, 7)));
+    // a reference to _S5_ that isn't its definition
+    let aml = [0x70, b'_', b'S', b'5', b'_', 0x12];
+    assert_eq!(parse_s5(&aml), None);
+}
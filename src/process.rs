@@ -0,0 +1,232 @@
+/*
+* A PROCESS is a running user program with everything it owns: an address space (a level 4 table cloned
+* from the kernel's, memory module) with the segments of its ELF file, a stack at the end of the user part,
+* a heap after the segments that it grows with the brk syscall, and a kernel thread (scheduler module) that
+* runs it. The scheduler saves CR3 with the registers of a thread, so switching to the thread of another
+* process switches to the address space of that process and every process only sees its own user part.
+*
+* create loads the program and starts its thread. When the program exits (or is killed) its memory is freed
+* but the process stays in the table with its exit status (a ZOMBIE) until it is reaped, like wait on Unix.
+*
+* The syscalls don't know which process called them, they find it by the active level 4 table.
+* */
+use crate::elf::{self, ElfError, Image};
+use crate::memory::{self, AddressSpace};
+use crate::scheduler;
+use crate::usermode::{self, ExitStatus};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+// the heap can grow up to the guard page below the stack
+const HEAP_LIMIT: u64 = memory::USER_END - (usermode::USER_STACK_PAGES + 1) * 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(pub u64);
+
+impl Pid {
+    fn new() -> Self {
+        // 0 is left out so it can mean "no process" for user programs
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Exited(ExitStatus),
+}
+
+// the memory of a process, shared by its thread and the syscalls it makes
+struct Memory {
+    address_space: AddressSpace,
+    heap_start: VirtAddr,
+    // the program break, the heap is mapped up to the page it is in
+    heap_end: VirtAddr,
+}
+
+struct Process {
+    name: String,
+    level_4_frame: PhysFrame,
+    state: State,
+    // None once the process exited
+    memory: Option<Arc<Mutex<Memory>>>,
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Process>> = Mutex::new(BTreeMap::new());
+
+// load the ELF executable and start it in a new process
+pub fn create(name: &str, file: &[u8]) -> Result<Pid, ElfError> {
+    start(name, elf::load(file)?)
+}
+
+fn start(name: &str, mut image: Image) -> Result<Pid, ElfError> {
+    let stack = usermode::map_stack(&mut image.address_space, usermode::USER_STACK_PAGES)
+        .ok_or(ElfError::OutOfMemory)?;
+    let heap_start = image.end.align_up(4096u64);
+    let pid = Pid::new();
+    let level_4_frame = image.address_space.level_4_frame();
+    let memory = Arc::new(Mutex::new(Memory {
+        address_space: image.address_space,
+        heap_start,
+        heap_end: heap_start,
+    }));
+    PROCESSES.lock().insert(
+        pid,
+        Process {
+            name: String::from(name),
+            level_4_frame,
+            state: State::Running,
+            memory: Some(memory.clone()),
+        },
+    );
+    let entry = image.entry;
+    scheduler::spawn(name, move || run(pid, memory, entry, stack));
+    Ok(pid)
+}
+
+// the thread of the process
+fn run(pid: Pid, memory: Arc<Mutex<Memory>>, entry: VirtAddr, stack: VirtAddr) {
+    let status = unsafe {
+        memory.lock().address_space.activate();
+        usermode::run_active(entry, stack)
+    };
+    drop(memory);
+    log::debug!("process {} {:?}", pid, status);
+    let memory = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get_mut(&pid).expect("running process not found");
+        process.state = State::Exited(status);
+        process.memory.take()
+    };
+    // the last reference, the address space is freed (the kernel's is active again since run_active returned)
+    drop(memory);
+}
+
+pub fn state(pid: Pid) -> Option<State> {
+    PROCESSES.lock().get(&pid).map(|process| process.state)
+}
+
+// remove the exited process and return its exit status, None if it is still running or doesn't exist
+pub fn reap(pid: Pid) -> Option<ExitStatus> {
+    let mut processes = PROCESSES.lock();
+    let State::Exited(status) = processes.get(&pid)?.state else {
+        return None;
+    };
+    processes.remove(&pid);
+    Some(status)
+}
+
+// let the other threads run until the process exits, then reap it
+pub fn wait(pid: Pid) -> Option<ExitStatus> {
+    loop {
+        match state(pid)? {
+            State::Exited(_) => return reap(pid),
+            State::Running => scheduler::yield_now(),
+        }
+    }
+}
+
+// the pid, name and state of every process
+pub fn list() -> Vec<(Pid, String, State)> {
+    PROCESSES
+        .lock()
+        .iter()
+        .map(|(pid, process)| (*pid, process.name.clone(), process.state))
+        .collect()
+}
+
+// the running process whose address space is active
+fn current() -> Option<(Pid, Arc<Mutex<Memory>>)> {
+    let (active, _) = Cr3::read();
+    PROCESSES
+        .lock()
+        .iter()
+        .find(|(_, process)| process.level_4_frame == active)
+        .and_then(|(pid, process)| Some((*pid, process.memory.clone()?)))
+}
+
+pub fn current_pid() -> Option<Pid> {
+    current().map(|(pid, _)| pid)
+}
+
+/*
+* Move the program break of the current process to the address (brk syscall). The pages up to it are lazy
+* (memory module) and mapped zeroed when touched, the pages above it are freed. Returns the new break, or
+* the unchanged one when the address is outside the heap; 0 asks for the current break.
+* */
+pub fn brk(address: u64) -> u64 {
+    let Some((_, memory)) = current() else {
+        return 0;
+    };
+    let mut memory = memory.lock();
+    let current = memory.heap_end;
+    if address < memory.heap_start.as_u64() || address > HEAP_LIMIT {
+        return current.as_u64();
+    }
+    let new = VirtAddr::new(address);
+    // the first pages that are not mapped now and after the move
+    let mapped_end = current.align_up(4096u64);
+    let new_end = new.align_up(4096u64);
+    if new_end > mapped_end {
+        // the pages get frames when the program touches them
+        let flags = usermode::data_flags();
+        memory.address_space.map_lazy(mapped_end..new_end, flags);
+    } else if new_end < mapped_end {
+        unsafe { memory.address_space.unmap_lazy(new_end..mapped_end) };
+    }
+    memory.heap_end = new;
+    address
+}
+
+// a process grows its heap, writes to it and exits with the size of the heap
+#[test_case]
+fn test_process_heap_and_reap() {
+    use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags};
+
+    let code: &[u8] = &[
+        0xb8, 0x03, 0, 0, 0, // mov eax, 3 (brk)
+        0x31, 0xff, // xor edi, edi (the current break)
+        0x0f, 0x05, // syscall
+        0x48, 0x89, 0xc3, // mov rbx, rax
+        0x48, 0x8d, 0xb8, 0x00, 0x20, 0, 0, // lea rdi, [rax + 0x2000]
+        0xb8, 0x03, 0, 0, 0, // mov eax, 3 (brk)
+        0x0f, 0x05, // syscall
+        0xc6, 0x83, 0xff, 0x1f, 0, 0, 0x01, // mov byte [rbx + 0x1fff], 1
+        0x48, 0x29, 0xd8, // sub rax, rbx
+        0x89, 0xc7, // mov edi, eax
+        0xb8, 0x00, 0, 0, 0, // mov eax, 0 (exit)
+        0x0f, 0x05, // syscall
+    ];
+    let mut address_space = AddressSpace::new().unwrap();
+    let frame = memory::with_frame_allocator(|allocator| allocator.allocate_frame()).unwrap();
+    let page = Page::containing_address(VirtAddr::new(memory::USER_START));
+    unsafe {
+        let virt = memory::phys_to_virt(frame.start_address());
+        core::ptr::copy_nonoverlapping(code.as_ptr(), virt.as_mut_ptr(), code.len());
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        address_space.map(page, frame, flags).unwrap();
+    }
+    let image = Image {
+        address_space,
+        entry: page.start_address(),
+        end: page.start_address() + code.len() as u64,
+    };
+    let pid = start("test", image).unwrap();
+    assert_eq!(wait(pid), Some(ExitStatus::Exited(0x2000)));
+    assert_eq!(state(pid), None);
+}
@@ -0,0 +1,134 @@
+/*
+* A SAMPLING PROFILER: while it runs, every timer interrupt records the address of the instruction it
+* interrupted. Functions that take a lot of time are interrupted often, so counting the samples per function
+* (with the symbol table of the symbols module) shows where the time goes. With TICKS_PER_SECOND interrupts
+* per second a few seconds of profiling are enough for the hot paths.
+*
+* The interrupt handler can't allocate a buffer or wait for a lock, so the samples go to a preallocated
+* buffer of atomics, the next free slot is taken with fetch_add. Once the buffer is full the samples are only
+* counted as dropped. Samples in user mode or in code without a symbol are reported together as unknown.
+* */
+use crate::symbols::{self, Symbol};
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+// 16 seconds of samples at 1000 ticks per second, 128 KiB
+pub const MAX_SAMPLES: usize = 16 * 1024;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SAMPLES: [AtomicU64; MAX_SAMPLES] = [const { AtomicU64::new(0) }; MAX_SAMPLES];
+// the number of samples taken, including the ones that didn't fit in SAMPLES
+static NEXT_SAMPLE: AtomicUsize = AtomicUsize::new(0);
+
+// forget the previous samples and start recording
+pub fn start() {
+    ENABLED.store(false, Ordering::SeqCst);
+    NEXT_SAMPLE.store(0, Ordering::SeqCst);
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+// stop recording, the samples are kept for the report
+pub fn stop() {
+    ENABLED.store(false, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// called by the timer interrupt with the instruction pointer it interrupted, must not block or allocate
+pub(crate) fn sample(instruction_pointer: u64) {
+    if !is_enabled() {
+        return;
+    }
+    let index = NEXT_SAMPLE.fetch_add(1, Ordering::Relaxed);
+    if let Some(slot) = SAMPLES.get(index) {
+        slot.store(instruction_pointer, Ordering::Relaxed);
+    }
+}
+
+// the number of samples in the buffer and the number that didn't fit
+pub fn sample_count() -> (usize, usize) {
+    let taken = NEXT_SAMPLE.load(Ordering::Relaxed);
+    (taken.min(MAX_SAMPLES), taken.saturating_sub(MAX_SAMPLES))
+}
+
+// a function and the number of samples in it, symbol is None for the samples without a symbol
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hotspot {
+    pub symbol: Option<Symbol>,
+    pub samples: usize,
+}
+
+// the samples counted per function, the functions with the most samples first
+pub fn report() -> Vec<Hotspot> {
+    let (count, _dropped) = sample_count();
+    // the functions by their start address, the offset of the symbol is the one of the first sample
+    let mut functions: BTreeMap<u64, Hotspot> = BTreeMap::new();
+    let mut unknown = 0;
+    for slot in &SAMPLES[..count] {
+        match symbols::lookup(slot.load(Ordering::Relaxed)) {
+            Some(symbol) => {
+                functions
+                    .entry(symbol.address)
+                    .or_insert(Hotspot {
+                        symbol: Some(symbol),
+                        samples: 0,
+                    })
+                    .samples += 1
+            }
+            None => unknown += 1,
+        }
+    }
+    let mut hotspots: Vec<Hotspot> = functions.into_values().collect();
+    if unknown > 0 {
+        hotspots.push(Hotspot {
+            symbol: None,
+            samples: unknown,
+        });
+    }
+    hotspots.sort_by_key(|hotspot| Reverse(hotspot.samples));
+    hotspots
+}
+
+#[test_case]
+fn test_samples_are_counted_per_function() {
+    #[inline(never)]
+    fn hot_function() {}
+
+    let address = hot_function as *const () as u64;
+    start();
+    for _ in 0..3 {
+        sample(address);
+    }
+    stop();
+    // the timer interrupts add their own samples
+    let (count, dropped) = sample_count();
+    assert!(count >= 3);
+    assert_eq!(dropped, 0);
+    sample(address);
+    assert_eq!(sample_count().0, count);
+
+    let hotspots = report();
+    assert_eq!(
+        hotspots
+            .iter()
+            .map(|hotspot| hotspot.samples)
+            .sum::<usize>(),
+        count
+    );
+    assert!(hotspots
+        .windows(2)
+        .all(|pair| pair[0].samples >= pair[1].samples));
+    // without the symbol table every sample is unknown
+    let hot = hotspots
+        .iter()
+        .find(|hotspot| match hotspot.symbol {
+            Some(symbol) => symbol.address == address,
+            None => symbols::symbol_count() == 0,
+        })
+        .expect("the samples of hot_function are missing");
+    assert!(hot.samples >= 3);
+}
@@ -0,0 +1,266 @@
+/*
+* The permissions in the page tables only protect the kernel from itself once the CPU enforces them:
+*  * EFER.NXE makes the NO_EXECUTE bit valid, without it the bit is reserved and the data pages are executable
+*  * CR0.WP (write protect) makes ring 0 respect read only pages too, by default only user code is stopped
+*  * CR4.SMEP (supervisor mode execution prevention) faults when the kernel executes a user page, a jump
+*    through a corrupted pointer can't run code a program prepared
+*  * CR4.SMAP (supervisor mode access prevention) faults when the kernel reads or writes a user page while
+*    EFLAGS.AC is clear. The system calls set it with stac and clear it with clac around the copies from user
+*    memory (with_user_access), every other access to a user page is a bug the page fault reports
+* SMEP and SMAP are only enabled when CPUID reports them.
+*
+* The bootloader maps the kernel segments writable and without caring much for execute permissions, so init
+* reads the kernel's own program headers (the linker defines __ehdr_start at the ELF header, it is part of
+* the first loaded segment) and sets the pages of every PT_LOAD segment from its flags:
+*
+*     code (R E)    present                   .text
+*     rodata (R)    present, no execute       .rodata, the ELF header
+*     data (RW)     writable, no execute      .data, .bss
+*
+* The PT_GNU_RELRO part of the data (.data.rel.ro, .got) is only written by relocations while linking, it
+* becomes read only. The physical memory mapping is data too, its level 4 entries get the NO_EXECUTE bit.
+*
+* The CPU doesn't clear EFLAGS.AC on interrupts, a program can set it before an interrupt and the handler
+* runs without SMAP. The syscall entry clears it (SFMask in the syscall module).
+* */
+use crate::cpu::{self, Feature};
+use crate::memory;
+use alloc::vec::Vec;
+use core::arch::asm;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Mapper, Page, PageTableFlags, PageTableIndex, Size4KiB};
+use x86_64::VirtAddr;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_RELRO: u32 = 0x6474_e552;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+extern "C" {
+    // the ELF header of the kernel, defined by the linker
+    static __ehdr_start: u8;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    vaddr: u64,
+    memory_size: u64,
+}
+
+impl ProgramHeader {
+    // the part of the page inside the segment, None if they don't overlap
+    fn overlap(&self, page: Page) -> Option<(u64, u64)> {
+        let start = page.start_address().as_u64().max(self.vaddr);
+        let end = (page.start_address().as_u64() + 4096).min(self.vaddr + self.memory_size);
+        (start < end).then_some((start, end))
+    }
+}
+
+fn ehdr_start() -> u64 {
+    (&raw const __ehdr_start) as u64
+}
+
+// the program headers of the running kernel, empty if the linker didn't load the ELF header
+fn kernel_program_headers() -> Vec<ProgramHeader> {
+    let header = ehdr_start();
+    let read_u16 = |address: u64| unsafe { (address as *const u16).read_unaligned() };
+    let read_u32 = |address: u64| unsafe { (address as *const u32).read_unaligned() };
+    let read_u64 = |address: u64| unsafe { (address as *const u64).read_unaligned() };
+    if read_u32(header) != u32::from_le_bytes(*b"\x7fELF") {
+        return Vec::new();
+    }
+    let table = header + read_u64(header + 32);
+    let entry_size = read_u16(header + 54) as u64;
+    let count = read_u16(header + 56) as u64;
+    (0..count)
+        .map(|index| {
+            let entry = table + index * entry_size;
+            ProgramHeader {
+                kind: read_u32(entry),
+                flags: read_u32(entry + 4),
+                vaddr: read_u64(entry + 16),
+                memory_size: read_u64(entry + 40),
+            }
+        })
+        .collect()
+}
+
+// the NO_EXECUTE flag if the CPU enforces it, the bit must stay clear otherwise
+pub fn no_execute() -> PageTableFlags {
+    if Efer::read().contains(EferFlags::NO_EXECUTE_ENABLE) {
+        PageTableFlags::NO_EXECUTE
+    } else {
+        PageTableFlags::empty()
+    }
+}
+
+/*
+* The permissions of a kernel page, a page shared by two segments gets the permissions of both. A data
+* page stays writable if a part of it outside of the RELRO range is data.
+* */
+fn kernel_page_flags(page: Page, headers: &[ProgramHeader]) -> PageTableFlags {
+    let relro = |(start, end): (u64, u64)| {
+        headers.iter().any(|header| {
+            header.kind == PT_GNU_RELRO
+                && header.vaddr <= start
+                && end <= header.vaddr + header.memory_size
+        })
+    };
+    let mut writable = false;
+    let mut executable = false;
+    for header in headers.iter().filter(|header| header.kind == PT_LOAD) {
+        if let Some(overlap) = header.overlap(page) {
+            writable |= header.flags & PF_W != 0 && !relro(overlap);
+            executable |= header.flags & PF_X != 0;
+        }
+    }
+    let mut flags = PageTableFlags::PRESENT;
+    if writable {
+        flags |= PageTableFlags::WRITABLE;
+    }
+    if !executable {
+        flags |= no_execute();
+    }
+    flags
+}
+
+// set the permissions of the kernel pages from the program headers, returns the number of pages
+fn protect_kernel(headers: &[ProgramHeader]) -> usize {
+    let mut count = 0;
+    memory::with_mapper(|mapper| {
+        for header in headers.iter().filter(|header| header.kind == PT_LOAD) {
+            if header.memory_size == 0 {
+                continue;
+            }
+            let first = Page::<Size4KiB>::containing_address(VirtAddr::new(header.vaddr));
+            let last = Page::<Size4KiB>::containing_address(VirtAddr::new(
+                header.vaddr + header.memory_size - 1,
+            ));
+            for page in Page::range_inclusive(first, last) {
+                // the guard page of the multiboot2 boot stack stays unmapped
+                if Some(page.start_address().as_u64()) == crate::multiboot2::stack_guard() {
+                    continue;
+                }
+                let flags = kernel_page_flags(page, headers);
+                // the bootloader maps the kernel with 4 KiB pages
+                match unsafe { mapper.update_flags(page, flags) } {
+                    Ok(flush) => {
+                        flush.flush();
+                        count += 1;
+                    }
+                    Err(error) => log::warn!("protection: can't protect {:?}: {:?}", page, error),
+                }
+            }
+        }
+    });
+    count
+}
+
+// mark the level 4 entries of the physical memory mapping no execute, except one shared with the kernel
+fn protect_physical_memory() {
+    let flag = no_execute();
+    if flag.is_empty() {
+        return;
+    }
+    let end = memory::with_frame_allocator(|frame_allocator| frame_allocator.physical_memory_end());
+    let kernel = VirtAddr::new(ehdr_start()).p4_index();
+    memory::with_mapper(|mapper| {
+        let start = mapper.phys_offset();
+        let first = u16::from(start.p4_index());
+        let last = u16::from((start + end.as_u64().max(1) - 1u64).p4_index());
+        let table = mapper.level_4_table();
+        for index in (first..=last).map(PageTableIndex::new) {
+            if index == kernel {
+                log::warn!(
+                    "protection: the physical memory mapping shares an entry with the kernel"
+                );
+                continue;
+            }
+            let entry = &mut table[index];
+            if !entry.is_unused() {
+                entry.set_flags(entry.flags() | flag);
+            }
+        }
+    });
+    x86_64::instructions::tlb::flush_all();
+}
+
+// the control register bits, set on every CPU
+pub fn init_cpu() {
+    unsafe {
+        if cpu::has(Feature::Nx) {
+            Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        }
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+        Cr4::update(|flags| {
+            if cpu::has(Feature::Smep) {
+                flags.insert(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION);
+            }
+            if cpu::has(Feature::Smap) {
+                flags.insert(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION);
+            }
+        });
+    }
+}
+
+// enable the protections and fix the permissions of the kernel pages (call memory::init first)
+pub fn init() {
+    init_cpu();
+    let headers = kernel_program_headers();
+    if headers.is_empty() {
+        log::warn!("protection: no program headers, the kernel pages keep their permissions");
+    }
+    let pages = protect_kernel(&headers);
+    protect_physical_memory();
+    let cr4 = Cr4::read();
+    log::info!(
+        "protection: {} kernel pages, nx {}, smep {}, smap {}",
+        pages,
+        !no_execute().is_empty(),
+        cr4.contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION),
+        cr4.contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)
+    );
+}
+
+pub fn smap_enabled() -> bool {
+    Cr4::read().contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)
+}
+
+// run a closure that reads or writes user memory, the caller checks the range (memory::is_user_accessible)
+// AC stays with the thread when it is preempted in the closure, switch_context saves RFLAGS
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    if !smap_enabled() {
+        return f();
+    }
+    unsafe { asm!("stac", options(nostack)) };
+    let result = f();
+    unsafe { asm!("clac", options(nostack)) };
+    result
+}
+
+#[test_case]
+fn test_kernel_sections_have_their_permissions() {
+    use x86_64::structures::paging::mapper::{Translate, TranslateResult};
+    static READ_ONLY: [u8; 4] = [1, 2, 3, 4];
+    static mut DATA: [u8; 4] = [0; 4];
+
+    let flags = |address: u64| {
+        memory::with_mapper(|mapper| match mapper.translate(VirtAddr::new(address)) {
+            TranslateResult::Mapped { flags, .. } => flags,
+            _ => panic!("{:#x} isn't mapped", address),
+        })
+    };
+    let nx = no_execute();
+    let code = flags(init_cpu as fn() as usize as u64);
+    assert!(!code.contains(PageTableFlags::WRITABLE));
+    assert!(!code.contains(PageTableFlags::NO_EXECUTE));
+    let rodata = flags(READ_ONLY.as_ptr() as u64);
+    assert!(!rodata.contains(PageTableFlags::WRITABLE) && rodata.contains(nx));
+    let data = flags((&raw const DATA) as u64);
+    assert!(data.contains(PageTableFlags::WRITABLE) && data.contains(nx));
+    assert!(Cr0::read().contains(Cr0Flags::WRITE_PROTECT));
+    assert_eq!(!nx.is_empty(), cpu::has(Feature::Nx));
+}
@@ -0,0 +1,216 @@
+/*
+* Random numbers for the kernel (address space randomization, stack canaries, network protocols...).
+*
+* CPUs since Ivy Bridge have two instructions for hardware random numbers, CPUID tells if they exist:
+*  * RDSEED returns the raw output of the entropy source, it is slow and made for seeding
+*  * RDRAND returns the output of a generator reseeded from the entropy source, fast enough to use directly
+* Both set the carry flag when the value is valid and may fail when the hardware is drained, so they are
+* retried a few times.
+*
+* Without RDRAND the numbers come from a CHACHA20 generator: the ChaCha20 block function (RFC 7539) turns a
+* 256 bit key and a counter into 64 random looking bytes. The key is seeded once with RDSEED, or if it is
+* missing too, with the timing jitter of the CPU: the low bits of the time stamp counter (TSC) measured
+* around memory accesses and the timer tick vary from run to run.
+* */
+use crate::cpu::{self, Feature};
+use core::arch::asm;
+use core::arch::x86_64::_rdtsc;
+use spin::Mutex;
+
+const RETRIES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    pub rdrand: bool,
+    pub rdseed: bool,
+}
+
+pub fn features() -> Features {
+    Features {
+        rdrand: cpu::has(Feature::Rdrand),
+        rdseed: cpu::has(Feature::Rdseed),
+    }
+}
+
+// the instructions exist only if CPUID says so
+unsafe fn rdrand() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        if ok != 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+unsafe fn rdseed() -> Option<u64> {
+    for _ in 0..RETRIES {
+        let value: u64;
+        let ok: u8;
+        asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack));
+        if ok != 0 {
+            return Some(value);
+        }
+        core::hint::spin_loop();
+    }
+    None
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+// the ChaCha20 block function of RFC 7539
+fn chacha20_block(key: &[u32; 8], counter: u32, nonce: &[u32; 3]) -> [u32; 16] {
+    // "expand 32-byte k"
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    input[4..12].copy_from_slice(key);
+    input[12] = counter;
+    input[13..].copy_from_slice(nonce);
+
+    let mut state = input;
+    for _ in 0..10 {
+        // the columns, then the diagonals
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for (word, input) in state.iter_mut().zip(input) {
+        *word = word.wrapping_add(input);
+    }
+    state
+}
+
+// a ChaCha20 keystream used as random numbers, the 64 bit counter uses the counter and the first nonce word
+pub struct ChaCha {
+    key: [u32; 8],
+    counter: u64,
+    block: [u32; 16],
+    // the next unused word of the block
+    index: usize,
+}
+
+impl ChaCha {
+    pub fn new(seed: [u32; 8]) -> ChaCha {
+        ChaCha {
+            key: seed,
+            counter: 0,
+            block: [0; 16],
+            index: 16,
+        }
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        if self.index == 16 {
+            let nonce = [(self.counter >> 32) as u32, 0, 0];
+            self.block = chacha20_block(&self.key, self.counter as u32, &nonce);
+            self.counter += 1;
+            self.index = 0;
+        }
+        self.index += 1;
+        self.block[self.index - 1]
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        (self.next_u32() as u64) << 32 | self.next_u32() as u64
+    }
+}
+
+/*
+* Collect a seed from the timing jitter. Every sample is the TSC difference of reading memory that may or may
+* not be cached and a spin loop that may be interrupted, the differences are mixed into the seed words with a
+* multiply and rotate so every bit of every sample changes the seed.
+* */
+fn jitter_seed() -> [u32; 8] {
+    let mut seed = [0u32; 8];
+    let mut scratch = [0u8; 256];
+    let mut previous = unsafe { _rdtsc() };
+    for round in 0..1024 {
+        let index = (previous as usize ^ round) % scratch.len();
+        scratch[index] = scratch[index].wrapping_add(previous as u8);
+        for _ in 0..(previous & 0xF) {
+            core::hint::spin_loop();
+        }
+        let now = unsafe { _rdtsc() };
+        let sample = (now.wrapping_sub(previous) ^ crate::timer::ticks()) as u32;
+        let word = &mut seed[round % 8];
+        *word = (*word ^ sample).wrapping_mul(0x9E37_79B9).rotate_left(13);
+        previous = now;
+    }
+    // keep the scratch accesses from being optimized away
+    seed[0] ^= core::hint::black_box(scratch.iter().map(|&byte| byte as u32).sum::<u32>());
+    seed
+}
+
+fn seed() -> [u32; 8] {
+    let mut seed = jitter_seed();
+    if features().rdseed {
+        // the hardware entropy is added to the jitter, a broken RDSEED can't make it worse
+        for pair in seed.chunks_mut(2) {
+            if let Some(value) = unsafe { rdseed() } {
+                pair[0] ^= value as u32;
+                pair[1] ^= (value >> 32) as u32;
+            }
+        }
+    }
+    seed
+}
+
+// None until the first random number is needed without RDRAND
+static GENERATOR: Mutex<Option<ChaCha>> = Mutex::new(None);
+
+pub fn next_u64() -> u64 {
+    if features().rdrand {
+        if let Some(value) = unsafe { rdrand() } {
+            return value;
+        }
+    }
+    // interrupt handlers may need random numbers too
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        GENERATOR
+            .lock()
+            .get_or_insert_with(|| ChaCha::new(seed()))
+            .next_u64()
+    })
+}
+
+pub fn fill_bytes(buffer: &mut [u8]) {
+    for chunk in buffer.chunks_mut(8) {
+        let bytes = next_u64().to_le_bytes();
+        chunk.copy_from_slice(&bytes[..chunk.len()]);
+    }
+}
+
+#[test_case]
+fn test_chacha20_block_and_random_numbers() {
+    // the test vector of RFC 7539 section 2.3.2
+    let key =
+        core::array::from_fn(|i| u32::from_le_bytes(core::array::from_fn(|j| (i * 4 + j) as u8)));
+    let block = chacha20_block(&key, 1, &[0x0900_0000, 0x4a00_0000, 0]);
+    assert_eq!(
+        block[..4],
+        [0xe4e7_f110, 0x1559_3bd1, 0x1fdd_0f50, 0xc471_20a3]
+    );
+    assert_eq!(block[15], 0x4e3c_50a2);
+
+    let mut generator = ChaCha::new(seed());
+    assert_ne!(generator.next_u64(), generator.next_u64());
+    let mut bytes = [0u8; 13];
+    fill_bytes(&mut bytes);
+    assert!(bytes.iter().any(|&byte| byte != 0));
+}
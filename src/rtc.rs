@@ -0,0 +1,179 @@
+/*
+* The REAL_TIME_CLOCK keeps the date and time in the CMOS memory while the machine is off (it runs from a
+* battery). The CMOS is read through two I/O ports: the register number is written to 0x70, then its value
+* is read from 0x71. Bit 7 of the index port disables the NMI, we leave it cleared.
+*
+* Two things make reading it tricky:
+*  * the clock updates its registers once per second, reading while the update is in progress (status
+*    register A bit 7) can give a mix of the old and new time, so we wait for the update to finish and read
+*    the registers until two reads in a row agree (both with a limit, a broken clock can't hang the kernel)
+*  * depending on status register B the values are BCD (0x59 means 59) or binary and the hour is
+*    in 12 hour format with bit 7 meaning PM or in 24 hour format
+* */
+use crate::time::DateTime;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const INDEX_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+// the century register isn't standardized, QEMU and most PCs use 0x32 (century_register)
+const DEFAULT_CENTURY: u8 = 0x32;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+const BINARY_MODE: u8 = 0x04;
+const HOUR_24: u8 = 0x02;
+const HOUR_PM: u8 = 0x80;
+
+// an update takes about 2 ms, the port reads take about 1 us each so a clock that never finishes is given up
+// on after about 100 ms
+const MAX_UPDATE_WAIT: u32 = 100_000;
+// reads until two in a row agree, the clock only updates once per second so two are normally enough
+const MAX_READS: u32 = 10;
+
+fn read_register(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(INDEX_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        index.write(register);
+        data.read()
+    }
+}
+
+// the raw register values, compared to detect a read that overlapped an update
+#[derive(PartialEq, Eq, Clone, Copy)]
+struct Registers {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+// the ACPI FADT names the century register (0 if the clock has none), before acpi::init it isn't known yet
+fn century_register() -> Option<u8> {
+    match crate::acpi::fadt() {
+        Some(fadt) if fadt.century_register == 0 => None,
+        Some(fadt) => Some(fadt.century_register),
+        None => Some(DEFAULT_CENTURY),
+    }
+}
+
+fn read_registers(century: Option<u8>) -> Registers {
+    for _ in 0..MAX_UPDATE_WAIT {
+        if read_register(STATUS_A) & UPDATE_IN_PROGRESS == 0 {
+            break;
+        }
+    }
+    Registers {
+        seconds: read_register(SECONDS),
+        minutes: read_register(MINUTES),
+        hours: read_register(HOURS),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        // decode assumes the 21st century without the register
+        century: century.map_or(0, read_register),
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+// convert the register values to a date with the format given by status register B
+fn decode(registers: Registers, status_b: u8) -> DateTime {
+    let binary = status_b & BINARY_MODE != 0;
+    let convert = |value: u8| if binary { value } else { from_bcd(value) };
+
+    let pm = registers.hours & HOUR_PM != 0;
+    let mut hour = convert(registers.hours & !HOUR_PM);
+    if status_b & HOUR_24 == 0 {
+        // 12 AM is midnight (0) and 12 PM is noon (12)
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    // a clock without the century register reads 0 (or garbage), assume the 21st century then
+    let century = match convert(registers.century) {
+        century @ 19..=99 => century as u16,
+        _ => 20,
+    };
+    DateTime {
+        year: century * 100 + convert(registers.year) as u16,
+        month: convert(registers.month),
+        day: convert(registers.day),
+        hour,
+        minute: convert(registers.minutes),
+        second: convert(registers.seconds),
+    }
+}
+
+// the current date and time of the clock, usually UTC (QEMU's default -rtc base=utc)
+pub fn read() -> DateTime {
+    // the index port selects the register for the next data access so an interrupt that reads the
+    // CMOS in between would make us read the wrong register
+    let century = century_register();
+    interrupts::without_interrupts(|| {
+        let mut registers = read_registers(century);
+        for _ in 1..MAX_READS {
+            let again = read_registers(century);
+            if again == registers {
+                break;
+            }
+            registers = again;
+        }
+        decode(registers, read_register(STATUS_B))
+    })
+}
+
+#[test_case]
+fn test_decode_bcd_and_12_hour_format() {
+    let registers = Registers {
+        seconds: 0x59,
+        minutes: 0x07,
+        hours: 0x12 | HOUR_PM,
+        day: 0x31,
+        month: 0x12,
+        year: 0x26,
+        century: 0x20,
+    };
+    let date = decode(registers, 0);
+    assert_eq!((date.year, date.month, date.day), (2026, 12, 31));
+    assert_eq!((date.hour, date.minute, date.second), (12, 7, 59));
+    // 12 AM is midnight
+    let midnight = decode(
+        Registers {
+            hours: 0x12,
+            ..registers
+        },
+        0,
+    );
+    assert_eq!(midnight.hour, 0);
+    // binary 24 hour mode without a century register
+    let binary = Registers {
+        seconds: 5,
+        minutes: 30,
+        hours: 23,
+        day: 1,
+        month: 2,
+        year: 24,
+        century: 0,
+    };
+    let date = decode(binary, BINARY_MODE | HOUR_24);
+    assert_eq!(
+        (date.year, date.month, date.day, date.hour),
+        (2024, 2, 1, 23)
+    );
+}
@@ -0,0 +1,375 @@
+/*
+* Kernel THREADS run at the same time by taking turns on the CPU. Every thread has its own stack, the
+* registers of a thread that doesn't run are saved on its stack: switch_context pushes RFLAGS and the callee
+* saved registers (the caller saved ones are already saved by the Rust code that calls it), stores the stack
+* pointer in the old thread and loads the one of the new thread, pops its registers and returns to where
+* the new thread called switch_context itself. A new thread's stack is prepared so that this return jumps
+* to thread_start which calls the closure of the thread.
+*
+* The ready threads wait in a round robin queue. A thread gives up the CPU with yield_now or exit, and the
+* timer interrupt PREEMPTS it after TIME_SLICE_TICKS so a thread in an endless loop doesn't stop the others.
+* The switch happens at the end of the interrupt handler after the EOI, the interrupted thread continues in
+* the handler when it gets the CPU back and returns to where it was interrupted with iretq.
+*
+* A thread that runs a user program has its own page tables, kernel stack (TSS RSP0, the syscall stack) and
+* return point of usermode::run, they are saved with the thread and restored when it runs again. So are the
+* FPU and SSE registers of every thread (fpu module), the programs use them even though the kernel doesn't.
+*
+* The code calling init (kernel_main) becomes the first thread, it uses the boot stack. When no thread is
+* ready and the running one exits the idle thread halts the CPU until an interrupt wakes a thread. Only the
+* bootstrap processor runs threads for now. The stacks have a guard page below them (stack module), so a
+* stack overflow is reported with the name of the thread instead of overwriting another stack.
+* */
+use crate::allocator::slab::{Cache, SlabBox};
+use crate::fpu::FpuState;
+use crate::gdt;
+use crate::memory;
+use crate::percpu;
+use crate::stack::Stack;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+// the number of timer ticks a thread runs before the next ready thread gets the CPU
+pub const TIME_SLICE_TICKS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(pub u64);
+
+struct Thread {
+    id: ThreadId,
+    name: String,
+    // None for the boot thread which runs on the stack of the bootloader, only kept to be freed with the thread
+    #[allow(dead_code)]
+    stack: Option<Stack>,
+    // the stack pointer saved by switch_context
+    rsp: u64,
+    // the state of the user program the thread runs (usermode), the kernel's while it runs none
+    level_4_frame: PhysFrame,
+    syscall_stack: VirtAddr,
+    user_return: u64,
+    fpu: FpuState,
+}
+
+impl Thread {
+    fn new(name: &str, stack: Option<Stack>, rsp: u64) -> SlabBox<Thread> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        THREADS
+            .alloc(Thread {
+                id: ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed)),
+                name: String::from(name),
+                stack,
+                rsp,
+                level_4_frame: memory::kernel_level_4_frame(),
+                syscall_stack: VirtAddr::zero(),
+                user_return: 0,
+                fpu: FpuState::new(),
+            })
+            .expect("out of memory for a thread")
+    }
+
+    fn save_cpu_state(&mut self) {
+        let block = percpu::current();
+        self.level_4_frame = Cr3::read().0;
+        self.syscall_stack = block.syscall_stack();
+        self.user_return = unsafe { *block.user_return() };
+        self.fpu.save();
+    }
+
+    fn restore_cpu_state(&self) {
+        let block = percpu::current();
+        let (level_4_frame, flags) = Cr3::read();
+        if level_4_frame != self.level_4_frame {
+            unsafe { Cr3::write(self.level_4_frame, flags) };
+        }
+        gdt::set_kernel_stack(self.syscall_stack);
+        block.set_syscall_stack(self.syscall_stack);
+        unsafe { *block.user_return() = self.user_return };
+        self.fpu.restore();
+    }
+}
+
+struct Scheduler {
+    current: Option<SlabBox<Thread>>,
+    ready: VecDeque<SlabBox<Thread>>,
+    // runs when no other thread is ready, it is never in the ready queue
+    idle: Option<SlabBox<Thread>>,
+    idle_id: ThreadId,
+    // exited threads whose stacks are freed by the next thread (not while running on them), they stay
+    // in their slots since switch_context saves the stack pointer into them after they were moved here
+    dead: Vec<SlabBox<Thread>>,
+}
+
+// the threads are kept in a slab cache, the scheduler moves them around but their slots don't move
+static THREADS: Cache<Thread> = Cache::new("thread");
+
+// None until init, always locked with interrupts disabled since the timer interrupt locks it too
+static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None);
+static NEED_RESCHEDULE: AtomicBool = AtomicBool::new(false);
+static SLICE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    // rdi = where the stack pointer of the old thread is saved, rsi = the stack pointer of the new thread
+    // RFLAGS too since a thread in protection::with_user_access has AC set, the others must not run with it
+    "pushfq",
+    "push %rbx",
+    "push %rbp",
+    "push %r12",
+    "push %r13",
+    "push %r14",
+    "push %r15",
+    "mov %rsp, (%rdi)",
+    "mov %rsi, %rsp",
+    "pop %r15",
+    "pop %r14",
+    "pop %r13",
+    "pop %r12",
+    "pop %rbp",
+    "pop %rbx",
+    "popfq",
+    "ret",
+    ".global thread_start",
+    "thread_start:",
+    // the closure of a new thread is in r12
+    "mov %r12, %rdi",
+    "call {entry}",
+    "ud2",
+    entry = sym thread_entry,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_start();
+}
+
+type Closure = Box<dyn FnOnce() + Send + 'static>;
+
+extern "C" fn thread_entry(closure: *mut Closure) -> ! {
+    // the thread was switched to with interrupts disabled
+    interrupts::enable();
+    reap();
+    let closure = unsafe { Box::from_raw(closure) };
+    closure();
+    exit();
+}
+
+/*
+* Free the threads that exited, called by the threads after a switch. Freeing locks the frame allocator which
+* a preempted thread may hold, so it must run with interrupts enabled (the holder gets the CPU again).
+* */
+fn reap() {
+    let dead = interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .map(|scheduler| core::mem::take(&mut scheduler.dead))
+    });
+    drop(dead);
+}
+
+/*
+* Switch to the next ready thread, the current one goes to the end of the queue or to the dead threads if
+* it exits. Must be called with interrupts disabled, returns when the thread gets the CPU again.
+* */
+fn schedule(exiting: bool) {
+    if percpu::cpu_id() != 0 {
+        return;
+    }
+    let (old_rsp, new_rsp) = {
+        let mut scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_mut() else {
+            return;
+        };
+        let next = match scheduler.ready.pop_front() {
+            Some(next) => next,
+            None if !exiting => return,
+            None => scheduler.idle.take().expect("the idle thread exited"),
+        };
+        let mut current = scheduler.current.take().expect("no current thread");
+        current.save_cpu_state();
+        // the slot doesn't move when the thread is moved to the queue, so the pointer stays valid for switch_context
+        let old_rsp: *mut u64 = &mut current.rsp;
+        if exiting {
+            scheduler.dead.push(current);
+        } else if current.id == scheduler.idle_id {
+            scheduler.idle = Some(current);
+        } else {
+            scheduler.ready.push_back(current);
+        }
+        next.restore_cpu_state();
+        let new_rsp = next.rsp;
+        scheduler.current = Some(next);
+        SLICE_TICKS.store(0, Ordering::Relaxed);
+        (old_rsp, new_rsp)
+    };
+    unsafe { switch_context(old_rsp, new_rsp) };
+}
+
+// create a thread that runs the closure, it is added to the end of the ready queue
+pub fn spawn(name: &str, f: impl FnOnce() + Send + 'static) -> ThreadId {
+    let stack = Stack::new().expect("out of memory for a thread stack");
+    let closure: *mut Closure = Box::into_raw(Box::new(Box::new(f)));
+    // the registers popped by switch_context (r15 first), RFLAGS (interrupts disabled, AC clear) and
+    // its return address
+    let frame = [
+        0,
+        0,
+        0,
+        closure as u64,
+        0,
+        0,
+        0x2,
+        thread_start as *const () as u64,
+    ];
+    let rsp = stack.top().as_u64() - core::mem::size_of_val(&frame) as u64;
+    unsafe { (rsp as *mut [u64; 8]).write(frame) };
+    let thread = Thread::new(name, Some(stack), rsp);
+    let id = thread.id;
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler
+            .as_mut()
+            .expect("scheduler::init must be called first");
+        scheduler.ready.push_back(thread);
+    });
+    id
+}
+
+// let the next ready thread run, returns at once if there is none
+pub fn yield_now() {
+    interrupts::without_interrupts(|| schedule(false));
+    reap();
+}
+
+// end the running thread, its stack is freed by the next thread
+pub fn exit() -> ! {
+    interrupts::disable();
+    schedule(true);
+    unreachable!("an exited thread was scheduled");
+}
+
+pub fn current_id() -> Option<ThreadId> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        Some(scheduler.as_ref()?.current.as_ref()?.id)
+    })
+}
+
+/*
+* Shows the running thread (and the async task it polls) in panic messages, it doesn't lock or allocate
+* since the panic may have happened while the scheduler was locked or the stack is gone.
+* */
+pub struct CurrentThread;
+
+impl fmt::Display for CurrentThread {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if percpu::cpu_id() != 0 {
+            // the other CPUs run no threads
+            write!(f, "<CPU {}>", percpu::cpu_id())?;
+        } else {
+            let scheduler = SCHEDULER.try_lock();
+            let current = scheduler
+                .as_ref()
+                .and_then(|scheduler| scheduler.as_ref()?.current.as_ref());
+            match current {
+                Some(thread) => write!(f, "\"{}\" (thread {})", thread.name, thread.id.0)?,
+                None => write!(f, "<unknown thread>")?,
+            }
+        }
+        match percpu::current().current_task() {
+            Some(task) => write!(f, ", async task {}", task),
+            None => Ok(()),
+        }
+    }
+}
+
+// the id and name of every thread, the running one first
+pub fn threads() -> Vec<(ThreadId, String)> {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_ref() else {
+            return Vec::new();
+        };
+        scheduler
+            .current
+            .iter()
+            .chain(scheduler.ready.iter())
+            .map(|thread| (thread.id, thread.name.clone()))
+            .collect()
+    })
+}
+
+// called by the timer interrupt handler, asks for a switch when the time slice is over
+pub fn tick() {
+    if SLICE_TICKS.fetch_add(1, Ordering::Relaxed) + 1 >= TIME_SLICE_TICKS {
+        NEED_RESCHEDULE.store(true, Ordering::Relaxed);
+    }
+}
+
+// called at the end of the interrupt handlers (after the EOI), switches if the time slice is over
+pub fn preempt() {
+    if NEED_RESCHEDULE.swap(false, Ordering::Relaxed) {
+        schedule(false);
+    }
+}
+
+// wakes up on every interrupt and gives the CPU to the threads that became ready
+fn idle() {
+    loop {
+        yield_now();
+        x86_64::instructions::hlt();
+    }
+}
+
+// make the running code the first thread and create the idle thread, needs the heap
+pub fn init() {
+    let boot = Thread::new("main", None, 0);
+    interrupts::without_interrupts(|| {
+        *SCHEDULER.lock() = Some(Scheduler {
+            current: Some(boot),
+            ready: VecDeque::new(),
+            idle: None,
+            idle_id: ThreadId(u64::MAX),
+            dead: Vec::new(),
+        });
+    });
+    let idle_id = spawn("idle", idle);
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let scheduler = scheduler.as_mut().unwrap();
+        scheduler.idle = scheduler.ready.pop_back();
+        scheduler.idle_id = idle_id;
+    });
+}
+
+#[test_case]
+fn test_threads_yield_and_are_preempted() {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    static FLAG: AtomicBool = AtomicBool::new(false);
+    for _ in 0..3 {
+        spawn("test", || {
+            COUNTER.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    while COUNTER.load(Ordering::Relaxed) < 3 {
+        yield_now();
+    }
+    // this loop never yields, only the timer interrupt lets the other thread run
+    spawn("test", || FLAG.store(true, Ordering::Relaxed));
+    while !FLAG.load(Ordering::Relaxed) {
+        core::hint::spin_loop();
+    }
+    assert!(threads().iter().all(|(_, name)| name != "idle"));
+}
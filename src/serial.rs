@@ -0,0 +1,90 @@
+/*
+* A serial port is a simple way to send data from our kernel to the host system.
+* The chips implementing a serial interface are called UARTs, most UARTs on x86 are compatible
+* with the 16550 UART so we use the uart_16550 crate instead of programming the registers ourselves.
+*
+* QEMU can redirect the bytes sent to the COM1 serial port to the stdout of the host
+* using the `-serial stdio` argument, this is how the test results reach the host terminal
+*
+* COM1 is also the serial console (console::SerialConsole) that shows the output of print!, and once the
+* shell runs the bytes it receives are keys for the shell (task::serial), so the kernel can be used with
+* only a terminal:
+*
+*     cargo run -- -nographic      or -serial stdio -display none
+*
+* The UART raises IRQ 4 when it received a byte (SerialPort::init enables the interrupt), the handler reads
+* all the bytes waiting in its FIFO.
+* */
+use crate::interrupts;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+const COM1_IRQ: u8 = 4;
+// the line status register, bit 0 is set while a received byte waits in the data register
+const LINE_STATUS: u16 = COM1 + 5;
+const DATA_READY: u8 = 1;
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = {
+        // unlike the VGA buffer the serial interface uses port mapped I/O
+        // 0x3F8 is the standard port number of the first serial interface (COM1)
+        // the UART is programmed through several I/O ports, SerialPort::new takes the first one
+        // and calculates the addresses of the others from it
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+/*
+* Take the input of the serial console, returns false if COM1 isn't a console (the console option) or gdb
+* uses the port. The receive queue must exist before the interrupts arrive, so the caller creates the
+* SerialStream first.
+* */
+pub fn init_console() -> bool {
+    if !crate::console::is_active("serial") || crate::gdb::port() == Some(COM1) {
+        return false;
+    }
+    lazy_static::initialize(&SERIAL1);
+    interrupts::register_irq_handler(COM1_IRQ, serial_interrupt_handler);
+    true
+}
+
+fn serial_interrupt_handler() {
+    let mut line_status: Port<u8> = Port::new(LINE_STATUS);
+    let mut data: Port<u8> = Port::new(COM1);
+    while unsafe { line_status.read() } & DATA_READY != 0 {
+        crate::task::serial::add_byte(unsafe { data.read() });
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: ::core::fmt::Arguments) {
+    use core::fmt::Write;
+    // like the VGA writer (vga_buffer::with_writer) the port is only locked with interrupts disabled
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        SERIAL1
+            .lock()
+            .write_fmt(args)
+            .expect("Printing to serial failed");
+    });
+}
+
+// Same as print! and println! but the output goes to the serial port instead of the VGA buffer
+#[macro_export]
+macro_rules! serial_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_print(format_args!($($arg)*));
+    };
+}
+
+#[macro_export]
+macro_rules! serial_println {
+    () => ($crate::serial_print!("\n"));
+    ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
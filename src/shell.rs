@@ -0,0 +1,744 @@
+/*
+* A small interactive shell that runs as an async task on the executor. It waits for the keys of the
+* keyboard and the serial console (KeyStream), edits the current line and runs the built-in command when
+* enter is pressed:
+*  * left/right, home/end move the cursor, backspace/delete remove characters
+*  * up/down go through the history of the last HISTORY_SIZE lines
+*  * page up/down scroll the screen like print_keypresses does
+*
+* The line is redrawn after every change: \r moves back to the start of the row, then the prompt and the line are
+* written, the rest of the row is erased (ESC [K) and the cursor moved back to its position (ESC [nD).
+* The redrawing only goes to the consoles (terminals understand the same escape sequences), the kernel
+* message buffer only gets the output of the commands.
+* The line is limited to one row so \r always finds its start.
+* */
+use crate::allocator::stats::SIZE_CLASSES;
+use crate::console::{self, Consoles};
+use crate::keyboard::{DecodedKey, KeyCode};
+use crate::task::keyboard::KeyStream;
+use crate::vfs::NodeKind;
+use crate::vga_buffer::{self, Writer, BUFFER_WIDTH};
+use crate::{print, println};
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use futures_util::stream::StreamExt;
+
+const PROMPT: &str = "> ";
+const MAX_LINE_LEN: usize = BUFFER_WIDTH - PROMPT.len() - 1;
+const HISTORY_SIZE: usize = 32;
+
+// what the key did to the line
+#[derive(Debug, PartialEq, Eq)]
+pub enum Edit {
+    Unchanged,
+    Changed,
+    // enter was pressed, the line is the command to run
+    Submit(String),
+}
+
+// the line being typed and the history of the submitted ones
+pub struct LineEditor {
+    // only printable ASCII so the cursor can index bytes
+    line: String,
+    cursor: usize,
+    // the oldest line first
+    history: VecDeque<String>,
+    // the history entry shown, None while editing a new line
+    history_index: Option<usize>,
+    // the new line, kept while going through the history
+    draft: String,
+}
+
+impl LineEditor {
+    pub fn new() -> LineEditor {
+        LineEditor {
+            line: String::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            history_index: None,
+            draft: String::new(),
+        }
+    }
+
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn handle_key(&mut self, key: DecodedKey) -> Edit {
+        match key {
+            DecodedKey::Unicode('\n') => return Edit::Submit(self.submit()),
+            DecodedKey::Unicode('\x08') if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            DecodedKey::RawKey(KeyCode::Delete) if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            DecodedKey::Unicode(character @ ' '..='~') if self.line.len() < MAX_LINE_LEN => {
+                self.line.insert(self.cursor, character);
+                self.cursor += 1;
+            }
+            DecodedKey::RawKey(KeyCode::ArrowLeft) if self.cursor > 0 => self.cursor -= 1,
+            DecodedKey::RawKey(KeyCode::ArrowRight) if self.cursor < self.line.len() => {
+                self.cursor += 1
+            }
+            DecodedKey::RawKey(KeyCode::Home) => self.cursor = 0,
+            DecodedKey::RawKey(KeyCode::End) => self.cursor = self.line.len(),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => self.history_previous(),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.history_next(),
+            _ => return Edit::Unchanged,
+        }
+        Edit::Changed
+    }
+
+    fn submit(&mut self) -> String {
+        let line = core::mem::take(&mut self.line);
+        self.cursor = 0;
+        self.history_index = None;
+        self.draft.clear();
+        // blank lines and repeating the last command don't fill the history
+        if !line.trim().is_empty() && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+
+    fn history_previous(&mut self) {
+        if self.history.is_empty() {
+            return;
+        }
+        let index = match self.history_index {
+            Some(0) => 0,
+            Some(index) => index - 1,
+            None => {
+                self.draft = core::mem::take(&mut self.line);
+                self.history.len() - 1
+            }
+        };
+        self.show_history(Some(index));
+    }
+
+    fn history_next(&mut self) {
+        match self.history_index {
+            Some(index) if index + 1 < self.history.len() => self.show_history(Some(index + 1)),
+            // past the newest entry is the line that was typed before going through the history
+            Some(_) => self.show_history(None),
+            None => {}
+        }
+    }
+
+    fn show_history(&mut self, index: Option<usize>) {
+        self.history_index = index;
+        self.line = match index {
+            Some(index) => self.history[index].clone(),
+            None => core::mem::take(&mut self.draft),
+        };
+        self.cursor = self.line.len();
+    }
+}
+
+impl Default for LineEditor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// a built-in command, it gets the words of the line after the name
+struct Command {
+    name: &'static str,
+    help: &'static str,
+    run: fn(&[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        help: "list the commands",
+        run: help,
+    },
+    Command {
+        name: "clear",
+        help: "clear the screen",
+        run: clear,
+    },
+    Command {
+        name: "keymap",
+        help: "show or set the keyboard layout, keymap [us|uk|de]",
+        run: keymap,
+    },
+    Command {
+        name: "cpuinfo",
+        help: "show the CPU model and features",
+        run: cpuinfo,
+    },
+    Command {
+        name: "mem",
+        help: "show the physical memory and heap usage",
+        run: mem,
+    },
+    Command {
+        name: "slabinfo",
+        help: "show the slab caches of the kernel objects",
+        run: slabinfo,
+    },
+    Command {
+        name: "uptime",
+        help: "show the time since boot",
+        run: uptime,
+    },
+    Command {
+        name: "date",
+        help: "show the current date and time",
+        run: date,
+    },
+    Command {
+        name: "dmesg",
+        help: "print the kernel message buffer",
+        run: dmesg,
+    },
+    Command {
+        name: "cmdline",
+        help: "show the kernel command line",
+        run: cmdline,
+    },
+    Command {
+        name: "lspci",
+        help: "list the PCI devices",
+        run: lspci,
+    },
+    Command {
+        name: "lsblk",
+        help: "list the disks and partitions",
+        run: lsblk,
+    },
+    Command {
+        name: "mount",
+        help: "list the mounted filesystems",
+        run: mount,
+    },
+    Command {
+        name: "ls",
+        help: "list a directory, ls [path]",
+        run: ls,
+    },
+    Command {
+        name: "exec",
+        help: "run an ELF program in user mode, exec <path>",
+        run: exec,
+    },
+    Command {
+        name: "ps",
+        help: "list the processes",
+        run: ps,
+    },
+    Command {
+        name: "threads",
+        help: "list the kernel threads",
+        run: threads,
+    },
+    Command {
+        name: "cat",
+        help: "print a file, cat <path>",
+        run: cat,
+    },
+    Command {
+        name: "net",
+        help: "show the network configuration and the ARP cache",
+        run: net,
+    },
+    Command {
+        name: "tcpecho",
+        help: "start a TCP echo server, tcpecho [port]",
+        run: tcpecho,
+    },
+    Command {
+        name: "irqstat",
+        help: "show how often every interrupt and exception was raised per CPU",
+        run: irqstat,
+    },
+    Command {
+        name: "prof",
+        help: "sample where the kernel spends its time, prof start|stop|report [count]",
+        run: prof,
+    },
+    Command {
+        name: "reboot",
+        help: "restart the machine",
+        run: reboot,
+    },
+    Command {
+        name: "shutdown",
+        help: "turn the machine off",
+        run: shutdown,
+    },
+];
+
+fn find_command(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|command| command.name == name)
+}
+
+// split the line into words and run the command named by the first one
+pub fn execute(line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = words.split_first() else {
+        return;
+    };
+    match find_command(name) {
+        Some(command) => (command.run)(args),
+        None => println!("{}: command not found, try help", name),
+    }
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        println!("  {:<8} {}", command.name, command.help);
+    }
+}
+
+fn clear(_args: &[&str]) {
+    console::clear();
+}
+
+fn keymap(args: &[&str]) {
+    let Some(&name) = args.first() else {
+        let names: Vec<&str> = crate::keymap::KEYMAPS
+            .iter()
+            .map(|keymap| keymap.name)
+            .collect();
+        println!(
+            "{} (available: {})",
+            crate::keymap::current().name,
+            names.join(", ")
+        );
+        return;
+    };
+    if !crate::keymap::set(name) {
+        println!("keymap: unknown keymap {}", name);
+    }
+}
+
+fn cpuinfo(_args: &[&str]) {
+    let info = crate::cpu::info();
+    println!("vendor:   {}", info.vendor());
+    println!("model:    {}", info.brand());
+    println!(
+        "family {:#x} model {:#x} stepping {}",
+        info.family, info.model, info.stepping
+    );
+    println!("features: {}", info);
+    let frequency = crate::hires_time::frequency();
+    println!(
+        "tsc:      {}.{:03} MHz",
+        frequency / 1_000_000,
+        frequency / 1000 % 1000
+    );
+}
+
+fn mem(_args: &[&str]) {
+    let (allocated, usable) = crate::memory::with_frame_allocator(|frame_allocator| {
+        (
+            frame_allocator.allocated_frames(),
+            frame_allocator.usable_frames(),
+        )
+    });
+    println!(
+        "frames: {} of {} allocated ({} KiB of {} KiB)",
+        allocated,
+        usable,
+        allocated * 4,
+        usable * 4
+    );
+    let heap = crate::allocator::stats();
+    println!(
+        "heap:   {} of {} KiB used, peak {} KiB, {} allocations live, {} failed",
+        heap.used_bytes() / 1024,
+        crate::allocator::HEAP_SIZE / 1024,
+        heap.peak_bytes / 1024,
+        heap.live_allocations(),
+        heap.failures
+    );
+    // the size classes that were used, with the total and the live allocations
+    for (class, &bound) in SIZE_CLASSES.iter().enumerate() {
+        if heap.allocations[class] == 0 {
+            continue;
+        }
+        let size = if bound == usize::MAX {
+            String::from(">2048")
+        } else {
+            format!("<={}", bound)
+        };
+        println!(
+            "  {:>6} bytes: {:>8} allocations, {:>6} live",
+            size,
+            heap.allocations[class],
+            heap.live(class)
+        );
+    }
+}
+
+fn slabinfo(_args: &[&str]) {
+    println!("cache        size  slabs  objects/slab  in use  allocations");
+    for cache in crate::allocator::slab::caches() {
+        println!(
+            "{:<12} {:>4}  {:>5}  {:>12}  {:>6}  {:>11}",
+            cache.name,
+            cache.object_size,
+            cache.slabs,
+            cache.objects_per_slab,
+            cache.in_use,
+            cache.allocations
+        );
+    }
+}
+
+fn uptime(_args: &[&str]) {
+    let uptime = crate::time::uptime();
+    let seconds = uptime.as_secs();
+    println!(
+        "up {}:{:02}:{:02}.{:03}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        uptime.subsec_millis()
+    );
+}
+
+fn date(_args: &[&str]) {
+    println!("{} UTC", crate::time::now());
+}
+
+fn cmdline(_args: &[&str]) {
+    println!("{}", crate::cmdline::as_str());
+}
+
+fn lspci(_args: &[&str]) {
+    for (device, driver) in crate::pci::devices() {
+        println!("{} ({})", device, driver.unwrap_or("no driver"));
+    }
+}
+
+fn lsblk(_args: &[&str]) {
+    for device in crate::block::devices() {
+        println!("  {:<8} {} KiB", device.name(), device.capacity() / 1024);
+    }
+}
+
+fn mount(_args: &[&str]) {
+    for (path, filesystem) in crate::vfs::mounts() {
+        println!("  {:<16} {}", path, filesystem);
+    }
+}
+
+fn ls(args: &[&str]) {
+    let path = args.first().copied().unwrap_or("/");
+    match crate::vfs::read_dir(path) {
+        Ok(entries) => {
+            for entry in entries {
+                match entry.kind {
+                    NodeKind::Directory => println!("  {}/", entry.name),
+                    NodeKind::File | NodeKind::Device => {
+                        println!("  {:<24} {}", entry.name, entry.size)
+                    }
+                }
+            }
+        }
+        Err(error) => println!("ls: {}: {}", path, error),
+    }
+}
+
+fn cat(args: &[&str]) {
+    let Some(&path) = args.first() else {
+        println!("cat: missing path");
+        return;
+    };
+    match crate::vfs::read(path) {
+        Ok(data) => print!("{}", String::from_utf8_lossy(&data)),
+        Err(error) => println!("cat: {}: {}", path, error),
+    }
+}
+
+fn exec(args: &[&str]) {
+    let Some(&path) = args.first() else {
+        println!("exec: missing path");
+        return;
+    };
+    let file = match crate::vfs::read(path) {
+        Ok(file) => file,
+        Err(error) => return println!("exec: {}: {}", path, error),
+    };
+    let pid = match crate::process::create(path, &file) {
+        Ok(pid) => pid,
+        Err(error) => return println!("exec: {}: {}", path, error),
+    };
+    match crate::process::wait(pid) {
+        Some(crate::usermode::ExitStatus::Exited(code)) => {
+            println!("{} exited with {}", path, code)
+        }
+        Some(crate::usermode::ExitStatus::Killed) => println!("{} was killed", path),
+        None => println!("exec: {}: the process was reaped by someone else", path),
+    }
+}
+
+fn ps(_args: &[&str]) {
+    for (pid, name, state) in crate::process::list() {
+        println!("{:>4} {:<16} {:?}", pid, name, state);
+    }
+}
+
+fn threads(_args: &[&str]) {
+    for (id, name) in crate::scheduler::threads() {
+        println!("{:>4} {}", id.0, name);
+    }
+}
+
+fn net(_args: &[&str]) {
+    if crate::e1000::nic().is_none() {
+        return println!("net: no network card");
+    }
+    let config = crate::net::config();
+    let mac = config.mac.map(|byte| format!("{:02x}", byte)).join(":");
+    println!("  mac      {}", mac);
+    println!("  address  {}/{}", config.address, config.prefix_len);
+    if let Some(gateway) = config.gateway {
+        println!("  gateway  {}", gateway);
+    }
+    if let Some(dns) = config.dns {
+        println!("  dns      {}", dns);
+    }
+    for (address, mac) in crate::net::arp::entries() {
+        let mac = mac.map(|byte| format!("{:02x}", byte)).join(":");
+        println!("  arp      {:<16} {}", address, mac);
+    }
+}
+
+fn tcpecho(args: &[&str]) {
+    let port = match args.first().map(|port| port.parse()) {
+        None => 7,
+        Some(Ok(port)) => port,
+        Some(Err(_)) => return println!("tcpecho: invalid port"),
+    };
+    match crate::net::tcp::TcpListener::bind(port) {
+        Ok(listener) => {
+            crate::task::spawn(crate::net::tcp::echo_server(listener));
+            println!("echoing on TCP port {}", port);
+        }
+        Err(error) => println!("tcpecho: {}", error),
+    }
+}
+
+fn irqstat(_args: &[&str]) {
+    use crate::interrupts::{self, IRQ_LINES, PIC_1_OFFSET, VECTORS};
+
+    let cpus = crate::smp::cpu_count();
+    let counts: Vec<[u64; VECTORS]> = (0..cpus).map(interrupts::vector_counts).collect();
+    print!("vector  name                       ");
+    for cpu in 0..cpus {
+        print!(" {:>10}", format!("cpu{}", cpu));
+    }
+    println!();
+    for vector in 0..VECTORS {
+        if counts.iter().all(|counts| counts[vector] == 0) {
+            continue;
+        }
+        let name = match interrupts::vector_name(vector as u8) {
+            Some(name) => String::from(name),
+            None if (PIC_1_OFFSET as usize..PIC_1_OFFSET as usize + IRQ_LINES)
+                .contains(&vector) =>
+            {
+                format!("irq {}", vector - PIC_1_OFFSET as usize)
+            }
+            None => String::from("?"),
+        };
+        print!("{:>6}  {:<26} ", vector, name);
+        for counts in &counts {
+            print!(" {:>10}", counts[vector]);
+        }
+        println!();
+    }
+}
+
+fn prof(args: &[&str]) {
+    match args.first().copied() {
+        Some("start") => {
+            crate::profiler::start();
+            println!("profiling, prof report shows the samples so far");
+        }
+        Some("stop") => {
+            crate::profiler::stop();
+            let (samples, dropped) = crate::profiler::sample_count();
+            match dropped {
+                0 => println!("profiler stopped, {} samples", samples),
+                _ => println!(
+                    "profiler stopped, {} samples ({} dropped)",
+                    samples, dropped
+                ),
+            }
+        }
+        Some("report") => {
+            let count = match args.get(1).map(|count| count.parse()) {
+                None => 20,
+                Some(Ok(count)) => count,
+                Some(Err(_)) => return println!("prof: invalid count"),
+            };
+            let (samples, dropped) = crate::profiler::sample_count();
+            if samples == 0 {
+                return println!("prof: no samples, start the profiler with prof start");
+            }
+            if dropped > 0 {
+                println!(
+                    "{} samples ({} dropped, the buffer is full)",
+                    samples, dropped
+                );
+            } else {
+                println!("{} samples", samples);
+            }
+            for hotspot in crate::profiler::report().iter().take(count) {
+                let percent = hotspot.samples * 100 / samples;
+                match hotspot.symbol {
+                    Some(symbol) => println!(
+                        "  {:>6} {:>3}%  {:#}",
+                        hotspot.samples,
+                        percent,
+                        rustc_demangle::demangle(symbol.name)
+                    ),
+                    None => println!("  {:>6} {:>3}%  (unknown)", hotspot.samples, percent),
+                }
+            }
+        }
+        _ => println!("prof: usage: prof start|stop|report [count]"),
+    }
+}
+
+fn dmesg(_args: &[&str]) {
+    // only to the consoles, writing the dump to the message buffer again would duplicate it
+    let _ = crate::dmesg::dump(&mut Consoles);
+}
+
+fn reboot(_args: &[&str]) {
+    println!("rebooting...");
+    crate::power::reboot();
+}
+
+fn shutdown(_args: &[&str]) {
+    println!("shutting down...");
+    crate::power::shutdown();
+}
+
+fn redraw(editor: &LineEditor) {
+    let back = editor.line().len() - editor.cursor();
+    let _ = write!(Consoles, "\r{}{}\x1b[K", PROMPT, editor.line());
+    if back > 0 {
+        let _ = write!(Consoles, "\x1b[{}D", back);
+    }
+}
+
+// the shell task, it reads the keyboard and the serial console so it replaces print_keypresses
+pub async fn run() {
+    let mut keys = KeyStream::new();
+    let mut editor = LineEditor::new();
+
+    println!("type help for a list of commands");
+    print!("{}", PROMPT);
+    while let Some(key) = keys.next().await {
+        match key {
+            DecodedKey::RawKey(KeyCode::PageUp) => vga_buffer::with_writer(Writer::scroll_page_up),
+            DecodedKey::RawKey(KeyCode::PageDown) => {
+                vga_buffer::with_writer(Writer::scroll_page_down)
+            }
+            key => match editor.handle_key(key) {
+                Edit::Unchanged => {}
+                Edit::Changed => redraw(&editor),
+                Edit::Submit(line) => {
+                    // the finished line goes to the message buffer too, so dmesg shows what ran
+                    print!("\r{}{}\x1b[K\n", PROMPT, line);
+                    execute(&line);
+                    print!("{}", PROMPT);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+fn type_keys(editor: &mut LineEditor, keys: &[DecodedKey]) -> Edit {
+    keys.iter()
+        .map(|&key| editor.handle_key(key))
+        .last()
+        .unwrap_or(Edit::Unchanged)
+}
+
+#[test_case]
+fn test_line_editing() {
+    use DecodedKey::{RawKey, Unicode};
+
+    let mut editor = LineEditor::new();
+    let keys = [
+        Unicode('m'),
+        Unicode('m'),
+        RawKey(KeyCode::ArrowLeft),
+        Unicode('e'),
+        RawKey(KeyCode::End),
+        Unicode('x'),
+        Unicode('\x08'),
+        RawKey(KeyCode::Home),
+        RawKey(KeyCode::Delete),
+        Unicode('m'),
+    ];
+    assert_eq!(type_keys(&mut editor, &keys), Edit::Changed);
+    assert_eq!(editor.line(), "mem");
+    assert_eq!(editor.cursor(), 1);
+    // the cursor can't move before the start of the line
+    assert_eq!(
+        type_keys(
+            &mut editor,
+            &[RawKey(KeyCode::Home), RawKey(KeyCode::ArrowLeft)]
+        ),
+        Edit::Unchanged
+    );
+    assert_eq!(
+        editor.handle_key(Unicode('\n')),
+        Edit::Submit(String::from("mem"))
+    );
+    assert_eq!(editor.line(), "");
+}
+
+#[test_case]
+fn test_history() {
+    use DecodedKey::{RawKey, Unicode};
+
+    let mut editor = LineEditor::new();
+    for line in ["help", "mem", "mem", ""] {
+        line.chars().for_each(|c| {
+            editor.handle_key(Unicode(c));
+        });
+        editor.handle_key(Unicode('\n'));
+    }
+    // blank and repeated lines aren't stored
+    assert_eq!(editor.history.len(), 2);
+
+    type_keys(&mut editor, &[Unicode('u'), RawKey(KeyCode::ArrowUp)]);
+    assert_eq!(editor.line(), "mem");
+    type_keys(
+        &mut editor,
+        &[RawKey(KeyCode::ArrowUp), RawKey(KeyCode::ArrowUp)],
+    );
+    assert_eq!(editor.line(), "help");
+    assert_eq!(editor.cursor(), 4);
+    // going down past the newest entry brings back the typed line
+    type_keys(
+        &mut editor,
+        &[RawKey(KeyCode::ArrowDown), RawKey(KeyCode::ArrowDown)],
+    );
+    assert_eq!(editor.line(), "u");
+    assert!(find_command("uptime").is_some() && find_command("u").is_none());
+}
@@ -0,0 +1,340 @@
+/*
+* At boot only one CPU runs (the BOOTSTRAP_PROCESSOR, BSP), the others (APPLICATION_PROCESSORS, APs) wait
+* until the BSP sends them an INIT IPI followed by two STARTUP IPIs through the local APIC. A startup IPI
+* makes the AP start executing in 16 bit real mode at the physical address vector * 4096, so that address
+* must be below 1 MiB and contain code that switches to long mode like the bootloader did for the BSP:
+*
+*     real mode -> load a temporary GDT, protected mode -> enable PAE, load the kernel's CR3, set
+*     EFER.LME (and NXE, the kernel page tables use the no execute bit), enable paging -> long mode
+*
+* This TRAMPOLINE is copied to the low frame reserved by the frame allocator, which is identity mapped
+* so the instructions right after enabling paging can still be fetched. The BSP stores the arguments for
+* the AP (page table, stack, entry function) at the end of the trampoline. The APs are started one at a
+* time since they share the trampoline, every AP gets its own stack with an unmapped guard page below it.
+*
+* The APs only load the GDT and IDT, enable their local APIC and halt in ap_main for now. The CPUs are
+* found in the MADT, without ACPI tables only the BSP runs.
+* */
+use crate::stack::Stack;
+use crate::{apic, backtrace, gdt, interrupts, memory, percpu, timer};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+// how long the BSP waits for an AP to report that it's running
+const AP_START_TIMEOUT_MS: u64 = 100;
+
+core::arch::global_asm!(
+    r#"
+.section .text.smp_trampoline, "ax"
+.global smp_trampoline_start
+.global smp_trampoline_end
+.code16
+smp_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    # the linear address of the trampoline, used for the accesses after leaving real mode
+    xor %ebx, %ebx
+    mov %cs, %bx
+    shl $4, %ebx
+    lgdtl (smp_trampoline_gdt_pointer - smp_trampoline_start)
+    mov %cr0, %eax
+    or $1, %eax
+    mov %eax, %cr0
+    ljmpl *(smp_trampoline_protected_jump - smp_trampoline_start)
+
+.code32
+.global smp_trampoline_protected
+smp_trampoline_protected:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    # PAE
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+    mov (smp_trampoline_cr3 - smp_trampoline_start)(%ebx), %eax
+    mov %eax, %cr3
+    # EFER.LME and EFER.NXE
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+    # paging and write protection (like on the BSP)
+    mov %cr0, %eax
+    or $((1 << 31) | (1 << 16)), %eax
+    mov %eax, %cr0
+    ljmpl *(smp_trampoline_long_jump - smp_trampoline_start)(%ebx)
+
+.code64
+.global smp_trampoline_long
+smp_trampoline_long:
+    xor %ax, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    # the upper half of the registers is undefined after the mode switch
+    mov %ebx, %ebx
+    mov (smp_trampoline_stack - smp_trampoline_start)(%rbx), %rsp
+    mov (smp_trampoline_argument - smp_trampoline_start)(%rbx), %rdi
+    mov (smp_trampoline_entry - smp_trampoline_start)(%rbx), %rax
+    # a zero frame pointer ends the backtraces
+    xor %ebp, %ebp
+    call *%rax
+1:
+    hlt
+    jmp 1b
+
+.align 8
+.global smp_trampoline_gdt
+smp_trampoline_gdt:
+    .quad 0
+    .quad 0x00CF9A000000FFFF
+    .quad 0x00CF92000000FFFF
+    .quad 0x00AF9A000000FFFF
+.global smp_trampoline_gdt_pointer
+smp_trampoline_gdt_pointer:
+    .word 31
+    .long 0
+.align 8
+.global smp_trampoline_protected_jump
+smp_trampoline_protected_jump:
+    .long 0
+    .word 0x08
+.align 8
+.global smp_trampoline_long_jump
+smp_trampoline_long_jump:
+    .long 0
+    .word 0x18
+.align 8
+.global smp_trampoline_cr3
+smp_trampoline_cr3:
+    .quad 0
+.global smp_trampoline_stack
+smp_trampoline_stack:
+    .quad 0
+.global smp_trampoline_entry
+smp_trampoline_entry:
+    .quad 0
+.global smp_trampoline_argument
+smp_trampoline_argument:
+    .quad 0
+smp_trampoline_end:
+.code64
+.section .text
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static smp_trampoline_start: u8;
+    static smp_trampoline_end: u8;
+    static smp_trampoline_protected: u8;
+    static smp_trampoline_long: u8;
+    static smp_trampoline_gdt: u8;
+    static smp_trampoline_gdt_pointer: u8;
+    static smp_trampoline_protected_jump: u8;
+    static smp_trampoline_long_jump: u8;
+    static smp_trampoline_cr3: u8;
+    static smp_trampoline_stack: u8;
+    static smp_trampoline_entry: u8;
+    static smp_trampoline_argument: u8;
+}
+
+// the offset of the trampoline symbol from its start
+fn offset_of(symbol: *const u8) -> usize {
+    symbol as usize - (&raw const smp_trampoline_start) as usize
+}
+
+// the number of running CPUs, 1 until init started the APs
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(1);
+// the CPU id of the next AP, reserved before it is started so an AP that comes up after its timeout
+// doesn't share its id with the next one
+static NEXT_CPU_ID: AtomicUsize = AtomicUsize::new(1);
+// set by the AP that is starting once it doesn't need the trampoline anymore
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+pub fn cpu_count() -> usize {
+    ONLINE_CPUS.load(Ordering::SeqCst)
+}
+
+// copy the trampoline to the frame and fill in the addresses that depend on where it is
+unsafe fn install_trampoline(frame: PhysFrame, cr3: u32) -> *mut u8 {
+    let base = frame.start_address().as_u64();
+    let trampoline = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    let start = &raw const smp_trampoline_start;
+    let len = offset_of(&raw const smp_trampoline_end);
+    assert!(len <= 4096, "the SMP trampoline doesn't fit in a page");
+    core::ptr::copy_nonoverlapping(start, trampoline, len);
+
+    // the linear addresses for lgdt and the far jumps, the GDT pointer is the limit (u16) and the address
+    let linear = |symbol: *const u8| (base + offset_of(symbol) as u64) as u32;
+    let write_u32 = |symbol: *const u8, value: u32| {
+        trampoline
+            .add(offset_of(symbol))
+            .cast::<u32>()
+            .write_unaligned(value)
+    };
+    write_u32(
+        (&raw const smp_trampoline_gdt_pointer).add(2),
+        linear(&raw const smp_trampoline_gdt),
+    );
+    write_u32(
+        &raw const smp_trampoline_protected_jump,
+        linear(&raw const smp_trampoline_protected),
+    );
+    write_u32(
+        &raw const smp_trampoline_long_jump,
+        linear(&raw const smp_trampoline_long),
+    );
+
+    write_u64(trampoline, &raw const smp_trampoline_cr3, cr3 as u64);
+    write_u64(
+        trampoline,
+        &raw const smp_trampoline_entry,
+        ap_entry as *const () as u64,
+    );
+    trampoline
+}
+
+unsafe fn write_u64(trampoline: *mut u8, symbol: *const u8, value: u64) {
+    trampoline
+        .add(offset_of(symbol))
+        .cast::<u64>()
+        .write_unaligned(value);
+}
+
+// map a stack for the AP with an unmapped guard page below it (stack module), returns its top
+fn map_ap_stack() -> Option<u64> {
+    let stack = Stack::new()?;
+    let (bottom, top) = (stack.bottom().as_u64(), stack.top().as_u64());
+    backtrace::register_stack(bottom..top);
+    // the AP runs on it forever
+    core::mem::forget(stack);
+    Some(top)
+}
+
+// identity map the trampoline, the AP runs it at its physical address when it enables paging
+fn identity_map(frame: PhysFrame) -> bool {
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    if memory::translate_addr(page.start_address()) == Some(frame.start_address()) {
+        return true;
+    }
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    memory::with_frame_allocator(|frame_allocator| unsafe {
+        memory::map_page(page, frame, flags, frame_allocator).is_ok()
+    })
+}
+
+// start the APs listed in the MADT, called after apic::init with interrupts enabled (for sleep_ms)
+pub fn init() {
+    let Some(madt) = crate::acpi::madt() else {
+        return;
+    };
+    if !apic::is_enabled() {
+        return;
+    }
+    // the AP loads CR3 in 32 bit mode
+    let (level_4_table, _) = x86_64::registers::control::Cr3::read();
+    let Ok(cr3) = u32::try_from(level_4_table.start_address().as_u64()) else {
+        log::warn!("smp: the APs can't load a level 4 page table above 4 GiB, running on one CPU");
+        return;
+    };
+    let Some(frame) = memory::with_frame_allocator(|allocator| allocator.take_low_frame()) else {
+        log::warn!("smp: no free memory below 1 MiB for the trampoline");
+        return;
+    };
+    if !identity_map(frame) {
+        log::warn!("smp: can't identity map the trampoline at {:?}", frame);
+        return;
+    }
+    let trampoline = unsafe { install_trampoline(frame, cr3) };
+    let vector = (frame.start_address().as_u64() / 4096) as u8;
+    let bsp = apic::lapic_id();
+
+    let aps = madt
+        .processors
+        .iter()
+        .filter(|cpu| cpu.enabled && cpu.apic_id != bsp as u32 && cpu.apic_id <= 0xFF)
+        .take(percpu::MAX_CPUS - 1);
+    for cpu in aps {
+        let Some(stack_top) = map_ap_stack() else {
+            log::warn!("smp: out of memory for the stack of CPU {}", cpu.apic_id);
+            break;
+        };
+        // the CPU ids are given out in the order the CPUs are started, the BSP is 0
+        let cpu_id = NEXT_CPU_ID.fetch_add(1, Ordering::SeqCst);
+        unsafe {
+            write_u64(trampoline, &raw const smp_trampoline_stack, stack_top);
+            write_u64(
+                trampoline,
+                &raw const smp_trampoline_argument,
+                cpu_id as u64,
+            );
+        }
+        if !start_ap(cpu.apic_id as u8, vector) {
+            log::warn!("smp: CPU {} didn't start", cpu.apic_id);
+        }
+    }
+    log::info!("smp: {} CPUs online", cpu_count());
+}
+
+// the INIT, STARTUP, STARTUP sequence of the Intel MultiProcessor specification
+fn start_ap(apic_id: u8, vector: u8) -> bool {
+    AP_STARTED.store(false, Ordering::SeqCst);
+    apic::send_init_ipi(apic_id);
+    timer::sleep_ms(10);
+    for _ in 0..2 {
+        apic::send_startup_ipi(apic_id, vector);
+        timer::sleep_ms(1);
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+    }
+    let deadline = timer::uptime_ms() + AP_START_TIMEOUT_MS;
+    while timer::uptime_ms() < deadline {
+        if AP_STARTED.load(Ordering::SeqCst) {
+            return true;
+        }
+        x86_64::instructions::hlt();
+    }
+    false
+}
+
+// the first Rust code of an AP, called by the trampoline on the new stack with the CPU id
+extern "C" fn ap_entry(cpu_id: u64) -> ! {
+    percpu::init_ap(cpu_id as usize, apic::lapic_id() as u32);
+    gdt::init_ap();
+    interrupts::init_idt();
+    apic::init_ap();
+    ONLINE_CPUS.fetch_add(1, Ordering::SeqCst);
+    // the BSP can reuse the trampoline for the next AP now
+    AP_STARTED.store(true, Ordering::SeqCst);
+    log::debug!("smp: CPU {} running (APIC id {})", cpu_id, apic::lapic_id());
+    ap_main()
+}
+
+// the idle loop of the APs, they get no interrupts yet so they halt until an NMI or INIT arrives
+fn ap_main() -> ! {
+    // the trampoline only set EFER.NXE, the other protections and the FPU are per CPU too
+    crate::protection::init_cpu();
+    crate::fpu::init();
+    loop {
+        x86_64::instructions::interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_trampoline_fits_in_a_page() {
+    let len = offset_of(&raw const smp_trampoline_end);
+    assert!(len > 0 && len <= 4096);
+    // the GDT pointer follows the 4 entries of the GDT and the parameters are aligned
+    let gdt = offset_of(&raw const smp_trampoline_gdt);
+    assert_eq!(offset_of(&raw const smp_trampoline_gdt_pointer) - gdt, 32);
+    assert_eq!(gdt % 8, 0);
+    assert_eq!(offset_of(&raw const smp_trampoline_cr3) % 8, 0);
+}
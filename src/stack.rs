@@ -0,0 +1,101 @@
+/*
+* Kernel stacks (of the threads, the user programs and the other CPUs) are mapped in their own region
+* instead of the heap, in slots of STACK_PAGES + 1 pages. The first page of every slot stays unmapped, it
+* is the GUARD_PAGE of the stack above it: a stack that overflows writes to the guard page which is a page
+* fault, instead of silently overwriting whatever lies below it.
+*
+* The page fault can't be handled on the overflowed stack itself (the CPU would push the exception frame
+* onto the guard page again), so it turns into a double fault which runs on its own stack (gdt module).
+* Both handlers check if the address was a guard page and report a stack overflow (interrupts module).
+* */
+use crate::memory;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+pub const STACK_PAGES: u64 = 16;
+const STACKS_START: u64 = 0x_7777_0000_0000;
+const SLOT_SIZE: u64 = (STACK_PAGES + 1) * 4096;
+
+// a stack in a slot of the stack region, its pages are unmapped and freed when it is dropped
+pub struct Stack {
+    slot: u64,
+}
+
+// the slots of the dropped stacks, reused before new slots are taken
+static FREE_SLOTS: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(0);
+
+impl Stack {
+    pub fn new() -> Option<Stack> {
+        let slot = interrupts::without_interrupts(|| FREE_SLOTS.lock().pop())
+            .unwrap_or_else(|| NEXT_SLOT.fetch_add(1, Ordering::Relaxed));
+        let stack = Stack { slot };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for page in stack.pages() {
+            // on errors the dropped stack unmaps the pages mapped so far
+            memory::with_frame_allocator(|allocator| {
+                let frame = allocator.allocate_frame()?;
+                match unsafe { memory::map_page(page, frame, flags, allocator) } {
+                    Ok(()) => Some(()),
+                    Err(_) => {
+                        unsafe { allocator.deallocate_frame(frame) };
+                        None
+                    }
+                }
+            })?;
+        }
+        Some(stack)
+    }
+
+    // the first page of the slot is the guard page
+    pub fn bottom(&self) -> VirtAddr {
+        VirtAddr::new(STACKS_START + self.slot * SLOT_SIZE + 4096)
+    }
+
+    pub fn top(&self) -> VirtAddr {
+        self.bottom() + STACK_PAGES * 4096
+    }
+
+    fn pages(&self) -> impl Iterator<Item = Page<Size4KiB>> {
+        let bottom = Page::containing_address(self.bottom());
+        Page::range(bottom, bottom + STACK_PAGES)
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        for page in self.pages() {
+            if let Some(frame) = unsafe { memory::unmap_page(page) } {
+                memory::with_frame_allocator(|allocator| unsafe {
+                    allocator.deallocate_frame(frame)
+                });
+            }
+        }
+        interrupts::without_interrupts(|| FREE_SLOTS.lock().push(self.slot));
+    }
+}
+
+// the address is in the guard page of a stack slot
+pub fn is_guard_page(address: VirtAddr) -> bool {
+    let address = address.as_u64();
+    let slots = NEXT_SLOT.load(Ordering::Relaxed);
+    address >= STACKS_START
+        && address < STACKS_START + slots * SLOT_SIZE
+        && (address - STACKS_START) % SLOT_SIZE < 4096
+}
+
+// writing below the bottom of a stack hits the guard page
+#[test_case]
+fn test_guard_page_below_stack() {
+    let stack = Stack::new().unwrap();
+    assert!(is_guard_page(stack.bottom() - 1u64));
+    assert!(!is_guard_page(stack.bottom()));
+    assert!(memory::translate_addr(stack.bottom() - 1u64).is_none());
+    assert!(memory::translate_addr(stack.top() - 1u64).is_some());
+}
@@ -0,0 +1,188 @@
+/*
+* The bootloader only loads the code and data of the kernel, not the symbol table of the ELF file, so the
+* kernel can't know the names of its own functions. Instead the build reserves a section (.ksymtab) that
+* tools/embed_symbols.py fills after linking with the function symbols of the kernel ELF file sorted by
+* address. Patching the reserved bytes doesn't move anything so the addresses in the table stay correct.
+* The cargo runner runs the tool before booting the kernel (.cargo/config.toml).
+*
+* Nothing in the code of some kernels (e.g. the tests that don't print a backtrace) uses the table, the
+* linker would drop the section, so the build passes --undefined=KERNEL_SYMBOL_TABLE to keep it.
+* The size is set at compile time in the RUST_OS_SYMBOL_TABLE_KIB environment variable (in KiB). Debug builds
+* need about 160 bytes per function (the mangled names are long), the tool only stores the symbols that fit.
+*
+* Layout (little endian):
+*     magic "KSYM", u32 number of symbols
+*     the symbols sorted by address: u64 address, u32 size, u32 name offset, u32 name length, u32 padding
+*     the names (mangled, they are demangled when printed)
+* */
+use core::fmt;
+
+pub const SYMBOL_TABLE_SIZE: usize =
+    parse_kib(option_env!("RUST_OS_SYMBOL_TABLE_KIB"), 4096) * 1024;
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 24;
+
+/*
+* Zero until the tool filled it. It is an exported static mut so the compiler can't assume it keeps the
+* initial zeros and constant fold the lookups away. The name is what the tool looks for (besides the section).
+* */
+#[no_mangle]
+#[link_section = ".ksymtab"]
+pub static mut KERNEL_SYMBOL_TABLE: [u8; SYMBOL_TABLE_SIZE] = [0; SYMBOL_TABLE_SIZE];
+
+// the size in KiB from the environment variable, evaluated at compile time
+const fn parse_kib(value: Option<&str>, default: usize) -> usize {
+    let Some(value) = value else {
+        return default;
+    };
+    let digits = value.as_bytes();
+    assert!(
+        !digits.is_empty(),
+        "RUST_OS_SYMBOL_TABLE_KIB must be a number"
+    );
+    let mut kib = 0;
+    let mut index = 0;
+    while index < digits.len() {
+        assert!(
+            digits[index].is_ascii_digit(),
+            "RUST_OS_SYMBOL_TABLE_KIB must be a number"
+        );
+        kib = kib * 10 + (digits[index] - b'0') as usize;
+        index += 1;
+    }
+    kib
+}
+
+fn table() -> &'static [u8] {
+    // nothing writes the table at runtime
+    unsafe {
+        core::slice::from_raw_parts(
+            (&raw const KERNEL_SYMBOL_TABLE).cast::<u8>(),
+            SYMBOL_TABLE_SIZE,
+        )
+    }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+// the number of symbols in the table, 0 if the tool didn't fill it (e.g. when booted without the cargo runner)
+pub fn symbol_count() -> usize {
+    let table = table();
+    if &table[..4] != MAGIC {
+        return 0;
+    }
+    let count = read_u32(table, 4) as usize;
+    // don't trust a count that doesn't fit in the table
+    count.min((SYMBOL_TABLE_SIZE - HEADER_SIZE) / ENTRY_SIZE)
+}
+
+// a function of the kernel and how far into it an address is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    // the mangled name, Display demangles it
+    pub name: &'static str,
+    pub address: u64,
+    pub size: u64,
+    pub offset: u64,
+}
+
+struct Entry {
+    address: u64,
+    size: u64,
+    name: &'static str,
+}
+
+fn entry(index: usize) -> Entry {
+    let table = table();
+    let start = HEADER_SIZE + index * ENTRY_SIZE;
+    let name_offset = read_u32(table, start + 12) as usize;
+    let name_len = read_u32(table, start + 16) as usize;
+    let name = table
+        .get(name_offset..name_offset + name_len)
+        .and_then(|name| core::str::from_utf8(name).ok())
+        .unwrap_or("?");
+    Entry {
+        address: read_u64(table, start),
+        size: read_u32(table, start + 8) as u64,
+        name,
+    }
+}
+
+// find the function that contains the address
+pub fn lookup(address: u64) -> Option<Symbol> {
+    let count = symbol_count();
+    // binary search for the last symbol that starts at or before the address
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry(middle).address <= address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    let entry = entry(low.checked_sub(1)?);
+    let offset = address - entry.address;
+    // symbols without a size can't be checked, assume the address belongs to them
+    if entry.size != 0 && offset >= entry.size {
+        return None;
+    }
+    Some(Symbol {
+        name: entry.name,
+        address: entry.address,
+        size: entry.size,
+        offset,
+    })
+}
+
+/*
+* A return address points at the instruction after the call, for a call at the very end of a function
+* (e.g. to a diverging function) that is already the next function, so the symbol of the call instruction is
+* looked up instead. The offset is still relative to the return address.
+* */
+pub fn lookup_return_address(address: u64) -> Option<Symbol> {
+    let mut symbol = lookup(address.checked_sub(1)?)?;
+    symbol.offset += 1;
+    Some(symbol)
+}
+
+// prints function+offset, e.g. rust_os::memory::init+0x2a
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the alternate format leaves out the hash of legacy mangled names
+        write!(
+            f,
+            "{:#}+{:#x}",
+            rustc_demangle::demangle(self.name),
+            self.offset
+        )
+    }
+}
+
+#[test_case]
+fn test_lookup_finds_kernel_functions() {
+    #[inline(never)]
+    fn some_function() {}
+
+    let address = some_function as *const () as u64;
+    // booted without the runner step there is no table to search
+    if symbol_count() == 0 {
+        assert_eq!(lookup(address), None);
+        return;
+    }
+    let symbol = lookup(address + 1).expect("function not in the symbol table");
+    assert_eq!(symbol.address, address);
+    assert_eq!(symbol.offset, 1);
+    let mut name = alloc::string::String::new();
+    fmt::write(&mut name, format_args!("{}", symbol)).unwrap();
+    assert!(name.contains("some_function+0x1"));
+    // the table is sorted so the first symbol has the lowest address
+    assert_eq!(lookup(entry(0).address - 1), None);
+}
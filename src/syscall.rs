@@ -0,0 +1,173 @@
+/*
+* User programs call the kernel with the syscall instruction. It jumps to the address in the LSTAR register
+* in ring 0 with the code segment from STAR, saves the return address in rcx and RFLAGS in r11, and clears
+* the RFLAGS bits in SFMASK (interrupts stay disabled until the entry is on the kernel stack). It doesn't
+* switch the stack, so the entry loads the kernel's GS base with swapgs (the program's may be anything),
+* saves the user stack pointer in the CPU block and loads the kernel stack of the program from it (percpu
+* SYSCALL_STACK_OFFSET). sysret returns to rcx with r11 as RFLAGS, after swapgs gave the program its GS base
+* back.
+*
+* The calling convention is the one of Linux: the number in rax, the arguments in rdi, rsi, rdx, r10, r8,
+* r9 and the result in rax, negative values are errors (-EFAULT...). All other registers are preserved.
+*
+*     0 exit(code)                 end the program, run returns ExitStatus::Exited(code)
+*     1 write(fd, buffer, len)     write to the console (fd 1 and 2), returns the number of bytes written
+*     2 sleep(ms)                  wait for at least the given milliseconds
+*     3 brk(address)               move the end of the heap, returns the new end (the old one on errors, 0 returns it)
+*     4 getpid()                   the id of the process
+* */
+use crate::gdt;
+use crate::memory;
+use crate::percpu;
+use crate::protection;
+use core::arch::global_asm;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::gdt::SegmentSelector;
+use x86_64::VirtAddr;
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_SLEEP: u64 = 2;
+pub const SYS_BRK: u64 = 3;
+pub const SYS_GETPID: u64 = 4;
+
+// the errors are returned negated
+pub const EBADF: i64 = 9;
+pub const EFAULT: i64 = 14;
+pub const ENOSYS: i64 = 38;
+
+// the registers of the program pushed by the entry, the first field is pushed last
+#[repr(C)]
+#[derive(Debug)]
+pub struct SyscallFrame {
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub rflags: u64,
+    pub rip: u64,
+    pub rsp: u64,
+}
+
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov %rsp, %gs:{user_stack}",
+    "mov %gs:{syscall_stack}, %rsp",
+    "pushq %gs:{user_stack}",
+    "push %rcx",
+    "push %r11",
+    "push %rax",
+    "push %rdi",
+    "push %rsi",
+    "push %rdx",
+    "push %r10",
+    "push %r8",
+    "push %r9",
+    // 10 pushes keep the 16 byte alignment of the stack top for the call
+    "mov %rsp, %rdi",
+    "sti",
+    "call {dispatch}",
+    "cli",
+    "pop %r9",
+    "pop %r8",
+    "pop %r10",
+    "pop %rdx",
+    "pop %rsi",
+    "pop %rdi",
+    "pop %rax",
+    "pop %r11",
+    "pop %rcx",
+    // the user stack pointer is read from the kernel stack, not from the CPU block
+    "swapgs",
+    "pop %rsp",
+    "sysretq",
+    user_stack = const percpu::USER_STACK_OFFSET,
+    syscall_stack = const percpu::SYSCALL_STACK_OFFSET,
+    dispatch = sym dispatch_frame,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+extern "C" fn dispatch_frame(frame: &mut SyscallFrame) {
+    let arguments = [
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ];
+    frame.rax = dispatch(frame.rax, arguments) as u64;
+}
+
+fn dispatch(number: u64, arguments: [u64; 6]) -> i64 {
+    match number {
+        SYS_EXIT => crate::usermode::exit(arguments[0] as i64),
+        SYS_WRITE => write(arguments[0], arguments[1], arguments[2]),
+        SYS_SLEEP => sleep(arguments[0]),
+        SYS_BRK => crate::process::brk(arguments[0]) as i64,
+        SYS_GETPID => crate::process::current_pid().map_or(0, |pid| pid.0 as i64),
+        _ => {
+            log::debug!("unknown syscall {}", number);
+            -ENOSYS
+        }
+    }
+}
+
+fn write(fd: u64, buffer: u64, len: u64) -> i64 {
+    if fd != 1 && fd != 2 {
+        return -EBADF;
+    }
+    let Ok(start) = VirtAddr::try_new(buffer) else {
+        return -EFAULT;
+    };
+    if !memory::is_user_accessible(start, len, false) {
+        return -EFAULT;
+    }
+    // the bytes are copied while SMAP allows the access
+    let text = protection::with_user_access(|| {
+        let bytes = unsafe { core::slice::from_raw_parts(start.as_ptr::<u8>(), len as usize) };
+        alloc::string::String::from_utf8_lossy(bytes).into_owned()
+    });
+    crate::print!("{}", text);
+    len as i64
+}
+
+fn sleep(ms: u64) -> i64 {
+    crate::timer::sleep_ms(ms);
+    0
+}
+
+// enable the syscall instruction, called by lib::init after the GDT is loaded
+pub fn init() {
+    Star::write(
+        SegmentSelector(gdt::USER_CODE_SELECTOR),
+        SegmentSelector(gdt::USER_DATA_SELECTOR),
+        SegmentSelector(gdt::KERNEL_CODE_SELECTOR),
+        SegmentSelector(gdt::KERNEL_DATA_SELECTOR),
+    )
+    .expect("the GDT order doesn't match STAR");
+    LStar::write(VirtAddr::new(syscall_entry as *const () as u64));
+    // a program could turn SMAP off for the kernel with EFLAGS.AC
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK,
+    );
+    unsafe { Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS) };
+}
+
+#[test_case]
+fn test_dispatch_rejects_bad_arguments() {
+    assert_eq!(dispatch(SYS_WRITE, [3, 0, 0, 0, 0, 0]), -EBADF);
+    // a program can't make the kernel print kernel memory
+    let kernel = [0u8; 4];
+    let address = kernel.as_ptr() as u64;
+    assert_eq!(dispatch(SYS_WRITE, [1, address, 4, 0, 0, 0]), -EFAULT);
+    assert_eq!(dispatch(1000, [0; 6]), -ENOSYS);
+}
@@ -0,0 +1,166 @@
+/*
+* Instead of threads (which need their own stacks and preemption) the kernel can run COOPERATIVE tasks
+* using rust's async/await. An async fn is compiled into a state machine that implements the Future trait,
+* calling poll advances it until it either completes (Poll::Ready) or has to wait for something (Poll::Pending).
+*
+* A pending future registers the Waker passed to poll (e.g. with an interrupt handler) and the waker
+* is called once the future can make progress, so an EXECUTOR only has to poll the tasks that were woken
+* instead of polling all of them in a loop.
+* */
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub mod channel;
+pub mod executor;
+pub mod keyboard;
+pub mod serial;
+pub mod timer;
+
+// a unique id for every task, used by the executor to find the task a waker belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+// the task the executor on this CPU is polling, None outside of tasks
+pub fn current_id() -> Option<TaskId> {
+    crate::percpu::current().current_task().map(TaskId)
+}
+
+/*
+* A task is a future without a result (spawn below wraps futures with a result so it can be awaited).
+* The future is stored on the heap as a trait object (dyn) so tasks of different types can be stored
+* together, and it is pinned because the compiled state machine may contain references to itself
+* which would become invalid if it was moved in memory.
+* */
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+/*
+* Tasks can be spawned from anywhere in the kernel (other tasks, drivers, interrupt handlers...) with spawn
+* instead of only by the code that owns the executor. The future is queued in SPAWNED and the executor
+* moves it to its tasks the next time it looks for ready tasks, tasks spawned while it is halted start
+* after the next interrupt. The queue is shared with other CPUs and threads so the futures must be Send.
+*
+* spawn returns a JoinHandle, a future that completes with the output of the task. The task stores the
+* output in the state it shares with the handle and wakes the task that awaits the handle. Dropping the
+* handle doesn't stop the task, its output is dropped then.
+* */
+struct Spawned {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+// locked with interrupts disabled since interrupt handlers may spawn tasks
+static SPAWNED: Mutex<VecDeque<Spawned>> = Mutex::new(VecDeque::new());
+
+pub fn spawn<T: Send + 'static>(future: impl Future<Output = T> + Send + 'static) -> JoinHandle<T> {
+    let id = TaskId::new();
+    let state = Arc::new(Mutex::new(JoinState {
+        output: None,
+        waker: None,
+    }));
+    let task_state = state.clone();
+    let future = async move {
+        let output = future.await;
+        let waker = {
+            let mut state = task_state.lock();
+            state.output = Some(output);
+            state.waker.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    };
+    without_interrupts(|| {
+        SPAWNED.lock().push_back(Spawned {
+            id,
+            future: Box::pin(future),
+        })
+    });
+    JoinHandle { id, state }
+}
+
+// the next spawned task for the executor
+fn take_spawned() -> Option<Task> {
+    let spawned = without_interrupts(|| SPAWNED.lock().pop_front())?;
+    Some(Task {
+        id: spawned.id,
+        future: spawned.future,
+    })
+}
+
+fn has_spawned() -> bool {
+    without_interrupts(|| !SPAWNED.lock().is_empty())
+}
+
+struct JoinState<T> {
+    output: Option<T>,
+    // the waker of the task awaiting the handle
+    waker: Option<Waker>,
+}
+
+pub struct JoinHandle<T> {
+    id: TaskId,
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state.lock().output.is_some()
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = T;
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<T> {
+        let mut state = self.state.lock();
+        match state.output.take() {
+            Some(output) => Poll::Ready(output),
+            None => {
+                state.waker = Some(context.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -0,0 +1,270 @@
+/*
+* A CHANNEL passes messages from any number of Senders to one Receiver (multi producer, single consumer).
+* The messages wait in a bounded lock free ArrayQueue, so Sender::try_send never blocks or allocates and can
+* be called by interrupt handlers: a driver pushes its events and a server task or thread handles them.
+*
+* Both sides can wait in two ways:
+*  * async tasks await send and recv, the waiting side registers its waker and is woken by the other side
+*  * kernel threads call send_blocking and recv_blocking which give the CPU to the other threads until
+*    the channel has room or a message
+*
+* The channel is closed when all Senders or the Receiver are dropped. recv returns the messages that are
+* still queued and then None, send fails at once and gives the message back.
+* */
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::future::poll_fn;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Disconnected(T),
+}
+
+// the receiver was dropped, the message is given back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    Empty,
+    Disconnected,
+}
+
+struct Shared<T> {
+    queue: ArrayQueue<T>,
+    // the waker of the receiving task
+    receiver: AtomicWaker,
+    // the wakers of the tasks waiting for room in the queue, locked with interrupts disabled
+    senders: Mutex<Vec<Waker>>,
+    sender_count: AtomicUsize,
+    receiver_dropped: AtomicBool,
+}
+
+impl<T> Shared<T> {
+    fn wake_senders(&self) {
+        let wakers = without_interrupts(|| core::mem::take(&mut *self.senders.lock()));
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+// a channel for up to capacity queued messages, allocates the queue so it can't be called by interrupt handlers
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        queue: ArrayQueue::new(capacity),
+        receiver: AtomicWaker::new(),
+        senders: Mutex::new(Vec::new()),
+        sender_count: AtomicUsize::new(1),
+        receiver_dropped: AtomicBool::new(false),
+    });
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+impl<T> Sender<T> {
+    // queue the message if there is room, doesn't block or allocate
+    pub fn try_send(&self, message: T) -> Result<(), TrySendError<T>> {
+        if self.shared.receiver_dropped.load(Ordering::Acquire) {
+            return Err(TrySendError::Disconnected(message));
+        }
+        self.shared
+            .queue
+            .push(message)
+            .map_err(TrySendError::Full)?;
+        self.shared.receiver.wake();
+        Ok(())
+    }
+
+    fn poll_send(
+        &self,
+        context: &mut Context,
+        message: &mut Option<T>,
+    ) -> Poll<Result<(), SendError<T>>> {
+        let value = message.take().expect("polled after completion");
+        let value = match self.try_send(value) {
+            Ok(()) => return Poll::Ready(Ok(())),
+            Err(TrySendError::Disconnected(value)) => return Poll::Ready(Err(SendError(value))),
+            Err(TrySendError::Full(value)) => value,
+        };
+        without_interrupts(|| self.shared.senders.lock().push(context.waker().clone()));
+        // the receiver may have made room before the waker was registered
+        match self.try_send(value) {
+            Ok(()) => Poll::Ready(Ok(())),
+            Err(TrySendError::Disconnected(value)) => Poll::Ready(Err(SendError(value))),
+            Err(TrySendError::Full(value)) => {
+                *message = Some(value);
+                Poll::Pending
+            }
+        }
+    }
+
+    // wait until there is room for the message
+    pub async fn send(&self, message: T) -> Result<(), SendError<T>> {
+        let mut message = Some(message);
+        poll_fn(|context| self.poll_send(context, &mut message)).await
+    }
+
+    // like send for kernel threads, the other threads run while the queue is full
+    pub fn send_blocking(&self, mut message: T) -> Result<(), SendError<T>> {
+        loop {
+            match self.try_send(message) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(value)) => return Err(SendError(value)),
+                Err(TrySendError::Full(value)) => message = value,
+            }
+            wait();
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.sender_count.fetch_add(1, Ordering::Relaxed);
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.shared.sender_count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // the receiver sees that the channel is closed
+            self.shared.receiver.wake();
+        }
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        match self.shared.queue.pop() {
+            Some(message) => {
+                self.shared.wake_senders();
+                Ok(message)
+            }
+            // a message sent just before the last sender was dropped is still received
+            None if self.shared.sender_count.load(Ordering::Acquire) == 0 => {
+                self.shared.queue.pop().ok_or(TryRecvError::Disconnected)
+            }
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    fn poll_recv(&mut self, context: &mut Context) -> Poll<Option<T>> {
+        match self.try_recv() {
+            Ok(message) => return Poll::Ready(Some(message)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(None),
+            Err(TryRecvError::Empty) => {}
+        }
+        self.shared.receiver.register(context.waker());
+        // a message may have been sent before the waker was registered
+        match self.try_recv() {
+            Ok(message) => {
+                self.shared.receiver.take();
+                Poll::Ready(Some(message))
+            }
+            Err(TryRecvError::Disconnected) => Poll::Ready(None),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    // the next message, None once all senders are dropped and the queue is empty
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|context| self.poll_recv(context)).await
+    }
+
+    // like recv for kernel threads, the other threads run while the queue is empty
+    pub fn recv_blocking(&mut self) -> Option<T> {
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Some(message),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => wait(),
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.shared.receiver_dropped.store(true, Ordering::Release);
+        self.shared.wake_senders();
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, context: &mut Context) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(context)
+    }
+}
+
+/*
+* Let the other threads run, then halt until the next interrupt in case no other thread was ready. The other
+* side of the channel is another thread, a task on the executor or an interrupt handler, all of them get the
+* CPU this way.
+* */
+fn wait() {
+    crate::scheduler::yield_now();
+    x86_64::instructions::hlt();
+}
+
+// a thread and a task send through the same channel, a full queue makes them wait for the receiver
+#[test_case]
+fn test_channel_between_threads_and_tasks() {
+    use super::executor::Executor;
+    use super::Task;
+
+    let (sender, mut receiver) = channel(2);
+    let thread_sender = sender.clone();
+    crate::scheduler::spawn("sender", move || {
+        for i in 0..5 {
+            thread_sender.send_blocking(i).unwrap();
+        }
+    });
+    for _ in 0..5 {
+        assert!(receiver.recv_blocking().unwrap() < 5);
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async move {
+        for i in 5..10 {
+            sender.send(i).await.unwrap();
+        }
+    }));
+    executor.spawn(Task::new(async move {
+        for i in 5..10 {
+            assert_eq!(receiver.recv().await, Some(i));
+        }
+        // the sender task completed and dropped the last sender
+        assert_eq!(receiver.recv().await, None);
+    }));
+    executor.run_until_complete();
+
+    let (sender, receiver) = channel::<u8>(1);
+    drop(receiver);
+    assert_eq!(sender.try_send(1), Err(TrySendError::Disconnected(1)));
+}
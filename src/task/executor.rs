@@ -0,0 +1,208 @@
+/*
+* The executor keeps all tasks in a BTreeMap and the ids of the tasks that are ready to be polled in a queue.
+* Wakers push the id of their task to the queue, since wakers are often called from interrupt handlers
+* the queue must not block or allocate, so it is a fixed size lock free ArrayQueue shared with the wakers
+* through an Arc (a Mutex would deadlock if the interrupt arrives while the executor holds the lock).
+* */
+use super::{Task, TaskId};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+
+// the maximal number of woken tasks that wait to be polled
+const TASK_QUEUE_SIZE: usize = 100;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    // a task is usually woken many times, caching its waker avoids allocating a new one every poll
+    waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    // new tasks are ready to run so they are queued right away
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.task_queue.push(task_id).expect("queue full");
+    }
+
+    // poll the woken tasks forever, the CPU is halted while no task is ready
+    pub fn run(&mut self) -> ! {
+        loop {
+            // every round shows the watchdog that the kernel isn't stuck
+            crate::watchdog::feed();
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    // like run but returns once all spawned tasks have completed (including the ones of task::spawn)
+    pub fn run_until_complete(&mut self) {
+        while !self.tasks.is_empty() || super::has_spawned() {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        while let Some(task) = super::take_spawned() {
+            self.spawn(task);
+        }
+
+        // destructure self to borrow the fields separately (the closure below borrows task_queue)
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+        } = self;
+
+        while let Some(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // the task completed but was woken again
+            };
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::new_waker(task_id, task_queue.clone()));
+            let mut context = Context::from_waker(waker);
+            let cpu = crate::percpu::current();
+            cpu.set_current_task(Some(task_id.0));
+            let poll = task.poll(&mut context);
+            cpu.set_current_task(None);
+            match poll {
+                Poll::Ready(()) => {
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        // an interrupt could wake a task between checking the queue and halting, then the task would
+        // wait until the next interrupt, so the interrupts are disabled while checking and
+        // enable_and_hlt enables them and halts in one atomic step
+        interrupts::disable();
+        if self.task_queue.is_empty() && !super::has_spawned() {
+            enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// waking a task pushes its id to the task queue of the executor
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn new_waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+        }))
+    }
+
+    fn wake_task(&self) {
+        self.task_queue.push(self.task_id).expect("task_queue full");
+    }
+}
+
+// the Wake trait of alloc creates the RawWaker vtable for wakers stored in an Arc
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+#[test_case]
+fn test_executor_runs_tasks_to_completion() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    static COMPLETED: AtomicUsize = AtomicUsize::new(0);
+
+    async fn number() -> usize {
+        42
+    }
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        executor.spawn(Task::new(async {
+            assert_eq!(number().await, 42);
+            COMPLETED.fetch_add(1, Ordering::SeqCst);
+        }));
+    }
+    executor.run_until_complete();
+    assert_eq!(COMPLETED.load(Ordering::SeqCst), 3);
+}
+
+// a task that returns Pending once is only polled again after its waker was called
+#[test_case]
+fn test_executor_repolls_woken_task() {
+    use core::future::Future;
+    use core::pin::Pin;
+
+    struct YieldOnce(bool);
+    impl Future for YieldOnce {
+        type Output = ();
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(YieldOnce(false)));
+    executor.run_until_complete();
+    assert!(executor.tasks.is_empty() && executor.waker_cache.is_empty());
+}
+
+// tasks spawned with task::spawn (here by another task) run on the executor and can be awaited
+#[test_case]
+fn test_spawn_and_join() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static JOINED: AtomicBool = AtomicBool::new(false);
+
+    let outer = super::spawn(async {
+        let inner = super::spawn(async { super::current_id() });
+        let inner_id = inner.id();
+        assert_eq!(inner.await, Some(inner_id));
+        JOINED.store(true, Ordering::SeqCst);
+    });
+    let mut executor = Executor::new();
+    executor.run_until_complete();
+    assert!(JOINED.load(Ordering::SeqCst));
+    assert!(outer.is_finished());
+    // the test code doesn't run in a task
+    assert_eq!(super::current_id(), None);
+}
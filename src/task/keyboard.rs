@@ -0,0 +1,187 @@
+/*
+* The keyboard interrupt handler pushes the raw scancodes to a queue and an async task decodes them.
+* Keeping the interrupt handler this short means it can't be slowed down or deadlocked by the decoding
+* or printing code, and tasks can `await` key presses instead of polling for them.
+*
+* The queue is a lock free ArrayQueue so the interrupt handler never blocks, it is allocated on the
+* heap when the ScancodeStream is created. A normal static can't allocate, and lazy_static would
+* allocate inside the interrupt handler if it was the first to access it, so we use OnceCell
+* which only initializes when ScancodeStream::new is called.
+* */
+use super::serial::{SerialDecoder, SerialStream};
+use crate::keyboard::{DecodedKey, KeyCode, Keyboard};
+use crate::print;
+use crate::vga_buffer::{with_writer, Writer};
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
+
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+// the waker of the task waiting for the next scancode, AtomicWaker can be updated and woken
+// from different contexts (task and interrupt handler) without a lock
+static WAKER: AtomicWaker = AtomicWaker::new();
+// the scancodes dropped because the queue was full, the stream logs the warning outside of the interrupt
+static DROPPED_SCANCODES: AtomicUsize = AtomicUsize::new(0);
+
+const SCANCODE_QUEUE_SIZE: usize = 100;
+
+/*
+* Called by the keyboard interrupt handler, returns false if there is no ScancodeStream yet
+* so the scancode can be handled another way.
+* Must not block or allocate since it runs in the interrupt handler.
+* */
+pub(crate) fn add_scancode(scancode: u8) -> bool {
+    let Ok(queue) = SCANCODE_QUEUE.try_get() else {
+        return false;
+    };
+    if queue.push(scancode).is_err() {
+        DROPPED_SCANCODES.fetch_add(1, Ordering::Relaxed);
+    } else {
+        WAKER.wake();
+    }
+    true
+}
+
+// the async stream of scancodes received from the keyboard, there can only be one
+pub struct ScancodeStream {
+    // prevents constructing the struct without calling new
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        SCANCODE_QUEUE
+            .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
+            .expect("ScancodeStream::new should only be called once");
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE
+            .try_get()
+            .expect("scancode queue not initialized");
+        let dropped = DROPPED_SCANCODES.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            log::warn!("scancode queue full; dropped {} scancodes", dropped);
+        }
+
+        // fast path, avoids registering the waker when a scancode is already available
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        // the interrupt handler may push a scancode between the first pop and registering the waker
+        // so we check the queue again after registering it
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/*
+* The keys of the keyboard and of the serial console in one stream, so the shell doesn't care where they
+* come from. Creating it takes the input of the serial console (serial::init_console), the keyboard alone is
+* used if COM1 isn't a console. It takes the single ScancodeStream and SerialStream, so there can only be one too.
+* */
+pub struct KeyStream {
+    scancodes: ScancodeStream,
+    keyboard: Keyboard,
+    serial: Option<SerialStream>,
+    decoder: SerialDecoder,
+}
+
+impl KeyStream {
+    pub fn new() -> Self {
+        let serial = SerialStream::new();
+        KeyStream {
+            scancodes: ScancodeStream::new(),
+            keyboard: Keyboard::new(),
+            serial: crate::serial::init_console().then_some(serial),
+            decoder: SerialDecoder::new(),
+        }
+    }
+}
+
+impl Default for KeyStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for KeyStream {
+    type Item = DecodedKey;
+
+    // both streams are polled until one has a key, so the wakers of both are registered when none has
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<DecodedKey>> {
+        let this = self.get_mut();
+        while let Poll::Ready(Some(scancode)) = this.scancodes.poll_next_unpin(cx) {
+            if let Some(key) = this.keyboard.process_scancode(scancode) {
+                return Poll::Ready(Some(key));
+            }
+        }
+        if let Some(serial) = this.serial.as_mut() {
+            while let Poll::Ready(Some(byte)) = serial.poll_next_unpin(cx) {
+                if let Some(key) = this.decoder.process_byte(byte) {
+                    return Poll::Ready(Some(key));
+                }
+            }
+        }
+        Poll::Pending
+    }
+}
+
+// echo the typed keys to the screen
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    let mut keyboard = Keyboard::new();
+
+    while let Some(scancode) = scancodes.next().await {
+        match keyboard.process_scancode(scancode) {
+            Some(DecodedKey::Unicode(character)) => print!("{}", character),
+            // page up/down scroll the screen through the lines that scrolled off the top
+            Some(DecodedKey::RawKey(KeyCode::PageUp)) => with_writer(Writer::scroll_page_up),
+            Some(DecodedKey::RawKey(KeyCode::PageDown)) => with_writer(Writer::scroll_page_down),
+            Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+            None => {}
+        }
+    }
+}
+
+#[test_case]
+fn test_scancode_stream_yields_added_scancodes() {
+    use futures_util::task::noop_waker_ref;
+    let mut stream = ScancodeStream::new();
+    let mut context = Context::from_waker(noop_waker_ref());
+    assert_eq!(stream.poll_next_unpin(&mut context), Poll::Pending);
+    // the scancodes are queued by the interrupt handler, here we add them directly
+    assert!(add_scancode(0x1E));
+    assert!(add_scancode(0x9E));
+    assert_eq!(
+        stream.poll_next_unpin(&mut context),
+        Poll::Ready(Some(0x1E))
+    );
+    assert_eq!(
+        stream.poll_next_unpin(&mut context),
+        Poll::Ready(Some(0x9E))
+    );
+    assert_eq!(stream.poll_next_unpin(&mut context), Poll::Pending);
+}
@@ -0,0 +1,200 @@
+/*
+* The input of the serial console. The interrupt handler of COM1 (serial::init_console) pushes the received
+* bytes to a queue like the keyboard interrupt handler does with the scancodes, and SerialStream yields them
+* to a task.
+*
+* A terminal sends the keys as bytes: the printable characters as themselves, enter as \r, backspace as DEL
+* (0x7F) or BS (0x08) and the other keys as VT100 escape sequences:
+*
+*     ESC [ A/B/C/D  the arrow keys (up, down, right, left)      ESC [ H/F, ESC O H/F  home, end
+*     ESC [ n ~      1/7 home, 2 insert, 3 delete, 4/8 end, 5 page up, 6 page down
+*     ESC O P/Q/R/S  F1 to F4
+*
+* SerialDecoder turns them into the DecodedKeys the keyboard produces, so the shell handles both the same
+* way. Bytes that aren't ASCII are dropped, the shell only takes ASCII anyway.
+* */
+use crate::keyboard::{DecodedKey, KeyCode};
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+const BYTE_QUEUE_SIZE: usize = 256;
+const ESCAPE: u8 = 0x1B;
+
+// called by the serial interrupt handler, must not block or allocate
+pub(crate) fn add_byte(byte: u8) {
+    let Ok(queue) = BYTE_QUEUE.try_get() else {
+        return;
+    };
+    if queue.push(byte).is_err() {
+        log::warn!("serial queue full; dropping serial input");
+    } else {
+        WAKER.wake();
+    }
+}
+
+// the async stream of bytes received on the serial console, there can only be one
+pub struct SerialStream {
+    _private: (),
+}
+
+impl SerialStream {
+    pub fn new() -> Self {
+        BYTE_QUEUE
+            .try_init_once(|| ArrayQueue::new(BYTE_QUEUE_SIZE))
+            .expect("SerialStream::new should only be called once");
+        SerialStream { _private: () }
+    }
+}
+
+impl Default for SerialStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for SerialStream {
+    type Item = u8;
+
+    // like ScancodeStream::poll_next
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = BYTE_QUEUE.try_get().expect("serial queue not initialized");
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    #[default]
+    Normal,
+    // after \r, terminals may send \r\n for enter
+    Return,
+    // after ESC
+    Escape,
+    // after ESC [, the number of an ESC [ n ~ sequence so far
+    Csi(u8),
+    // after ESC O
+    Ss3,
+}
+
+// turns the bytes sent by a terminal into keys
+#[derive(Debug, Default)]
+pub struct SerialDecoder {
+    state: State,
+}
+
+impl SerialDecoder {
+    pub const fn new() -> SerialDecoder {
+        SerialDecoder {
+            state: State::Normal,
+        }
+    }
+
+    pub fn process_byte(&mut self, byte: u8) -> Option<DecodedKey> {
+        let state = core::mem::take(&mut self.state);
+        let key = match (state, byte) {
+            (State::Normal, ESCAPE) => {
+                self.state = State::Escape;
+                return None;
+            }
+            (State::Return, b'\n') => return None,
+            (State::Return, byte) => return self.process_byte(byte),
+            (State::Normal, b'\r') => {
+                self.state = State::Return;
+                DecodedKey::Unicode('\n')
+            }
+            (State::Normal, b'\n') => DecodedKey::Unicode('\n'),
+            (State::Normal, 0x7F | 0x08) => DecodedKey::Unicode('\x08'),
+            (State::Normal, byte) if byte.is_ascii() => DecodedKey::Unicode(byte as char),
+            (State::Normal, _) => return None,
+
+            (State::Escape, b'[') => {
+                self.state = State::Csi(0);
+                return None;
+            }
+            (State::Escape, b'O') => {
+                self.state = State::Ss3;
+                return None;
+            }
+            (State::Escape, ESCAPE) => DecodedKey::RawKey(KeyCode::Escape),
+            // a lone ESC (the escape key) is dropped, the byte after it is a normal key
+            (State::Escape, byte) => return self.process_byte(byte),
+
+            (State::Csi(number), digit @ b'0'..=b'9') => {
+                self.state = State::Csi(number.saturating_mul(10).saturating_add(digit - b'0'));
+                return None;
+            }
+            // the modifiers after the ; (ESC [ 1 ; 5 C is ctrl right) are ignored
+            (State::Csi(_), b';') => {
+                self.state = State::Csi(0);
+                return None;
+            }
+            (State::Csi(number), b'~') => DecodedKey::RawKey(match number {
+                1 | 7 => KeyCode::Home,
+                2 => KeyCode::Insert,
+                3 => KeyCode::Delete,
+                4 | 8 => KeyCode::End,
+                5 => KeyCode::PageUp,
+                6 => KeyCode::PageDown,
+                _ => return None,
+            }),
+            (State::Csi(_) | State::Ss3, byte) => DecodedKey::RawKey(match byte {
+                b'A' => KeyCode::ArrowUp,
+                b'B' => KeyCode::ArrowDown,
+                b'C' => KeyCode::ArrowRight,
+                b'D' => KeyCode::ArrowLeft,
+                b'H' => KeyCode::Home,
+                b'F' => KeyCode::End,
+                b'P'..=b'S' if state == State::Ss3 => KeyCode::F(byte - b'P' + 1),
+                _ => return None,
+            }),
+        };
+        Some(key)
+    }
+}
+
+#[test_case]
+fn test_decode_terminal_keys() {
+    use DecodedKey::{RawKey, Unicode};
+
+    let mut decoder = SerialDecoder::new();
+    let mut decode = |bytes: &[u8]| -> alloc::vec::Vec<DecodedKey> {
+        bytes
+            .iter()
+            .filter_map(|&byte| decoder.process_byte(byte))
+            .collect()
+    };
+    assert_eq!(decode(b"ls\r"), [Unicode('l'), Unicode('s'), Unicode('\n')]);
+    assert_eq!(decode(b"\r\n\n"), [Unicode('\n'), Unicode('\n')]);
+    assert_eq!(decode(b"\x7f"), [Unicode('\x08')]);
+    assert_eq!(
+        decode(b"\x1b[A\x1b[D\x1bOH\x1b[3~\x1b[6~\x1bOQ"),
+        [
+            RawKey(KeyCode::ArrowUp),
+            RawKey(KeyCode::ArrowLeft),
+            RawKey(KeyCode::Home),
+            RawKey(KeyCode::Delete),
+            RawKey(KeyCode::PageDown),
+            RawKey(KeyCode::F(2)),
+        ]
+    );
+    assert_eq!(decode(b"\x1b[1;5C"), [RawKey(KeyCode::ArrowRight)]);
+    // unknown sequences and bytes that aren't ASCII are dropped
+    assert_eq!(decode(b"\x1b[Z\xc3x"), [Unicode('x')]);
+}
@@ -0,0 +1,110 @@
+/*
+* A task that waits for some time can't call timer::sleep_ms, it would halt the CPU and all the other tasks
+* of the executor with it. sleep_ms here returns a future instead: polling it stores the waker with the
+* deadline (in timer ticks) in SLEEPERS and the timer interrupt wakes the tasks whose deadline passed.
+*
+* SLEEPERS is ordered by the deadline so the interrupt only looks at the first entries, the id of the Sleep
+* makes the keys of sleeps with the same deadline unique. A Sleep that is dropped before it completed
+* (e.g. the loser of a timeout) removes its entry.
+* */
+use crate::timer::{self, TICKS_PER_SECOND};
+use alloc::collections::BTreeMap;
+use core::future::{poll_fn, Future};
+use core::pin::{pin, Pin};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+// locked with interrupts disabled since the timer interrupt takes it
+static SLEEPERS: Mutex<BTreeMap<(u64, u64), Waker>> = Mutex::new(BTreeMap::new());
+
+pub struct Sleep {
+    deadline: u64,
+    id: u64,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if timer::ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let key = (self.deadline, self.id);
+        without_interrupts(|| SLEEPERS.lock().insert(key, context.waker().clone()));
+        // the deadline may have passed before the waker was stored
+        if timer::ticks() >= self.deadline {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        let key = (self.deadline, self.id);
+        without_interrupts(|| SLEEPERS.lock().remove(&key));
+    }
+}
+
+// wait until the timer reached the tick
+pub fn sleep_until_tick(deadline: u64) -> Sleep {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    Sleep {
+        deadline,
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+    }
+}
+
+// wait for at least the given number of milliseconds, like timer::sleep_ms
+pub fn sleep_ms(ms: u64) -> Sleep {
+    let ticks = (ms * TICKS_PER_SECOND as u64).div_ceil(1000);
+    // +1 because the current tick is already partly over
+    sleep_until_tick(timer::ticks() + ticks + 1)
+}
+
+// the output of the future, or None if it didn't complete within the given number of milliseconds
+pub async fn timeout<F: Future>(ms: u64, future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    let mut sleep = sleep_ms(ms);
+    poll_fn(|context| {
+        if let Poll::Ready(output) = future.as_mut().poll(context) {
+            return Poll::Ready(Some(output));
+        }
+        match Pin::new(&mut sleep).poll(context) {
+            Poll::Ready(()) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    })
+    .await
+}
+
+// called by the timer interrupt handler
+pub(crate) fn wake_expired(ticks: u64) {
+    let mut sleepers = SLEEPERS.lock();
+    while let Some(entry) = sleepers.first_entry() {
+        if entry.key().0 > ticks {
+            break;
+        }
+        entry.remove().wake();
+    }
+}
+
+#[test_case]
+fn test_sleep_and_timeout() {
+    use super::executor::Executor;
+    use super::Task;
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(async {
+        let start = timer::ticks();
+        sleep_ms(10).await;
+        assert!(timer::ticks() >= start + 10);
+        assert_eq!(timeout(10, core::future::pending::<()>()).await, None);
+        assert_eq!(timeout(1000, async { 42 }).await, Some(42));
+    }));
+    executor.run_until_complete();
+    assert!(without_interrupts(|| SLEEPERS.lock().is_empty()));
+}
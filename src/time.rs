@@ -0,0 +1,145 @@
+/*
+* The wall clock time is read from the RTC once at boot and then advanced with the timer ticks: reading
+* the RTC takes up to a second when it waits for an update and it only counts whole seconds, the ticks are
+* cheap to read and count milliseconds. The boot time is stored as seconds since the unix epoch
+* (1970-01-01 00:00:00 UTC) which makes adding the uptime simple.
+* */
+use crate::timer;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8, // 1 - 12
+    pub day: u8,   // 1 - 31
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/*
+* The conversion between dates and days since the epoch use the algorithm of Howard Hinnant
+* (days_from_civil/civil_from_days): counting the years from march puts the leap day at the end of the
+* year, then the month lengths repeat every 5 months (153 days) and the leap years every 400 years (an era).
+* */
+impl DateTime {
+    pub fn from_unix_time(seconds: u64) -> DateTime {
+        let days = seconds / SECONDS_PER_DAY;
+        let time = seconds % SECONDS_PER_DAY;
+
+        let days = days as i64 + 719_468; // days since 0000-03-01
+        let era = days.div_euclid(146_097);
+        let day_of_era = days.rem_euclid(146_097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_from_march = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+        let month = if month_from_march < 10 {
+            month_from_march + 3
+        } else {
+            month_from_march - 9
+        };
+        let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+
+    // dates before the epoch give 0
+    pub fn to_unix_time(&self) -> u64 {
+        let month = self.month as i64;
+        let year = self.year as i64 - if month <= 2 { 1 } else { 0 };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let month_from_march = (month + 9) % 12;
+        let day_of_year = (153 * month_from_march + 2) / 5 + self.day as i64 - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        let seconds =
+            days * SECONDS_PER_DAY as i64 + self.hour as i64 * 3600 + self.minute as i64 * 60;
+        (seconds + self.second as i64).max(0) as u64
+    }
+}
+
+// ISO 8601, e.g. 2026-10-14 09:30:00
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// the unix time of the RTC when init ran, minus the uptime at that point
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+// read the RTC, called after the timer is initialized
+pub fn init() {
+    let rtc = crate::rtc::read();
+    let boot_time = rtc.to_unix_time().saturating_sub(uptime().as_secs());
+    BOOT_TIME.store(boot_time, Ordering::Relaxed);
+    log::info!("time: {} UTC", rtc);
+}
+
+// the time since the timer was initialized
+pub fn uptime() -> Duration {
+    let ticks = timer::ticks();
+    let per_second = timer::TICKS_PER_SECOND as u64;
+    Duration::new(
+        ticks / per_second,
+        ((ticks % per_second) * 1_000_000_000 / per_second) as u32,
+    )
+}
+
+// seconds since the unix epoch, 0 plus the uptime if init didn't run yet
+pub fn unix_time() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed) + uptime().as_secs()
+}
+
+// the current date and time in UTC
+pub fn now() -> DateTime {
+    DateTime::from_unix_time(unix_time())
+}
+
+#[test_case]
+fn test_unix_time_conversion() {
+    let epoch = DateTime::from_unix_time(0);
+    assert_eq!((epoch.year, epoch.month, epoch.day), (1970, 1, 1));
+    // a leap day
+    let date = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 14,
+        second: 15,
+    };
+    assert_eq!(date.to_unix_time(), 1_709_212_455);
+    assert_eq!(DateTime::from_unix_time(1_709_212_455), date);
+    // the last second of a year
+    let new_year = DateTime::from_unix_time(1_735_689_599);
+    assert_eq!(
+        (new_year.year, new_year.month, new_year.day),
+        (2024, 12, 31)
+    );
+    assert_eq!(
+        (new_year.hour, new_year.minute, new_year.second),
+        (23, 59, 59)
+    );
+    let mut text = alloc::string::String::new();
+    fmt::write(&mut text, format_args!("{}", date)).unwrap();
+    assert_eq!(text, "2024-02-29 13:14:15");
+}
@@ -0,0 +1,87 @@
+/*
+* The PROGRAMMABLE_INTERVAL_TIMER (PIT, Intel 8253/8254) is an oscillator running at ~1.193182 MHz
+* connected to a counter. Channel 0 of the PIT decrements its counter on every oscillation and raises
+* IRQ 0 when it reaches 0, in rate generator mode it then reloads the counter and starts again.
+* So by choosing the reload value (the divisor) we choose how many timer interrupts we get per second.
+*
+* We count these interrupts (ticks) to know how much time passed since the timer was started.
+* */
+use crate::interrupts::{self, InterruptIndex};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+// the frequency of the PIT oscillator in Hz
+const PIT_BASE_FREQUENCY: u32 = 1_193_182;
+// the number of timer interrupts per second
+pub const TICKS_PER_SECOND: u32 = 1000;
+
+// I/O ports of the PIT
+const CHANNEL_0_PORT: u16 = 0x40;
+const COMMAND_PORT: u16 = 0x43;
+
+// incremented by the timer interrupt handler, an atomic since it is written from an interrupt
+// handler while being read by the rest of the kernel (a Mutex could deadlock here)
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+pub fn init() {
+    set_frequency(TICKS_PER_SECOND);
+    interrupts::register_irq_handler(InterruptIndex::Timer.irq(), timer_interrupt_handler);
+}
+
+// program channel 0 of the PIT to fire the given number of interrupts per second
+fn set_frequency(frequency: u32) {
+    // the divisor is a 16 bit value (0 means 65536) so the lowest possible frequency is ~18.2 Hz
+    let divisor = (PIT_BASE_FREQUENCY / frequency).clamp(1, u16::MAX as u32) as u16;
+
+    let mut command: Port<u8> = Port::new(COMMAND_PORT);
+    let mut channel_0: Port<u8> = Port::new(CHANNEL_0_PORT);
+    unsafe {
+        // 0x36 = channel 0 (bits 6-7), write the low byte then the high byte (bits 4-5),
+        // mode 3 square wave generator (bits 1-3), binary counter (bit 0)
+        command.write(0x36);
+        channel_0.write((divisor & 0xFF) as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+pub(crate) fn timer_interrupt_handler() {
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    // fail the test run if a test hangs
+    crate::check_test_timeout(ticks);
+    // panic if the kernel main loop is stuck
+    crate::watchdog::check(ticks);
+    // wake the tasks waiting in task::timer::sleep_ms
+    crate::task::timer::wake_expired(ticks);
+    crate::scheduler::tick();
+}
+
+// the number of timer interrupts since the timer was initialized, it never decreases
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// the time since the timer was initialized in milliseconds
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TICKS_PER_SECOND as u64
+}
+
+/*
+* Wait for at least the given number of milliseconds. Instead of spinning (busy waiting) the CPU is halted
+* until the next interrupt so it doesn't burn cycles, interrupts must be enabled or it never wakes up.
+* */
+pub fn sleep_ms(ms: u64) {
+    // round up so we sleep at least the requested time
+    let ticks_to_wait = (ms * TICKS_PER_SECOND as u64).div_ceil(1000);
+    // +1 because the current tick is already partly over
+    let target = ticks() + ticks_to_wait + 1;
+    while ticks() < target {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn test_ticks_increase() {
+    let start = ticks();
+    sleep_ms(10);
+    assert!(ticks() >= start + 10);
+}
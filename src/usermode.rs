@@ -0,0 +1,245 @@
+/*
+* User programs run in ring 3 where they can't touch the kernel's pages, execute privileged instructions or
+* disable interrupts. The CPU only lowers the privilege level when it returns from an interrupt, so
+* enter_user builds the frame iretq expects (the user stack segment and pointer, RFLAGS with interrupts
+* enabled, the user code segment and the entry point) and "returns" to the program.
+*
+* The program comes back to the kernel with a syscall (syscall module), an interrupt or an exception. For
+* the last two the CPU switches to RSP0 of the TSS, every program gets a kernel stack of its own for that and
+* for the syscalls. enter_user saves the callee saved registers and the stack pointer of the kernel code
+* that started the program, when the program exits (or is killed by an exception) leave_user jumps back
+* there, so run returns like a normal function call:
+*
+*     run -> enter_user -> iretq -> program -> syscall exit -> leave_user -> run returns
+*
+* The registers are cleared before iretq so the program doesn't see kernel values. For now run blocks the
+* thread that calls it until the program exits (other threads keep running) and only the bootstrap processor
+* runs programs.
+* */
+use crate::elf::{self, ElfError};
+use crate::gdt;
+use crate::memory::{self, AddressSpace};
+use crate::per_cpu;
+use crate::percpu;
+use crate::stack::Stack;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::model_specific::KernelGsBase;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, Page, PageTableFlags};
+use x86_64::VirtAddr;
+
+// the user stack is at the end of the user part, the page below it stays unmapped as a guard
+pub const USER_STACK_PAGES: u64 = 16;
+// the interrupt flag (bit 9) and the always set bit 1
+const USER_RFLAGS: u64 = 0x202;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    // the program called exit with the code
+    Exited(i64),
+    // the program caused an exception
+    Killed,
+}
+
+per_cpu! {
+    // set when leave_user is called for an exception instead of exit
+    static KILLED: AtomicBool = AtomicBool::new(false);
+}
+
+global_asm!(
+    ".global enter_user",
+    "enter_user:",
+    // rdi = entry point, rsi = user stack pointer, rdx = where the kernel stack pointer is saved
+    "push %rbx",
+    "push %rbp",
+    "push %r12",
+    "push %r13",
+    "push %r14",
+    "push %r15",
+    "mov %rsp, (%rdx)",
+    "push ${user_data}",
+    "push %rsi",
+    "push ${rflags}",
+    "push ${user_code}",
+    "push %rdi",
+    "xor %eax, %eax",
+    "xor %ebx, %ebx",
+    "xor %ecx, %ecx",
+    "xor %edx, %edx",
+    "xor %esi, %esi",
+    "xor %edi, %edi",
+    "xor %ebp, %ebp",
+    "xor %r8d, %r8d",
+    "xor %r9d, %r9d",
+    "xor %r10d, %r10d",
+    "xor %r11d, %r11d",
+    "xor %r12d, %r12d",
+    "xor %r13d, %r13d",
+    "xor %r14d, %r14d",
+    "xor %r15d, %r15d",
+    // the program runs with its own GS base, the kernel's is swapped back in when it enters the kernel
+    "swapgs",
+    "iretq",
+    ".global leave_user",
+    "leave_user:",
+    // rdi = the kernel stack pointer saved by enter_user, rsi = the value enter_user returns
+    "mov %rdi, %rsp",
+    "mov %rsi, %rax",
+    "pop %r15",
+    "pop %r14",
+    "pop %r13",
+    "pop %r12",
+    "pop %rbp",
+    "pop %rbx",
+    "ret",
+    user_data = const gdt::USER_DATA_SELECTOR,
+    user_code = const gdt::USER_CODE_SELECTOR,
+    rflags = const USER_RFLAGS,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn enter_user(entry: u64, stack: u64, saved_stack: *mut u64) -> i64;
+    fn leave_user(saved_stack: u64, value: i64) -> !;
+}
+
+/*
+* Run the program at the entry point in the address space until it exits. Interrupts are enabled when it
+* returns since the program ran with them enabled.
+* */
+pub fn run(address_space: &mut AddressSpace, entry: VirtAddr, stack: VirtAddr) -> ExitStatus {
+    unsafe {
+        address_space.activate();
+        run_active(entry, stack)
+    }
+}
+
+// like run for the active address space, unsafe because the program must be mapped in it
+pub unsafe fn run_active(entry: VirtAddr, stack: VirtAddr) -> ExitStatus {
+    assert_eq!(
+        percpu::cpu_id(),
+        0,
+        "user programs run on the bootstrap processor"
+    );
+    // a syscall that overflows the kernel stack hits its guard page, it is freed when the program exits
+    let kernel_stack = Stack::new().expect("out of memory for a kernel stack");
+    let block = percpu::current();
+    gdt::set_kernel_stack(kernel_stack.top());
+    block.set_syscall_stack(kernel_stack.top());
+    KILLED.get().store(false, Ordering::Relaxed);
+    // the GS base a program loaded isn't passed on to the next one
+    KernelGsBase::write(VirtAddr::new(0));
+    let value = enter_user(entry.as_u64(), stack.as_u64(), block.user_return());
+    memory::activate_kernel();
+    // read before interrupts are enabled, the scheduler may run another program on this CPU after that
+    let killed = KILLED.get().swap(false, Ordering::Relaxed);
+    x86_64::instructions::interrupts::enable();
+    drop(kernel_stack);
+    if killed {
+        ExitStatus::Killed
+    } else {
+        ExitStatus::Exited(value)
+    }
+}
+
+// called by the exit syscall, returns from run
+pub fn exit(code: i64) -> ! {
+    let saved_stack = unsafe { *percpu::current().user_return() };
+    unsafe { leave_user(saved_stack, code) }
+}
+
+/*
+* Called by the exception handlers when the exception happened in user mode: the program is stopped instead
+* of panicking the kernel. The interrupt frame on the kernel stack of the program is abandoned.
+* */
+pub fn kill(reason: &str) -> ! {
+    log::warn!("user program killed: {}", reason);
+    KILLED.get().store(true, Ordering::Relaxed);
+    exit(-1)
+}
+
+// the flags of the program's data, writable and not executable
+pub fn data_flags() -> PageTableFlags {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    // NO_EXECUTE is only valid once EFER.NXE is set
+    if elf::nx_enabled() {
+        flags
+    } else {
+        flags - PageTableFlags::NO_EXECUTE
+    }
+}
+
+// the user stack below the end of the user part, it is lazy so only the pages the program uses get frames
+pub fn map_stack(address_space: &mut AddressSpace, pages: u64) -> Option<VirtAddr> {
+    let top = VirtAddr::new(memory::USER_END);
+    address_space.map_lazy(top - pages * 4096..top, data_flags());
+    Some(top)
+}
+
+// map zeroed pages for the program's data
+pub fn map_zeroed(
+    address_space: &mut AddressSpace,
+    pages: impl Iterator<Item = Page>,
+) -> Option<()> {
+    let flags = data_flags();
+    for page in pages {
+        let frame = memory::with_frame_allocator(|allocator| allocator.allocate_frame())?;
+        unsafe {
+            memory::phys_to_virt(frame.start_address())
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, 4096);
+            if address_space.map(page, frame, flags).is_err() {
+                memory::with_frame_allocator(|allocator| allocator.deallocate_frame(frame));
+                return None;
+            }
+        }
+    }
+    Some(())
+}
+
+// load the ELF executable and run it in the calling thread, process::create runs it in a process of its own
+pub fn exec(file: &[u8]) -> Result<ExitStatus, ElfError> {
+    let mut image = elf::load(file)?;
+    let stack =
+        map_stack(&mut image.address_space, USER_STACK_PAGES).ok_or(ElfError::OutOfMemory)?;
+    Ok(run(&mut image.address_space, image.entry, stack))
+}
+
+#[test_case]
+fn test_run_user_program() {
+    let code: &[u8] = &[
+        // a program can load gs (base 0), the kernel must not use that GS base
+        0x66, 0xb8, 0x1b, 0x00, // mov ax, 0x1b (the user data selector)
+        0x8e, 0xe8, // mov gs, ax
+        0xb8, 0x01, 0, 0, 0, // mov eax, 1 (write)
+        0xbf, 0x01, 0, 0, 0, // mov edi, 1 (stdout)
+        0x48, 0x8d, 0x35, 0x12, 0, 0, 0, // lea rsi, [rip + 0x12] (the message)
+        0xba, 0x03, 0, 0, 0, // mov edx, 3
+        0x0f, 0x05, // syscall
+        0x89, 0xc7, // mov edi, eax (the bytes written are the exit code)
+        0xb8, 0x00, 0, 0, 0, // mov eax, 0 (exit)
+        0x0f, 0x05, // syscall
+        0x0f, 0x0b, // ud2, not reached
+        b'o', b'k', b'\n',
+    ];
+    let mut address_space = AddressSpace::new().unwrap();
+    let frame = memory::with_frame_allocator(|allocator| allocator.allocate_frame()).unwrap();
+    let page = Page::containing_address(VirtAddr::new(memory::USER_START));
+    unsafe {
+        let virt = memory::phys_to_virt(frame.start_address());
+        core::ptr::copy_nonoverlapping(code.as_ptr(), virt.as_mut_ptr(), code.len());
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        address_space.map(page, frame, flags).unwrap();
+    }
+    let stack = map_stack(&mut address_space, 1).unwrap();
+    let entry = page.start_address();
+    assert_eq!(run(&mut address_space, entry, stack), ExitStatus::Exited(3));
+    // the ud2 at the end raises an invalid opcode exception
+    assert_eq!(
+        run(&mut address_space, entry + 39u64, stack),
+        ExitStatus::Killed
+    );
+}
@@ -0,0 +1,330 @@
+/*
+* The VIRTUAL_FILE_SYSTEM gives all filesystems one tree of paths: every filesystem is mounted at a
+* directory of the tree (/dev, /mnt/ata0p1...) and open, read_dir... find the filesystem mounted at the
+* longest prefix of the path and ask it for the rest of the path.
+*
+* A filesystem only has to provide its root directory as an Inode, an object for a file, directory or
+* device that can be read at an offset, listed, and searched for a name. The paths are resolved one
+* component at a time with lookup, so a filesystem never parses paths itself:
+*
+*     /mnt/ata0p1/boot/config.txt -> the filesystem at /mnt/ata0p1, root.lookup("boot").lookup("config.txt")
+*
+* Paths are absolute, "." and ".." are resolved before the mount table is searched. Mount points don't
+* have to exist in the parent filesystem, they are added to the listing of their parent directory.
+* */
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VfsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    // the path isn't absolute
+    InvalidPath,
+    AlreadyMounted,
+    ReadOnly,
+    // the filesystem or device failed (the filesystems log the details)
+    Io,
+}
+
+impl fmt::Display for VfsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VfsError::NotFound => write!(f, "no such file or directory"),
+            VfsError::NotADirectory => write!(f, "not a directory"),
+            VfsError::IsADirectory => write!(f, "is a directory"),
+            VfsError::InvalidPath => write!(f, "the path must start with /"),
+            VfsError::AlreadyMounted => write!(f, "a filesystem is already mounted there"),
+            VfsError::ReadOnly => write!(f, "read only"),
+            VfsError::Io => write!(f, "input/output error"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    File,
+    Directory,
+    Device,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub kind: NodeKind,
+    pub size: u64,
+}
+
+pub trait Inode: Send + Sync {
+    fn kind(&self) -> NodeKind;
+
+    // the size in bytes, 0 for directories
+    fn size(&self) -> u64;
+
+    // read from the offset, returns the number of bytes read, 0 at the end
+    fn read_at(&self, _offset: u64, _buffer: &mut [u8]) -> Result<usize, VfsError> {
+        Err(VfsError::IsADirectory)
+    }
+
+    fn write_at(&self, _offset: u64, _buffer: &[u8]) -> Result<usize, VfsError> {
+        Err(VfsError::ReadOnly)
+    }
+
+    // the entries of a directory without . and ..
+    fn read_dir(&self) -> Result<Vec<DirEntry>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+
+    // the child of a directory with the name
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, VfsError> {
+        Err(VfsError::NotADirectory)
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    // the type of the filesystem shown by mounts, e.g. fat32
+    fn name(&self) -> &str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+struct Mount {
+    // the components of the mount point, empty for /
+    path: Vec<String>,
+    filesystem: Arc<dyn FileSystem>,
+}
+
+static MOUNTS: Mutex<Vec<Mount>> = Mutex::new(Vec::new());
+
+// the components of an absolute path with . and .. resolved
+fn components(path: &str) -> Result<Vec<String>, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut components: Vec<String> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            name => components.push(String::from(name)),
+        }
+    }
+    Ok(components)
+}
+
+// the path of the components, / for none
+fn join(components: &[String]) -> String {
+    if components.is_empty() {
+        return String::from("/");
+    }
+    components
+        .iter()
+        .fold(String::new(), |path, component| path + "/" + component)
+}
+
+pub fn mount(path: &str, filesystem: Arc<dyn FileSystem>) -> Result<(), VfsError> {
+    let path = components(path)?;
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().any(|mount| mount.path == path) {
+        return Err(VfsError::AlreadyMounted);
+    }
+    log::info!("vfs: mounted {} at {}", filesystem.name(), join(&path));
+    mounts.push(Mount { path, filesystem });
+    Ok(())
+}
+
+pub fn unmount(path: &str) -> Result<(), VfsError> {
+    let path = components(path)?;
+    let mut mounts = MOUNTS.lock();
+    let index = mounts
+        .iter()
+        .position(|mount| mount.path == path)
+        .ok_or(VfsError::NotFound)?;
+    mounts.remove(index);
+    Ok(())
+}
+
+// (mount point, filesystem type) of the mounted filesystems
+pub fn mounts() -> Vec<(String, String)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (join(&mount.path), String::from(mount.filesystem.name())))
+        .collect()
+}
+
+/*
+* The inode at the path. Directories that only exist because a filesystem is mounted below them (/mnt for
+* /mnt/ata0p1) have no inode, for them the error is NotFound and mount_points_below isn't empty.
+* */
+fn resolve(path: &[String]) -> Result<Arc<dyn Inode>, VfsError> {
+    let root = {
+        let mounts = MOUNTS.lock();
+        mounts
+            .iter()
+            .filter(|mount| path.starts_with(&mount.path))
+            .max_by_key(|mount| mount.path.len())
+            .map(|mount| (mount.path.len(), mount.filesystem.root()))
+    };
+    let (depth, mut inode) = root.ok_or(VfsError::NotFound)?;
+    for name in &path[depth..] {
+        inode = inode.lookup(name)?;
+    }
+    Ok(inode)
+}
+
+// the names of the mount points directly below the directory
+fn mount_points_below(path: &[String]) -> Vec<String> {
+    MOUNTS
+        .lock()
+        .iter()
+        .filter(|mount| mount.path.len() > path.len() && mount.path.starts_with(path))
+        .map(|mount| mount.path[path.len()].clone())
+        .collect()
+}
+
+pub fn stat(path: &str) -> Result<DirEntry, VfsError> {
+    let path = components(path)?;
+    let name = path.last().cloned().unwrap_or_else(|| String::from("/"));
+    match resolve(&path) {
+        Ok(inode) => Ok(DirEntry {
+            name,
+            kind: inode.kind(),
+            size: inode.size(),
+        }),
+        Err(VfsError::NotFound) if !mount_points_below(&path).is_empty() => Ok(DirEntry {
+            name,
+            kind: NodeKind::Directory,
+            size: 0,
+        }),
+        Err(error) => Err(error),
+    }
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, VfsError> {
+    let path = components(path)?;
+    let mount_points = mount_points_below(&path);
+    let mut entries = match resolve(&path) {
+        Ok(inode) => inode.read_dir()?,
+        Err(VfsError::NotFound) if !mount_points.is_empty() => Vec::new(),
+        Err(error) => return Err(error),
+    };
+    for name in mount_points {
+        // the mount point can exist in the parent and two mounts (/mnt/a/x, /mnt/a/y) add the same name
+        if !entries.iter().any(|entry| entry.name == name) {
+            entries.push(DirEntry {
+                name,
+                kind: NodeKind::Directory,
+                size: 0,
+            });
+        }
+    }
+    Ok(entries)
+}
+
+// an open file, reads and writes continue at the offset where the last one stopped
+pub struct File {
+    inode: Arc<dyn Inode>,
+    offset: u64,
+}
+
+pub fn open(path: &str) -> Result<File, VfsError> {
+    let inode = resolve(&components(path)?)?;
+    if inode.kind() == NodeKind::Directory {
+        return Err(VfsError::IsADirectory);
+    }
+    Ok(File { inode, offset: 0 })
+}
+
+impl File {
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, VfsError> {
+        let len = self.inode.read_at(self.offset, buffer)?;
+        self.offset += len as u64;
+        Ok(len)
+    }
+
+    pub fn write(&mut self, buffer: &[u8]) -> Result<usize, VfsError> {
+        let len = self.inode.write_at(self.offset, buffer)?;
+        self.offset += len as u64;
+        Ok(len)
+    }
+
+    pub fn seek(&mut self, offset: u64) {
+        self.offset = offset;
+    }
+
+    pub fn size(&self) -> u64 {
+        self.inode.size()
+    }
+
+    // read from the offset to the end of the file
+    pub fn read_to_end(&mut self) -> Result<Vec<u8>, VfsError> {
+        let mut data = Vec::with_capacity(self.size().saturating_sub(self.offset) as usize);
+        let mut chunk = vec![0; 4096];
+        loop {
+            let len = self.read(&mut chunk)?;
+            if len == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&chunk[..len]);
+        }
+    }
+}
+
+// read the whole file into memory
+pub fn read(path: &str) -> Result<Vec<u8>, VfsError> {
+    open(path)?.read_to_end()
+}
+
+// mount the pseudo filesystems and the filesystems that were found on the disks
+pub fn init() {
+    let _ = mount("/dev", Arc::new(crate::devfs::DevFs));
+    for filesystem in crate::fat32::filesystems() {
+        let path = alloc::format!("/mnt/{}", filesystem.device().name());
+        if let Err(error) = mount(&path, Arc::new(filesystem)) {
+            log::warn!("vfs: can't mount {}: {}", path, error);
+        }
+    }
+}
+
+#[test_case]
+fn test_mount_and_resolve_paths() {
+    assert_eq!(
+        components("/a/./b/../c/"),
+        Ok(vec![String::from("a"), String::from("c")])
+    );
+    assert_eq!(components("relative"), Err(VfsError::InvalidPath));
+    mount("/test/dev", Arc::new(crate::devfs::DevFs)).unwrap();
+    assert_eq!(
+        mount("/test/dev", Arc::new(crate::devfs::DevFs)),
+        Err(VfsError::AlreadyMounted)
+    );
+    // the mount point shows up in its parent which doesn't exist otherwise
+    assert!(read_dir("/test")
+        .unwrap()
+        .iter()
+        .any(|entry| entry.name == "dev" && entry.kind == NodeKind::Directory));
+    let names: Vec<String> = read_dir("/test/dev")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert!(names.iter().any(|name| name == "zero"));
+
+    let mut zero = open("/test/../test/dev/./zero").unwrap();
+    let mut buffer = [1; 8];
+    assert_eq!(zero.read(&mut buffer), Ok(8));
+    assert_eq!(buffer, [0; 8]);
+    assert_eq!(stat("/test/dev/null").unwrap().kind, NodeKind::Device);
+    assert_eq!(open("/test/dev").err(), Some(VfsError::IsADirectory));
+    assert_eq!(open("/test/dev/missing").err(), Some(VfsError::NotFound));
+    unmount("/test/dev").unwrap();
+    assert_eq!(open("/test/dev/zero").err(), Some(VfsError::NotFound));
+}
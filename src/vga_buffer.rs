@@ -25,19 +25,80 @@ pub enum Color {
     White = 15,
 }
 
+impl Color {
+    // the color with the given 4 bit value, the higher bits are ignored
+    pub fn from_u8(value: u8) -> Color {
+        match value & 0x0F {
+            0 => Color::Black,
+            1 => Color::Blue,
+            2 => Color::Green,
+            3 => Color::Cyan,
+            4 => Color::Red,
+            5 => Color::Magenta,
+            6 => Color::Brown,
+            7 => Color::LightGray,
+            8 => Color::DarkGray,
+            9 => Color::LightBlue,
+            10 => Color::LightGreen,
+            11 => Color::LightCyan,
+            12 => Color::LightRed,
+            13 => Color::Pink,
+            14 => Color::Yellow,
+            _ => Color::White,
+        }
+    }
+}
+
 // define the color including foreground and background
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(transparent)] // this ensures the struct  layout in memory is the same as the type u8 this
                      // can be useful for FFI (Foreign Function Interface) with C code.
-struct ColorCode(u8);
+pub struct ColorCode(u8);
 
 impl ColorCode {
-    fn new(foreground: Color, background: Color) -> ColorCode {
+    pub const fn new(foreground: Color, background: Color) -> ColorCode {
         // the color code is a u8 where the first 4 bits are the background color and the last 4 bits are the foreground color
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    fn with_foreground(self, foreground: u8) -> ColorCode {
+        ColorCode(self.0 & 0xF0 | (foreground & 0x0F))
+    }
+
+    fn with_background(self, background: u8) -> ColorCode {
+        ColorCode(self.0 & 0x0F | (background & 0x0F) << 4)
+    }
+
+    pub fn foreground(self) -> Color {
+        Color::from_u8(self.0 & 0x0F)
+    }
+
+    pub fn background(self) -> Color {
+        Color::from_u8(self.0 >> 4)
+    }
+
+    // the colors 8-15 are the bright versions of the colors 0-7
+    fn brighten_foreground(self) -> ColorCode {
+        ColorCode(self.0 | 0x08)
+    }
 }
 
+/*
+* ANSI color numbers are ordered black, red, green, yellow, blue, magenta, cyan, white
+* while the VGA order is black, blue, green, cyan, red, magenta, brown, light gray.
+* In both cases adding 8 gives the bright version of the color.
+* */
+const ANSI_TO_VGA: [Color; 8] = [
+    Color::Black,
+    Color::Red,
+    Color::Green,
+    Color::Brown,
+    Color::Blue,
+    Color::Magenta,
+    Color::Cyan,
+    Color::LightGray,
+];
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)] // ensure the ordering of the fields is the same as in C. The default ordering is undefined
 struct ScreenChar {
@@ -46,63 +107,290 @@ struct ScreenChar {
 }
 
 // a VGA buffer is a 2D array of 25 rows and 80 columns
-const BUFFER_WIDTH: usize = 80;
-const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+// the number of lines that scrolled off the top of the screen that are kept for scrolling back
+const SCROLLBACK_LINES: usize = 200;
+
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+// interprets the ANSI escape sequences in the written strings
+pub(crate) mod ansi;
+// the code page 437 glyphs of the characters that aren't ASCII
+pub mod cp437;
 
 use volatile::Volatile; // if we don't read the written values the compiler might optimize it away so we use volatile to prevent that
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/*
+* The lines that scroll off the top of the screen are kept in a ring buffer (the oldest line is
+* overwritten once it's full) so they can be shown again by scrolling the view back.
+* The lines take 32 KB, so they are a static instead of being built on the stack when the writer is created.
+* */
+static mut SCROLLBACK_BUFFER: [Line; SCROLLBACK_LINES] =
+    [[ScreenChar::blank(ColorCode::new(Color::Yellow, Color::Black)); BUFFER_WIDTH];
+        SCROLLBACK_LINES];
+
+struct Scrollback {
+    lines: &'static mut [Line; SCROLLBACK_LINES],
+    start: usize, // index of the oldest line
+    len: usize,
+}
+
+impl Scrollback {
+    fn new(lines: &'static mut [Line; SCROLLBACK_LINES]) -> Scrollback {
+        Scrollback {
+            lines,
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, line: Line) {
+        let index = (self.start + self.len) % SCROLLBACK_LINES;
+        self.lines[index] = line;
+        if self.len < SCROLLBACK_LINES {
+            self.len += 1;
+        } else {
+            self.start = (self.start + 1) % SCROLLBACK_LINES;
+        }
+    }
+
+    // the line `back` lines before the newest one (1 is the newest line)
+    fn line_from_end(&self, back: usize) -> &Line {
+        &self.lines[(self.start + self.len - back) % SCROLLBACK_LINES]
+    }
+}
+
 // To actually write to the screen we define a writer struct
-// the writer always writes to the last row
+// the writer writes to the last row unless the cursor was moved with an escape sequence
 // The static lifetime is required, we specify static because the buffer (VGA buffer) lives for the entire duration of the program
 pub struct Writer {
     color_code: ColorCode,
+    // the color restored by the ANSI reset sequence (ESC [ 0 m)
+    default_color_code: ColorCode,
+    // the ANSI bold attribute, shown as the bright version of the foreground color
+    bold: bool,
     column_position: usize,
+    row_position: usize,
     buffer: &'static mut Buffer,
+    ansi_parser: ansi::Parser,
+    scrollback: Scrollback,
+    // how many lines the view is scrolled back, 0 shows the live screen
+    scroll_offset: usize,
+    /*
+     * Reading and writing the VGA memory is slow (it's memory mapped I/O and volatile so the compiler
+     * can't optimize it) so all the changes are done in a SHADOW_BUFFER in RAM. The rows that changed
+     * are marked as dirty and copied to the VGA buffer in one pass when the writer flushes, which happens
+     * once at the end of every public write operation instead of for every character.
+     * */
+    shadow: [Line; BUFFER_HEIGHT],
+    // bit n is set if row n of the view has to be copied to the VGA buffer
+    dirty_rows: u32,
 }
 
+impl ScreenChar {
+    const fn blank(color_code: ColorCode) -> ScreenChar {
+        ScreenChar {
+            ascii_character: b' ',
+            color_code,
+        }
+    }
+}
+
+const ALL_ROWS_DIRTY: u32 = (1 << BUFFER_HEIGHT) - 1;
+
 impl Writer {
+    fn new(
+        color_code: ColorCode,
+        buffer: &'static mut Buffer,
+        scrollback: &'static mut [Line; SCROLLBACK_LINES],
+    ) -> Writer {
+        // start with what is already on the screen (e.g. the messages of the bootloader)
+        let mut shadow = [[ScreenChar::blank(color_code); BUFFER_WIDTH]; BUFFER_HEIGHT];
+        for (row, line) in shadow.iter_mut().enumerate() {
+            for (col, character) in line.iter_mut().enumerate() {
+                *character = buffer.chars[row][col].read();
+            }
+        }
+        Writer {
+            column_position: 0,
+            row_position: BUFFER_HEIGHT - 1,
+            color_code,
+            default_color_code: color_code,
+            bold: false,
+            buffer,
+            ansi_parser: ansi::Parser::new(),
+            scrollback: Scrollback::new(scrollback),
+            scroll_offset: 0,
+            shadow,
+            dirty_rows: 0,
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
+        self.put_byte(byte);
+        self.flush();
+    }
+
+    // write a byte to the shadow buffer only (write_string flushes once at the end)
+    fn put_byte(&mut self, byte: u8) {
         match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
-                }
+            b'\n' => {
+                // new output is written to the live screen so jump back to it
+                self.scroll_to_bottom();
+                self.new_line();
+            }
+            byte => self.put_glyph(byte),
+        }
+    }
+
+    // draw the code page 437 glyph, unlike put_byte 0x0A is the ◙ glyph and not a new line
+    fn put_glyph(&mut self, glyph: u8) {
+        self.scroll_to_bottom();
+        if self.column_position >= BUFFER_WIDTH {
+            self.new_line();
+        }
 
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
+        let row = self.row_position;
+        let col = self.column_position;
 
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
-                self.column_position += 1;
+        let color_code = self.color_code;
+        self.shadow[row][col] = ScreenChar {
+            ascii_character: glyph,
+            color_code,
+        };
+        self.mark_dirty(row);
+        self.column_position += 1;
+    }
+
+    pub fn write_string(&mut self, s: &str) {
+        // a write_str call never splits a character, so the string can be decoded by itself
+        for character in s.chars() {
+            // the escape sequences are ASCII, the other characters are drawn with their code page 437 glyph
+            if !character.is_ascii() {
+                self.put_glyph(cp437::encode(character));
+                continue;
+            }
+            // escape sequences can be split across several write_string calls (e.g. by write!)
+            // so the parser keeps its state between calls
+            match self.ansi_parser.advance(character as u8) {
+                Some(ansi::Action::Print(byte)) => match byte {
+                    0x20..=0x7e | b'\n' => self.put_byte(byte),
+                    b'\r' => self.column_position = 0,
+                    // write a ■ character for the other control characters
+                    _ => self.put_glyph(cp437::UNKNOWN),
+                },
+                Some(action) => self.apply_ansi_action(action),
+                None => {}
             }
         }
+        self.flush();
     }
 
-    pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // rust strings are utf8 so we need to write only printable ASCII bytes or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe), // write a ■ character for unprintable bytes
+    fn apply_ansi_action(&mut self, action: ansi::Action) {
+        use ansi::Action;
+
+        self.scroll_to_bottom();
+        let (row, col) = (self.row_position, self.column_position);
+        match action {
+            Action::Print(byte) => self.put_byte(byte),
+            Action::SetGraphics(params) => self.set_graphics(params),
+            // the escape sequences count from 1
+            Action::CursorPosition { row, col } => {
+                self.row_position = (row as usize - 1).min(BUFFER_HEIGHT - 1);
+                self.column_position = (col as usize - 1).min(BUFFER_WIDTH - 1);
             }
+            Action::CursorUp(n) => self.row_position = row.saturating_sub(n as usize),
+            Action::CursorDown(n) => self.row_position = (row + n as usize).min(BUFFER_HEIGHT - 1),
+            Action::CursorForward(n) => {
+                self.column_position = (col + n as usize).min(BUFFER_WIDTH - 1)
+            }
+            Action::CursorBack(n) => self.column_position = col.saturating_sub(n as usize),
+            Action::EraseDisplay(mode) => match mode {
+                0 => {
+                    self.clear_columns(row, col, BUFFER_WIDTH);
+                    (row + 1..BUFFER_HEIGHT).for_each(|row| self.clear_row(row));
+                }
+                1 => {
+                    (0..row).for_each(|row| self.clear_row(row));
+                    self.clear_columns(row, 0, col + 1);
+                }
+                _ => (0..BUFFER_HEIGHT).for_each(|row| self.clear_row(row)),
+            },
+            Action::EraseLine(mode) => match mode {
+                0 => self.clear_columns(row, col, BUFFER_WIDTH),
+                1 => self.clear_columns(row, 0, col + 1),
+                _ => self.clear_row(row),
+            },
         }
     }
 
-    fn new_line(&mut self) {
-        // move all the lines up one row
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character)
+    // SELECT_GRAPHIC_RENDITION, each parameter changes one attribute, no parameters means reset
+    fn set_graphics(&mut self, params: ansi::Params) {
+        if params.is_empty() {
+            self.reset_graphics();
+        }
+        for param in params.iter() {
+            match param {
+                0 => self.reset_graphics(),
+                1 => {
+                    self.bold = true;
+                    self.color_code = self.color_code.brighten_foreground();
+                }
+                22 => self.bold = false,
+                30..=37 => {
+                    let color = ANSI_TO_VGA[(param - 30) as usize] as u8;
+                    self.color_code = self.color_code.with_foreground(color);
+                    if self.bold {
+                        self.color_code = self.color_code.brighten_foreground();
+                    }
+                }
+                90..=97 => {
+                    let color = ANSI_TO_VGA[(param - 90) as usize] as u8 + 8;
+                    self.color_code = self.color_code.with_foreground(color);
+                }
+                39 => {
+                    let default = self.default_color_code.0 & 0x0F;
+                    self.color_code = self.color_code.with_foreground(default);
+                }
+                // the highest bit of the VGA color code makes the character blink instead of
+                // brightening the background, so bright backgrounds use the normal color
+                40..=47 | 100..=107 => {
+                    let color = ANSI_TO_VGA[((param % 10) as usize) % 8] as u8;
+                    self.color_code = self.color_code.with_background(color);
+                }
+                49 => {
+                    let default = self.default_color_code.0 >> 4;
+                    self.color_code = self.color_code.with_background(default);
+                }
+                // unsupported attributes (underline, italic...) are ignored
+                _ => {}
             }
         }
+    }
+
+    fn reset_graphics(&mut self) {
+        self.color_code = self.default_color_code;
+        self.bold = false;
+    }
+
+    fn new_line(&mut self) {
+        // below the last row there is still space so only the cursor moves
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+            self.column_position = 0;
+            return;
+        }
+
+        // keep the top row which is about to disappear
+        self.scrollback.push(self.shadow[0]);
+
+        // move all the lines up one row, every row changes so the whole screen is redrawn on flush
+        self.shadow.copy_within(1.., 0);
+        self.dirty_rows = ALL_ROWS_DIRTY;
         // empty current row
         self.clear_row(BUFFER_HEIGHT - 1);
         // move the cursor to the beginning of the row
@@ -110,14 +398,220 @@ impl Writer {
     }
 
     fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
-            ascii_character: b' ',
-            color_code: self.color_code,
-        };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        self.clear_columns(row, 0, BUFFER_WIDTH);
+    }
+
+    // clear the columns start..end of the row
+    fn clear_columns(&mut self, row: usize, start: usize, end: usize) {
+        let blank = ScreenChar::blank(self.color_code);
+        for col in start..end.min(BUFFER_WIDTH) {
+            self.shadow[row][col] = blank;
+        }
+        self.mark_dirty(row);
+    }
+
+    fn mark_dirty(&mut self, row: usize) {
+        // while scrolled back the live row is shown scroll_offset rows lower (or not at all)
+        let view_row = row + self.scroll_offset;
+        if view_row < BUFFER_HEIGHT {
+            self.dirty_rows |= 1 << view_row;
+        }
+    }
+
+    // copy the dirty rows of the view to the VGA buffer and move the hardware cursor
+    fn flush(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            if self.dirty_rows & (1 << row) == 0 {
+                continue;
+            }
+            // the view shows the last scroll_offset lines of the history followed by the top of the live screen
+            let line = if row < self.scroll_offset {
+                self.scrollback.line_from_end(self.scroll_offset - row)
+            } else {
+                &self.shadow[row - self.scroll_offset]
+            };
+            for (col, character) in line.iter().enumerate() {
+                self.buffer.chars[row][col].write(*character);
+            }
+        }
+        self.dirty_rows = 0;
+        self.update_cursor();
+    }
+
+    // move the view the given number of lines back in the history (stops at the oldest line)
+    pub fn scroll_up(&mut self, lines: usize) {
+        let offset = (self.scroll_offset + lines).min(self.scrollback.len);
+        self.set_scroll_offset(offset);
+    }
+
+    // move the view the given number of lines towards the live screen
+    pub fn scroll_down(&mut self, lines: usize) {
+        let offset = self.scroll_offset.saturating_sub(lines);
+        self.set_scroll_offset(offset);
+    }
+
+    pub fn scroll_page_up(&mut self) {
+        self.scroll_up(BUFFER_HEIGHT - 1);
+    }
+
+    pub fn scroll_page_down(&mut self) {
+        self.scroll_down(BUFFER_HEIGHT - 1);
+    }
+
+    pub fn scroll_to_bottom(&mut self) {
+        self.set_scroll_offset(0);
+    }
+
+    // the color used for the following characters
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.set_color_code(ColorCode::new(foreground, background));
+    }
+
+    pub fn set_color_code(&mut self, color_code: ColorCode) {
+        self.color_code = color_code;
+        self.bold = false;
+    }
+
+    pub fn color_code(&self) -> ColorCode {
+        self.color_code
+    }
+
+    // run the closure with a different color, the previous color is restored afterwards
+    pub fn with_color<R>(
+        &mut self,
+        foreground: Color,
+        background: Color,
+        f: impl FnOnce(&mut Writer) -> R,
+    ) -> R {
+        let (color_code, bold) = (self.color_code, self.bold);
+        self.set_color(foreground, background);
+        let result = f(self);
+        self.color_code = color_code;
+        self.bold = bold;
+        result
+    }
+
+    /*
+     * The blinking HARDWARE_CURSOR is drawn by the VGA controller itself, we only tell it where to draw it.
+     * The controller has many internal registers that are accessed through two I/O ports: the index of the
+     * register is written to 0x3D4 and then its value is read from or written to 0x3D5.
+     * */
+
+    // show the cursor, it is drawn from the scanline cursor_start to cursor_end of the character cell (0-15)
+    pub fn enable_cursor(&mut self, cursor_start: u8, cursor_end: u8) {
+        unsafe {
+            // bit 5 of the cursor start register disables the cursor, the upper bits are reserved
+            let start = read_crtc_register(CURSOR_START_REGISTER);
+            write_crtc_register(
+                CURSOR_START_REGISTER,
+                (start & 0xC0) | (cursor_start & 0x1F),
+            );
+            let end = read_crtc_register(CURSOR_END_REGISTER);
+            write_crtc_register(CURSOR_END_REGISTER, (end & 0xE0) | (cursor_end & 0x1F));
+        }
+        self.update_cursor();
+    }
+
+    pub fn disable_cursor(&mut self) {
+        unsafe { write_crtc_register(CURSOR_START_REGISTER, CURSOR_DISABLE) };
+    }
+
+    // clear the whole screen and continue writing at the top left corner
+    pub fn clear_screen(&mut self) {
+        self.scroll_to_bottom();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.row_position = 0;
+        self.column_position = 0;
+        self.flush();
+    }
+
+    /*
+     * Write a string at a fixed position without moving the cursor, e.g. for a status bar.
+     * The string doesn't wrap, the part that doesn't fit in the row is cut off.
+     * */
+    pub fn write_at(&mut self, row: usize, col: usize, s: &str) {
+        if row >= BUFFER_HEIGHT {
+            return;
+        }
+        self.scroll_to_bottom();
+        let color_code = self.color_code;
+        for (col, character) in (col..BUFFER_WIDTH).zip(s.chars()) {
+            let ascii_character = match character {
+                ' '..='~' => character as u8,
+                character if !character.is_ascii() => cp437::encode(character),
+                _ => cp437::UNKNOWN,
+            };
+            self.shadow[row][col] = ScreenChar {
+                ascii_character,
+                color_code,
+            };
+        }
+        self.mark_dirty(row);
+        self.flush();
+    }
+
+    // the (row, column) where the next character is written
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    // move the cursor, the next character is written at this position
+    pub fn set_cursor_position(&mut self, row: usize, col: usize) {
+        self.scroll_to_bottom();
+        self.row_position = row.min(BUFFER_HEIGHT - 1);
+        self.column_position = col.min(BUFFER_WIDTH - 1);
+        self.flush();
+    }
+
+    // move the hardware cursor to where the next character will be written
+    fn update_cursor(&mut self) {
+        // when the line is full the next character wraps, until then the cursor stays on the last column
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        // while scrolled back the live screen is moved down by scroll_offset rows,
+        // a position below the screen hides the cursor
+        let row = (self.row_position + self.scroll_offset).min(BUFFER_HEIGHT);
+        // the position is the index of the character in the buffer
+        let position = (row * BUFFER_WIDTH + col) as u16;
+        unsafe {
+            write_crtc_register(CURSOR_LOCATION_LOW_REGISTER, (position & 0xFF) as u8);
+            write_crtc_register(CURSOR_LOCATION_HIGH_REGISTER, (position >> 8) as u8);
         }
     }
+
+    fn set_scroll_offset(&mut self, offset: usize) {
+        if offset == self.scroll_offset {
+            return;
+        }
+        // the live screen stays in the shadow buffer, only the view changes
+        self.scroll_offset = offset;
+        self.dirty_rows = ALL_ROWS_DIRTY;
+        self.flush();
+    }
+}
+
+// the I/O ports of the CRT controller (the part of the VGA card that draws the screen)
+const CRTC_ADDRESS_PORT: u16 = 0x3D4;
+const CRTC_DATA_PORT: u16 = 0x3D5;
+// the CRT controller registers that control the cursor
+const CURSOR_START_REGISTER: u8 = 0x0A;
+const CURSOR_END_REGISTER: u8 = 0x0B;
+const CURSOR_LOCATION_HIGH_REGISTER: u8 = 0x0E;
+const CURSOR_LOCATION_LOW_REGISTER: u8 = 0x0F;
+const CURSOR_DISABLE: u8 = 0x20;
+
+// unsafe because writing the wrong register can change the video mode
+unsafe fn write_crtc_register(index: u8, value: u8) {
+    use x86_64::instructions::port::Port;
+    Port::<u8>::new(CRTC_ADDRESS_PORT).write(index);
+    Port::<u8>::new(CRTC_DATA_PORT).write(value);
+}
+
+unsafe fn read_crtc_register(index: u8) -> u8 {
+    use x86_64::instructions::port::Port;
+    Port::<u8>::new(CRTC_ADDRESS_PORT).write(index);
+    Port::<u8>::new(CRTC_DATA_PORT).read()
 }
 
 // Implement the rust fmt write so we can easily use the write! macro and print different types
@@ -158,15 +652,27 @@ We use SPINLOCKS to ensure that only one thread can access the resource at a tim
 use lazy_static::lazy_static;
 use spin::Mutex;
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer::new(
+        ColorCode::new(Color::Yellow, Color::Black),
+        // raw pointer to the VGA buffer. The unsafe block is needed because we are dereferencing a raw pointer
+        unsafe { &mut *(0xb8000 as *mut Buffer) },
+        // only the writer uses the scrollback lines and it is created once
+        unsafe { &mut *core::ptr::addr_of_mut!(SCROLLBACK_BUFFER) },
+    ));
+}
+
+/*
+* Interrupt handlers print too (log messages, exceptions...). If an interrupt arrived while the interrupted
+* code holds the WRITER lock, the handler would spin on the lock forever since the holder can only continue
+* after the handler returns. So the writers are only locked with interrupts disabled, which also keeps the
+* scheduler from switching away from a thread that holds the lock.
+* */
+pub fn with_writer<R>(f: impl FnOnce(&mut Writer) -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(|| f(&mut WRITER.lock()))
 }
 
 // define our own !prinln macro, they are copied from rust's defintion with only a change to use
-// the VGA write
+// the consoles (console module)
 // #[macro_export] makes the macro available for the whole crate
 #[macro_export]
 macro_rules! print {
@@ -179,8 +685,247 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+// like print!/println! but with the given foreground color, the background stays the same
+// e.g. println_colored!(Color::Green, "[ok] {}", name)
+#[macro_export]
+macro_rules! print_colored {
+    ($color:expr, $($arg:tt)*) => ($crate::vga_buffer::_print_colored($color, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println_colored {
+    ($color:expr) => ($crate::print_colored!($color, "\n"));
+    ($color:expr, $($arg:tt)*) => ($crate::print_colored!($color, "{}\n", format_args!($($arg)*)));
+}
+
+// print errors in light red, like eprint!/eprintln! of the standard library print to stderr
+#[macro_export]
+macro_rules! eprint {
+    ($($arg:tt)*) => ($crate::print_colored!($crate::vga_buffer::Color::LightRed, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! eprintln {
+    () => ($crate::eprint!("\n"));
+    ($($arg:tt)*) => ($crate::eprint!("{}\n", format_args!($($arg)*)));
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
+    // everything printed is also kept in the kernel message buffer
+    crate::dmesg::DMESG.write_fmt(args);
+    crate::console::write(None, args);
+}
+
+#[doc(hidden)]
+pub fn _print_colored(foreground: Color, args: fmt::Arguments) {
+    crate::dmesg::DMESG.write_fmt(args);
+    crate::console::write(Some(foreground), args);
+}
+
+#[test_case]
+fn test_println_output() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    let s = "Some test string that fits on a single line";
+    // lock the writer for the whole test so no interrupt handler prints in between
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\n{}", s).expect("writeln failed");
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+    });
+}
+
+#[test_case]
+fn test_non_ascii_characters_get_their_cp437_glyph() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        // ö is 0x94, ä between two escape sequences is 0x84 in red
+        writeln!(writer, "\nWörld \x1b[31mä\x1b[0m!").unwrap();
+        let row = writer.shadow[BUFFER_HEIGHT - 2];
+        let bytes: [u8; 8] = core::array::from_fn(|i| row[i].ascii_character);
+        assert_eq!(&bytes, b"W\x94rld \x84!");
+        assert_eq!(row[6].color_code.0 & 0x0f, Color::Red as u8);
+        assert_eq!(row[7].color_code, writer.default_color_code);
+        assert_eq!(writer.buffer.chars[BUFFER_HEIGHT - 2][1].read(), row[1]);
+    });
+}
+
+#[test_case]
+fn test_scrollback_shows_lines_that_scrolled_off() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "scrollback marker").unwrap();
+        // the marker is on the second to last row, push it off the top of the screen
+        for _ in 0..BUFFER_HEIGHT - 1 {
+            writeln!(writer).unwrap();
+        }
+        let live_row = writer.buffer.chars[BUFFER_HEIGHT - 2][0].read();
+
+        // the marker is the newest line of the scrollback so it's the top row when scrolled back by 1
+        writer.scroll_up(1);
+        assert_eq!(writer.buffer.chars[0][0].read().ascii_character, b's');
+        assert_eq!(writer.buffer.chars[0][10].read().ascii_character, b'k');
+
+        // writing snaps the view back to the live screen
+        writer.scroll_page_up();
+        writer.write_byte(b'x');
+        assert_eq!(writer.scroll_offset, 0);
+        assert_eq!(writer.buffer.chars[BUFFER_HEIGHT - 2][0].read(), live_row);
+        writer.write_byte(b'\n');
+    });
+}
+
+#[test_case]
+fn test_ansi_colors_and_cursor_position() {
     use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        // red on blue at row 3 column 5, then reset the colors and go back to the bottom row
+        write!(writer, "\x1b[31;44m\x1b[3;5Hab\x1b[0m").unwrap();
+        let character = writer.buffer.chars[2][4].read();
+        assert_eq!(character.ascii_character, b'a');
+        assert_eq!(
+            character.color_code,
+            ColorCode::new(Color::Red, Color::Blue)
+        );
+        assert_eq!(writer.color_code, writer.default_color_code);
+        // erase the line from the cursor, 'a' stays and 'b' is cleared
+        write!(writer, "\x1b[1D\x1b[K").unwrap();
+        assert_eq!(writer.buffer.chars[2][4].read().ascii_character, b'a');
+        assert_eq!(writer.buffer.chars[2][5].read().ascii_character, b' ');
+        writeln!(writer, "\x1b[{};1H", BUFFER_HEIGHT).unwrap();
+        assert_eq!(writer.row_position, BUFFER_HEIGHT - 1);
+    });
+}
+
+#[test_case]
+fn test_hardware_cursor_follows_writes() {
+    use x86_64::instructions::interrupts;
+
+    let cursor_location = || unsafe {
+        let high = read_crtc_register(CURSOR_LOCATION_HIGH_REGISTER) as usize;
+        let low = read_crtc_register(CURSOR_LOCATION_LOW_REGISTER) as usize;
+        high << 8 | low
+    };
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.set_cursor_position(3, 7);
+        assert_eq!(cursor_location(), 3 * BUFFER_WIDTH + 7);
+        writer.write_string("ab");
+        assert_eq!(cursor_location(), 3 * BUFFER_WIDTH + 9);
+        writer.set_cursor_position(BUFFER_HEIGHT - 1, 0);
+        writer.write_byte(b'\n');
+        assert_eq!(cursor_location(), (BUFFER_HEIGHT - 1) * BUFFER_WIDTH);
+    });
+}
+
+#[test_case]
+fn test_with_color_restores_previous_color() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let previous = writer.color_code();
+        writer.with_color(Color::White, Color::Red, |writer| {
+            writer.write_byte(b'!');
+            let (row, col) = (writer.row_position, writer.column_position - 1);
+            let character = writer.buffer.chars[row][col].read();
+            assert_eq!(character.color_code.foreground(), Color::White);
+            assert_eq!(character.color_code.background(), Color::Red);
+        });
+        assert_eq!(writer.color_code(), previous);
+        writer.write_byte(b'\n');
+    });
+}
+
+#[test_case]
+fn test_write_at_keeps_cursor_and_clear_screen() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        let cursor = writer.cursor_position();
+        // the part that doesn't fit in the row is cut off
+        writer.write_at(0, BUFFER_WIDTH - 2, "status");
+        assert_eq!(
+            writer.buffer.chars[0][BUFFER_WIDTH - 2]
+                .read()
+                .ascii_character,
+            b's'
+        );
+        assert_eq!(
+            writer.buffer.chars[0][BUFFER_WIDTH - 1]
+                .read()
+                .ascii_character,
+            b't'
+        );
+        assert_eq!(writer.cursor_position(), cursor);
+
+        writer.clear_screen();
+        assert_eq!(writer.cursor_position(), (0, 0));
+        assert_eq!(
+            writer.buffer.chars[0][BUFFER_WIDTH - 1]
+                .read()
+                .ascii_character,
+            b' '
+        );
+        writer.set_cursor_position(BUFFER_HEIGHT - 1, 0);
+    });
+}
+
+#[test_case]
+fn test_only_dirty_rows_are_flushed() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.write_byte(b'\n');
+        // change the VGA memory behind the back of the writer, a flush must not touch the clean rows
+        let marker = ScreenChar::blank(ColorCode::new(Color::Black, Color::Green));
+        writer.buffer.chars[0][0].write(marker);
+        writer.write_byte(b'x');
+        assert_eq!(writer.buffer.chars[0][0].read(), marker);
+        assert_eq!(
+            writer.buffer.chars[BUFFER_HEIGHT - 1][0]
+                .read()
+                .ascii_character,
+            b'x'
+        );
+        // scrolling redraws every row from the shadow buffer
+        writer.write_byte(b'\n');
+        assert_ne!(writer.buffer.chars[0][0].read(), marker);
+    });
+}
+
+// the timer interrupt prints while the test prints, the writer must never be locked when the interrupt arrives
+#[test_case]
+fn test_println_while_interrupt_handler_prints() {
+    use crate::interrupts::{register_irq_handler, InterruptIndex};
+    use core::sync::atomic::{AtomicU64, Ordering};
+    static PRINTED: AtomicU64 = AtomicU64::new(0);
+
+    fn printing_timer_handler() {
+        crate::timer::timer_interrupt_handler();
+        print!("i");
+        PRINTED.fetch_add(1, Ordering::Relaxed);
+    }
+    let timer = InterruptIndex::Timer.irq();
+    register_irq_handler(timer, printing_timer_handler);
+    while PRINTED.load(Ordering::Relaxed) < 20 {
+        println!("the timer interrupt prints while this line is printed");
+    }
+    register_irq_handler(timer, crate::timer::timer_interrupt_handler);
 }
@@ -0,0 +1,218 @@
+/*
+* Terminals are controlled with ANSI ESCAPE SEQUENCES embedded in the printed text, most of them are
+* CONTROL_SEQUENCE_INTRODUCER (CSI) sequences: ESC [ followed by numeric parameters separated by ;
+* and a final letter choosing the command, e.g. "\x1b[31m" (red foreground) or "\x1b[5;10H" (move the
+* cursor to row 5 column 10).
+*
+* The parser is a small state machine that is fed one byte at a time, normal bytes are returned as
+* Print actions and complete escape sequences as the matching action. Unsupported or malformed
+* sequences are dropped so they don't show up as garbage on the screen.
+* */
+
+const ESC: u8 = 0x1b;
+const MAX_PARAMS: usize = 8;
+
+// the numeric parameters of a control sequence, missing parameters are 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Params {
+    values: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl Params {
+    const fn new() -> Params {
+        Params {
+            values: [0; MAX_PARAMS],
+            len: 0,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.values[..self.len].iter().copied()
+    }
+
+    // the parameter at the index, using the default if it is missing or 0
+    pub fn get_or(&self, index: usize, default: u16) -> u16 {
+        match self.values[..self.len].get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Print(u8),
+    // SELECT_GRAPHIC_RENDITION (CSI ... m): colors and attributes
+    SetGraphics(Params),
+    // CSI row ; col H (or f), 1 based
+    CursorPosition { row: u16, col: u16 },
+    CursorUp(u16),
+    CursorDown(u16),
+    CursorForward(u16),
+    CursorBack(u16),
+    // CSI n J: 0 = cursor to end of screen, 1 = start of screen to cursor, 2 = whole screen
+    EraseDisplay(u16),
+    // CSI n K: 0 = cursor to end of line, 1 = start of line to cursor, 2 = whole line
+    EraseLine(u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+pub struct Parser {
+    state: State,
+    params: Params,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            params: Params::new(),
+        }
+    }
+
+    pub fn advance(&mut self, byte: u8) -> Option<Action> {
+        match self.state {
+            State::Ground => {
+                if byte == ESC {
+                    self.state = State::Escape;
+                    None
+                } else {
+                    Some(Action::Print(byte))
+                }
+            }
+            State::Escape => {
+                if byte == b'[' {
+                    self.params = Params::new();
+                    self.state = State::Csi;
+                } else {
+                    // other escape sequences (e.g. ESC c) are not supported
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::Csi => self.advance_csi(byte),
+        }
+    }
+
+    fn advance_csi(&mut self, byte: u8) -> Option<Action> {
+        match byte {
+            b'0'..=b'9' => {
+                if self.params.len == 0 {
+                    self.params.len = 1;
+                }
+                if let Some(value) = self.params.values.get_mut(self.params.len - 1) {
+                    *value = value
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as u16);
+                }
+                None
+            }
+            b';' => {
+                // an empty first parameter (e.g. "\x1b[;5H") still counts as a parameter
+                if self.params.len == 0 {
+                    self.params.len = 1;
+                }
+                if self.params.len < MAX_PARAMS {
+                    self.params.len += 1;
+                }
+                None
+            }
+            // private mode or intermediate bytes (e.g. "\x1b[?25l"), kept in the CSI state until the final byte
+            0x20..=0x2f | b'<'..=b'?' => None,
+            // the final byte of the sequence
+            0x40..=0x7e => {
+                self.state = State::Ground;
+                let params = self.params;
+                Some(match byte {
+                    b'm' => Action::SetGraphics(params),
+                    b'H' | b'f' => Action::CursorPosition {
+                        row: params.get_or(0, 1),
+                        col: params.get_or(1, 1),
+                    },
+                    b'A' => Action::CursorUp(params.get_or(0, 1)),
+                    b'B' => Action::CursorDown(params.get_or(0, 1)),
+                    b'C' => Action::CursorForward(params.get_or(0, 1)),
+                    b'D' => Action::CursorBack(params.get_or(0, 1)),
+                    b'J' => Action::EraseDisplay(params.get_or(0, 0)),
+                    b'K' => Action::EraseLine(params.get_or(0, 0)),
+                    _ => return None,
+                })
+            }
+            // anything else aborts the sequence
+            _ => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+fn parse_last(bytes: &[u8]) -> Option<Action> {
+    let mut parser = Parser::new();
+    let mut last = None;
+    for &byte in bytes {
+        last = parser.advance(byte);
+    }
+    last
+}
+
+#[test_case]
+fn test_parse_cursor_position() {
+    assert_eq!(
+        parse_last(b"\x1b[5;10H"),
+        Some(Action::CursorPosition { row: 5, col: 10 })
+    );
+    // missing parameters default to 1
+    assert_eq!(
+        parse_last(b"\x1b[H"),
+        Some(Action::CursorPosition { row: 1, col: 1 })
+    );
+    assert_eq!(
+        parse_last(b"\x1b[;7f"),
+        Some(Action::CursorPosition { row: 1, col: 7 })
+    );
+    assert_eq!(parse_last(b"\x1b[A"), Some(Action::CursorUp(1)));
+}
+
+#[test_case]
+fn test_parse_graphics_and_erase() {
+    match parse_last(b"\x1b[1;31;44m") {
+        Some(Action::SetGraphics(params)) => {
+            let mut values = params.iter();
+            assert_eq!(values.next(), Some(1));
+            assert_eq!(values.next(), Some(31));
+            assert_eq!(values.next(), Some(44));
+            assert_eq!(values.next(), None);
+        }
+        other => panic!("unexpected action {:?}", other),
+    }
+    assert_eq!(parse_last(b"\x1b[2J"), Some(Action::EraseDisplay(2)));
+    assert_eq!(parse_last(b"\x1b[K"), Some(Action::EraseLine(0)));
+}
+
+#[test_case]
+fn test_unsupported_sequences_are_dropped() {
+    let mut parser = Parser::new();
+    for &byte in b"\x1b[?25l\x1bc" {
+        assert_eq!(parser.advance(byte), None);
+    }
+    assert_eq!(parser.advance(b'a'), Some(Action::Print(b'a')));
+}
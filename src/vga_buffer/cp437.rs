@@ -0,0 +1,73 @@
+/*
+* The VGA text mode font (and the framebuffer font, which is a copy of it) is CODE PAGE 437, the character set
+* of the original IBM PC: ASCII in the printable range, symbols (smileys, card suits, arrows) in place of the
+* control characters 0x01-0x1F and 0x7F, and accented latin letters, greek letters, math symbols and box
+* drawing characters in 0x80-0xFF.
+*
+* The strings are UTF-8, so the writers look up the code page 437 byte of every character that isn't ASCII.
+* A few characters without a glyph are shown with one that looks alike (typographic quotes and dashes), the
+* rest with ■ (0xFE).
+* */
+
+// the fallback for the characters without a glyph
+pub const UNKNOWN: u8 = 0xFE;
+
+// the characters of the glyphs 0x00-0x1F, 0x00 is empty
+const LOW: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', //
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+// the characters of the glyphs 0x80-0xFF
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', //
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', //
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', //
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐', //
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧', //
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀', //
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', //
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+// the byte of the glyph that shows the character
+pub fn encode(character: char) -> u8 {
+    if character.is_ascii() && character != '\x7F' {
+        return character as u8;
+    }
+    if let Some(index) = HIGH.iter().position(|&glyph| glyph == character) {
+        return 0x80 + index as u8;
+    }
+    if let Some(index) = LOW.iter().skip(1).position(|&glyph| glyph == character) {
+        return 1 + index as u8;
+    }
+    match character {
+        '⌂' => 0x7F,
+        // the letters that share a glyph with another one
+        'β' => 0xE1,
+        'μ' => 0xE6,
+        'Ø' | '∅' => 0xED,
+        '∈' => 0xEE,
+        '‘' | '’' | '′' => b'\'',
+        '“' | '”' | '″' => b'"',
+        '‐' | '–' | '—' | '−' => b'-',
+        _ => UNKNOWN,
+    }
+}
+
+#[test_case]
+fn test_encode() {
+    assert_eq!(encode('A'), b'A');
+    assert_eq!(encode('ö'), 0x94);
+    assert_eq!(encode('é'), 0x82);
+    assert_eq!(encode('╔'), 0xC9);
+    assert_eq!(encode('░'), 0xB0);
+    assert_eq!(encode('☺'), 0x01);
+    assert_eq!(encode('▼'), 0x1F);
+    assert_eq!(encode('⌂'), 0x7F);
+    assert_eq!(encode('—'), b'-');
+    // ■ has its own glyph, the characters without one get it too
+    assert_eq!(encode('■'), UNKNOWN);
+    assert_eq!(encode('€'), UNKNOWN);
+    assert_eq!(encode('\u{1F600}'), UNKNOWN);
+}
@@ -0,0 +1,446 @@
+/*
+* QEMU's paravirtualized disk (-drive if=virtio): instead of emulating the registers of real hardware
+* the device and the driver share VIRTQUEUES in memory, so a whole request costs one port write and one
+* interrupt instead of one port access per 2 bytes like ATA PIO. We use the legacy PCI transport (the
+* transitional device 1AF4:1001) whose registers are in the I/O BAR 0.
+*
+* A virtqueue of N entries has three parts in physically contiguous memory:
+*  * the descriptor table: N buffers (physical address, length, flags, index of the next in a chain)
+*  * the available ring, written by the driver: the first descriptors of the chains the device should process
+*  * the used ring (on the next page), written by the device: the chains it finished
+*
+* A block request is a chain of a header (read/write, sector), the data buffers and a status byte the device
+* writes. The data descriptors point directly to the caller's buffer, split into physically contiguous
+* pieces. After the chain is added to the available ring the device is notified, when it is done it adds the
+* chain to the used ring and raises its interrupt. The handler acknowledges it by reading the ISR register
+* and the waiting request sees the used ring advance. One request is in flight at a time.
+* */
+use crate::block::{self, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::dma::DmaBuffer;
+use crate::interrupts;
+use crate::memory;
+use crate::pci::{self, Bar, DeviceMatch, PciDevice, PciDriver};
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+const TIMEOUT_MS: u64 = 2000;
+
+// the legacy registers, offsets from the I/O BAR
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0C;
+const REG_QUEUE_SELECT: u16 = 0x0E;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+const REG_ISR_STATUS: u16 = 0x13;
+// the block device configuration follows the common registers (without MSI-X)
+const REG_CAPACITY: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+const FEATURE_READ_ONLY: u32 = 1 << 5;
+const FEATURE_FLUSH: u32 = 1 << 9;
+
+const DESCRIPTOR_NEXT: u16 = 1;
+const DESCRIPTOR_WRITE: u16 = 2;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+// the value of the status byte before the device wrote it
+const REQUEST_PENDING: u8 = 0xFF;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    address: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+// the offsets of the available and used rings and the total size of a legacy virtqueue with size entries
+fn queue_layout(size: usize) -> (usize, usize, usize) {
+    let align = |value: usize| value.div_ceil(4096) * 4096;
+    let available = size * 16;
+    // flags, index, the ring and the used event
+    let used = align(available + 2 * (3 + size));
+    // flags, index, the ring of (id, length) and the available event
+    let total = used + align(2 * 3 + 8 * size);
+    (available, used, total)
+}
+
+struct Queue {
+    size: u16,
+    // the descriptor table, then the available and used rings at these offsets
+    memory: DmaBuffer,
+    available: usize,
+    used: usize,
+    // the used index the driver has seen, the device is done when it moves on
+    last_used: u16,
+    // the header and status byte of the request
+    request: DmaBuffer,
+}
+
+impl Queue {
+    fn descriptor(&self, index: usize) -> *mut Descriptor {
+        self.memory.pointer(index * size_of::<Descriptor>())
+    }
+
+    // the entry of the available ring: flags, index, ring
+    fn available(&self, index: usize) -> *mut u16 {
+        self.memory.pointer(self.available + index * 2)
+    }
+
+    fn header(&self) -> *mut RequestHeader {
+        self.request.pointer(0)
+    }
+
+    // the status byte, right after the header
+    fn status(&self) -> *mut u8 {
+        self.request.pointer(size_of::<RequestHeader>())
+    }
+
+    fn used_index(&self) -> u16 {
+        // the used ring starts with the flags then the index
+        unsafe { self.memory.pointer::<u16>(self.used + 2).read_volatile() }
+    }
+}
+
+// split the buffer into physically contiguous pieces, the pages of a virtual buffer can be anywhere
+fn physical_segments(buffer: VirtAddr, len: usize) -> Option<Vec<(PhysAddr, usize)>> {
+    let mut segments: Vec<(PhysAddr, usize)> = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let address = buffer + offset as u64;
+        let in_page = (4096 - address.as_u64() % 4096) as usize;
+        let chunk = in_page.min(len - offset);
+        let phys = memory::translate_addr(address)?;
+        match segments.last_mut() {
+            Some((start, segment_len)) if *start + *segment_len as u64 == phys => {
+                *segment_len += chunk;
+            }
+            _ => segments.push((phys, chunk)),
+        }
+        offset += chunk;
+    }
+    Some(segments)
+}
+
+pub struct VirtioBlk {
+    address: pci::PciAddress,
+    // vda is the first disk, vdb the second...
+    number: usize,
+    io_base: u16,
+    irq: u8,
+    sectors: u64,
+    pub read_only: bool,
+    flush: bool,
+    queue: Mutex<Queue>,
+}
+
+impl VirtioBlk {
+    fn read_u8(&self, register: u16) -> u8 {
+        unsafe { Port::new(self.io_base + register).read() }
+    }
+
+    fn setup(device: &'static PciDevice, number: usize) -> Option<VirtioBlk> {
+        let Some(Bar::Io { port, .. }) = device.bars[0] else {
+            return None;
+        };
+        device.enable(pci::COMMAND_IO_SPACE | pci::COMMAND_BUS_MASTER);
+        let io = |register: u16| port + register;
+        let write_status = |status: u8| unsafe { Port::new(io(REG_DEVICE_STATUS)).write(status) };
+        // reset the device, then tell it that we found it and know how to drive it
+        write_status(0);
+        write_status(STATUS_ACKNOWLEDGE);
+        write_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let features: u32 = unsafe { Port::new(io(REG_DEVICE_FEATURES)).read() };
+        let accepted = features & (FEATURE_READ_ONLY | FEATURE_FLUSH);
+        unsafe {
+            Port::new(io(REG_GUEST_FEATURES)).write(accepted);
+            Port::<u16>::new(io(REG_QUEUE_SELECT)).write(0);
+        }
+        let size: u16 = unsafe { Port::new(io(REG_QUEUE_SIZE)).read() };
+        // a request needs at least the header, one data buffer and the status
+        if size < 3 {
+            write_status(STATUS_FAILED);
+            return None;
+        }
+        let (available, used, total) = queue_layout(size as usize);
+        let (Some(queue), Some(request)) = (
+            DmaBuffer::new(total, 4096),
+            DmaBuffer::new(size_of::<RequestHeader>() + 1, 16),
+        ) else {
+            write_status(STATUS_FAILED);
+            return None;
+        };
+        unsafe {
+            // the device takes the page number of the queue
+            Port::<u32>::new(io(REG_QUEUE_ADDRESS))
+                .write((queue.phys_addr().as_u64() / 4096) as u32);
+        }
+        write_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+
+        let capacity_low: u32 = unsafe { Port::new(io(REG_CAPACITY)).read() };
+        let capacity_high: u32 = unsafe { Port::new(io(REG_CAPACITY + 4)).read() };
+        Some(VirtioBlk {
+            address: device.address,
+            number,
+            io_base: port,
+            irq: device.interrupt_line,
+            sectors: (capacity_high as u64) << 32 | capacity_low as u64,
+            read_only: accepted & FEATURE_READ_ONLY != 0,
+            flush: accepted & FEATURE_FLUSH != 0,
+            queue: Mutex::new(Queue {
+                size,
+                memory: queue,
+                available,
+                used,
+                last_used: 0,
+                request,
+            }),
+        })
+    }
+
+    // the largest request in bytes, its buffer spans at most one page more than its length
+    fn max_request(&self) -> usize {
+        let data_descriptors = (self.queue.lock().size as usize - 2).min(64);
+        (data_descriptors - 1) * 4096
+    }
+
+    // submit one request and wait for the device to finish it
+    fn request(
+        &self,
+        kind: u32,
+        sector: u64,
+        buffer: VirtAddr,
+        len: usize,
+    ) -> Result<(), BlockError> {
+        let segments = physical_segments(buffer, len).ok_or(BlockError::BufferSize)?;
+        let mut queue = self.queue.lock();
+        unsafe {
+            queue.header().write(RequestHeader {
+                kind,
+                reserved: 0,
+                sector,
+            });
+            queue.status().write_volatile(REQUEST_PENDING);
+        }
+        // the device writes to the buffer of a read and the status
+        let data_flags = if kind == REQUEST_IN {
+            DESCRIPTOR_WRITE
+        } else {
+            0
+        };
+        let header = (queue.request.phys_addr(), size_of::<RequestHeader>(), 0);
+        let status = (
+            queue.request.phys_at(size_of::<RequestHeader>()),
+            1,
+            DESCRIPTOR_WRITE,
+        );
+        let chain = core::iter::once(header)
+            .chain(segments.iter().map(|&(phys, len)| (phys, len, data_flags)))
+            .chain(core::iter::once(status));
+        let count = segments.len() + 2;
+        for (index, (address, len, flags)) in chain.enumerate() {
+            let next = if index + 1 < count {
+                DESCRIPTOR_NEXT
+            } else {
+                0
+            };
+            unsafe {
+                queue.descriptor(index).write_volatile(Descriptor {
+                    address: address.as_u64(),
+                    len: len as u32,
+                    flags: flags | next,
+                    next: index as u16 + 1,
+                });
+            }
+        }
+        // the available ring: flags, index, ring; the chain starts at descriptor 0
+        unsafe {
+            let index = queue.available(1).read_volatile();
+            queue
+                .available(2 + (index % queue.size) as usize)
+                .write_volatile(0);
+            // the device must see the descriptors before the new index
+            fence(Ordering::SeqCst);
+            queue.available(1).write_volatile(index.wrapping_add(1));
+            fence(Ordering::SeqCst);
+            Port::<u16>::new(self.io_base + REG_QUEUE_NOTIFY).write(0);
+        }
+        self.wait(&queue)?;
+        queue.last_used = queue.last_used.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        match unsafe { queue.status().read_volatile() } {
+            0 => Ok(()),
+            status => Err(BlockError::Device(status)),
+        }
+    }
+
+    // wait for the used ring to move on, halting until the completion interrupt (or a timer tick)
+    fn wait(&self, queue: &Queue) -> Result<(), BlockError> {
+        use x86_64::instructions::interrupts as cpu;
+
+        let deadline = crate::timer::uptime_ms() + TIMEOUT_MS;
+        loop {
+            let enabled = cpu::are_enabled();
+            cpu::disable();
+            if queue.used_index() != queue.last_used {
+                if enabled {
+                    cpu::enable();
+                }
+                return Ok(());
+            }
+            if crate::timer::uptime_ms() > deadline {
+                if enabled {
+                    cpu::enable();
+                }
+                return Err(BlockError::Timeout);
+            }
+            if enabled {
+                cpu::enable_and_hlt();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> String {
+        alloc::format!("vd{}", (b'a' + self.number as u8) as char)
+    }
+
+    fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_range(self, lba, buffer.len())?;
+        let max = self.max_request();
+        for (index, chunk) in buffer.chunks_mut(max).enumerate() {
+            let sector = lba + (index * max / SECTOR_SIZE) as u64;
+            let address = VirtAddr::from_ptr(chunk.as_mut_ptr());
+            self.request(REQUEST_IN, sector, address, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_range(self, lba, buffer.len())?;
+        let max = self.max_request();
+        for (index, chunk) in buffer.chunks(max).enumerate() {
+            let sector = lba + (index * max / SECTOR_SIZE) as u64;
+            self.request(
+                REQUEST_OUT,
+                sector,
+                VirtAddr::from_ptr(chunk.as_ptr()),
+                chunk.len(),
+            )?;
+        }
+        if self.flush {
+            self.request(REQUEST_FLUSH, 0, VirtAddr::zero(), 0)?;
+        }
+        Ok(())
+    }
+}
+
+static DRIVES: Mutex<Vec<&'static VirtioBlk>> = Mutex::new(Vec::new());
+
+// the interrupt handler takes the lock too, so it is only taken with interrupts disabled
+pub fn drives() -> Vec<&'static VirtioBlk> {
+    interrupts_disabled(|| DRIVES.lock().clone())
+}
+
+fn interrupts_disabled<R>(f: impl FnOnce() -> R) -> R {
+    x86_64::instructions::interrupts::without_interrupts(f)
+}
+
+fn irq_handler() {
+    // the PCI interrupt lines can be shared, reading the ISR register acknowledges the interrupt
+    for drive in DRIVES.lock().iter() {
+        drive.read_u8(REG_ISR_STATUS);
+    }
+}
+
+fn probe(device: &'static PciDevice) -> bool {
+    // the disks are named in the order they are found
+    let number = drives().len();
+    let Some(drive) = VirtioBlk::setup(device, number) else {
+        log::warn!("virtio-blk: {} can't be set up", device.address);
+        return false;
+    };
+    let drive: &'static VirtioBlk = Box::leak(Box::new(drive));
+    log::info!(
+        "virtio-blk: {}: {} MiB at {}{}",
+        drive.name(),
+        drive.capacity() / 1024 / 1024,
+        drive.address,
+        if drive.read_only { ", read only" } else { "" }
+    );
+    interrupts_disabled(|| DRIVES.lock().push(drive));
+    // without a routed interrupt the requests still complete, they are checked on every timer tick
+    if (drive.irq as usize) < interrupts::IRQ_LINES
+        && !interrupts::try_register_irq_handler(drive.irq, irq_handler)
+    {
+        log::warn!(
+            "virtio-blk: IRQ {} is used by another device, polling",
+            drive.irq
+        );
+    }
+    block::register(drive);
+    true
+}
+
+static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    matches: &[DeviceMatch::Id {
+        vendor_id: 0x1AF4,
+        device_id: 0x1001,
+    }],
+    probe,
+};
+
+// register the driver, the disks are set up when the PCI devices are probed
+pub fn init() {
+    pci::register_driver(&DRIVER);
+}
+
+#[test_case]
+fn test_queue_layout_and_physical_segments() {
+    // the queue size of QEMU's legacy virtio-blk
+    assert_eq!(queue_layout(256), (4096, 8192, 12288));
+    assert_eq!(queue_layout(16), (256, 4096, 8192));
+    // a heap buffer over several pages is split where the physical frames aren't contiguous
+    let buffer = alloc::vec![0u8; 3 * 4096];
+    let start = VirtAddr::from_ptr(buffer.as_ptr());
+    let segments = physical_segments(start, buffer.len()).unwrap();
+    assert_eq!(
+        segments.iter().map(|&(_, len)| len).sum::<usize>(),
+        buffer.len()
+    );
+    assert_eq!(segments[0].0, memory::translate_addr(start).unwrap());
+}
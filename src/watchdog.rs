@@ -0,0 +1,80 @@
+/*
+* A software WATCHDOG for the kernel main loop (the executor of kernel_main): every round of the loop feeds
+* it with the current tick, and the timer interrupt checks that the last feed isn't older than the timeout.
+* The loop halts while no task is ready, but every interrupt (at least the timer) wakes it for another round,
+* so a loop that doesn't come around for the timeout is stuck, e.g. in a task that never returns from poll
+* or waits for a lock forever. The watchdog then panics from the timer interrupt and the panic policy
+* (panic_policy) decides what happens next.
+*
+* The shell commands run in the shell task, so a command that takes longer than the timeout trips the
+* watchdog too. It is only enabled with the watchdog=<seconds> option of the command line, meant for
+* automated runs (with panic=qemu-exit), and is armed by the first feed.
+* */
+use crate::timer::{self, TICKS_PER_SECOND};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// the timeout in ticks, 0 while the watchdog is disabled
+static TIMEOUT_TICKS: AtomicU64 = AtomicU64::new(0);
+// the tick of the last feed, 0 before the first one
+static LAST_FEED: AtomicU64 = AtomicU64::new(0);
+// the watchdog fires once, the panic must not be interrupted by another one
+static FIRED: AtomicBool = AtomicBool::new(false);
+
+// read the watchdog option
+pub fn init() {
+    if let Some(seconds) = crate::cmdline::parse::<u64>("watchdog") {
+        set_timeout_ms(seconds * 1000);
+        if seconds > 0 {
+            log::info!(
+                "watchdog: the main loop must run at least every {} s",
+                seconds
+            );
+        }
+    }
+}
+
+// 0 disables the watchdog
+pub fn set_timeout_ms(ms: u64) {
+    TIMEOUT_TICKS.store(ms * TICKS_PER_SECOND as u64 / 1000, Ordering::SeqCst);
+}
+
+pub fn timeout_ms() -> u64 {
+    TIMEOUT_TICKS.load(Ordering::SeqCst) * 1000 / TICKS_PER_SECOND as u64
+}
+
+// called by the main loop on every round
+pub fn feed() {
+    // tick 0 is the value of a watchdog that wasn't fed yet
+    LAST_FEED.store(timer::ticks().max(1), Ordering::Relaxed);
+}
+
+fn is_expired(ticks: u64, last_feed: u64, timeout: u64) -> bool {
+    timeout != 0 && last_feed != 0 && ticks.saturating_sub(last_feed) > timeout
+}
+
+// called by the timer interrupt handler on every tick
+pub(crate) fn check(ticks: u64) {
+    let last_feed = LAST_FEED.load(Ordering::Relaxed);
+    if !is_expired(ticks, last_feed, TIMEOUT_TICKS.load(Ordering::Relaxed)) {
+        return;
+    }
+    if FIRED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    panic!(
+        "watchdog: the kernel main loop didn't run for {} ms",
+        (ticks - last_feed) * 1000 / TICKS_PER_SECOND as u64
+    );
+}
+
+#[test_case]
+fn test_watchdog_expires_after_the_timeout() {
+    // the tests don't run the main loop, so the watchdog is never armed
+    assert_eq!(LAST_FEED.load(Ordering::Relaxed), 0);
+    assert!(!is_expired(100, 0, 10));
+    assert!(!is_expired(100, 95, 0));
+    assert!(!is_expired(100, 90, 10));
+    assert!(is_expired(101, 90, 10));
+    // a feed between reading the tick and the check
+    assert!(!is_expired(100, 101, 10));
+}
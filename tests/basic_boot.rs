@@ -0,0 +1,37 @@
+/*
+* Integration tests are separate executables, this one runs right after boot without calling init
+* so it checks that printing works before the GDT, IDT, paging and heap are set up.
+* */
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::println;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    test_main();
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn test_println() {
+    println!("test_println output");
+}
+
+#[test_case]
+fn test_println_many() {
+    for i in 0..200 {
+        println!("test_println_many output {}", i);
+    }
+}
@@ -0,0 +1,70 @@
+/*
+* A test that passes when executing a data page faults. The kernel's page fault handler would panic, so
+* this binary loads its own IDT whose page fault handler reports success to QEMU when the fault is an
+* instruction fetch. A `ret` instruction is written to a static in .data (writable, no execute after
+* protection::init) and called, returning from it means the page was executable.
+* */
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use rust_os::{
+    allocator, exit_qemu, memory, protection, serial_print, serial_println, QemuExitCode,
+};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+
+// ret
+static mut CODE: [u8; 1] = [0xC3];
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        idt.page_fault.set_handler_fn(test_page_fault_handler);
+        idt
+    };
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    serial_print!("nx::execute_data_page...\t");
+    rust_os::gdt::init();
+    TEST_IDT.load();
+    unsafe {
+        memory::init(
+            x86_64::VirtAddr::new(boot_info.physical_memory_offset),
+            &boot_info.memory_map,
+        )
+    };
+    allocator::init_heap().expect("heap initialization failed");
+    protection::init();
+
+    let code: extern "C" fn() = unsafe { core::mem::transmute(&raw const CODE) };
+    code();
+    // the data page was executable
+    serial_println!("[test did not fault]");
+    exit_qemu(QemuExitCode::Failed);
+    rust_os::hlt_loop();
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[unexpected page fault: {:?}]", error_code);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    rust_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
@@ -0,0 +1,33 @@
+/*
+* A test that passes when it panics. The custom test frameworks can't continue after a panic
+* (there is no unwinding) so this binary has no test runner (harness = false in Cargo.toml),
+* it runs a single test from its entry point and the panic handler reports success to QEMU.
+* */
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    should_fail();
+    // the test returned without panicking
+    serial_println!("[test did not panic]");
+    exit_qemu(QemuExitCode::Failed);
+    rust_os::hlt_loop();
+}
+
+fn should_fail() {
+    serial_print!("should_panic::should_fail...\t");
+    assert_eq!(0, 1);
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    rust_os::hlt_loop();
+}
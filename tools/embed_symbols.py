@@ -0,0 +1,134 @@
+#!/usr/bin/env python3
+"""
+Fill the .ksymtab section of the kernel ELF file with its function symbols (see src/symbols.rs).
+
+    embed_symbols.py <kernel> [args...]      embed the symbols, then boot it with `bootimage runner`
+    embed_symbols.py --embed-only <kernel>   only embed the symbols
+
+The kernel reserves the section with a fixed size, so writing the table into it doesn't move any code
+or data and the addresses in the table stay valid. If the table doesn't fit only the symbols that fit
+are stored, and a kernel that can't get a table is booted without one (the backtraces only show
+addresses then), the runner always boots. Only the python standard library is used.
+"""
+import os
+import struct
+import sys
+
+SECTION = b".ksymtab"
+MAGIC = b"KSYM"
+HEADER_SIZE = 8
+ENTRY_SIZE = 24
+SHT_SYMTAB = 2
+SHT_NOBITS = 8
+STT_FUNC = 2
+
+
+class EmbedError(Exception):
+    pass
+
+
+def read_sections(elf):
+    if elf[:4] != b"\x7fELF" or elf[4] != 2 or elf[5] != 1:
+        raise EmbedError("not a little endian ELF64 file")
+    shoff, = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    sections = []
+    for index in range(shnum):
+        (name, kind, _flags, _addr, offset, size, link, _info, _align, entsize) = \
+            struct.unpack_from("<IIQQQQIIQQ", elf, shoff + index * shentsize)
+        sections.append(dict(name=name, kind=kind, offset=offset, size=size, link=link,
+                             entsize=entsize))
+    names = sections[shstrndx]
+    for section in sections:
+        start = names["offset"] + section["name"]
+        section["name"] = elf[start:elf.index(b"\0", start)]
+    return sections
+
+
+def function_symbols(elf, sections):
+    symtab = next((s for s in sections if s["kind"] == SHT_SYMTAB), None)
+    if symtab is None:
+        raise EmbedError("the kernel has no symbol table (was it stripped?)")
+    strtab = sections[symtab["link"]]
+    symbols = {}
+    for offset in range(symtab["offset"], symtab["offset"] + symtab["size"], symtab["entsize"]):
+        name, info, _other, shndx, value, size = struct.unpack_from("<IBBHQQ", elf, offset)
+        if info & 0xF != STT_FUNC or shndx == 0 or value == 0:
+            continue
+        start = strtab["offset"] + name
+        # aliases share an address, keep the first name
+        symbols.setdefault(value, (size, elf[start:elf.index(b"\0", start)]))
+    return sorted((address, size, name) for address, (size, name) in symbols.items())
+
+
+def table_size(symbols):
+    return HEADER_SIZE + sum(ENTRY_SIZE + len(name) for _address, _size, name in symbols)
+
+
+# the number of symbols (from the lowest address) whose entries and names fit in the capacity
+def symbols_that_fit(symbols, capacity):
+    used = HEADER_SIZE
+    for count, (_address, _size, name) in enumerate(symbols):
+        used += ENTRY_SIZE + len(name)
+        if used > capacity:
+            return count
+    return len(symbols)
+
+
+def build_table(symbols, capacity):
+    names_start = HEADER_SIZE + len(symbols) * ENTRY_SIZE
+    entries = bytearray()
+    names = bytearray()
+    for address, size, name in symbols:
+        entries += struct.pack("<QIIII", address, min(size, 0xFFFF_FFFF), names_start + len(names),
+                               len(name), 0)
+        names += name
+    table = MAGIC + struct.pack("<I", len(symbols)) + entries + names
+    return table + bytes(capacity - len(table))
+
+
+def warn(message):
+    print("embed_symbols: warning: " + message, file=sys.stderr)
+
+
+def embed(path):
+    with open(path, "rb") as file:
+        elf = bytearray(file.read())
+    sections = read_sections(elf)
+    section = next((s for s in sections if s["name"] == SECTION), None)
+    if section is None or section["kind"] == SHT_NOBITS:
+        raise EmbedError("the kernel has no {} section with file contents".format(SECTION.decode()))
+    symbols = function_symbols(elf, sections)
+    capacity = section["size"]
+    count = symbols_that_fit(symbols, capacity)
+    if count < len(symbols):
+        warn("the symbol table needs {} bytes but {} only has {}, storing {} of {} symbols "
+             "(set RUST_OS_SYMBOL_TABLE_KIB to at least {} when building)".format(
+                 table_size(symbols), SECTION.decode(), capacity, count, len(symbols),
+                 -(-table_size(symbols) // 1024)))
+    table = build_table(symbols[:count], capacity)
+    elf[section["offset"]:section["offset"] + capacity] = table
+    with open(path, "wb") as file:
+        file.write(elf)
+    return count
+
+
+def main(args):
+    if len(args) == 2 and args[0] == "--embed-only":
+        try:
+            embed(args[1])
+        except EmbedError as error:
+            sys.exit("embed_symbols: " + str(error))
+        return
+    if not args:
+        sys.exit(__doc__)
+    try:
+        embed(args[0])
+    except EmbedError as error:
+        # a missing table only costs the function names in the backtraces, not the test run
+        warn("{}, booting without symbols".format(error))
+    os.execvp("bootimage", ["bootimage", "runner"] + args)
+
+
+if __name__ == "__main__":
+    main(sys.argv[1:])